@@ -33,7 +33,12 @@ start() {
 
 stop_sub() {
   echo "stoping pqx subscriber"
-  kill `pidof subscriber`
+  SUB_PID=`pidof subscriber`
+  kill $SUB_PID
+  # wait for in-flight tasks to drain
+  while kill -0 $SUB_PID 2> /dev/null; do
+    sleep 1
+  done
   # SUB_PID=`cat subscriber.pid`
   # if [ -n "$SUB_PID" ]
   # then
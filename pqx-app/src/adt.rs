@@ -4,18 +4,24 @@
 //! brief:
 
 use std::collections::HashMap;
+use std::str::FromStr;
 
-use chrono::Local;
-use pqx::amqprs::BasicProperties;
+use chrono::{DateTime, Local};
+use pqx::amqprs::{BasicProperties, FieldTable};
 use pqx::ec::CmdArg;
-use pqx::error::PqxError;
-use pqx::mq::FieldTableBuilder;
+use pqx::error::{PqxError, PqxResult};
+use pqx::mq::{
+    epoch_millis, gen_message_id, next_hop, Aggregate, AggregatePolicy, Overflow, PqxHeaders,
+    QueueType, ReplyStatus, RetryPolicy, MAX_DELAY, X_DELIVERY_LIMIT, X_MAX_AGE, X_MAX_LENGTH,
+    X_MAX_LENGTH_BYTES, X_OVERFLOW,
+};
 use pqx::pqx_custom_err;
 use sea_orm::Set;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::entities::{message_history, message_result};
+use crate::entities::{message_aggregate, message_history, message_result};
+use crate::routing::{stored_recipients, Recipient};
 
 // ================================================================================================
 // MailingTo & Command
@@ -27,11 +33,17 @@ pub struct Config {
     pub poke: Option<u16>,
     pub waiting_timeout: Option<u32>,
     pub consuming_timeout: Option<u32>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub priority: Option<u8>, // effective up to `max_priority` of the header queue
+    pub run_at: Option<DateTime<Local>>, // run once at, e.g. "2023-07-28T02:00:00+08:00"
+    pub delay: Option<u32>,   // seconds, run once after being published, unless `run_at` is set
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Command {
-    pub mailing_to: Vec<HashMap<String, String>>,
+    #[serde(default)]
+    pub message_id: Option<String>, // stable across redeliveries and republishing, for dedup
+    pub mailing_to: Vec<Recipient>, // criteria (header routing) or routing keys (topic routing)
     pub config: Config,
     pub cmd: CmdArg,
 }
@@ -39,13 +51,58 @@ pub struct Command {
 impl Command {
     pub fn new(cmd: CmdArg) -> Self {
         Self {
+            message_id: None,
             mailing_to: Vec::new(),
             config: Config::default(),
             cmd,
         }
     }
 
-    pub fn mailing_to(&self) -> &[HashMap<String, String>] {
+    pub fn message_id(&self) -> Option<&str> {
+        self.message_id.as_deref()
+    }
+
+    // generate a `message_id` if absent, call it once before (re)publishing
+    pub fn ensure_message_id(&mut self) -> &str {
+        self.message_id.get_or_insert_with(gen_message_id)
+    }
+
+    // a new `message_id`, so that a replayed command is not taken as a duplicate
+    pub fn renew_message_id(&mut self) -> &str {
+        self.message_id.insert(gen_message_id())
+    }
+
+    // whether to run at a future time, published to the delayed exchange
+    pub fn scheduled(&self) -> bool {
+        self.config.run_at.is_some() || self.config.delay.is_some()
+    }
+
+    // turn `delay` into `run_at` (from now), call it once before publishing
+    pub fn ensure_run_at(&mut self) -> Option<DateTime<Local>> {
+        if let (None, Some(d)) = (self.config.run_at, self.config.delay) {
+            self.config.run_at = Some(Local::now() + chrono::Duration::seconds(d.into()));
+        }
+
+        self.config.run_at
+    }
+
+    // read back from `message_history` or a dead letter queue, where `mailing_to` may be of the
+    // former format (see `routing::stored_recipients`)
+    pub fn from_stored(mut value: Value) -> PqxResult<Self> {
+        let mailing_to = value
+            .get_mut("mailing_to")
+            .map(Value::take)
+            .unwrap_or_default();
+        let mailing_to = stored_recipients(mailing_to)?;
+        value["mailing_to"] = Value::Array(Vec::new());
+
+        let mut cmd: Self = serde_json::from_value(value)?;
+        cmd.mailing_to = mailing_to;
+
+        Ok(cmd)
+    }
+
+    pub fn mailing_to(&self) -> &[Recipient] {
         &self.mailing_to
     }
 
@@ -56,6 +113,66 @@ impl Command {
     pub fn cmd(&self) -> &CmdArg {
         &self.cmd
     }
+
+    pub fn apply_patch(&mut self, patch: &CommandPatch) -> &mut Self {
+        if let Some(mt) = &patch.mailing_to {
+            self.mailing_to = mt.clone();
+        }
+        if let Some(c) = &patch.config {
+            self.config = c.clone();
+        }
+
+        self
+    }
+
+    // message headers derived from `config`, shared by every `mailing_to`
+    pub fn config_headers(&self) -> PqxResult<FieldTable> {
+        // a scheduled command is delayed by its first hop (none if due), instead of `poke`
+        let run_at = self.config.run_at.map(|t| t.timestamp_millis());
+        let x_delay = match run_at {
+            Some(r) => next_hop(r, epoch_millis(), MAX_DELAY),
+            None => self.config.poke.map(|p| i32::from(p) * 1000),
+        };
+
+        // durations are converted to milliseconds
+        let headers = PqxHeaders {
+            x_retries: self.config.retry.map(i16::from),
+            x_delay,
+            x_run_at: run_at,
+            x_message_ttl: self.config.waiting_timeout.map(|t| i64::from(t) * 1000),
+            x_consume_ttl: self.config.consuming_timeout.map(|t| i64::from(t) * 1000),
+            ..Default::default()
+        };
+
+        FieldTable::try_from(&headers)
+    }
+
+    // properties of a message sent to `recipient`: config headers & criterion headers (if any),
+    // priority and message id. The routing key of a recipient is `Recipient::routing_key`
+    pub fn props(&self, recipient: &Recipient) -> PqxResult<BasicProperties> {
+        let mut headers = self.config_headers()?;
+        if let Recipient::Criterion(c) = recipient {
+            c.insert_into(&mut headers)?;
+        }
+
+        let mut props = BasicProperties::default();
+        props.with_headers(headers);
+        if let Some(p) = self.config.priority {
+            props.with_priority(p);
+        }
+        if let Some(id) = &self.message_id {
+            props.with_message_id(id);
+        }
+
+        Ok(props)
+    }
+}
+
+// fields to overwrite when a `Command` is replayed
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CommandPatch {
+    pub mailing_to: Option<Vec<Recipient>>,
+    pub config: Option<Config>,
 }
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
@@ -66,11 +183,18 @@ impl<'a> TryFrom<&'a Command> for message_history::ActiveModel {
 
     fn try_from(cmd: &'a Command) -> Result<Self, Self::Error> {
         let am = message_history::ActiveModel {
+            message_id: Set(cmd.message_id.clone()),
             mailing_to: Set(serde_json::json!(cmd.mailing_to)),
             retry: Set(cmd.config.retry.map(i16::from)),
             poke: Set(cmd.config.poke.map(i32::from)),
             waiting_timeout: Set(cmd.config.waiting_timeout.map(i64::from)),
             consuming_timeout: Set(cmd.config.consuming_timeout.map(i64::from)),
+            retry_policy: Set(cmd
+                .config
+                .retry_policy
+                .as_ref()
+                .map(|p| serde_json::json!(p))),
+            priority: Set(cmd.config.priority.map(i16::from)),
             cmd: Set(serde_json::json!(cmd.cmd)),
             time: Set(Local::now()),
             ..Default::default()
@@ -86,12 +210,16 @@ impl TryFrom<message_history::Model> for Command {
 
     fn try_from(m: message_history::Model) -> Result<Self, Self::Error> {
         let res = Self {
-            mailing_to: serde_json::from_value(m.mailing_to)?,
+            message_id: m.message_id,
+            mailing_to: stored_recipients(m.mailing_to)?,
             config: Config {
                 retry: m.retry.map(u8::try_from).transpose()?,
                 poke: m.poke.map(u16::try_from).transpose()?,
                 waiting_timeout: m.waiting_timeout.map(u32::try_from).transpose()?,
                 consuming_timeout: m.consuming_timeout.map(u32::try_from).transpose()?,
+                retry_policy: m.retry_policy.map(serde_json::from_value).transpose()?,
+                priority: m.priority.map(u8::try_from).transpose()?,
+                ..Default::default()
             },
             cmd: serde_json::from_value(m.cmd)?,
         };
@@ -105,49 +233,7 @@ impl<'a> TryFrom<&'a Command> for Vec<BasicProperties> {
     type Error = PqxError;
 
     fn try_from(cmd: &'a Command) -> Result<Self, Self::Error> {
-        let headers = {
-            let mut ftb = FieldTableBuilder::new();
-
-            if let Some(r) = cmd.config.retry {
-                ftb.x_retries(r.into());
-            }
-
-            if let Some(p) = cmd.config.poke {
-                // convert to milliseconds
-                let p = i32::from(p) * 1000;
-                ftb.x_delay(p);
-            }
-
-            if let Some(t) = cmd.config.waiting_timeout {
-                // convert to milliseconds
-                let t = i64::from(t) * 1000;
-                ftb.x_message_ttl(t);
-            }
-
-            if let Some(t) = cmd.config.consuming_timeout {
-                // convert to milliseconds
-                let t = i64::from(t) * 1000;
-                ftb.x_consume_ttl(t);
-            }
-
-            ftb.finish()
-        };
-
-        let mut res = vec![];
-
-        for mt in cmd.mailing_to.iter() {
-            let mut ftb = FieldTableBuilder::from(headers.clone());
-
-            for (k, v) in mt.iter() {
-                ftb.x_common_pair(k, v);
-            }
-
-            let mut props = BasicProperties::default();
-            props.with_headers(ftb.finish());
-            res.push(props);
-        }
-
-        Ok(res)
+        cmd.mailing_to.iter().map(|mt| cmd.props(mt)).collect()
     }
 }
 
@@ -196,6 +282,74 @@ impl From<message_result::Model> for ExecutionResult {
     }
 }
 
+// ================================================================================================
+// AggregateResult
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecipientExecution {
+    pub queue: String,
+    pub status: ReplyStatus,
+    pub result: Option<ExecutionResult>,
+}
+
+// one record for a broadcast `Command`, gathering the `ExecutionResult` of each recipient queue
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AggregateResult {
+    pub policy: AggregatePolicy,
+    pub expected: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub missing: usize,
+    pub complete: bool,
+    pub passed: bool,
+    pub recipients: Vec<RecipientExecution>,
+}
+
+impl From<&Aggregate> for AggregateResult {
+    fn from(agg: &Aggregate) -> Self {
+        let recipients = agg
+            .recipients
+            .iter()
+            .map(|r| RecipientExecution {
+                queue: r.queue.clone(),
+                status: r.status,
+                result: r.reply.as_ref().and_then(|r| r.decode().ok()),
+            })
+            .collect();
+
+        Self {
+            policy: agg.policy,
+            expected: agg.expected(),
+            succeeded: agg.succeeded(),
+            failed: agg.failed(),
+            missing: agg.missing(),
+            complete: agg.complete,
+            passed: agg.passed,
+            recipients,
+        }
+    }
+}
+
+impl AggregateResult {
+    pub fn into_active_model(&self, cmd: &Command) -> message_aggregate::ActiveModel {
+        message_aggregate::ActiveModel {
+            mailing_to: Set(serde_json::json!(cmd.mailing_to)),
+            policy: Set(serde_json::json!(self.policy)),
+            expected: Set(self.expected as i32),
+            succeeded: Set(self.succeeded as i32),
+            failed: Set(self.failed as i32),
+            missing: Set(self.missing as i32),
+            complete: Set(self.complete),
+            passed: Set(self.passed),
+            detail: Set(serde_json::json!(self.recipients)),
+            cmd: Set(serde_json::json!(cmd.cmd)),
+            time: Set(Local::now()),
+            ..Default::default()
+        }
+    }
+}
+
 // ================================================================================================
 // Inspection result
 // ================================================================================================
@@ -218,13 +372,70 @@ pub struct QueueInfo {
     pub state: String,
     pub vhost: String,
     pub consumers: i64,
-    pub consumer_capacity: i64,
-    pub consumer_utilisation: i64,
+    pub consumer_capacity: f64,    // not listed for streams
+    pub consumer_utilisation: f64, // not listed for streams
+    pub messages_ready: i64,
+    pub message_bytes_ready: i64,
     pub exclusive: bool,
     pub durable: bool,
     pub arguments: HashMap<String, Value>,
 }
 
+impl QueueInfo {
+    pub fn kind(&self) -> PqxResult<QueueType> {
+        QueueType::from_str(&self.queue_type)
+    }
+
+    // quorum queue
+    pub fn delivery_limit(&self) -> Option<i64> {
+        self.arguments
+            .get(&X_DELIVERY_LIMIT.to_string())
+            .and_then(Value::as_i64)
+    }
+
+    // stream queue
+    pub fn max_age(&self) -> Option<&str> {
+        self.arguments
+            .get(&X_MAX_AGE.to_string())
+            .and_then(Value::as_str)
+    }
+
+    pub fn max_length(&self) -> Option<i64> {
+        self.arguments
+            .get(&X_MAX_LENGTH.to_string())
+            .and_then(Value::as_i64)
+    }
+
+    pub fn max_length_bytes(&self) -> Option<i64> {
+        self.arguments
+            .get(&X_MAX_LENGTH_BYTES.to_string())
+            .and_then(Value::as_i64)
+    }
+
+    pub fn overflow(&self) -> PqxResult<Overflow> {
+        match self.arguments.get(&X_OVERFLOW.to_string()) {
+            Some(Value::String(o)) => Overflow::from_str(o),
+            None => Ok(Overflow::default()),
+            _ => Err("x-overflow is not a string".into()),
+        }
+    }
+
+    // the highest ratio of ready messages (or their bytes) to the queue limits, `None` if unlimited
+    pub fn limit_usage(&self) -> Option<f64> {
+        let len = self
+            .max_length()
+            .map(|l| self.messages_ready as f64 / l.max(1) as f64);
+        let bytes = self
+            .max_length_bytes()
+            .map(|b| self.message_bytes_ready as f64 / b.max(1) as f64);
+
+        match (len, bytes) {
+            (Some(l), Some(b)) => Some(l.max(b)),
+            (l, b) => l.or(b),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct BindingInfo {
     pub source: String,
@@ -332,14 +543,20 @@ impl<'a> TryFrom<&'a Value> for QueueInfo {
             .ok_or(pqx_custom_err!("i64"))?;
         let consumer_capacity = object
             .get("consumer_capacity")
-            .ok_or(pqx_custom_err!("consumer_capacity"))?
-            .as_i64()
-            .ok_or(pqx_custom_err!("i64"))?;
+            .and_then(Value::as_f64)
+            .unwrap_or_default();
         let consumer_utilisation = object
             .get("consumer_utilisation")
-            .ok_or(pqx_custom_err!("consumer_utilisation"))?
-            .as_i64()
-            .ok_or(pqx_custom_err!("i64"))?;
+            .and_then(Value::as_f64)
+            .unwrap_or_default();
+        let messages_ready = object
+            .get("messages_ready")
+            .and_then(Value::as_i64)
+            .unwrap_or_default();
+        let message_bytes_ready = object
+            .get("message_bytes_ready")
+            .and_then(Value::as_i64)
+            .unwrap_or_default();
         let exclusive = object
             .get("exclusive")
             .ok_or(pqx_custom_err!("exclusive"))?
@@ -367,6 +584,8 @@ impl<'a> TryFrom<&'a Value> for QueueInfo {
             consumers,
             consumer_capacity,
             consumer_utilisation,
+            messages_ready,
+            message_bytes_ready,
             exclusive,
             durable,
             arguments,
@@ -427,6 +646,93 @@ impl<'a> TryFrom<&'a Value> for BindingInfo {
     }
 }
 
+// a consumer of `MqQuery::consumers`, with the connection it belongs to
+#[derive(Debug)]
+pub struct ConsumerInfo {
+    pub consumer_tag: String,
+    pub queue: String,
+    pub vhost: String,
+    pub connection_name: String,
+    pub peer_host: String,
+    pub prefetch_count: i64,
+    pub exclusive: bool,
+    pub active: bool, // not listed before RabbitMQ 3.8
+}
+
+impl<'a> TryFrom<&'a Value> for ConsumerInfo {
+    type Error = PqxError;
+
+    fn try_from(value: &'a Value) -> Result<Self, Self::Error> {
+        let object = value.as_object().ok_or(pqx_custom_err!("ConsumerInfo"))?;
+
+        let consumer_tag = object
+            .get("consumer_tag")
+            .ok_or(pqx_custom_err!("consumer_tag"))?
+            .as_str()
+            .ok_or(pqx_custom_err!("str"))?
+            .to_owned();
+        let queue = object
+            .get("queue")
+            .ok_or(pqx_custom_err!("queue"))?
+            .as_object()
+            .ok_or(pqx_custom_err!("object"))?;
+        let vhost = queue
+            .get("vhost")
+            .ok_or(pqx_custom_err!("vhost"))?
+            .as_str()
+            .ok_or(pqx_custom_err!("str"))?
+            .to_owned();
+        let queue = queue
+            .get("name")
+            .ok_or(pqx_custom_err!("name"))?
+            .as_str()
+            .ok_or(pqx_custom_err!("str"))?
+            .to_owned();
+        let channel = object
+            .get("channel_details")
+            .ok_or(pqx_custom_err!("channel_details"))?
+            .as_object()
+            .ok_or(pqx_custom_err!("object"))?;
+        let connection_name = channel
+            .get("connection_name")
+            .ok_or(pqx_custom_err!("connection_name"))?
+            .as_str()
+            .ok_or(pqx_custom_err!("str"))?
+            .to_owned();
+        let peer_host = channel
+            .get("peer_host")
+            .ok_or(pqx_custom_err!("peer_host"))?
+            .as_str()
+            .ok_or(pqx_custom_err!("str"))?
+            .to_owned();
+        let prefetch_count = object
+            .get("prefetch_count")
+            .ok_or(pqx_custom_err!("prefetch_count"))?
+            .as_i64()
+            .ok_or(pqx_custom_err!("i64"))?;
+        let exclusive = object
+            .get("exclusive")
+            .ok_or(pqx_custom_err!("exclusive"))?
+            .as_bool()
+            .ok_or(pqx_custom_err!("bool"))?;
+        let active = object
+            .get("active")
+            .and_then(Value::as_bool)
+            .unwrap_or(true);
+
+        Ok(Self {
+            consumer_tag,
+            queue,
+            vhost,
+            connection_name,
+            peer_host,
+            prefetch_count,
+            exclusive,
+            active,
+        })
+    }
+}
+
 // ================================================================================================
 // Test
 // ================================================================================================
@@ -436,9 +742,17 @@ mod test_adt {
     use pqx::pqx_util::*;
 
     use super::*;
+    use crate::routing::{Criterion, Labels};
 
     const TASK: &str = "task.json";
 
+    fn criterion(unique_key: &str) -> Recipient {
+        Recipient::Criterion(Criterion::any(Labels::from([(
+            "unique_key".to_string(),
+            unique_key.into(),
+        )])))
+    }
+
     #[test]
     fn command_se_de_success() {
         // read task.json
@@ -448,4 +762,99 @@ mod test_adt {
 
         println!("{:?}", task);
     }
+
+    #[test]
+    fn command_priority_success() {
+        let mut cmd = Command::new(CmdArg::Ping {
+            addr: "localhost".to_string(),
+        });
+        cmd.mailing_to = vec![criterion("h1")];
+        cmd.config.priority = Some(7);
+
+        let props = Vec::<BasicProperties>::try_from(&cmd).unwrap();
+        assert_eq!(props.len(), 1);
+        assert_eq!(props[0].priority(), Some(7));
+    }
+
+    #[test]
+    fn command_message_id_success() {
+        let mut cmd = Command::new(CmdArg::Ping {
+            addr: "localhost".to_string(),
+        });
+        cmd.mailing_to = vec![criterion("h1"), criterion("h2")];
+
+        // stable once generated
+        let id = cmd.ensure_message_id().to_owned();
+        assert_eq!(cmd.ensure_message_id(), id);
+
+        // shared by every recipient
+        let props = Vec::<BasicProperties>::try_from(&cmd).unwrap();
+        assert!(props.iter().all(|p| p.message_id() == Some(&id)));
+
+        assert_ne!(cmd.renew_message_id(), id);
+    }
+
+    #[test]
+    fn command_topic_success() {
+        let mut cmd: Command = serde_json::from_value(serde_json::json!({
+            "mailing_to": ["etl.daily.cn", "etl.daily.us"],
+            "config": {"retry": 2},
+            "cmd": {"Ping": {"addr": "localhost"}}
+        }))
+        .unwrap();
+        cmd.ensure_message_id();
+        assert_eq!(cmd.mailing_to()[1].routing_key(), "etl.daily.us");
+
+        // routed by keys, only config headers
+        let props = Vec::<BasicProperties>::try_from(&cmd).unwrap();
+        let headers = PqxHeaders::from_props(&props[0]).unwrap();
+        assert_eq!(headers.x_retries, Some(2));
+        assert_eq!(props[0].headers().unwrap().as_ref().len(), 1);
+    }
+
+    #[test]
+    fn command_stored_success() {
+        let value = serde_json::json!({
+            "mailing_to": [{"unique_key": "h1"}],
+            "config": {"retry": 2},
+            "cmd": {"Ping": {"addr": "localhost"}}
+        });
+        // the former `mailing_to` is only read back from storage
+        assert!(serde_json::from_value::<Command>(value.clone()).is_err());
+
+        let cmd = Command::from_stored(value).unwrap();
+        assert_eq!(cmd.mailing_to(), &[criterion("h1")]);
+        assert_eq!(cmd.config().retry, Some(2));
+    }
+
+    #[test]
+    fn command_scheduled_success() {
+        let mut cmd: Command = serde_json::from_value(serde_json::json!({
+            "mailing_to": ["etl.daily.cn"],
+            "config": {"poke": 60, "delay": 3600},
+            "cmd": {"Ping": {"addr": "localhost"}}
+        }))
+        .unwrap();
+        assert!(cmd.scheduled());
+
+        // stable once resolved
+        let run_at = cmd.ensure_run_at().unwrap();
+        assert_eq!(cmd.ensure_run_at(), Some(run_at));
+
+        // delayed by the first hop instead of `poke`
+        let props = Vec::<BasicProperties>::try_from(&cmd).unwrap();
+        let headers = PqxHeaders::from_props(&props[0]).unwrap();
+        assert_eq!(headers.x_run_at, Some(run_at.timestamp_millis()));
+        assert!((3_590_000..=3_600_000).contains(&headers.x_delay.unwrap()));
+
+        // beyond the limit of `x-delay`
+        cmd.config.run_at = Some(Local::now() + chrono::Duration::days(60));
+        let headers = PqxHeaders::try_from(&cmd.config_headers().unwrap()).unwrap();
+        assert_eq!(headers.x_delay, Some(i32::MAX));
+
+        // due
+        cmd.config.run_at = Some(Local::now() - chrono::Duration::seconds(1));
+        let headers = PqxHeaders::try_from(&cmd.config_headers().unwrap()).unwrap();
+        assert_eq!(headers.x_delay, None);
+    }
 }
@@ -6,10 +6,12 @@
 use clap::Parser;
 use pqx::amqprs::channel::{ExchangeType, QueueBindArguments, QueueDeclareArguments};
 use pqx::error::PqxResult;
-use pqx::mq::{FieldTableBuilder, MqClient, EXCHANGE_TYPE_DELAYED};
+use pqx::mq::{FieldTableBuilder, MatchType, MqClient, RetryBackend, EXCHANGE_TYPE_DELAYED};
 use pqx::pqx_util::*;
-use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig};
+use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig, RetryBackendType};
 use pqx_app::persist::MessagePersistent;
+use pqx_app::routing::{self, RoutingMode};
+use pqx_app::topology::{field_table, LiveTopology, Topology};
 use tracing::info;
 
 // ================================================================================================
@@ -19,9 +21,13 @@ use tracing::info;
 // commands
 const DECL_X: &str = "decl_x";
 const DECL_DX: &str = "decl_dx";
+const DECL_WQ: &str = "decl_wq";
 const DECL_DLX: &str = "decl_dlx";
 const CRT_TBL: &str = "crt_tbl";
 const INIT: &str = "init";
+const PLAN: &str = "plan";
+const APPLY: &str = "apply";
+const DESTROY: &str = "destroy";
 
 // default constants
 const LOGGING_DIR: &str = "./logs";
@@ -38,6 +44,12 @@ struct Args {
     #[arg(short, long)]
     option: String,
     config: Option<String>,
+    // topology file of `plan`/`apply`/`destroy`, derived from `init.yml` if absent
+    #[arg(long)]
+    topology: Option<String>,
+    // allow `apply` to delete and redeclare drifted exchanges and queues
+    #[arg(long)]
+    replace: bool,
 }
 
 // ================================================================================================
@@ -55,25 +67,36 @@ async fn declare_exchange_and_queues_then_bind(
 
     // declare queues and bind to exchange
     for hq in &config.header_queues {
-        // declare queue
+        // declare queue, classic (default), quorum or stream
+        hq.check()?;
         let mut args = QueueDeclareArguments::new(&hq.queue);
-        let mut headers = FieldTableBuilder::new();
-        // set dead letter exchange
-        headers.x_dead_letter_exchange(&config.dead_letter_exchange, "");
-        args.arguments(headers.finish());
+        // dead letter exchange, max priority, delivery limit and etc.
+        let arguments = hq.arguments(&config.dead_letter_exchange);
+        args.durable(hq.durable())
+            .arguments(field_table(&arguments)?);
         client.declare_queue_by_args(args).await?;
 
-        // bind queue to exchange
-        let mut args = QueueBindArguments::new(&hq.queue, &config.header_exchange, "");
-        let mut headers = FieldTableBuilder::new();
-        // set "x-match"
-        headers.x_match(&hq.match_type);
-        // set matching pattern
-        for (k, v) in hq.kv.iter() {
-            headers.x_common_pair(k, v);
+        // bind queue to exchange, once per way a `mailing_to` criterion reaches it
+        for arguments in routing::bindings(&hq.kv)? {
+            let mut args = QueueBindArguments::new(&hq.queue, &config.header_exchange, "");
+            args.arguments(field_table(&arguments)?);
+            client.bind_queue_by_args(args).await?;
         }
-        args.arguments(headers.finish());
-        client.bind_queue_by_args(args).await?;
+    }
+
+    // declare topic exchange and bind queues by their patterns
+    if let Some(x) = &config.topic_exchange {
+        client.declare_exchange(x, &ExchangeType::Topic).await?;
+        for hq in &config.header_queues {
+            for pattern in hq.patterns.iter() {
+                client.bind_queue(x, pattern, &hq.queue).await?;
+            }
+        }
+    }
+
+    // declare control exchange, bound by exclusive queues of subscribers
+    if let Some(x) = &config.control_exchange {
+        client.declare_exchange(x, &ExchangeType::Topic).await?;
     }
 
     Ok(())
@@ -83,9 +106,13 @@ async fn declare_delayed_exchange_and_bind_queues(
     client: &MqClient,
     config: &InitiationsConfig,
 ) -> PqxResult<()> {
-    // declare delayed exchange
+    // declare delayed exchange, routes as the exchange commands are published to
+    let delayed_type = match config.routing {
+        RoutingMode::Header => ExchangeType::Headers,
+        RoutingMode::Topic => ExchangeType::Topic,
+    };
     let mut args = FieldTableBuilder::new();
-    args.x_delayed_type(&ExchangeType::Headers);
+    args.x_delayed_type(&delayed_type);
     client
         .declare_exchange_with_args(
             &config.delayed_exchange,
@@ -96,20 +123,71 @@ async fn declare_delayed_exchange_and_bind_queues(
 
     // bind existing queues to delayed exchange (suppose queue has already been declared in the former step)
     for hq in &config.header_queues {
-        let mut args = QueueBindArguments::new(&hq.queue, &config.delayed_exchange, "");
-        let mut headers = FieldTableBuilder::new();
-        headers.x_match(&hq.match_type);
-        for (k, v) in hq.kv.iter() {
-            headers.x_common_pair(k, v);
+        match config.routing {
+            RoutingMode::Header => {
+                for arguments in routing::bindings(&hq.kv)? {
+                    let mut args = QueueBindArguments::new(&hq.queue, &config.delayed_exchange, "");
+                    args.arguments(field_table(&arguments)?);
+                    client.bind_queue_by_args(args).await?;
+                }
+            }
+            RoutingMode::Topic => {
+                for pattern in hq.patterns.iter() {
+                    client
+                        .bind_queue(&config.delayed_exchange, pattern, &hq.queue)
+                        .await?;
+                }
+            }
         }
-        args.arguments(headers.finish());
+    }
+
+    Ok(())
+}
+
+async fn declare_wait_exchange_and_queues(
+    client: &MqClient,
+    config: &InitiationsConfig,
+) -> PqxResult<()> {
+    let (exchange, tiers) = match config.retry_backend()? {
+        RetryBackend::WaitQueues { exchange, tiers } => (exchange, tiers),
+        _ => return Err("retry_backend is not wait_queues".into()),
+    };
+
+    // declare wait exchange
+    client
+        .declare_exchange(&exchange, &ExchangeType::Headers)
+        .await?;
 
+    // declare a wait queue for each delay tier, and bind to wait exchange by `x-wait`
+    for tier in tiers {
+        let que = RetryBackend::wait_queue_name(&exchange, tier);
+        // expired messages go back to the exchange commands are published to (header or topic),
+        // with their original routing key
+        client
+            .declare_wait_queue(&que, config.exchange()?, i64::from(tier) * 1000)
+            .await?;
+
+        let mut args = QueueBindArguments::new(&que, &exchange, "");
+        let mut headers = FieldTableBuilder::new();
+        // "x-wait" begins with "x-", which is ignored by "all"
+        headers.x_match(&MatchType::AllWithX);
+        headers.x_wait(tier.into());
+        args.arguments(headers.finish());
         client.bind_queue_by_args(args).await?;
     }
 
     Ok(())
 }
 
+async fn declare_retry_backend(client: &MqClient, config: &InitiationsConfig) -> PqxResult<()> {
+    match config.retry_backend {
+        RetryBackendType::DelayedExchange => {
+            declare_delayed_exchange_and_bind_queues(client, config).await
+        }
+        RetryBackendType::WaitQueues => declare_wait_exchange_and_queues(client, config).await,
+    }
+}
+
 async fn declare_dead_letter_exchange_and_bind_queues(
     client: &MqClient,
     config: &InitiationsConfig,
@@ -124,7 +202,7 @@ async fn declare_dead_letter_exchange_and_bind_queues(
     let mut headers = FieldTableBuilder::new();
     // set "x-message-ttl"
     if let Some(ttl) = &config.dead_message_ttl {
-        headers.x_consume_ttl(*ttl);
+        headers.x_message_ttl(*ttl);
     }
     args.arguments(headers.finish());
     client.declare_queue_by_args(args).await?;
@@ -135,6 +213,16 @@ async fn declare_dead_letter_exchange_and_bind_queues(
     Ok(())
 }
 
+fn read_topology(path: Option<&str>, config: &InitiationsConfig) -> PqxResult<Topology> {
+    match path {
+        Some(p) => {
+            let pth = get_cur_dir_file(p)?;
+            Topology::from_yaml(pth.to_string_lossy())
+        }
+        None => Topology::try_from(config),
+    }
+}
+
 async fn create_table(client: &PersistClient) {
     let db = client.db().expect("connection is established");
     let mp = MessagePersistent::new(db.clone());
@@ -150,9 +238,13 @@ async fn create_table(client: &PersistClient) {
 ///
 /// 1. cargo run --bin initiator -- -o decl_x
 /// 2. cargo run --bin initiator -- -o decl_dx
-/// 3. cargo run --bin initiator -- -o decl_dlx
-/// 4. cargo run --bin initiator -- -o crt_tbl
-/// 4. cargo run --bin initiator -- -o init
+/// 3. cargo run --bin initiator -- -o decl_wq
+/// 4. cargo run --bin initiator -- -o decl_dlx
+/// 5. cargo run --bin initiator -- -o crt_tbl
+/// 6. cargo run --bin initiator -- -o init
+/// 7. cargo run --bin initiator -- -o plan --topology topology.yml
+/// 8. cargo run --bin initiator -- -o apply --topology topology.yml [--replace]
+/// 9. cargo run --bin initiator -- -o destroy --topology topology.yml
 ///
 #[tokio::main]
 async fn main() {
@@ -171,6 +263,8 @@ async fn main() {
     let mut mq_client = MqClient::new();
     mq_client.connect(config.mq).await.unwrap();
     mq_client.open_channel(None).await.unwrap();
+    // mq-api client
+    let api_client = MqApiClient::new(config.mq_api);
     // db client
     let mut db_client = PersistClient::new(config.db);
     db_client.with_sqlx_logging(false).connect().await.unwrap();
@@ -193,6 +287,12 @@ async fn main() {
                 .await
                 .unwrap();
         }
+        DECL_WQ => {
+            info!("{} DECL_WQ", now!());
+            declare_wait_exchange_and_queues(&mq_client, &config)
+                .await
+                .unwrap();
+        }
         DECL_DLX => {
             info!("{} DECL_DLX", now!());
             declare_dead_letter_exchange_and_bind_queues(&mq_client, &config)
@@ -208,14 +308,37 @@ async fn main() {
             declare_exchange_and_queues_then_bind(&mq_client, &config)
                 .await
                 .unwrap();
-            declare_delayed_exchange_and_bind_queues(&mq_client, &config)
-                .await
-                .unwrap();
+            declare_retry_backend(&mq_client, &config).await.unwrap();
             declare_dead_letter_exchange_and_bind_queues(&mq_client, &config)
                 .await
                 .unwrap();
             create_table(&db_client).await;
         }
+        PLAN => {
+            info!("{} PLAN", now!());
+            let topology = read_topology(args.topology.as_deref(), &config).unwrap();
+            let live = LiveTopology::fetch(&api_client).await.unwrap();
+            let plan = topology.plan(&live);
+            info!("{} plan:\n{}", now!(), plan);
+        }
+        APPLY => {
+            info!("{} APPLY", now!());
+            let topology = read_topology(args.topology.as_deref(), &config).unwrap();
+            let live = LiveTopology::fetch(&api_client).await.unwrap();
+            let plan = topology.plan(&live);
+            info!("{} plan:\n{}", now!(), plan);
+            plan.apply(&mq_client, &api_client, args.replace)
+                .await
+                .unwrap();
+        }
+        DESTROY => {
+            info!("{} DESTROY", now!());
+            let topology = read_topology(args.topology.as_deref(), &config).unwrap();
+            let live = LiveTopology::fetch(&api_client).await.unwrap();
+            let plan = topology.destroy_plan(&live);
+            info!("{} plan:\n{}", now!(), plan);
+            plan.apply(&mq_client, &api_client, false).await.unwrap();
+        }
         _ => panic!("undefined option"),
     }
 
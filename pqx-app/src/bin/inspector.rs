@@ -3,15 +3,19 @@
 //! date: 2023/06/26 00:03:35 Monday
 //! brief:
 
+use chrono::Local;
 use clap::Parser;
 use pqx::error::PqxResult;
-use pqx::mq::MqClient;
+use pqx::mq::{CancelRequest, MqClient, Publisher, QueueType, CANCEL_ROUTING_KEY};
 use pqx::pqx_custom_err;
 use pqx::pqx_util::*;
-use pqx_app::adt::{BindingInfo, ExchangeInfo, QueueInfo};
-use pqx_app::cfg::ConnectionsConfig;
+use pqx_app::adt::{BindingInfo, ConsumerInfo, ExchangeInfo, QueueInfo};
+use pqx_app::cancel::PgCancelStore;
+use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig};
+use pqx_app::delay::PgScheduleStore;
 use pqx_app::persist::MessagePersistent;
-use tracing::info;
+use pqx_app::worker::{check_workers, WorkerRegistry};
+use tracing::{info, warn};
 
 // ================================================================================================
 // Const
@@ -19,11 +23,18 @@ use tracing::info;
 
 // commands
 const INSP: &str = "insp";
+const SCHED: &str = "sched";
+const CANCEL_SCHED: &str = "cancel_sched";
+const CANCEL: &str = "cancel";
+const WORKERS: &str = "workers";
 
 // default constants
 const LOGGING_DIR: &str = "./logs";
 const FILENAME_PREFIX: &str = "pqx_inspector";
 const CONN_CONFIG: &str = "conn.yml";
+const INIT_CONFIG: &str = "init.yml";
+// warns when a queue is filled up to this ratio of its limit
+const LIMIT_WARNING: f64 = 0.8;
 
 static DEFAULT_EXCHANGE: &[&str] = &[
     "",
@@ -44,6 +55,14 @@ struct Args {
     #[arg(short, long)]
     option: String,
     config: Option<String>,
+    // scheduled command to cancel
+    #[arg(long)]
+    message_id: Option<String>,
+    // queued or running task to cancel, by its `message_id`
+    #[arg(long)]
+    task_id: Option<String>,
+    #[arg(long)]
+    reason: Option<String>,
 }
 
 // ================================================================================================
@@ -60,39 +79,172 @@ async fn check_tables(client: &PersistClient) -> PqxResult<(bool, bool)> {
 async fn check_mq(
     client: &MqApiClient,
 ) -> PqxResult<(Vec<ExchangeInfo>, Vec<QueueInfo>, Vec<BindingInfo>)> {
-    let query = MqQuery::new(&client);
+    let query = MqQuery::new(client);
 
     let res1 = query
         .exchanges_with_vhost(client.vhost())
         .await?
         .as_array()
         .ok_or(pqx_custom_err!("array"))?
-        .into_iter()
+        .iter()
         .map(ExchangeInfo::try_from)
         .collect::<PqxResult<Vec<_>>>()?
         .into_iter()
         .filter(|e| !DEFAULT_EXCHANGE.contains(&e.name.as_ref()))
         .collect::<Vec<_>>();
     let res2 = query
-        .queues_with_vhost(&client.vhost())
+        .queues_with_vhost(client.vhost())
         .await?
         .as_array()
         .ok_or(pqx_custom_err!("array"))?
-        .into_iter()
+        .iter()
         .map(QueueInfo::try_from)
         .collect::<PqxResult<Vec<_>>>()?;
     let res3 = query
-        .bindings_with_vhost(&client.vhost())
+        .bindings_with_vhost(client.vhost())
         .await?
         .as_array()
         .ok_or(pqx_custom_err!("array"))?
-        .into_iter()
+        .iter()
         .map(BindingInfo::try_from)
         .collect::<PqxResult<Vec<_>>>()?;
 
     Ok((res1, res2, res3))
 }
 
+// configured type of each header queue against the declared one
+fn check_queue_types(config: &InitiationsConfig, queues: &[QueueInfo]) -> PqxResult<()> {
+    for hq in config.header_queues.iter() {
+        let q = match queues.iter().find(|q| q.name == hq.queue) {
+            Some(q) => q,
+            None => {
+                info!("{} [QueueType] {}: not declared", now!(), hq.queue);
+                continue;
+            }
+        };
+        let kind = q.kind()?;
+        if kind != hq.queue_type {
+            info!(
+                "{} [QueueType] {}: configured as {}, but declared as {}",
+                now!(),
+                hq.queue,
+                hq.queue_type,
+                kind
+            );
+            continue;
+        }
+        match kind {
+            QueueType::Classic => info!("{} [QueueType] {}: classic", now!(), q.name),
+            QueueType::Quorum => info!(
+                "{} [QueueType] {}: quorum, delivery_limit: {:?}",
+                now!(),
+                q.name,
+                q.delivery_limit()
+            ),
+            QueueType::Stream => info!(
+                "{} [QueueType] {}: stream, max_age: {:?}",
+                now!(),
+                q.name,
+                q.max_age()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+// how close each limited queue is to its `x-max-length` / `x-max-length-bytes`
+fn check_queue_limits(queues: &[QueueInfo]) -> PqxResult<()> {
+    for q in queues.iter() {
+        let usage = match q.limit_usage() {
+            Some(u) => u,
+            None => continue,
+        };
+        let msg = format!(
+            "[QueueLimit] {}: {}/{:?} messages, {}/{:?} bytes, {:.1}% used, overflow: {}",
+            q.name,
+            q.messages_ready,
+            q.max_length(),
+            q.message_bytes_ready,
+            q.max_length_bytes(),
+            usage * 100.0,
+            q.overflow()?
+        );
+        if usage >= LIMIT_WARNING {
+            warn!("{} {}", now!(), msg);
+        } else {
+            info!("{} {}", now!(), msg);
+        }
+    }
+
+    Ok(())
+}
+
+async fn list_consumers(client: &MqApiClient) -> PqxResult<Vec<ConsumerInfo>> {
+    let query = MqQuery::new(client);
+
+    query
+        .consumers_with_vhost(client.vhost())
+        .await?
+        .as_array()
+        .ok_or(pqx_custom_err!("array"))?
+        .iter()
+        .map(ConsumerInfo::try_from)
+        .collect::<PqxResult<Vec<_>>>()
+}
+
+// registered workers by their heartbeats, cross-checked against the consumers of header queues
+async fn check_worker_registry(
+    registry: &WorkerRegistry,
+    client: &MqApiClient,
+    config: &InitiationsConfig,
+) -> PqxResult<()> {
+    let workers = registry.workers().await?;
+    let consumers = list_consumers(client).await?;
+    let (checks, orphans) = check_workers(&workers, &consumers, Local::now());
+
+    for c in checks.iter() {
+        let w = c.worker;
+        let msg = format!(
+            "[Worker] {} ({}): queues: {}, labels: {}, version: {}, capacity: {}, current_task: {:?}, heartbeat_at: {}, consumers: {}",
+            w.worker_id,
+            c.status,
+            w.queues,
+            w.labels,
+            w.version,
+            w.capacity,
+            w.current_task,
+            w.heartbeat_at,
+            c.consumers.len()
+        );
+        if c.consistent() {
+            info!("{} {}", now!(), msg);
+        } else {
+            warn!("{} {}", now!(), msg);
+        }
+    }
+    // e.g. a subscriber of an older version, or another client
+    let queues = config
+        .header_queues
+        .iter()
+        .map(|hq| hq.queue.as_str())
+        .collect::<Vec<_>>();
+    for c in orphans
+        .iter()
+        .filter(|c| queues.contains(&c.queue.as_str()))
+    {
+        warn!(
+            "{} [Worker] unregistered consumer {} of {}, from {}",
+            now!(),
+            c.consumer_tag,
+            c.queue,
+            c.connection_name
+        );
+    }
+
+    Ok(())
+}
+
 // ================================================================================================
 // Main
 // ================================================================================================
@@ -100,6 +252,10 @@ async fn check_mq(
 /// Options
 ///
 /// 0. cargo run --bin inspector -- -o insp
+/// 1. cargo run --bin inspector -- -o sched
+/// 2. cargo run --bin inspector -- -o cancel_sched --message-id <MESSAGE_ID>
+/// 3. cargo run --bin inspector -- -o cancel --task-id <MESSAGE_ID> --reason <REASON>
+/// 4. cargo run --bin inspector -- -o workers
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -140,6 +296,77 @@ async fn main() {
             info!("{} [ExchangeInfo] {:?}", now!(), &res1);
             info!("{} [QueueInfo] {:?}", now!(), &res2);
             info!("{} [BindingInfo] {:?}", now!(), &res3);
+
+            // check queue types
+            let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+            let config_path = config_path.to_string_lossy();
+            let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
+            check_queue_types(&init_config, &res2).unwrap();
+            check_queue_limits(&res2).unwrap();
+        }
+        SCHED => {
+            info!("{} SCHED", now!());
+            let store = PgScheduleStore::new(db_client.db.unwrap());
+            for t in store.pending().await.unwrap() {
+                info!(
+                    "{} [ScheduledTask] {} run at {}, mailing_to: {}, cmd: {}",
+                    now!(),
+                    t.message_id,
+                    t.run_at,
+                    t.mailing_to,
+                    t.cmd
+                );
+            }
+        }
+        CANCEL_SCHED => {
+            info!("{} CANCEL_SCHED", now!());
+            let message_id = args.message_id.expect("--message-id is required");
+            let store = PgScheduleStore::new(db_client.db.unwrap());
+            if store.cancel(&message_id).await.unwrap() {
+                info!("{} {} cancelled", now!(), message_id);
+            } else {
+                warn!("{} {} is not pending", now!(), message_id);
+            }
+        }
+        CANCEL => {
+            info!("{} CANCEL", now!());
+            let task_id = args.task_id.expect("--task-id is required");
+            let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+            let config_path = config_path.to_string_lossy();
+            let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
+            let exchange = init_config
+                .control_exchange
+                .expect("control_exchange is required by cancellation");
+
+            let mut req = CancelRequest::new(&task_id);
+            if let Some(r) = args.reason {
+                req = req.with_reason(r);
+            }
+            // recorded before published, loaded by subscribers starting afterwards
+            let db = db_client.db.unwrap();
+            if !PgCancelStore::new(db.clone()).request(&req).await.unwrap() {
+                warn!("{} {} has been cancelled", now!(), task_id);
+            }
+            // so is a pending scheduled command
+            if PgScheduleStore::new(db).cancel(&task_id).await.unwrap() {
+                info!("{} scheduled {} cancelled", now!(), task_id);
+            }
+            let publisher = Publisher::new(mq_client.channel().unwrap());
+            publisher
+                .publish(&exchange, CANCEL_ROUTING_KEY, req)
+                .await
+                .unwrap();
+            info!("{} cancel of {} requested", now!(), task_id);
+        }
+        WORKERS => {
+            info!("{} WORKERS", now!());
+            let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+            let config_path = config_path.to_string_lossy();
+            let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
+            let registry = WorkerRegistry::new(db_client.db.unwrap());
+            check_worker_registry(&registry, &api_client, &init_config)
+                .await
+                .unwrap();
         }
         _ => panic!("undefined option"),
     }
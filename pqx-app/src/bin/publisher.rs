@@ -3,13 +3,21 @@
 //! date: 2023/06/25 09:36:46 Sunday
 //! brief: turn `task.json` into `Command` and send to MQ
 
+use std::future::IntoFuture;
+use std::process::ExitCode;
+use std::time::Duration;
+
 use clap::Parser;
+use futures::future::join_all;
 use pqx::amqprs::BasicProperties;
-use pqx::mq::{MqClient, Publisher};
+use pqx::mq::{AggregatePolicy, Compression, MessageCodec, MqClient, Publisher, ReplyMode};
 use pqx::pqx_util::*;
-use pqx_app::adt::Command;
+use pqx_app::adt::{AggregateResult, Command};
 use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig};
-use tracing::{debug, info};
+use pqx_app::delay::PgScheduleStore;
+use pqx_app::persist::MessagePersistent;
+use pqx_app::rpc::CommandRpc;
+use tracing::{debug, error, info};
 
 // ================================================================================================
 // Const
@@ -17,13 +25,18 @@ use tracing::{debug, info};
 
 // commands
 const PUB: &str = "pub";
+const RPC: &str = "rpc";
+const AGG: &str = "agg";
 
 // default constants
 const LOGGING_DIR: &str = "./logs";
 const FILENAME_PREFIX: &str = "pqx_publisher";
+const SERVICE_NAME: &str = "pqx-publisher";
 const CONN_CONFIG: &str = "conn.yml";
 const INIT_CONFIG: &str = "init.yml";
 const TASK: &str = "task.json";
+const RPC_TIMEOUT: u64 = 3600; // seconds
+const COMPRESS_THRESHOLD: usize = 1024; // bytes
 
 // ================================================================================================
 // Args
@@ -35,22 +48,50 @@ struct Args {
     option: String,
     config: Option<String>,
     task: Option<String>,
+    // seconds to wait for replies of `rpc`, or the deadline of `agg`
+    #[arg(long)]
+    timeout: Option<u64>,
+    // aggregate policy of `agg`: all | any | quorum:<n>, rejected by clap if malformed
+    #[arg(long)]
+    policy: Option<AggregatePolicy>,
+    // encoding of the command: json | msgpack | cbor
+    #[arg(long)]
+    codec: Option<MessageCodec>,
+    // compression of the command: gzip | zstd
+    #[arg(long)]
+    compress: Option<Compression>,
+    // compress if the encoded command reaches this size in bytes
+    #[arg(long)]
+    threshold: Option<usize>,
 }
 
-// ================================================================================================
-// Fn
-// ================================================================================================
-
 // ================================================================================================
 // Main
 // ================================================================================================
 
 /// 0. cargo run --bin publisher -- -o pub
+/// 1. cargo run --bin publisher -- -o rpc --timeout 600
+/// 2. cargo run --bin publisher -- -o agg --timeout 600 --policy quorum:2
+/// 3. cargo run --bin publisher -- -o pub --codec msgpack
+/// 4. cargo run --bin publisher -- -o pub --compress zstd --threshold 512
+/// 5. cargo run --bin publisher -- -o pub scheduled_task.json (`run_at` or `delay` in `config`)
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
     let args = Args::parse();
 
-    let _guard = logging_init(LOGGING_DIR, FILENAME_PREFIX, tracing::Level::INFO).unwrap();
+    // read setup config, before logging since it might export traces
+    let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+    let config_path = config_path.to_string_lossy();
+    let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
+
+    let _guard = logging_init_with_trace(
+        LOGGING_DIR,
+        FILENAME_PREFIX,
+        tracing::Level::INFO,
+        SERVICE_NAME,
+        init_config.trace.as_ref(),
+    )
+    .unwrap();
 
     info!("{} Start publisher... 🫨", now!());
 
@@ -59,11 +100,6 @@ async fn main() {
     let config_path = config_path.to_string_lossy();
     let conn_config: ConnectionsConfig = read_yaml(config_path).unwrap();
 
-    // read setup config
-    let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
-    let config_path = config_path.to_string_lossy();
-    let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
-
     // mq client
     let mut mq_client = MqClient::new();
     mq_client.connect(conn_config.mq).await.unwrap();
@@ -73,21 +109,151 @@ async fn main() {
     // read task.json
     let task_path = get_cur_dir_file(args.task.as_deref().unwrap_or(TASK)).unwrap();
     let task_path = task_path.to_string_lossy();
-    let task: Command = read_json(task_path).unwrap();
+    let mut task: Command = read_json(task_path).unwrap();
+    // a recipient of the other routing mode, or using unbound keys, reaches no queue
+    for r in task.mailing_to() {
+        if let Err(e) = init_config.check_recipient(r) {
+            error!(
+                "{} invalid mailing_to {} ({}): {}",
+                now!(),
+                r,
+                init_config.routing,
+                e
+            );
+            return ExitCode::FAILURE;
+        }
+    }
+    let exchange = init_config.exchange().unwrap();
+    // every recipient shares the same `message_id`, deduplicated per queue by subscribers
+    task.ensure_message_id();
+    // so does `run_at`, a scheduled command goes through the delayed exchange
+    let scheduled = task.scheduled();
+    if scheduled {
+        if args.option != PUB {
+            error!("{} a scheduled command is only published by `pub`", now!());
+            return ExitCode::FAILURE;
+        }
+        if let Err(e) = init_config.check_scheduling() {
+            error!("{} {}", now!(), e);
+            return ExitCode::FAILURE;
+        }
+        info!("{} scheduled at {:?}", now!(), task.ensure_run_at());
+    }
 
     debug!("{} task: {:?}", now!(), &task);
 
+    let codec = args.codec.unwrap_or_default();
+
     // publisher
-    let publisher = Publisher::new(chan);
+    let mut publisher = Publisher::new(chan);
+    publisher.set_codec(codec);
+    if let Some(c) = args.compress {
+        publisher.set_compression(c, args.threshold.unwrap_or(COMPRESS_THRESHOLD));
+    }
 
+    let mut failed = 0;
     match args.option.as_str() {
         PUB => {
-            let props_list = Vec::<BasicProperties>::try_from(&task).unwrap();
-            for props in props_list.into_iter() {
-                publisher
-                    .publish_with_props(&init_config.header_exchange, "", task.clone(), props)
+            // recorded as pending, listed and cancelled by the inspector
+            let exchange = if scheduled {
+                let mut ps = PersistClient::new(conn_config.db);
+                ps.with_sqlx_logging(false).connect().await.unwrap();
+                PgScheduleStore::new(ps.db.unwrap())
+                    .record(&task)
                     .await
                     .unwrap();
+                &init_config.delayed_exchange
+            } else {
+                exchange
+            };
+            // a message rejected by a full queue (overflow `reject-publish`) is nacked
+            publisher.enable_confirm().await.unwrap();
+            let props_list = Vec::<BasicProperties>::try_from(&task).unwrap();
+            for (r, props) in task.mailing_to().iter().zip(props_list) {
+                match publisher
+                    .publish_with_props(exchange, r.routing_key(), task.clone(), props)
+                    .await
+                {
+                    Ok(_) => debug!("{} published to {}", now!(), r),
+                    Err(e) => {
+                        error!("{} publish to {} failed: {}", now!(), r, e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+        RPC => {
+            let timeout = Duration::from_secs(args.timeout.unwrap_or(RPC_TIMEOUT));
+            let mut rpc = CommandRpc::new(chan, ReplyMode::DirectReplyTo, exchange)
+                .await
+                .unwrap();
+            rpc.set_codec(codec);
+            match rpc.send(&task, timeout).await {
+                Ok(futs) => {
+                    info!("{} waiting for {} replies...", now!(), futs.len());
+
+                    let (recipients, futs): (Vec<_>, Vec<_>) = futs.into_iter().unzip();
+                    let results = join_all(futs.into_iter().map(IntoFuture::into_future)).await;
+                    for (r, res) in recipients.iter().zip(results) {
+                        match res {
+                            Ok(er) => info!("{} {} replied: {:?}", now!(), r, er),
+                            Err(e) => {
+                                error!("{} {} failed: {:?}", now!(), r, e);
+                                failed += 1;
+                            }
+                        }
+                    }
+                }
+                Err(e) => {
+                    error!("{} rpc failed: {}", now!(), e);
+                    failed += 1;
+                }
+            }
+            rpc.close().await.unwrap();
+        }
+        AGG => {
+            let timeout = Duration::from_secs(args.timeout.unwrap_or(RPC_TIMEOUT));
+            let policy = args.policy.unwrap_or_default();
+
+            // setup db
+            let mut ps = PersistClient::new(conn_config.db);
+            ps.with_sqlx_logging(false).connect().await.unwrap();
+            let mp = MessagePersistent::new(ps.db.unwrap());
+
+            let mut rpc = CommandRpc::new(chan, ReplyMode::DirectReplyTo, exchange)
+                .await
+                .unwrap();
+            rpc.set_codec(codec);
+            info!("{} aggregating by {:?}...", now!(), policy);
+            let agg = rpc
+                .aggregate(&task, &init_config.header_queues, policy, timeout)
+                .await;
+            rpc.close().await.unwrap();
+            let agg = match agg {
+                Ok(a) => a,
+                Err(e) => {
+                    error!("{} aggregate failed: {}", now!(), e);
+                    return ExitCode::FAILURE;
+                }
+            };
+
+            let res = AggregateResult::from(&agg);
+            for r in res.recipients.iter() {
+                info!("{} {} {:?}: {:?}", now!(), r.queue, r.status, r.result);
+            }
+            info!(
+                "{} expected: {}, succeeded: {}, failed: {}, missing: {}, passed: {}",
+                now!(),
+                res.expected,
+                res.succeeded,
+                res.failed,
+                res.missing,
+                res.passed
+            );
+            let id = mp.insert_aggregate(&task, &res).await.unwrap();
+            info!("{} aggregate record id: {}", now!(), id);
+            if !res.passed {
+                failed += 1;
             }
         }
         _ => panic!("undefined option"),
@@ -95,5 +261,15 @@ async fn main() {
 
     publisher.block(1).await;
 
+    // non-zero if any publish or request failed (or the aggregate did not pass), for a script
+    // checking the exit status
+    let code = if failed > 0 {
+        error!("{} {} failure(s)", now!(), failed);
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    };
     info!("{} End publisher 😎", now!());
+
+    code
 }
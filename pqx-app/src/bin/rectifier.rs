@@ -7,7 +7,7 @@ use clap::Parser;
 use pqx::error::PqxResult;
 use pqx::mq::MqClient;
 use pqx::pqx_util::*;
-use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig};
+use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig, RetryBackendType};
 use pqx_app::persist::MessagePersistent;
 use tracing::info;
 
@@ -46,13 +46,28 @@ async fn delete_queues(client: &MqClient, config: &InitiationsConfig) -> PqxResu
     for hq in config.header_queues.iter() {
         client.delete_queue(&hq.queue).await?;
     }
+    for que in config.wait_queue_names() {
+        client.delete_queue(&que).await?;
+    }
 
     Ok(())
 }
 
 async fn delete_exchanges(client: &MqClient, config: &InitiationsConfig) -> PqxResult<()> {
     client.delete_exchange(&config.header_exchange).await?;
-    client.delete_exchange(&config.delayed_exchange).await?;
+    if let Some(x) = &config.topic_exchange {
+        client.delete_exchange(x).await?;
+    }
+    match config.retry_backend {
+        RetryBackendType::DelayedExchange => {
+            client.delete_exchange(&config.delayed_exchange).await?
+        }
+        RetryBackendType::WaitQueues => {
+            if let Some(x) = &config.wait_exchange {
+                client.delete_exchange(x).await?;
+            }
+        }
+    }
     client.delete_exchange(&config.dead_letter_exchange).await?;
 
     Ok(())
@@ -0,0 +1,274 @@
+//! file: replayer.rs
+//! author: Jacob Xie
+//! date: 2023/07/10 22:05:41 Monday
+//! brief: browse dead-lettered messages, and replay (republish) or move them
+
+use clap::Parser;
+use pqx::error::PqxResult;
+use pqx::mq::{
+    compress_content, decode_content, Codec, MessageCodec, MqClient, Republish, Shovel,
+    ShovelMessage,
+};
+use pqx::pqx_util::*;
+use pqx_app::adt::{Command, CommandPatch};
+use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig};
+use tracing::info;
+
+// ================================================================================================
+// Const
+// ================================================================================================
+
+// commands
+const LS: &str = "ls";
+const REPLAY: &str = "replay";
+const MV: &str = "mv";
+
+// replay targets
+const TARGET_QUEUE: &str = "queue";
+const TARGET_EXCHANGE: &str = "exchange";
+
+// default constants
+const LOGGING_DIR: &str = "./logs";
+const FILENAME_PREFIX: &str = "pqx_replayer";
+const CONN_CONFIG: &str = "conn.yml";
+const INIT_CONFIG: &str = "init.yml";
+const LIMIT: usize = 100;
+
+// ================================================================================================
+// Args
+// ================================================================================================
+
+#[derive(Debug, Parser)]
+struct Args {
+    #[arg(short, long)]
+    option: String,
+    // source queue, default to `dead_letter_queue`
+    #[arg(short, long)]
+    queue: Option<String>,
+    // target queue of `mv`
+    #[arg(long)]
+    to: Option<String>,
+    // max number of messages to be fetched
+    #[arg(short, long)]
+    limit: Option<usize>,
+    // indices (listed by `ls`) of messages to be replayed/moved, all if empty
+    #[arg(short, long, value_delimiter = ',')]
+    pick: Vec<usize>,
+    // only the messages dead-lettered from this queue
+    #[arg(long)]
+    origin: Option<String>,
+    // `queue` (default): back to the original queue; `exchange`: back to the header exchange
+    #[arg(long)]
+    target: Option<String>,
+    // json file of `CommandPatch`, which overwrites `mailing_to` and/or `config`
+    #[arg(long)]
+    patch: Option<String>,
+}
+
+impl Args {
+    fn is_picked(&self, m: &ShovelMessage) -> bool {
+        let by_index = self.pick.is_empty() || self.pick.contains(&m.index);
+        let by_origin = match &self.origin {
+            Some(o) => m.origin_queue().as_ref() == Some(o),
+            None => true,
+        };
+
+        by_index && by_origin
+    }
+}
+
+// ================================================================================================
+// Fn
+// ================================================================================================
+
+fn codec_of(m: &ShovelMessage) -> PqxResult<MessageCodec> {
+    MessageCodec::from_content_type(m.props.content_type().map(String::as_str))
+}
+
+fn show(m: &ShovelMessage) {
+    info!(
+        "{} [{}] exchange: {:?}, routing_key: {:?}, redelivered: {}",
+        now!(),
+        m.index,
+        m.exchange,
+        m.routing_key,
+        m.redelivered
+    );
+    for d in m.x_death().iter() {
+        info!(
+            "{} [{}] x-death > reason: {}, queue: {}, exchange: {}, routing_keys: {:?}, count: {}",
+            now!(),
+            m.index,
+            d.reason,
+            d.queue,
+            d.exchange,
+            d.routing_keys,
+            d.count
+        );
+    }
+    if let Some(h) = m.headers() {
+        info!("{} [{}] headers: {}", now!(), m.index, h);
+    }
+    // decoded by `content_encoding` & `content_type`, e.g. compressed MessagePack
+    let content = decode_content::<serde_json::Value>(&m.props, &m.content)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| String::from_utf8_lossy(&m.content).to_string());
+    info!("{} [{}] content: {}", now!(), m.index, content);
+}
+
+// republish a dead-lettered `Command` with retries reset and an optional patch applied:
+// 1. if `mailing_to` is patched, the command is sent to the new recipients by `exchange` (the
+//    header or topic exchange, by the routing mode);
+// 2. otherwise it goes back to its original recipient, either to the original queue directly or
+//    by `exchange` with its original routing key.
+fn replay(
+    m: &ShovelMessage,
+    patch: &CommandPatch,
+    target: &str,
+    init_config: &InitiationsConfig,
+) -> PqxResult<Vec<Republish>> {
+    let exchange = init_config.exchange()?;
+    // keep the original encoding & compression
+    let codec = codec_of(m)?;
+    let mut cmd = Command::from_stored(decode_content(&m.props, &m.content)?)?;
+    cmd.apply_patch(patch);
+    // a dead-lettered command has been marked as consumed, replay it as a new one
+    cmd.renew_message_id();
+    let content = compress_content(&m.props, codec.encode(&cmd)?)?;
+
+    if patch.mailing_to.is_some() {
+        let mut res = vec![];
+        let props_list = Vec::<pqx::amqprs::BasicProperties>::try_from(&cmd)?;
+        for (recipient, mut props) in cmd.mailing_to().iter().zip(props_list) {
+            props.with_content_type(codec.content_type());
+            if let Some(ce) = m.props.content_encoding() {
+                props.with_content_encoding(ce);
+            }
+            let mut r = Republish::new(exchange, recipient.routing_key(), m);
+            r.set_props(props).set_content(content.clone());
+            res.push(r);
+        }
+
+        return Ok(res);
+    }
+
+    let mut r = match target {
+        TARGET_QUEUE => Republish::to_origin_queue(m)?,
+        TARGET_EXCHANGE => {
+            // routing keys before dead-lettering, empty by the header exchange
+            let death = m.last_death().unwrap_or_default();
+            let rout = death.routing_keys.first().cloned().unwrap_or_default();
+            Republish::new(exchange, &rout, m)
+        }
+        _ => return Err("target: queue/exchange".into()),
+    };
+
+    // original criterion (if routed by the header exchange) + (patched) config
+    let criterion = m
+        .headers()
+        .map(|h| init_config.criterion_of(h))
+        .unwrap_or_default();
+    let mut headers = cmd.config_headers()?;
+    if !criterion.labels.is_empty() {
+        criterion.insert_into(&mut headers)?;
+    }
+    r.reset_retries()
+        .set_headers(headers)
+        .set_message_id(cmd.message_id().unwrap_or_default())
+        .set_content(content);
+    if let Some(p) = cmd.config.priority {
+        r.set_priority(p);
+    }
+
+    Ok(vec![r])
+}
+
+// ================================================================================================
+// Main
+// ================================================================================================
+
+/// Options
+///
+/// 1. cargo run --bin replayer -- -o ls
+/// 2. cargo run --bin replayer -- -o replay -p 0,2 --patch patch.json
+/// 3. cargo run --bin replayer -- -o replay --origin pqx.dev.que.h1 --target exchange
+/// 4. cargo run --bin replayer -- -o mv -q pqx.dev.que.h1 --to pqx.dev.que.h2
+///
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let _guard = logging_init(LOGGING_DIR, FILENAME_PREFIX, tracing::Level::INFO).unwrap();
+
+    info!("{} Start replayer... 🫨", now!());
+
+    // read connection config
+    let config_path = get_cur_dir_file(CONN_CONFIG).unwrap();
+    let config_path = config_path.to_string_lossy();
+    let conn_config: ConnectionsConfig = read_yaml(config_path).unwrap();
+
+    // read setup config
+    let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+    let config_path = config_path.to_string_lossy();
+    let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
+
+    // mq client
+    let mut mq_client = MqClient::new();
+    mq_client.connect(conn_config.mq).await.unwrap();
+    mq_client.open_channel(None).await.unwrap();
+    let chan = mq_client.channel().unwrap();
+
+    let shovel = Shovel::new(chan);
+    let que = args
+        .queue
+        .clone()
+        .unwrap_or(init_config.dead_letter_queue.clone());
+    let limit = args.limit.unwrap_or(LIMIT);
+
+    match args.option.as_str() {
+        LS => {
+            info!("{} LS {}", now!(), que);
+            let messages = shovel.browse(&que, limit).await.unwrap();
+            messages.iter().filter(|m| args.is_picked(m)).for_each(show);
+            info!("{} {} message(s) listed", now!(), messages.len());
+        }
+        REPLAY => {
+            info!("{} REPLAY {}", now!(), que);
+            let patch: CommandPatch = match &args.patch {
+                Some(p) => {
+                    let p = get_cur_dir_file(p).unwrap();
+                    read_json(p.to_string_lossy()).unwrap()
+                }
+                None => CommandPatch::default(),
+            };
+            let target = args.target.as_deref().unwrap_or(TARGET_QUEUE);
+            let count = shovel
+                .transfer(&que, limit, |m| {
+                    if !args.is_picked(m) {
+                        return Ok(vec![]);
+                    }
+                    replay(m, &patch, target, &init_config)
+                })
+                .await
+                .unwrap();
+            info!("{} {} message(s) replayed", now!(), count);
+        }
+        MV => {
+            let to = args.to.as_deref().expect("`--to` is required");
+            info!("{} MV {} -> {}", now!(), que, to);
+            let count = shovel
+                .transfer(&que, limit, |m| {
+                    if !args.is_picked(m) {
+                        return Ok(vec![]);
+                    }
+                    Ok(vec![Republish::to_queue(to, m)])
+                })
+                .await
+                .unwrap();
+            info!("{} {} message(s) moved", now!(), count);
+        }
+        _ => panic!("undefined option"),
+    }
+
+    info!("{} End replayer 😎", now!());
+}
@@ -0,0 +1,270 @@
+//! file: scheduler.rs
+//! author: Jacob Xie
+//! date: 2023/07/26 22:31:09 Wednesday
+//! brief: fire the `Command` of cron schedules, only by the leader among schedulers
+
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use clap::Parser;
+use pqx::amqprs::BasicProperties;
+use pqx::error::PqxResult;
+use pqx::mq::{shutdown_signal, MqClient, Publisher};
+use pqx::pqx_util::*;
+use pqx_app::adt::Command;
+use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig};
+use pqx_app::schedule::{
+    CronSchedule, FiringStatus, FiringStore, LeaderLock, Overlap, ScheduleConfig,
+};
+use tracing::{debug, error, info, warn};
+
+// ================================================================================================
+// Const
+// ================================================================================================
+
+// commands
+const RUN: &str = "run";
+const NEXT: &str = "next";
+const HISTORY: &str = "history";
+
+// default constants
+const LOGGING_DIR: &str = "./logs";
+const FILENAME_PREFIX: &str = "pqx_scheduler";
+const CONN_CONFIG: &str = "conn.yml";
+const INIT_CONFIG: &str = "init.yml";
+const SCHEDULE_CONFIG: &str = "schedule.yml";
+const TICK: u64 = 1; // seconds
+const COUNT: usize = 5;
+
+// ================================================================================================
+// Args
+// ================================================================================================
+
+#[derive(Debug, Parser)]
+struct Args {
+    #[arg(short, long)]
+    option: String,
+    config: Option<String>, // schedule file path
+    // number of upcoming firings of `next`, or of recent firings of `history`
+    #[arg(short, long)]
+    count: Option<usize>,
+}
+
+// ================================================================================================
+// Fn
+// ================================================================================================
+
+async fn publish(publisher: &Publisher<'_>, exchange: &str, cmd: &Command) -> PqxResult<()> {
+    let props_list = Vec::<BasicProperties>::try_from(cmd)?;
+    for (r, props) in cmd.mailing_to().iter().zip(props_list) {
+        publisher
+            .publish_with_props(exchange, r.routing_key(), cmd.clone(), props)
+            .await?;
+    }
+
+    Ok(())
+}
+
+// firing times due since the latest recorded one (or `since`, for a new schedule), so that those
+// missed while no scheduler was leading are caught up or recorded as skipped. With
+// `Overlap::Forbid`, the rest wait for the running firing, and are fired one per tick
+async fn tick(
+    store: &FiringStore,
+    publisher: &Publisher<'_>,
+    exchange: &str,
+    schedule: &CronSchedule,
+    since: DateTime<Local>,
+    grace: Duration,
+) -> PqxResult<()> {
+    let now = Local::now();
+    let after = match store.last(schedule.name()).await? {
+        Some(l) => l.scheduled_at,
+        None => since,
+    };
+    let due = schedule.due(after, now);
+    if due.is_empty() {
+        return Ok(());
+    }
+    let forbid = schedule.entry().overlap == Overlap::Forbid;
+    if forbid && store.running(schedule).await? {
+        debug!("{} {} is running, waiting", now!(), schedule.name());
+        return Ok(());
+    }
+
+    let plan = schedule.plan(due, now, grace);
+    for t in plan.skipped {
+        if store
+            .claim(schedule, t, FiringStatus::SkippedMisfire)
+            .await?
+        {
+            warn!("{} {} misfired at {}", now!(), schedule.name(), t);
+        }
+    }
+    for t in plan.fire {
+        // claimed by another scheduler, which used to be the leader
+        if !store.claim(schedule, t, FiringStatus::Firing).await? {
+            continue;
+        }
+        let cmd = schedule.command(&t);
+        let res = publish(publisher, exchange, &cmd).await;
+        match &res {
+            Ok(_) => info!("{} fired {} of {}", now!(), cmd.message_id().unwrap(), t),
+            Err(e) => error!("{} fire {} failed: {}", now!(), schedule.name(), e),
+        }
+        store
+            .finish(schedule.name(), t, res.err().map(|e| e.to_string()))
+            .await?;
+
+        if forbid {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// ================================================================================================
+// Main
+// ================================================================================================
+
+/// 0. cargo run --bin scheduler -- -o run
+/// 1. cargo run --bin scheduler -- -o next -c 10
+/// 2. cargo run --bin scheduler -- -o history -c 10
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    let _guard = logging_init(LOGGING_DIR, FILENAME_PREFIX, tracing::Level::INFO).unwrap();
+
+    info!("{} Start scheduler... ⏰", now!());
+
+    // read configs
+    let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+    let config_path = config_path.to_string_lossy();
+    let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
+    let config_path = get_cur_dir_file(CONN_CONFIG).unwrap();
+    let config_path = config_path.to_string_lossy();
+    let conn_config: ConnectionsConfig = read_yaml(config_path).unwrap();
+    let config_path = get_cur_dir_file(args.config.as_deref().unwrap_or(SCHEDULE_CONFIG)).unwrap();
+    let config_path = config_path.to_string_lossy();
+    let schedule_config: ScheduleConfig = read_yaml(config_path).unwrap();
+
+    // a recipient of the other routing mode, or using unbound keys, reaches no queue
+    let schedules = match schedule_config.compile(&init_config) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("{} invalid schedules: {}", now!(), e);
+            return;
+        }
+    };
+    let count = args.count.unwrap_or(COUNT);
+
+    match args.option.as_str() {
+        NEXT => {
+            for s in schedules.iter() {
+                for t in s.upcoming(Local::now(), count) {
+                    info!(
+                        "{} {} next: {}",
+                        now!(),
+                        s.name(),
+                        t.with_timezone(&s.timezone())
+                    );
+                }
+            }
+        }
+        HISTORY => {
+            let mut ps = PersistClient::new(conn_config.db);
+            ps.with_sqlx_logging(false).connect().await.unwrap();
+            let store = FiringStore::new(ps.db.unwrap(), instance_id());
+
+            for s in schedules.iter() {
+                for f in store.recent(s.name(), count as u64).await.unwrap() {
+                    info!(
+                        "{} {} {}: {} {:?} by {} {:?}",
+                        now!(),
+                        s.name(),
+                        f.scheduled_at,
+                        f.status,
+                        f.message_id,
+                        f.scheduler,
+                        f.error
+                    );
+                }
+            }
+        }
+        RUN => {
+            // firing store
+            let mut ps = PersistClient::new(conn_config.db.clone());
+            ps.with_sqlx_logging(false).connect().await.unwrap();
+            let store = FiringStore::new(ps.db.unwrap(), instance_id());
+
+            // a dedicated connection holding the advisory lock
+            let mut ps = PersistClient::new(conn_config.db);
+            ps.with_sqlx_logging(false)
+                .with_max_connection(1)
+                .with_min_connection(1)
+                .connect()
+                .await
+                .unwrap();
+            let lock = LeaderLock::new(ps.db.unwrap(), schedule_config.lock_key());
+
+            // mq client
+            let mut mq_client = MqClient::new();
+            mq_client.connect(conn_config.mq).await.unwrap();
+            mq_client.open_channel(None).await.unwrap();
+            let chan = mq_client.channel().unwrap();
+            let exchange = init_config.exchange().unwrap();
+
+            let mut publisher = Publisher::new(chan);
+            publisher.enable_confirm().await.unwrap();
+
+            let grace = schedule_config.misfire_grace();
+            let since = Local::now();
+            let mut leader = false;
+            let mut interval = tokio::time::interval(Duration::from_secs(TICK));
+            // kept across ticks, not to miss a signal while ticking
+            let shutdown = shutdown_signal();
+            tokio::pin!(shutdown);
+            info!("{} {} schedules loaded", now!(), schedules.len());
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {},
+                    res = &mut shutdown => {
+                        res.unwrap();
+                        break;
+                    },
+                }
+
+                match lock.acquire().await {
+                    Ok(l) if l != leader => {
+                        leader = l;
+                        info!("{} leader: {}", now!(), leader);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("{} leader election failed: {}", now!(), e);
+                        leader = false;
+                    }
+                }
+                if !leader {
+                    continue;
+                }
+
+                for s in schedules.iter() {
+                    if let Err(e) = tick(&store, &publisher, exchange, s, since, grace).await {
+                        error!("{} {} tick failed: {}", now!(), s.name(), e);
+                    }
+                }
+            }
+
+            if leader {
+                lock.release().await.unwrap();
+            }
+            publisher.block(1).await;
+        }
+        _ => panic!("undefined option"),
+    }
+
+    info!("{} End scheduler 🫡", now!());
+}
@@ -4,14 +4,24 @@
 //! brief:
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
+use pqx::amqprs::channel::{QueueBindArguments, QueueDeclareArguments};
 use pqx::error::PqxResult;
-use pqx::mq::{MqClient, Subscriber};
+use pqx::mq::{
+    set_max_decompressed_size, AppIdFilter, CancelListener, DedupStore, Hop, MemoryDedupStore,
+    Metrics, MqClient, QueueType, Requeue, Subscriber, Tombstones, CANCEL_ROUTING_KEY,
+};
 use pqx::pqx_util::*;
-use pqx_app::cfg::{ConnectionsConfig, InitiationsConfig};
+use pqx_app::cancel::PgCancelStore;
+use pqx_app::cfg::{ConnectionsConfig, DedupConfig, InitiationsConfig};
+use pqx_app::dedup::PgDedupStore;
+use pqx_app::delay::PgScheduleStore;
 use pqx_app::exec::Executor;
+use pqx_app::middleware::{CurrentTask, Logging};
 use pqx_app::persist::MessagePersistent;
+use pqx_app::worker::{WorkerInfo, WorkerRegistry};
 use tracing::{error, info, instrument};
 
 // ================================================================================================
@@ -20,8 +30,14 @@ use tracing::{error, info, instrument};
 
 const LOGGING_DIR: &str = "./logs";
 const FILENAME_PREFIX: &str = "pqx_subscriber";
+const SERVICE_NAME: &str = "pqx-subscriber";
 const CONN_CONFIG: &str = "conn.yml";
 const INIT_CONFIG: &str = "init.yml";
+const DRAIN_TIMEOUT: u64 = 600; // seconds
+const KILL_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+const TOMBSTONE_TTL: u64 = 86400; // seconds
+const HEARTBEAT: u64 = 10; // seconds
+const CAPACITY: u16 = 1; // prefetch count
 
 // ================================================================================================
 // Helper
@@ -62,8 +78,20 @@ struct Args {
 async fn main() {
     let args = Args::parse();
 
+    // read setup config, before logging since it might export traces
+    let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+    let config_path = config_path.to_string_lossy();
+    let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
+
     // let _guard = logging_file_init(LOGGING_DIR, FILENAME_PREFIX, tracing::Level::INFO).unwrap();
-    let _guard = logging_file_init(LOGGING_DIR, FILENAME_PREFIX, tracing::Level::DEBUG).unwrap();
+    let _guard = logging_file_init_with_trace(
+        LOGGING_DIR,
+        FILENAME_PREFIX,
+        tracing::Level::DEBUG,
+        SERVICE_NAME,
+        init_config.trace.as_ref(),
+    )
+    .unwrap();
 
     info!("{} Start subscriber... 🫨", now!());
 
@@ -71,11 +99,6 @@ async fn main() {
     let config_path = get_cur_dir_file(CONN_CONFIG).unwrap();
     let conn_config: ConnectionsConfig = read_yaml(config_path.to_str().unwrap()).unwrap();
 
-    // read setup config
-    let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
-    let config_path = config_path.to_string_lossy();
-    let init_config: InitiationsConfig = read_yaml(config_path).unwrap();
-
     // setup mq
     let mut mq = MqClient::new();
     mq.connect(conn_config.mq).await.unwrap();
@@ -84,25 +107,184 @@ async fn main() {
     // setup db
     let mut ps = PersistClient::new(conn_config.db);
     ps.with_sqlx_logging(false).connect().await.unwrap();
-    let mp = MessagePersistent::new(ps.db.unwrap());
+    let db = ps.db.unwrap();
+    let mp = MessagePersistent::new(db.clone());
+
+    // a compressed command inflating beyond the limit is discarded
+    if let Some(n) = init_config.subscriber.max_decompressed_size {
+        set_max_decompressed_size(n);
+    }
 
     // setup consumer
-    let mut consumer = Executor::new(init_config.delayed_exchange, mp);
+    let mut consumer = Executor::new(init_config.retry_backend().unwrap(), mp);
+    if let Some(p) = init_config.subscriber.retry_policy.clone() {
+        consumer.set_retry_policy(p);
+    }
+    // commands exiting with `requeue_exit_codes` are requeued until `max_requeues`, then
+    // dead-lettered to the DLX of queues
+    if let Some(codes) = init_config.subscriber.requeue_exit_codes.clone() {
+        consumer.set_requeue_exit_codes(codes);
+    }
+    if let Some(n) = init_config.subscriber.max_requeues {
+        let mut requeue = Requeue::new(n, &init_config.dead_letter_exchange, "");
+        if let Some(d) = init_config.subscriber.requeue_delay {
+            requeue.set_delay(Duration::from_millis(d));
+        }
+        consumer.set_requeue(requeue);
+    }
+    // scheduled commands hop through the delayed exchange until due, unless cancelled. They are
+    // rejected by the publisher under other retry backends
+    if init_config.check_scheduling().is_ok() {
+        let mut hop = Hop::new(&init_config.delayed_exchange);
+        hop.set_store(Arc::new(PgScheduleStore::new(db.clone())));
+        consumer.set_hop(hop);
+    }
+    // cancelled commands are skipped while queued, and killed while running. The ones cancelled
+    // while this subscriber was down are loaded
+    let tombstones = match &init_config.control_exchange {
+        Some(_) => {
+            let ttl = init_config
+                .subscriber
+                .tombstone_ttl
+                .unwrap_or(TOMBSTONE_TTL);
+            let ttl = Duration::from_secs(ttl);
+            let tombstones = Tombstones::new(ttl);
+            let n = PgCancelStore::new(db.clone())
+                .load(&tombstones, ttl)
+                .await
+                .unwrap();
+            info!("{} {} cancelled task(s) loaded", now!(), n);
+            consumer.set_tombstones(tombstones.clone());
+            Some(tombstones)
+        }
+        None => None,
+    };
+    if let Some(d) = init_config.subscriber.dedup {
+        let store: Arc<dyn DedupStore> = match d {
+            DedupConfig::Memory { capacity, .. } => {
+                Arc::new(MemoryDedupStore::new(capacity, d.ttl()))
+            }
+            DedupConfig::Postgres { .. } => Arc::new(PgDedupStore::new(db.clone(), d.ttl())),
+        };
+        consumer.set_dedup_store(store);
+    }
     consumer
         .exec_mut()
         .register_stdout_fn(Arc::new(logging_info))
         .register_stderr_fn(Arc::new(logging_error));
 
+    // shares the running children, killed unless drained
+    let exec = consumer.exec().clone();
+
     // setup subscriber
     let chan = mq.channel().unwrap();
     let mut subscriber = Subscriber::new(chan, consumer);
-    subscriber.set_prefetch(0, 1, false).await.unwrap();
+    subscriber.set_prefetch(0, CAPACITY, false).await.unwrap();
+    // middleware layers, from the outermost one
+    let metrics = Metrics::new();
+    let current_task = CurrentTask::default();
+    subscriber
+        .add_middleware(Logging)
+        .add_middleware(metrics.clone())
+        .add_middleware(current_task.clone());
+    if let Some(ids) = init_config.subscriber.allowed_app_ids {
+        subscriber.add_middleware(AppIdFilter::new(ids));
+    }
+    // a stream queue is read from an offset, which is `next` by default
+    let hq = init_config
+        .header_queues
+        .iter()
+        .find(|hq| hq.queue == args.queue);
+    if let Some(hq) = hq.filter(|hq| hq.queue_type == QueueType::Stream) {
+        let offset = hq.stream_offset.clone().unwrap_or_default();
+        info!("{} consume stream {} from {:?}", now!(), hq.queue, offset);
+        subscriber.set_stream_offset(&offset);
+    }
+
+    // cancel requests reach every subscriber, by an exclusive queue of its own
+    let mut listener = None;
+    if let (Some(x), Some(t)) = (&init_config.control_exchange, tombstones) {
+        let declare = QueueDeclareArguments::exclusive_server_named();
+        let (que, _, _) = chan.queue_declare(declare).await.unwrap().unwrap();
+        let bind = QueueBindArguments::new(&que, x, CANCEL_ROUTING_KEY);
+        chan.queue_bind(bind).await.unwrap();
+        let mut l = Subscriber::new(chan, CancelListener::new(t));
+        l.consume(&que).await.unwrap();
+        listener = Some(l);
+    }
 
     // start consume
     subscriber.consume(&args.queue).await.unwrap();
 
-    // block until fail
-    subscriber.soft_fail_block().await;
+    // register as a worker, and heartbeat with the task being consumed
+    let heartbeat = init_config.subscriber.heartbeat.unwrap_or(HEARTBEAT);
+    let heartbeat = Duration::from_secs(heartbeat.max(1));
+    let labels = hq.map(|hq| hq.kv.clone()).unwrap_or_default();
+    let mut info = WorkerInfo::new(&args.queue, labels, CAPACITY);
+    info.set_heartbeat(heartbeat);
+    if let Some(tag) = subscriber.consumer_tag() {
+        info.add_consumer_tag(tag);
+    }
+    if let Some(tag) = listener.as_ref().and_then(|l| l.consumer_tag()) {
+        info.add_consumer_tag(tag);
+    }
+    let registry = WorkerRegistry::new(db);
+    registry.register(&info).await.unwrap();
+    info!("{} registered as worker {}", now!(), registry.worker_id());
+    let heartbeat_task = tokio::spawn({
+        let registry = registry.clone();
+        async move {
+            let mut interval = tokio::time::interval(heartbeat);
+            loop {
+                interval.tick().await;
+                if let Err(e) = registry.heartbeat(current_task.get()).await {
+                    error!("{} heartbeat failed: {:?}", now!(), e);
+                }
+            }
+        }
+    });
+
+    // block until fail or SIGTERM/SIGINT, then drain in-flight tasks
+    let drain_timeout = init_config
+        .subscriber
+        .drain_timeout
+        .unwrap_or(DRAIN_TIMEOUT);
+    match subscriber
+        .graceful_block(Duration::from_secs(drain_timeout))
+        .await
+    {
+        Ok(true) => info!("{} drained", now!()),
+        Ok(false) => {
+            error!(
+                "{} drain timeout, {} task(s) unfinished",
+                now!(),
+                subscriber.in_flight()
+            );
+            // the commands would outlive the subscriber, while their messages are requeued. Once
+            // killed, they are handled as failed ones
+            let n = exec.kill_running();
+            info!("{} {} running command(s) killed", now!(), n);
+            if !subscriber.drain(KILL_DRAIN_TIMEOUT).await {
+                error!("{} killed command(s) unfinished", now!());
+            }
+        }
+        Err(e) => {
+            error!("{} shutdown failed: {:?}", now!(), e);
+            // no command outlives the subscriber, whatever the way it stops
+            let n = exec.kill_running();
+            info!("{} {} running command(s) killed", now!(), n);
+        }
+    }
+    info!("{} {:?}", now!(), metrics.snapshot());
+    heartbeat_task.abort();
+    if let Err(e) = registry.deregister().await {
+        error!("{} deregister failed: {:?}", now!(), e);
+    }
+
+    // close channel & connection
+    drop(listener);
+    drop(subscriber);
+    mq.disconnect().await.unwrap();
 
     info!("{} End subscriber 😎", now!());
 }
@@ -0,0 +1,80 @@
+//! file: cancel.rs
+//! author: Jacob Xie
+//! date: 2023/07/28 21:20:03 Friday
+//! brief: cancel requests recorded in Postgres, tombstoned by subscribers on startup
+
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use pqx::error::PqxResult;
+use pqx::mq::{CancelRequest, Tombstones};
+use pqx::pqx_util::PqxUtilError;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::*;
+
+use crate::entities::task_cancel;
+
+// ================================================================================================
+// PgCancelStore
+//
+// A cancel request is recorded before being published to the control exchange, which only
+// reaches the running subscribers. Hence a subscriber tombstones the recent ones on startup, so
+// that the queued copies it consumes afterwards are skipped as well.
+// ================================================================================================
+
+#[derive(Clone, Debug)]
+pub struct PgCancelStore {
+    db: DatabaseConnection,
+}
+
+impl PgCancelStore {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    // false if the task has been cancelled
+    pub async fn request(&self, req: &CancelRequest) -> PqxResult<bool> {
+        let am = task_cancel::ActiveModel {
+            task_id: Set(req.task_id.clone()),
+            reason: Set(req.reason.clone()),
+            requested_at: Set(Local::now()),
+        };
+        let on_conflict = OnConflict::column(task_cancel::Column::TaskId)
+            .do_nothing()
+            .to_owned();
+        let res = task_cancel::Entity::insert(am)
+            .on_conflict(on_conflict)
+            .exec_without_returning(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res == 1)
+    }
+
+    // requested within `ttl`, the latest first
+    pub async fn recent(&self, ttl: Duration) -> PqxResult<Vec<task_cancel::Model>> {
+        let ttl = chrono::Duration::from_std(ttl).unwrap_or(chrono::Duration::zero());
+        let res = task_cancel::Entity::find()
+            .filter(task_cancel::Column::RequestedAt.gt(Local::now() - ttl))
+            .order_by_desc(task_cancel::Column::RequestedAt)
+            .all(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res)
+    }
+
+    // tombstone the tasks cancelled within `ttl`, keeping their time of being requested
+    pub async fn load(&self, tombstones: &Tombstones, ttl: Duration) -> PqxResult<usize> {
+        let recent = self.recent(ttl).await?;
+        for m in recent.iter() {
+            let elapsed = (Local::now() - m.requested_at).to_std().unwrap_or_default();
+            let at = Instant::now()
+                .checked_sub(elapsed)
+                .unwrap_or_else(Instant::now);
+            tombstones.cancel_since(&m.task_id, at);
+        }
+
+        Ok(recent.len())
+    }
+}
@@ -3,11 +3,23 @@
 //! date: 2023/06/18 00:47:30 Sunday
 //! brief:
 
-use std::collections::HashMap;
+use std::time::Duration;
 
-use pqx::mq::{MatchType, MqConn};
-use pqx::pqx_util::{MqApiCfg, PersistConn};
-use serde::Deserialize;
+use pqx::amqprs::FieldTable;
+use pqx::error::PqxResult;
+use pqx::mq::{
+    topic_matches, DeadLetterStrategy, MqConn, Overflow, QueueType, RetryBackend, RetryPolicy,
+    StreamOffset, X_DEAD_LETTER_EXCHANGE, X_DEAD_LETTER_STRATEGY, X_DEAD_ROUTING_KEY,
+    X_DELIVERY_LIMIT, X_MAX_AGE, X_MAX_LENGTH, X_MAX_LENGTH_BYTES, X_MAX_PRIORITY, X_MESSAGE_TTL,
+    X_OVERFLOW, X_QUEUE_TYPE,
+};
+use pqx::pqx_util::{MqApiCfg, PersistConn, TraceExport};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use serde_json::json;
+
+use crate::routing::{Criterion, Labels, Recipient, RoutingMode, MAX_LABELS};
+use crate::topology::Arguments;
 
 // ================================================================================================
 // Connections config
@@ -27,18 +39,337 @@ pub struct ConnectionsConfig {
 #[derive(Debug, Deserialize)]
 pub struct HeaderQueue {
     pub queue: String,
-    pub match_type: MatchType,
-    pub kv: HashMap<String, String>,
+    // removed (a `mailing_to` criterion carries its own match mode), an error if given
+    #[serde(default, deserialize_with = "removed_match_type")]
+    pub match_type: (),
+    #[serde(default)]
+    pub kv: Labels, // bound for both `all` & `any` criteria, see `routing`
+    #[serde(default)]
+    pub patterns: Vec<String>, // topic binding keys, e.g. `etl.*.cn`
+    pub max_priority: Option<u8>, // declares the queue with `x-max-priority`, classic only
+    #[serde(default)]
+    pub queue_type: QueueType,
+    pub delivery_limit: Option<i64>, // quorum only
+    pub dead_letter_strategy: Option<DeadLetterStrategy>, // quorum only
+    pub max_age: Option<String>,     // retention, stream only
+    pub stream_offset: Option<StreamOffset>, // where a subscriber starts reading, stream only
+    pub max_length: Option<i64>,     // number of ready messages, not for streams
+    pub max_length_bytes: Option<i64>, // total body size of ready messages
+    pub overflow: Option<Overflow>,  // behaviour of a full queue, not for streams
+    pub message_ttl: Option<i64>,    // milliseconds, not for streams
+}
+
+fn removed_match_type<'de, D: Deserializer<'de>>(_: D) -> Result<(), D::Error> {
+    Err(D::Error::custom(
+        "header_queues.match_type is removed, give `match` (all/any) in each `mailing_to` criterion \
+         instead, see `routing`",
+    ))
+}
+
+impl HeaderQueue {
+    pub fn check(&self) -> PqxResult<()> {
+        if self.kv.len() > MAX_LABELS {
+            return Err("kv has more than 8 labels".into());
+        }
+        if self.kv.keys().any(|k| k.starts_with("x-")) {
+            return Err("kv key must not begin with x-".into());
+        }
+        if self.patterns.iter().any(|p| p.is_empty() || p.len() > 255) {
+            return Err("pattern must be of 1 to 255 bytes".into());
+        }
+        let qt = self.queue_type;
+        if self.max_priority.is_some() && qt != QueueType::Classic {
+            return Err("max_priority is only supported by classic queues".into());
+        }
+        let quorum = self.delivery_limit.is_some() || self.dead_letter_strategy.is_some();
+        if quorum && qt != QueueType::Quorum {
+            return Err("delivery_limit & dead_letter_strategy require a quorum queue".into());
+        }
+        let stream = self.max_age.is_some() || self.stream_offset.is_some();
+        if stream && qt != QueueType::Stream {
+            return Err("max_age & stream_offset require a stream queue".into());
+        }
+        let limits = self.max_length.is_some() || self.overflow.is_some();
+        if (limits || self.message_ttl.is_some()) && qt == QueueType::Stream {
+            return Err("max_length, overflow & message_ttl are not supported by streams".into());
+        }
+        if self.overflow == Some(Overflow::RejectPublishDlx) && qt != QueueType::Classic {
+            return Err("overflow reject-publish-dlx is only supported by classic queues".into());
+        }
+        if self.dead_letter_strategy == Some(DeadLetterStrategy::AtLeastOnce)
+            && self.overflow.unwrap_or(Overflow::RejectPublish) != Overflow::RejectPublish
+        {
+            return Err(
+                "dead_letter_strategy at-least-once requires overflow reject-publish".into(),
+            );
+        }
+
+        Ok(())
+    }
+
+    // quorum & stream queues have to be durable
+    pub fn durable(&self) -> bool {
+        self.queue_type != QueueType::Classic
+    }
+
+    // declare arguments, failed messages are dead-lettered to `dead_letter_exchange` (except
+    // streams, which do not dead-letter)
+    pub fn arguments(&self, dead_letter_exchange: &str) -> Arguments {
+        let mut args = Arguments::new();
+        if self.queue_type != QueueType::Classic {
+            args.insert(X_QUEUE_TYPE.to_string(), json!(self.queue_type.to_string()));
+        }
+        if self.queue_type != QueueType::Stream {
+            args.insert(
+                X_DEAD_LETTER_EXCHANGE.to_string(),
+                json!(dead_letter_exchange),
+            );
+            args.insert(X_DEAD_ROUTING_KEY.to_string(), json!(""));
+        }
+        if let Some(p) = self.max_priority {
+            args.insert(X_MAX_PRIORITY.to_string(), json!(p));
+        }
+        if let Some(l) = self.delivery_limit {
+            args.insert(X_DELIVERY_LIMIT.to_string(), json!(l));
+        }
+        if let Some(s) = self.dead_letter_strategy {
+            args.insert(X_DEAD_LETTER_STRATEGY.to_string(), json!(s.to_string()));
+            if s == DeadLetterStrategy::AtLeastOnce {
+                args.insert(X_OVERFLOW.to_string(), json!("reject-publish"));
+            }
+        }
+        if let Some(a) = &self.max_age {
+            args.insert(X_MAX_AGE.to_string(), json!(a));
+        }
+        if let Some(l) = self.max_length {
+            args.insert(X_MAX_LENGTH.to_string(), json!(l));
+        }
+        if let Some(b) = self.max_length_bytes {
+            args.insert(X_MAX_LENGTH_BYTES.to_string(), json!(b));
+        }
+        if let Some(o) = self.overflow {
+            args.insert(X_OVERFLOW.to_string(), json!(o.to_string()));
+        }
+        if let Some(t) = self.message_ttl {
+            args.insert(X_MESSAGE_TTL.to_string(), json!(t));
+        }
+
+        args
+    }
+
+    // whether a message sent to `recipient` is routed to this queue, by the header exchange or
+    // the topic exchange
+    pub fn matches(&self, recipient: &Recipient) -> bool {
+        match recipient {
+            Recipient::Criterion(c) => c.matches(&self.kv),
+            Recipient::RoutingKey(k) => self.patterns.iter().any(|p| topic_matches(p, k)),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryBackendType {
+    // requires plugin `rabbitmq_delayed_message_exchange`
+    #[default]
+    DelayedExchange,
+    // TTL + DLX wait queues, no plugin required
+    WaitQueues,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct InitiationsConfig {
+    #[serde(default)]
+    pub routing: RoutingMode, // which exchange commands are published to, and retried by
     pub header_exchange: String,
+    pub topic_exchange: Option<String>,
     pub header_queues: Vec<HeaderQueue>,
+    #[serde(default)]
+    pub retry_backend: RetryBackendType,
     pub delayed_exchange: String,
+    pub wait_exchange: Option<String>,
+    #[serde(default)]
+    pub wait_queues: Vec<u32>, // seconds, delay tiers of wait queues
     pub dead_letter_exchange: String,
     pub dead_letter_queue: String,
-    pub dead_message_ttl: Option<i64>, // milliseconds
+    pub dead_message_ttl: Option<i64>,    // milliseconds
+    pub control_exchange: Option<String>, // carries cancel requests, disabled if absent
+    #[serde(default)]
+    pub subscriber: SubscriberConfig,
+    pub trace: Option<TraceExport>, // export spans of publishes & deliveries, disabled if absent
+}
+
+impl InitiationsConfig {
+    pub fn retry_backend(&self) -> PqxResult<RetryBackend> {
+        match self.retry_backend {
+            RetryBackendType::DelayedExchange => {
+                Ok(RetryBackend::delayed_exchange(&self.delayed_exchange))
+            }
+            RetryBackendType::WaitQueues => {
+                let exchange = self
+                    .wait_exchange
+                    .as_ref()
+                    .ok_or("wait_exchange is required by wait_queues backend")?;
+                if self.wait_queues.is_empty() {
+                    return Err("wait_queues is required by wait_queues backend".into());
+                }
+
+                Ok(RetryBackend::wait_queues(
+                    exchange,
+                    self.wait_queues.iter().copied(),
+                ))
+            }
+        }
+    }
+
+    // scheduled commands (`run_at` or `delay`) hop through the delayed exchange, which is only
+    // declared by the `delayed_exchange` backend
+    pub fn check_scheduling(&self) -> PqxResult<()> {
+        if self.retry_backend != RetryBackendType::DelayedExchange {
+            return Err("run_at & delay require the delayed_exchange retry backend".into());
+        }
+
+        Ok(())
+    }
+
+    // the exchange commands are published to
+    pub fn exchange(&self) -> PqxResult<&str> {
+        match self.routing {
+            RoutingMode::Header => Ok(&self.header_exchange),
+            RoutingMode::Topic => Ok(self
+                .topic_exchange
+                .as_ref()
+                .ok_or("topic_exchange is required by topic routing")?),
+        }
+    }
+
+    // queues a `mailing_to` recipient is routed to
+    pub fn route(&self, recipient: &Recipient) -> Vec<String> {
+        self.header_queues
+            .iter()
+            .filter(|hq| hq.matches(recipient))
+            .map(|hq| hq.queue.clone())
+            .collect()
+    }
+
+    // a recipient has to follow `routing`, and is checked against the bound keys or patterns
+    pub fn check_recipient(&self, recipient: &Recipient) -> PqxResult<()> {
+        if recipient.mode() != self.routing {
+            return Err("recipient doesn't follow the routing mode".into());
+        }
+
+        match recipient {
+            Recipient::Criterion(c) => self.check_criterion(c),
+            Recipient::RoutingKey(k) => self.check_routing_key(k),
+        }
+    }
+
+    // a routing key has no wildcard, and is matched by some queue's patterns
+    pub fn check_routing_key(&self, key: &str) -> PqxResult<()> {
+        if key.is_empty() || key.len() > 255 {
+            return Err("routing key must be of 1 to 255 bytes".into());
+        }
+        if key.split('.').any(|w| w == "*" || w == "#") {
+            return Err("routing key must not contain wildcards".into());
+        }
+        if self.route(&Recipient::from(key)).is_empty() {
+            return Err("routing key is not matched by any queue pattern".into());
+        }
+
+        Ok(())
+    }
+
+    // a criterion can only use the keys bound by `header_queues`, with the same value types,
+    // otherwise it silently reaches nothing
+    pub fn check_criterion(&self, criterion: &Criterion) -> PqxResult<()> {
+        if criterion.labels.is_empty() {
+            return Err("criterion has no label".into());
+        }
+        for (k, v) in criterion.labels.iter() {
+            let declared = self
+                .header_queues
+                .iter()
+                .filter_map(|hq| hq.kv.get(k))
+                .collect::<Vec<_>>();
+            if declared.is_empty() {
+                return Err("criterion key is not bound by any queue".into());
+            }
+            if !declared.iter().any(|d| d.type_name() == v.type_name()) {
+                return Err("criterion value type differs from the bound one".into());
+            }
+        }
+
+        Ok(())
+    }
+
+    // the criterion a delivered message was routed by, only the keys bound by `header_queues`
+    // are labels, other headers (e.g. `traceparent`) are not
+    pub fn criterion_of(&self, headers: &FieldTable) -> Criterion {
+        let mut criterion = Criterion::from_headers(headers);
+        criterion
+            .labels
+            .retain(|k, _| self.header_queues.iter().any(|hq| hq.kv.contains_key(k)));
+
+        criterion
+    }
+
+    pub fn wait_queue_names(&self) -> Vec<String> {
+        match &self.wait_exchange {
+            Some(x) => self
+                .wait_queues
+                .iter()
+                .map(|t| RetryBackend::wait_queue_name(x, *t))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+// ================================================================================================
+// Subscriber config
+// ================================================================================================
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SubscriberConfig {
+    // used when a `Command` has neither `retry_policy` nor `poke`
+    pub retry_policy: Option<RetryPolicy>,
+    // deduplicate deliveries by `message_id`, disabled if absent
+    pub dedup: Option<DedupConfig>,
+    // seconds to wait for in-flight tasks when shutting down
+    pub drain_timeout: Option<u64>,
+    // only consume commands published with one of these `app_id`, disabled if absent
+    pub allowed_app_ids: Option<Vec<String>>,
+    // seconds a cancelled task id is kept, skipping its queued copies
+    pub tombstone_ttl: Option<u64>,
+    // seconds between heartbeats of the worker registry
+    pub heartbeat: Option<u64>,
+    // bytes a compressed command may decompress to, `MAX_DECOMPRESSED_SIZE` if absent
+    pub max_decompressed_size: Option<usize>,
+    // exit codes of a command which are requeued (`ConsumerResult::Failure`) instead of retried,
+    // e.g. 75 (EX_TEMPFAIL)
+    pub requeue_exit_codes: Option<Vec<i32>>,
+    // requeues of a failed command before it is dead-lettered, without limit if absent
+    pub max_requeues: Option<u8>,
+    // milliseconds to wait before each requeue
+    pub requeue_delay: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum DedupConfig {
+    // in-memory LRU, only for the consumers of a single subscriber
+    Memory { capacity: usize, ttl: u64 },
+    // `message_dedup` table, shared by all subscribers
+    Postgres { ttl: u64 },
+}
+
+impl DedupConfig {
+    pub fn ttl(&self) -> Duration {
+        match self {
+            DedupConfig::Memory { ttl, .. } => Duration::from_secs(*ttl),
+            DedupConfig::Postgres { ttl } => Duration::from_secs(*ttl),
+        }
+    }
 }
 
 // ================================================================================================
@@ -49,7 +380,11 @@ pub struct InitiationsConfig {
 mod test_cfg {
     use pqx::pqx_util::{get_cur_dir_file, read_yaml};
 
+    use pqx::amqprs::{FieldName, FieldValue};
+    use pqx::mq::{FieldTableBuilder, TraceContext};
+
     use super::*;
+    use crate::routing::{LabelValue, X_PQX_LABELS};
 
     const INIT_CONFIG: &str = "init.template.yml";
 
@@ -61,4 +396,183 @@ mod test_cfg {
 
         println!("{:?}", config);
     }
+
+    #[test]
+    fn header_queue_match_type_fail() {
+        let hq = json!({"queue": "h1", "kv": {"unique_key": "h1"}});
+        assert!(serde_json::from_value::<HeaderQueue>(hq).is_ok());
+
+        let hq = json!({"queue": "h1", "match_type": "any", "kv": {"unique_key": "h1"}});
+        let err = serde_json::from_value::<HeaderQueue>(hq).unwrap_err();
+        assert!(err.to_string().contains("match_type is removed"));
+    }
+
+    #[test]
+    fn route_success() {
+        let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+        let config_path = config_path.to_string_lossy();
+        let config: InitiationsConfig = read_yaml(config_path).unwrap();
+
+        let labels = |pairs: &[(&str, LabelValue)]| -> Labels {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect()
+        };
+
+        let route = |c: &Criterion| config.route(&Recipient::Criterion(c.clone()));
+
+        let criterion = Criterion::any(labels(&[("unique_key", "h1".into())]));
+        assert_eq!(route(&criterion), vec!["h1"]);
+
+        let criterion = Criterion::any(labels(&[("common_key", "dev".into())]));
+        assert_eq!(route(&criterion), vec!["h1", "h2"]);
+
+        let criterion = Criterion::all(labels(&[
+            ("common_key", "dev".into()),
+            ("gpu", true.into()),
+        ]));
+        assert_eq!(route(&criterion), vec!["h1"]);
+
+        let criterion = Criterion::any(labels(&[("unique_key", "h3".into())]));
+        assert!(route(&criterion).is_empty());
+
+        // validated against the bound keys
+        assert!(config.check_criterion(&criterion).is_ok());
+        let criterion = Criterion::all(labels(&[("gpu", "true".into())]));
+        assert!(config.check_criterion(&criterion).is_err());
+        let criterion = Criterion::any(labels(&[("host", "h1".into())]));
+        assert!(config.check_criterion(&criterion).is_err());
+        assert!(config
+            .check_criterion(&Criterion::all(Labels::new()))
+            .is_err());
+    }
+
+    #[test]
+    fn criterion_of_success() {
+        let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+        let config_path = config_path.to_string_lossy();
+        let config: InitiationsConfig = read_yaml(config_path).unwrap();
+
+        let c = Criterion::all(
+            [("common_key", "dev".into()), ("gpu", true.into())]
+                .into_iter()
+                .map(|(k, v): (&str, LabelValue)| (k.to_string(), v))
+                .collect(),
+        );
+        let mut headers = FieldTable::new();
+        c.insert_into(&mut headers).unwrap();
+        let mut builder = FieldTableBuilder::from(headers);
+        builder.traceparent(&TraceContext::new_root());
+        let headers = builder.finish();
+
+        // a replayed `all` criterion counts the bound labels only
+        let replayed = config.criterion_of(&headers);
+        assert_eq!(replayed, c);
+        let mut headers = FieldTable::new();
+        replayed.insert_into(&mut headers).unwrap();
+        let labels = FieldName::try_from(X_PQX_LABELS).unwrap();
+        assert_eq!(headers.get(&labels), Some(&FieldValue::l(2)));
+    }
+
+    #[test]
+    fn topic_route_success() {
+        let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+        let config_path = config_path.to_string_lossy();
+        let mut config: InitiationsConfig = read_yaml(config_path).unwrap();
+
+        assert_eq!(config.route(&"etl.daily.cn".into()), vec!["h1"]);
+        assert_eq!(config.route(&"etl.weekly.us".into()), vec!["h2"]);
+        assert!(config.route(&"etl.daily.jp".into()).is_empty());
+
+        // recipients follow the routing mode
+        assert!(config.check_recipient(&"etl.daily.cn".into()).is_err());
+        config.routing = RoutingMode::Topic;
+        assert_eq!(config.exchange().unwrap(), "pqx.dev.topic");
+        assert!(config.check_recipient(&"etl.daily.cn".into()).is_ok());
+        assert!(config.check_recipient(&"etl.*.cn".into()).is_err());
+        assert!(config.check_recipient(&"etl.daily.jp".into()).is_err());
+    }
+
+    #[test]
+    fn check_scheduling_success() {
+        let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+        let config_path = config_path.to_string_lossy();
+        let mut config: InitiationsConfig = read_yaml(config_path).unwrap();
+
+        config.retry_backend = RetryBackendType::DelayedExchange;
+        assert!(config.check_scheduling().is_ok());
+        // no delayed exchange is declared by wait queues
+        config.retry_backend = RetryBackendType::WaitQueues;
+        assert!(config.check_scheduling().is_err());
+    }
+
+    #[test]
+    fn queue_type_success() {
+        let quorum: HeaderQueue = serde_json::from_str(
+            r#"{"queue": "q1", "kv": {}, "queue_type": "quorum",
+                "delivery_limit": 5, "dead_letter_strategy": "at-least-once"}"#,
+        )
+        .unwrap();
+        assert!(quorum.check().is_ok());
+        assert!(quorum.durable());
+        let args = quorum.arguments("dlx");
+        assert_eq!(args["x-queue-type"], "quorum");
+        assert_eq!(args["x-delivery-limit"], 5);
+        assert_eq!(args["x-overflow"], "reject-publish");
+
+        let stream: HeaderQueue = serde_json::from_str(
+            r#"{"queue": "s1", "kv": {}, "queue_type": "stream",
+                "max_age": "7D", "stream_offset": {"type": "offset", "offset": 10}}"#,
+        )
+        .unwrap();
+        assert!(stream.check().is_ok());
+        assert_eq!(
+            stream.stream_offset,
+            Some(StreamOffset::Offset { offset: 10 })
+        );
+        assert!(!stream
+            .arguments("dlx")
+            .contains_key("x-dead-letter-exchange"));
+
+        // priorities are only supported by classic queues
+        let invalid: HeaderQueue = serde_json::from_str(
+            r#"{"queue": "s2", "kv": {}, "queue_type": "stream",
+                "max_priority": 10}"#,
+        )
+        .unwrap();
+        assert!(invalid.check().is_err());
+    }
+
+    #[test]
+    fn queue_limit_success() {
+        let limited: HeaderQueue = serde_json::from_str(
+            r#"{"queue": "q1", "kv": {}, "max_length": 1000,
+                "max_length_bytes": 1048576, "overflow": "reject-publish-dlx",
+                "message_ttl": 60000}"#,
+        )
+        .unwrap();
+        assert!(limited.check().is_ok());
+        let args = limited.arguments("dlx");
+        assert_eq!(args["x-max-length"], 1000);
+        assert_eq!(args["x-max-length-bytes"], 1048576);
+        assert_eq!(args["x-overflow"], "reject-publish-dlx");
+        assert_eq!(args["x-message-ttl"], 60000);
+
+        // streams are only limited in bytes
+        let stream: HeaderQueue = serde_json::from_str(
+            r#"{"queue": "s1", "kv": {}, "queue_type": "stream",
+                "max_length": 1000}"#,
+        )
+        .unwrap();
+        assert!(stream.check().is_err());
+
+        // at-least-once dead-lettering cannot drop the head
+        let quorum: HeaderQueue = serde_json::from_str(
+            r#"{"queue": "q2", "kv": {}, "queue_type": "quorum",
+                "dead_letter_strategy": "at-least-once", "overflow": "drop-head"}"#,
+        )
+        .unwrap();
+        assert!(quorum.check().is_err());
+    }
 }
@@ -0,0 +1,170 @@
+//! file: dedup.rs
+//! author: Jacob Xie
+//! date: 2023/07/14 21:12:30 Friday
+//! brief: dedup store backed by Postgres, shared by subscribers on different machines
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use chrono::Local;
+use pqx::error::PqxResult;
+use pqx::mq::DedupStore;
+use pqx::pqx_util::PqxUtilError;
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::*;
+
+use crate::entities::message_dedup;
+
+// ================================================================================================
+// PgDedupStore
+//
+// Keys are kept for `ttl`, expired keys are purged at most once per `PURGE_INTERVAL` while claiming.
+// A key is claimed by inserting its row, hence only one of the concurrent consumers inserts it.
+// ================================================================================================
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct PgDedupStore {
+    db: DatabaseConnection,
+    ttl: Duration,
+    last_purge: Arc<Mutex<Option<Instant>>>,
+}
+
+impl PgDedupStore {
+    pub fn new(db: DatabaseConnection, ttl: Duration) -> Self {
+        Self {
+            db,
+            ttl,
+            last_purge: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    fn expiry(&self) -> chrono::DateTime<Local> {
+        let ttl = chrono::Duration::from_std(self.ttl).unwrap_or(chrono::Duration::max_value());
+        Local::now() - ttl
+    }
+
+    pub async fn purge(&self) -> PqxResult<u64> {
+        let res = message_dedup::Entity::delete_many()
+            .filter(message_dedup::Column::ConsumedAt.lt(self.expiry()))
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res.rows_affected)
+    }
+
+    fn purge_due(&self) -> bool {
+        let mut last_purge = self.last_purge.lock().unwrap();
+        match *last_purge {
+            Some(t) if t.elapsed() < PURGE_INTERVAL => false,
+            _ => {
+                *last_purge = Some(Instant::now());
+                true
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DedupStore for PgDedupStore {
+    async fn claim(&self, key: &str, takeover: bool) -> PqxResult<bool> {
+        // an expired key is reclaimed anyway, failing to purge doesn't stop consuming
+        if self.purge_due() {
+            let _ = self.purge().await;
+        }
+
+        let am = message_dedup::ActiveModel {
+            key: Set(key.to_owned()),
+            consumed_at: Set(Local::now()),
+            consumed: Set(false),
+            skipped: Set(0),
+            skipped_at: Set(None),
+        };
+        let on_conflict = OnConflict::column(message_dedup::Column::Key)
+            .do_nothing()
+            .to_owned();
+        let n = message_dedup::Entity::insert(am)
+            .on_conflict(on_conflict)
+            .exec_without_returning(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+        if n == 1 {
+            return Ok(true);
+        }
+
+        // an expired key may still be there, or a claim left by a gone consumer is taken over
+        let mut reclaimable =
+            Condition::any().add(message_dedup::Column::ConsumedAt.lt(self.expiry()));
+        if takeover {
+            reclaimable = reclaimable.add(message_dedup::Column::Consumed.eq(false));
+        }
+        let res = message_dedup::Entity::update_many()
+            .col_expr(message_dedup::Column::ConsumedAt, Expr::value(Local::now()))
+            .col_expr(message_dedup::Column::Consumed, Expr::value(false))
+            .col_expr(message_dedup::Column::Skipped, Expr::value(0))
+            .col_expr(
+                message_dedup::Column::SkippedAt,
+                Expr::value(Option::<chrono::DateTime<Local>>::None),
+            )
+            .filter(message_dedup::Column::Key.eq(key))
+            .filter(reclaimable)
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res.rows_affected == 1)
+    }
+
+    async fn mark(&self, key: &str) -> PqxResult<()> {
+        let am = message_dedup::ActiveModel {
+            key: Set(key.to_owned()),
+            consumed_at: Set(Local::now()),
+            consumed: Set(true),
+            skipped: Set(0),
+            skipped_at: Set(None),
+        };
+        // the claim may have been purged, e.g. consumed for longer than `ttl`
+        let on_conflict = OnConflict::column(message_dedup::Column::Key)
+            .update_columns([
+                message_dedup::Column::ConsumedAt,
+                message_dedup::Column::Consumed,
+            ])
+            .to_owned();
+        message_dedup::Entity::insert(am)
+            .on_conflict(on_conflict)
+            .exec_without_returning(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> PqxResult<()> {
+        message_dedup::Entity::delete_many()
+            .filter(message_dedup::Column::Key.eq(key))
+            .filter(message_dedup::Column::Consumed.eq(false))
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+
+    async fn skip(&self, key: &str) -> PqxResult<()> {
+        message_dedup::Entity::update_many()
+            .col_expr(
+                message_dedup::Column::Skipped,
+                Expr::col(message_dedup::Column::Skipped).add(1),
+            )
+            .col_expr(message_dedup::Column::SkippedAt, Expr::value(Local::now()))
+            .filter(message_dedup::Column::Key.eq(key))
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+}
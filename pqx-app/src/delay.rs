@@ -0,0 +1,114 @@
+//! file: delay.rs
+//! author: Jacob Xie
+//! date: 2023/07/27 21:52:39 Thursday
+//! brief: scheduled commands recorded in Postgres, listed and cancelled by the inspector
+
+use async_trait::async_trait;
+use chrono::Local;
+use pqx::error::PqxResult;
+use pqx::mq::ScheduleStore;
+use pqx::pqx_util::PqxUtilError;
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::*;
+
+use crate::adt::Command;
+use crate::entities::scheduled_task;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const PENDING: &str = "pending";
+const CANCELLED: &str = "cancelled";
+
+// ================================================================================================
+// PgScheduleStore
+//
+// A scheduled command is recorded by the publisher, and is pending until its `run_at`. Cancelling
+// a pending one makes subscribers drop it when it arrives, either as a hop or when due.
+// ================================================================================================
+
+#[derive(Clone, Debug)]
+pub struct PgScheduleStore {
+    db: DatabaseConnection,
+}
+
+impl PgScheduleStore {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self { db }
+    }
+
+    // requires `message_id` & `run_at`, see `Command::ensure_message_id` & `ensure_run_at`
+    pub async fn record(&self, cmd: &Command) -> PqxResult<()> {
+        let message_id = cmd.message_id().ok_or("message_id is required")?;
+        let run_at = cmd.config().run_at.ok_or("run_at is required")?;
+        let am = scheduled_task::ActiveModel {
+            message_id: Set(message_id.to_owned()),
+            mailing_to: Set(serde_json::json!(cmd.mailing_to)),
+            cmd: Set(serde_json::json!(cmd.cmd)),
+            run_at: Set(run_at),
+            status: Set(PENDING.to_owned()),
+            created_at: Set(Local::now()),
+            cancelled_at: Set(None),
+        };
+        // republished, keeps the status
+        let on_conflict = OnConflict::column(scheduled_task::Column::MessageId)
+            .update_columns([
+                scheduled_task::Column::MailingTo,
+                scheduled_task::Column::Cmd,
+                scheduled_task::Column::RunAt,
+            ])
+            .to_owned();
+        scheduled_task::Entity::insert(am)
+            .on_conflict(on_conflict)
+            .exec_without_returning(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+
+    // not cancelled and not yet due, the earliest first
+    pub async fn pending(&self) -> PqxResult<Vec<scheduled_task::Model>> {
+        let res = scheduled_task::Entity::find()
+            .filter(scheduled_task::Column::Status.eq(PENDING))
+            .filter(scheduled_task::Column::RunAt.gt(Local::now()))
+            .order_by_asc(scheduled_task::Column::RunAt)
+            .all(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res)
+    }
+
+    // false if the command is not pending
+    pub async fn cancel(&self, message_id: &str) -> PqxResult<bool> {
+        let res = scheduled_task::Entity::update_many()
+            .col_expr(scheduled_task::Column::Status, Expr::value(CANCELLED))
+            .col_expr(
+                scheduled_task::Column::CancelledAt,
+                Expr::value(Local::now()),
+            )
+            .filter(scheduled_task::Column::MessageId.eq(message_id))
+            .filter(scheduled_task::Column::Status.eq(PENDING))
+            .filter(scheduled_task::Column::RunAt.gt(Local::now()))
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res.rows_affected == 1)
+    }
+}
+
+#[async_trait]
+impl ScheduleStore for PgScheduleStore {
+    async fn cancelled(&self, message_id: &str) -> PqxResult<bool> {
+        let res = scheduled_task::Entity::find_by_id(message_id.to_owned())
+            .filter(scheduled_task::Column::Status.eq(CANCELLED))
+            .one(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res.is_some())
+    }
+}
@@ -0,0 +1,34 @@
+//! file: message_aggregate.rs
+//! author: Jacob Xie
+//! date: 2023/07/13 22:15:08 Thursday
+//! brief:
+
+use sea_orm::entity::prelude::*;
+
+// ================================================================================================
+// Model: message_aggregate
+// ================================================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "message_aggregate")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: i64,
+    pub mailing_to: Json,
+    pub policy: Json,
+    pub expected: i32,
+    pub succeeded: i32,
+    pub failed: i32,
+    pub missing: i32,
+    pub complete: bool,
+    pub passed: bool,
+    pub detail: Json, // result of each recipient queue
+    pub cmd: Json,
+    pub time: chrono::DateTime<chrono::Local>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {}
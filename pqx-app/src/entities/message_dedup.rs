@@ -0,0 +1,29 @@
+//! file: message_dedup.rs
+//! author: Jacob Xie
+//! date: 2023/07/14 21:05:47 Friday
+//! brief:
+
+use sea_orm::entity::prelude::*;
+
+// ================================================================================================
+// Model: message_dedup
+// ================================================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "message_dedup")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub key: String, // `{queue}/{message_id}`
+    pub consumed_at: chrono::DateTime<chrono::Local>, // or claimed at, while being consumed
+    #[sea_orm(default_value = true)]
+    pub consumed: bool, // false while being claimed
+    pub skipped: i32,
+    #[sea_orm(nullable)]
+    pub skipped_at: Option<chrono::DateTime<chrono::Local>>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {}
@@ -14,6 +14,8 @@ use sea_orm::entity::prelude::*;
 pub struct Model {
     #[sea_orm(primary_key)]
     pub id: i64,
+    #[sea_orm(nullable)]
+    pub message_id: Option<String>,
     pub mailing_to: Json,
     #[sea_orm(nullable)]
     pub retry: Option<i16>,
@@ -23,7 +25,13 @@ pub struct Model {
     pub waiting_timeout: Option<i64>,
     #[sea_orm(nullable)]
     pub consuming_timeout: Option<i64>,
+    #[sea_orm(nullable)]
+    pub retry_policy: Option<Json>,
+    #[sea_orm(nullable)]
+    pub priority: Option<i16>,
     pub cmd: Json,
+    #[sea_orm(nullable)]
+    pub trace_id: Option<String>,
     pub time: chrono::DateTime<chrono::Local>,
 }
 
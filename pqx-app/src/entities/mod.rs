@@ -3,5 +3,11 @@
 //! date: 2023/06/13 23:04:32 Tuesday
 //! brief:
 
+pub mod message_aggregate;
+pub mod message_dedup;
 pub mod message_history;
 pub mod message_result;
+pub mod schedule_firing;
+pub mod scheduled_task;
+pub mod task_cancel;
+pub mod worker;
@@ -0,0 +1,34 @@
+//! file: schedule_firing.rs
+//! author: Jacob Xie
+//! date: 2023/07/26 21:18:04 Wednesday
+//! brief:
+
+use sea_orm::entity::prelude::*;
+
+// ================================================================================================
+// Model: schedule_firing
+// ================================================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "schedule_firing")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub schedule: String,
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub scheduled_at: chrono::DateTime<chrono::Local>,
+    pub status: String, // `FiringStatus`
+    #[sea_orm(nullable)]
+    pub message_id: Option<String>,
+    pub expected: i32,     // number of queues the command is routed to
+    pub scheduler: String, // `{hostname}/{pid}` of the firing scheduler
+    #[sea_orm(nullable)]
+    pub fired_at: Option<chrono::DateTime<chrono::Local>>,
+    #[sea_orm(nullable)]
+    pub error: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,30 @@
+//! file: scheduled_task.rs
+//! author: Jacob Xie
+//! date: 2023/07/27 21:40:26 Thursday
+//! brief:
+
+use sea_orm::entity::prelude::*;
+
+// ================================================================================================
+// Model: scheduled_task
+// ================================================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "scheduled_task")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub message_id: String,
+    pub mailing_to: Json,
+    pub cmd: Json,
+    pub run_at: chrono::DateTime<chrono::Local>,
+    pub status: String, // `pending` | `cancelled`
+    pub created_at: chrono::DateTime<chrono::Local>,
+    #[sea_orm(nullable)]
+    pub cancelled_at: Option<chrono::DateTime<chrono::Local>>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,26 @@
+//! file: task_cancel.rs
+//! author: Jacob Xie
+//! date: 2023/07/28 21:12:47 Friday
+//! brief:
+
+use sea_orm::entity::prelude::*;
+
+// ================================================================================================
+// Model: task_cancel
+// ================================================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "task_cancel")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub task_id: String,
+    #[sea_orm(nullable)]
+    pub reason: Option<String>,
+    pub requested_at: chrono::DateTime<chrono::Local>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {}
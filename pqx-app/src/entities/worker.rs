@@ -0,0 +1,37 @@
+//! file: worker.rs
+//! author: Jacob Xie
+//! date: 2023/07/29 10:26:41 Saturday
+//! brief:
+
+use sea_orm::entity::prelude::*;
+
+// ================================================================================================
+// Model: worker
+// ================================================================================================
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "worker")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub worker_id: String, // `{hostname}/{pid}`
+    pub hostname: String,
+    pub pid: i32,
+    pub queues: Json,
+    pub labels: Json, // `kv` of the queues
+    pub version: String,
+    pub capacity: i32, // prefetch count
+    pub consumer_tags: Json,
+    #[sea_orm(nullable)]
+    pub current_task: Option<String>,
+    pub heartbeat_interval: i32, // seconds
+    pub started_at: chrono::DateTime<chrono::Local>,
+    pub heartbeat_at: chrono::DateTime<chrono::Local>,
+    #[sea_orm(nullable)]
+    pub stopped_at: Option<chrono::DateTime<chrono::Local>>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+#[async_trait::async_trait]
+impl ActiveModelBehavior for ActiveModel {}
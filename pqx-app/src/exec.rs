@@ -4,37 +4,107 @@
 //! brief:
 
 use std::process::ExitStatus;
+use std::sync::Arc;
 
 use async_trait::async_trait;
-use pqx::ec::CmdAsyncExecutor;
+use pqx::ec::{kill_process_group, CmdAsyncExecutor};
 use pqx::error::{PqxError, PqxResult};
-use pqx::mq::{Consumer, ConsumerResult, Retry};
+use pqx::mq::{
+    Consumer, ConsumerResult, DedupStore, Hop, Outcome, Requeue, Retry, RetryBackend, RetryPolicy,
+    Tombstones,
+};
 use pqx::pqx_util::now;
-use tracing::{debug, instrument};
+use serde_json::Value;
+use tracing::{debug, info, instrument, warn};
 
 use crate::adt::{Command, ExecutionResult};
 use crate::persist::MessagePersistent;
 
+// ================================================================================================
+// const
+// ================================================================================================
+
+// `result` of a cancelled command
+pub const CANCELLED: &str = "cancelled";
+
 // ================================================================================================
 // Executor
 // ================================================================================================
 
 #[derive(Clone, Debug)]
 pub struct Executor {
-    delayed_exchange: String,
+    retry_backend: RetryBackend,
+    retry_policy: RetryPolicy,
+    requeue: Option<Requeue>,
+    requeue_exit_codes: Vec<i32>,
     exec: CmdAsyncExecutor,
     persist: MessagePersistent,
+    dedup: Option<Arc<dyn DedupStore>>,
+    hop: Option<Hop>,
+    tombstones: Option<Tombstones>,
 }
 
 impl Executor {
-    pub fn new(delayed_exchange: impl Into<String>, persist: MessagePersistent) -> Self {
+    pub fn new(retry_backend: RetryBackend, persist: MessagePersistent) -> Self {
         Self {
-            delayed_exchange: delayed_exchange.into(),
+            retry_backend,
+            retry_policy: RetryPolicy::default(),
+            requeue: None,
+            requeue_exit_codes: Vec::new(),
             exec: CmdAsyncExecutor::new(),
             persist,
+            dedup: None,
+            hop: None,
+            tombstones: None,
         }
     }
 
+    // skip commands which have been consumed, by their `message_id`
+    pub fn set_dedup_store(&mut self, store: Arc<dyn DedupStore>) -> &mut Self {
+        self.dedup = Some(store);
+
+        self
+    }
+
+    // consume scheduled commands when due, re-delaying the early ones
+    pub fn set_hop(&mut self, hop: Hop) -> &mut Self {
+        self.hop = Some(hop);
+
+        self
+    }
+
+    // skip cancelled commands by their `message_id`, and kill the running ones
+    pub fn set_tombstones(&mut self, tombstones: Tombstones) -> &mut Self {
+        self.tombstones = Some(tombstones);
+
+        self
+    }
+
+    // default retry policy, used when a `Command` has neither `retry_policy` nor `poke`
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) -> &mut Self {
+        self.retry_policy = policy;
+
+        self
+    }
+
+    // bound the requeues of failed commands (see `set_requeue_exit_codes`), which are
+    // dead-lettered with a reason once exhausted
+    pub fn set_requeue(&mut self, requeue: Requeue) -> &mut Self {
+        self.requeue = Some(requeue);
+
+        self
+    }
+
+    // a command exiting with one of these codes fails (requeued), other non-zero codes are retried
+    pub fn set_requeue_exit_codes<I>(&mut self, codes: I) -> &mut Self
+    where
+        I: IntoIterator<Item = i32>,
+    {
+        self.requeue_exit_codes = codes.into_iter().collect();
+
+        self
+    }
+
     pub fn exec(&self) -> &CmdAsyncExecutor {
         &self.exec
     }
@@ -49,27 +119,86 @@ impl Consumer<Command, ExitStatus> for Executor {
     #[instrument]
     async fn consume(&mut self, message: &Command) -> PqxResult<ConsumerResult<ExitStatus>> {
         debug!("{} start executing...", now!());
-        let es = self.exec.exec(1, message.cmd()).await?;
+        // the process group of the command is killed once cancelled
+        let cancel = self.tombstones.as_ref().zip(message.message_id());
+        let es = self
+            .exec
+            .exec_with(1, message.cmd(), |pid| {
+                cancel.map(|(t, id)| {
+                    t.on_cancel(id, move || {
+                        if let Err(e) = kill_process_group(pid) {
+                            warn!("{} kill {} failed: {:?}", now!(), pid, e);
+                        }
+                    })
+                })
+            })
+            .await?;
         debug!("{} end execution", now!());
 
+        let requeued = es
+            .code()
+            .is_some_and(|c| self.requeue_exit_codes.contains(&c));
         let res = if es.success() {
             ConsumerResult::success(es)
+        } else if requeued {
+            ConsumerResult::failure(es)
         } else {
-            // instead of `Requeue`, use `Retry`
             ConsumerResult::retry(Some(es))
         };
-        debug!("{} consumed result: {:?}", now!(), &res);
 
         Ok(res)
     }
 
-    fn gen_retry(&self, message: &Command) -> Retry {
-        Retry::new(
-            &self.delayed_exchange,
-            "",                                // header exchange, no need routing_key
-            message.config.poke.unwrap_or(10), // default 10s
+    fn gen_retry(&self, message: &Command) -> Option<Retry> {
+        // `retry_policy` > `poke` (fixed delay) > default policy
+        let policy = message
+            .config
+            .retry_policy
+            .clone()
+            .or_else(|| message.config.poke.map(|p| RetryPolicy::fixed(p.into())))
+            .unwrap_or_else(|| self.retry_policy.clone());
+
+        let mut retry = Retry::new(
+            self.retry_backend.clone(),
+            "",                                // overwritten by the delivered routing key
+            policy,                            // delay of each retry
             message.config.retry.unwrap_or(1), // default retry once
-        )
+        );
+        // empty by the header exchange, or the `mailing_to` key by the topic exchange
+        retry.keep_routing_key();
+
+        Some(retry)
+    }
+
+    fn gen_requeue(&self, _message: &Command) -> Option<Requeue> {
+        self.requeue.clone()
+    }
+
+    fn dedup_store(&self) -> Option<Arc<dyn DedupStore>> {
+        self.dedup.clone()
+    }
+
+    fn hop(&self) -> Option<&Hop> {
+        self.hop.as_ref()
+    }
+
+    fn tombstones(&self) -> Option<&Tombstones> {
+        self.tombstones.as_ref()
+    }
+
+    // reply `ExecutionResult` to the publisher (if requested)
+    fn gen_reply(&self, outcome: Outcome<'_, Command, ExitStatus>) -> Option<Value> {
+        let er = match outcome {
+            Outcome::Success(_, es) => ExecutionResult::new(es.code().unwrap_or(0)),
+            Outcome::Exhausted(_, Some(es)) => {
+                ExecutionResult::new_with_result(es.code().unwrap_or(1), "retries exhausted")
+            }
+            Outcome::Exhausted(_, None) => ExecutionResult::new_with_result(1, "timeout"),
+            Outcome::Discarded(_, e) => ExecutionResult::new_with_result(1, e.to_string()),
+            Outcome::Cancelled(_) => ExecutionResult::new_with_result(1, CANCELLED),
+        };
+
+        serde_json::to_value(er).ok()
     }
 
     #[instrument]
@@ -114,4 +243,98 @@ impl Consumer<Command, ExitStatus> for Executor {
 
         Ok(())
     }
+
+    // the final state of a cancelled command, whether it was queued or running
+    #[instrument]
+    async fn cancel_callback(&mut self, message: &Command, task_id: &str) -> PqxResult<()> {
+        info!("{} cancelled task_id: {}", now!(), task_id);
+
+        let id = self.persist.insert_history(message).await?;
+        debug!("{} cancel insert_history id: {}", now!(), id);
+        let er = ExecutionResult::new_with_result(1, CANCELLED);
+        let id = self.persist.insert_result(id, &er).await?;
+        debug!("{} cancel insert_result id: {}", now!(), id);
+
+        Ok(())
+    }
+
+    #[instrument]
+    async fn skip_callback(&mut self, message: &Command, message_id: &str) -> PqxResult<()> {
+        info!("{} skip duplicate message_id: {}", now!(), message_id);
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_exec {
+    use pqx::amqprs::channel::ExchangeType;
+    use pqx::ec::CmdArg;
+    use pqx::mq::{MemoryBroker, PqxHeaders, Publisher, Subscriber};
+    use sea_orm::DatabaseConnection;
+
+    use super::*;
+
+    fn executor() -> Executor {
+        let persist = MessagePersistent::new(DatabaseConnection::Disconnected);
+
+        Executor::new(RetryBackend::delayed_exchange("delayed"), persist)
+    }
+
+    fn bash(cmd: &str) -> Command {
+        Command::new(CmdArg::Bash {
+            cmd: vec![cmd.to_string()],
+        })
+    }
+
+    #[tokio::test]
+    async fn consume_exit_codes_success() {
+        let mut exec = executor();
+        exec.set_requeue_exit_codes([75]);
+
+        let res = exec.consume(&bash("exit 0")).await.unwrap();
+        assert!(matches!(res, ConsumerResult::Success(_)));
+        let res = exec.consume(&bash("exit 75")).await.unwrap();
+        assert!(matches!(res, ConsumerResult::Failure(_)));
+        let res = exec.consume(&bash("exit 1")).await.unwrap();
+        assert!(matches!(res, ConsumerResult::Retry(_)));
+    }
+
+    #[tokio::test]
+    async fn requeue_exit_codes_dead_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker.declare_queue("que").unwrap();
+        broker
+            .declare_exchange("dlx", &ExchangeType::Direct)
+            .unwrap();
+        broker.declare_and_bind_queue("dlx", "", "dlq").unwrap();
+
+        let mut exec = executor();
+        exec.set_requeue_exit_codes([75])
+            .set_requeue(Requeue::new(2, "dlx", ""));
+        let mut subscriber = Subscriber::new(&chan, exec);
+        subscriber.consume("que").await.unwrap();
+
+        Publisher::new(&chan)
+            .publish("", "que", bash("exit 75"))
+            .await
+            .unwrap();
+
+        // executed once, requeued twice, then dead-lettered with its reason
+        assert_eq!(broker.run_until_idle().await.unwrap(), 3);
+        assert_eq!((broker.message_count("que"), chan.unacked()), (0, 0));
+        let dead = broker.get("dlq").unwrap();
+        let headers = PqxHeaders::from_props(&dead.props).unwrap();
+        assert!(headers
+            .x_dead_reason
+            .unwrap()
+            .starts_with("requeued 2 times"));
+        assert_eq!(headers.x_dead_queue.as_deref(), Some("que"));
+    }
 }
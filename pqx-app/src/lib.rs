@@ -4,7 +4,16 @@
 //! brief:
 
 pub mod adt;
+pub mod cancel;
 pub mod cfg;
+pub mod dedup;
+pub mod delay;
 pub mod entities;
 pub mod exec;
+pub mod middleware;
 pub mod persist;
+pub mod routing;
+pub mod rpc;
+pub mod schedule;
+pub mod topology;
+pub mod worker;
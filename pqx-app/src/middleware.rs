@@ -0,0 +1,125 @@
+//! file: middleware.rs
+//! author: Jacob Xie
+//! date: 2023/07/21 21:37:50 Friday
+//! brief: middleware layers of the subscriber
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::{ConsumerResult, DeliveryContext, Middleware};
+use pqx::pqx_util::now;
+use tracing::{debug, info, warn};
+
+use crate::adt::Command;
+
+// ================================================================================================
+// Logging
+//
+// Logs each delivery and its result, with the time spent since it was received.
+// ================================================================================================
+
+#[derive(Debug, Clone, Default)]
+pub struct Logging;
+
+#[async_trait]
+impl<R> Middleware<Command, R> for Logging
+where
+    R: Send + Debug + 'static,
+{
+    async fn before(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        message: &Command,
+    ) -> PqxResult<Option<ConsumerResult<R>>> {
+        debug!(
+            "{} deliver #{} from {:?}, message_id: {:?}, redelivered: {}, cmd: {:?}",
+            now!(),
+            ctx.delivery_tag,
+            ctx.queue,
+            ctx.properties.message_id(),
+            ctx.redelivered,
+            message.cmd()
+        );
+
+        Ok(None)
+    }
+
+    async fn after(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        _message: &Command,
+        result: PqxResult<ConsumerResult<R>>,
+    ) -> PqxResult<ConsumerResult<R>> {
+        match &result {
+            Ok(r) => info!(
+                "{} delivery #{} consumed in {:?}: {:?}",
+                now!(),
+                ctx.delivery_tag,
+                ctx.elapsed(),
+                r
+            ),
+            Err(e) => warn!(
+                "{} delivery #{} discarded in {:?}: {}",
+                now!(),
+                ctx.delivery_tag,
+                ctx.elapsed(),
+                e
+            ),
+        }
+
+        result
+    }
+}
+
+// ================================================================================================
+// CurrentTask
+//
+// Tracks the commands being consumed by their `message_id`, reported by the heartbeats of a
+// worker. Shared by all the clones.
+// ================================================================================================
+
+#[derive(Debug, Clone, Default)]
+pub struct CurrentTask {
+    running: Arc<Mutex<BTreeMap<u64, String>>>, // by delivery tag
+}
+
+impl CurrentTask {
+    // the earliest delivered one
+    pub fn get(&self) -> Option<String> {
+        self.running.lock().unwrap().values().next().cloned()
+    }
+}
+
+#[async_trait]
+impl<R> Middleware<Command, R> for CurrentTask
+where
+    R: Send + Debug + 'static,
+{
+    async fn before(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        message: &Command,
+    ) -> PqxResult<Option<ConsumerResult<R>>> {
+        let task = match message.message_id() {
+            Some(id) => id.to_owned(),
+            None => format!("delivery #{}", ctx.delivery_tag),
+        };
+        self.running.lock().unwrap().insert(ctx.delivery_tag, task);
+
+        Ok(None)
+    }
+
+    async fn after(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        _message: &Command,
+        result: PqxResult<ConsumerResult<R>>,
+    ) -> PqxResult<ConsumerResult<R>> {
+        self.running.lock().unwrap().remove(&ctx.delivery_tag);
+
+        result
+    }
+}
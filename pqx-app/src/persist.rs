@@ -4,12 +4,16 @@
 //! brief:
 
 use pqx::error::{PqxError, PqxResult};
+use pqx::mq::TraceContext;
 use pqx::pqx_util::PqxUtilError;
 use sea_orm::sea_query::*;
 use sea_orm::*;
 
-use crate::adt::{Command, ExecutionResult};
-use crate::entities::{message_history, message_result};
+use crate::adt::{AggregateResult, Command, ExecutionResult};
+use crate::entities::{
+    message_aggregate, message_dedup, message_history, message_result, schedule_firing,
+    scheduled_task, task_cancel, worker,
+};
 
 // ================================================================================================
 // const & types
@@ -17,6 +21,24 @@ use crate::entities::{message_history, message_result};
 
 const MR: &str = "message_result";
 const MH: &str = "message_history";
+const MA: &str = "message_aggregate";
+const MD: &str = "message_dedup";
+const SF: &str = "schedule_firing";
+const ST: &str = "scheduled_task";
+const TC: &str = "task_cancel";
+const WK: &str = "worker";
+
+// columns added to `message_history` after its creation, which `create_table_from_entity` doesn't
+// add to a table created by a prior version
+const MH_ADDED_COLUMNS: &[message_history::Column] = &[
+    message_history::Column::RetryPolicy,
+    message_history::Column::Priority,
+    message_history::Column::MessageId,
+    message_history::Column::TraceId,
+];
+
+// columns added to `message_dedup` after its creation
+const MD_ADDED_COLUMNS: &[message_dedup::Column] = &[message_dedup::Column::Consumed];
 
 pub type MessageHistoryAndResult = (Command, Option<ExecutionResult>);
 
@@ -50,7 +72,7 @@ pub struct MessagePersistent {
     db: DatabaseConnection,
 }
 
-impl<'a> MessagePersistent {
+impl MessagePersistent {
     pub fn new(db: DatabaseConnection) -> Self {
         Self { db }
     }
@@ -91,14 +113,90 @@ impl<'a> MessagePersistent {
         let stmt = builder.build(&schema.create_table_from_entity(message_history::Entity));
         let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
 
+        // add the columns missing from an existing message_history table
+        for col in MH_ADDED_COLUMNS {
+            let mut def = schema.get_column_def::<message_history::Entity>(*col);
+            let stmt = Table::alter()
+                .table(Alias::new(MH))
+                .add_column_if_not_exists(&mut def)
+                .to_owned();
+            let stmt = builder.build(&stmt);
+            let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+        }
+
         // create message_result table
         let stmt = builder.build(&schema.create_table_from_entity(message_result::Entity));
         let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+
+        // create message_aggregate table
+        let stmt = builder.build(&schema.create_table_from_entity(message_aggregate::Entity));
+        let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+
+        // create message_dedup table
+        let stmt = builder.build(&schema.create_table_from_entity(message_dedup::Entity));
+        let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+
+        // add the columns missing from an existing message_dedup table
+        for col in MD_ADDED_COLUMNS {
+            let mut def = schema.get_column_def::<message_dedup::Entity>(*col);
+            let stmt = Table::alter()
+                .table(Alias::new(MD))
+                .add_column_if_not_exists(&mut def)
+                .to_owned();
+            let stmt = builder.build(&stmt);
+            let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+        }
+
+        // create schedule_firing table
+        let stmt = builder.build(&schema.create_table_from_entity(schedule_firing::Entity));
+        let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+
+        // create scheduled_task table
+        let stmt = builder.build(&schema.create_table_from_entity(scheduled_task::Entity));
+        let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+
+        // create task_cancel table
+        let stmt = builder.build(&schema.create_table_from_entity(task_cancel::Entity));
+        let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
+
+        // create worker table
+        let stmt = builder.build(&schema.create_table_from_entity(worker::Entity));
+        let _ = self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm);
     }
 
     pub async fn drop_table(&self) -> PqxResult<()> {
         let builder = self.db.get_database_backend();
 
+        // drop `worker`
+        let stmt = Table::drop().table(Alias::new(WK)).if_exists().to_owned();
+        let stmt = builder.build(&stmt);
+        self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm)?;
+
+        // drop `task_cancel`
+        let stmt = Table::drop().table(Alias::new(TC)).if_exists().to_owned();
+        let stmt = builder.build(&stmt);
+        self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm)?;
+
+        // drop `scheduled_task`
+        let stmt = Table::drop().table(Alias::new(ST)).if_exists().to_owned();
+        let stmt = builder.build(&stmt);
+        self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm)?;
+
+        // drop `schedule_firing`
+        let stmt = Table::drop().table(Alias::new(SF)).if_exists().to_owned();
+        let stmt = builder.build(&stmt);
+        self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm)?;
+
+        // drop `message_dedup`
+        let stmt = Table::drop().table(Alias::new(MD)).if_exists().to_owned();
+        let stmt = builder.build(&stmt);
+        self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm)?;
+
+        // drop `message_aggregate`
+        let stmt = Table::drop().table(Alias::new(MA)).if_exists().to_owned();
+        let stmt = builder.build(&stmt);
+        self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm)?;
+
         // drop `message_result`
         let stmt = Table::drop().table(Alias::new(MR)).to_owned();
         let stmt = builder.build(&stmt);
@@ -113,7 +211,9 @@ impl<'a> MessagePersistent {
     }
 
     pub async fn insert_history(&self, cmd: &Command) -> PqxResult<i64> {
-        let am = message_history::ActiveModel::try_from(cmd)?;
+        let mut am = message_history::ActiveModel::try_from(cmd)?;
+        // inserted while consuming a delivery, links the history to its trace
+        am.trace_id = Set(TraceContext::current().map(|c| c.trace_id()));
         let id = message_history::Entity::insert(am)
             .exec(&self.db)
             .await
@@ -134,6 +234,17 @@ impl<'a> MessagePersistent {
         Ok(id)
     }
 
+    pub async fn insert_aggregate(&self, cmd: &Command, res: &AggregateResult) -> PqxResult<i64> {
+        let am = res.into_active_model(cmd);
+        let id = message_aggregate::Entity::insert(am)
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?
+            .last_insert_id;
+
+        Ok(id)
+    }
+
     pub async fn find_one(&self, history_id: i64) -> PqxResult<MessageHistoryAndResult> {
         message_history::Entity::find_by_id(history_id)
             .find_also_related(message_result::Entity)
@@ -0,0 +1,537 @@
+//! file: routing.rs
+//! author: Jacob Xie
+//! date: 2023/07/24 09:41:18 Monday
+//! brief: `mailing_to` recipients, and the bindings that honour them
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use pqx::amqprs::{FieldName, FieldTable, FieldValue};
+use pqx::error::{PqxError, PqxResult};
+use pqx::mq::{MatchType, X_MATCH};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::topology::Arguments;
+
+// ================================================================================================
+// Routing
+//
+// A criterion of `mailing_to` carries typed labels and its own match mode: `all` reaches the
+// queues having every label of the criterion, `any` reaches the queues sharing at least one label.
+// Since a binding of the header exchange has a single `x-match`, the mode is carried by the message
+// (`x-pqx-match`) and each header queue is bound once per way it can be reached, all bindings use
+// `all-with-x` (so that `x-pqx-*` headers count):
+//
+// - `any`: a binding per label of the queue;
+// - `all`: a binding per non-empty subset of the queue's labels, pinned by `x-pqx-labels` (the
+//   number of labels), hence a message matches iff its labels are one of these subsets.
+//
+// A queue is reached at most once, even if several of its bindings match. The number of subset
+// bindings grows exponentially, so a queue has at most `MAX_LABELS` labels.
+// ================================================================================================
+
+pub const X_PQX_MATCH: &str = "x-pqx-match";
+pub const X_PQX_LABELS: &str = "x-pqx-labels";
+
+pub const MAX_LABELS: usize = 8;
+
+// labels of a header queue or a criterion
+pub type Labels = BTreeMap<String, LabelValue>;
+
+// values of the same type match, e.g. `4` doesn't match `"4"`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum LabelValue {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+}
+
+impl LabelValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LabelValue::Bool(_) => "bool",
+            LabelValue::Int(_) => "int",
+            LabelValue::Str(_) => "string",
+        }
+    }
+
+    // same as `topology::field_table` does to a Json value
+    pub fn to_field_value(&self) -> FieldValue {
+        match self {
+            LabelValue::Bool(b) => FieldValue::t(*b),
+            LabelValue::Int(i) => FieldValue::l(*i),
+            LabelValue::Str(s) => FieldValue::from(s.clone()),
+        }
+    }
+
+    pub fn from_field_value(value: &FieldValue) -> Option<Self> {
+        let v = match value {
+            FieldValue::t(b) => LabelValue::Bool(*b),
+            FieldValue::b(i) => LabelValue::Int(i64::from(*i)),
+            FieldValue::B(i) => LabelValue::Int(i64::from(*i)),
+            FieldValue::s(i) => LabelValue::Int(i64::from(*i)),
+            FieldValue::u(i) => LabelValue::Int(i64::from(*i)),
+            FieldValue::I(i) => LabelValue::Int(i64::from(*i)),
+            FieldValue::i(i) => LabelValue::Int(i64::from(*i)),
+            FieldValue::l(i) => LabelValue::Int(*i),
+            FieldValue::S(s) => LabelValue::Str(s.as_ref().clone()),
+            _ => return None,
+        };
+
+        Some(v)
+    }
+}
+
+impl fmt::Display for LabelValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LabelValue::Bool(b) => write!(f, "{}", b),
+            LabelValue::Int(i) => write!(f, "{}", i),
+            LabelValue::Str(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+impl From<&str> for LabelValue {
+    fn from(value: &str) -> Self {
+        LabelValue::Str(value.to_owned())
+    }
+}
+
+impl From<bool> for LabelValue {
+    fn from(value: bool) -> Self {
+        LabelValue::Bool(value)
+    }
+}
+
+impl From<i64> for LabelValue {
+    fn from(value: i64) -> Self {
+        LabelValue::Int(value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchMode {
+    All,
+    #[default]
+    Any,
+}
+
+impl fmt::Display for MatchMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchMode::All => write!(f, "all"),
+            MatchMode::Any => write!(f, "any"),
+        }
+    }
+}
+
+impl FromStr for MatchMode {
+    type Err = PqxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(MatchMode::All),
+            "any" => Ok(MatchMode::Any),
+            _ => Err("match: all/any".into()),
+        }
+    }
+}
+
+// ================================================================================================
+// Criterion
+// ================================================================================================
+
+// e.g. `{"match": "all", "labels": {"common_key": "dev", "gpu": true}}`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Criterion {
+    #[serde(rename = "match")]
+    pub mode: MatchMode,
+    pub labels: Labels,
+}
+
+impl Criterion {
+    pub fn new(mode: MatchMode, labels: Labels) -> Self {
+        Self { mode, labels }
+    }
+
+    pub fn all(labels: Labels) -> Self {
+        Self::new(MatchMode::All, labels)
+    }
+
+    pub fn any(labels: Labels) -> Self {
+        Self::new(MatchMode::Any, labels)
+    }
+
+    // whether a queue labeled by `labels` is reached, an empty criterion reaches nothing
+    pub fn matches(&self, labels: &Labels) -> bool {
+        let mut pairs = self.labels.iter().map(|(k, v)| labels.get(k) == Some(v));
+
+        match self.mode {
+            MatchMode::All => !self.labels.is_empty() && pairs.all(|b| b),
+            MatchMode::Any => pairs.any(|b| b),
+        }
+    }
+
+    // message headers: match mode, labels and (for `all`) the number of labels
+    pub fn insert_into(&self, headers: &mut FieldTable) -> PqxResult<()> {
+        headers.insert(
+            FieldName::try_from(X_PQX_MATCH)?,
+            FieldValue::from(self.mode.to_string()),
+        );
+        if self.mode == MatchMode::All {
+            let n = i64::try_from(self.labels.len()).unwrap_or(i64::MAX);
+            headers.insert(FieldName::try_from(X_PQX_LABELS)?, FieldValue::l(n));
+        }
+        for (k, v) in self.labels.iter() {
+            let key =
+                FieldName::try_from(k.as_str()).map_err(|_| "key is longer than 255 bytes")?;
+            headers.insert(key, v.to_field_value());
+        }
+
+        Ok(())
+    }
+
+    // the criterion of a delivered message, i.e. headers not beginning with "x-", `any` if the
+    // mode is absent
+    pub fn from_headers(headers: &FieldTable) -> Self {
+        let mode = match headers.get(&FieldName::try_from(X_PQX_MATCH).unwrap()) {
+            Some(FieldValue::S(s)) => s.as_ref().parse().unwrap_or_default(),
+            _ => MatchMode::default(),
+        };
+        let labels = headers
+            .as_ref()
+            .iter()
+            .filter(|(k, _)| !k.as_ref().starts_with("x-"))
+            .filter_map(|(k, v)| Some((k.as_ref().clone(), LabelValue::from_field_value(v)?)))
+            .collect();
+
+        Self { mode, labels }
+    }
+}
+
+impl fmt::Display for Criterion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let labels = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>();
+
+        write!(f, "{}{{{}}}", self.mode, labels.join(", "))
+    }
+}
+
+// ================================================================================================
+// Recipient
+//
+// Commands are routed either by the header exchange (criteria) or by the topic exchange (routing
+// keys, e.g. `etl.daily.cn`, matched by the queues' patterns like `etl.*.cn` or `etl.#`), chosen
+// by `routing` in `init.yml`. A recipient of `mailing_to` is either of them, Json objects being
+// criteria and Json strings being routing keys.
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoutingMode {
+    #[default]
+    Header,
+    Topic,
+}
+
+impl fmt::Display for RoutingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingMode::Header => write!(f, "header"),
+            RoutingMode::Topic => write!(f, "topic"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Recipient {
+    Criterion(Criterion),
+    RoutingKey(String),
+}
+
+impl Recipient {
+    pub fn mode(&self) -> RoutingMode {
+        match self {
+            Recipient::Criterion(_) => RoutingMode::Header,
+            Recipient::RoutingKey(_) => RoutingMode::Topic,
+        }
+    }
+
+    // the header exchange ignores routing keys
+    pub fn routing_key(&self) -> &str {
+        match self {
+            Recipient::Criterion(_) => "",
+            Recipient::RoutingKey(k) => k,
+        }
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recipient::Criterion(c) => write!(f, "{}", c),
+            Recipient::RoutingKey(k) => write!(f, "{:?}", k),
+        }
+    }
+}
+
+impl From<Criterion> for Recipient {
+    fn from(value: Criterion) -> Self {
+        Recipient::Criterion(value)
+    }
+}
+
+impl From<&str> for Recipient {
+    fn from(value: &str) -> Self {
+        Recipient::RoutingKey(value.to_owned())
+    }
+}
+
+// a plain string map is the former `mailing_to` entry (still in `message_history`, and in messages
+// dead-lettered before), read as `any`. Never accepted from `task.json` or the schedule
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredRecipient {
+    Recipient(Recipient),
+    Legacy(BTreeMap<String, String>),
+}
+
+impl From<StoredRecipient> for Recipient {
+    fn from(stored: StoredRecipient) -> Self {
+        match stored {
+            StoredRecipient::Recipient(r) => r,
+            StoredRecipient::Legacy(kv) => Recipient::Criterion(Criterion::any(
+                kv.into_iter()
+                    .map(|(k, v)| (k, LabelValue::Str(v)))
+                    .collect(),
+            )),
+        }
+    }
+}
+
+// `mailing_to` of a stored command, which may be of the former format
+pub fn stored_recipients(value: Value) -> PqxResult<Vec<Recipient>> {
+    let stored: Vec<StoredRecipient> = serde_json::from_value(value)?;
+
+    Ok(stored.into_iter().map(Recipient::from).collect())
+}
+
+// ================================================================================================
+// Bindings
+// ================================================================================================
+
+// header exchange bindings of a queue labeled by `labels`, at most `MAX_LABELS`
+pub fn bindings(labels: &Labels) -> PqxResult<Vec<Arguments>> {
+    if labels.len() > MAX_LABELS {
+        return Err("kv has more than 8 labels".into());
+    }
+
+    let base = |mode: MatchMode| {
+        Arguments::from([
+            (X_MATCH.to_string(), json!(MatchType::AllWithX.to_string())),
+            (X_PQX_MATCH.to_string(), json!(mode.to_string())),
+        ])
+    };
+    let pairs = labels.iter().collect::<Vec<_>>();
+    let mut res = vec![];
+
+    for (k, v) in pairs.iter() {
+        let mut args = base(MatchMode::Any);
+        args.insert(k.to_string(), json!(v));
+        res.push(args);
+    }
+
+    // a bit mask for each subset
+    for mask in 1..(1_u32 << pairs.len()) {
+        let subset = pairs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, p)| p);
+        let mut args = base(MatchMode::All);
+        args.insert(X_PQX_LABELS.to_string(), json!(mask.count_ones()));
+        args.extend(subset.map(|(k, v)| (k.to_string(), json!(v))));
+        res.push(args);
+    }
+
+    Ok(res)
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_routing {
+    use super::*;
+
+    fn labels(pairs: &[(&str, LabelValue)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect()
+    }
+
+    // what the header exchange does with `all-with-x` bindings
+    fn routed(headers: &FieldTable, queue_labels: &Labels) -> bool {
+        bindings(queue_labels).unwrap().iter().any(|args| {
+            args.iter()
+                .filter(|(k, _)| **k != X_MATCH.to_string())
+                .all(|(k, v)| {
+                    let key = FieldName::try_from(k.as_str()).unwrap();
+                    match (headers.get(&key), v) {
+                        (Some(FieldValue::S(s)), serde_json::Value::String(v)) => s.as_ref() == v,
+                        (Some(FieldValue::l(i)), serde_json::Value::Number(v)) => {
+                            v.as_i64() == Some(*i)
+                        }
+                        (Some(FieldValue::t(b)), serde_json::Value::Bool(v)) => b == v,
+                        _ => false,
+                    }
+                })
+        })
+    }
+
+    #[test]
+    fn criterion_serde_success() {
+        let c: Criterion = serde_json::from_str(
+            r#"{"match": "all", "labels": {"env": "dev", "gpu": true, "slots": 4}}"#,
+        )
+        .unwrap();
+        assert_eq!(c.mode, MatchMode::All);
+        assert_eq!(c.labels["gpu"], LabelValue::Bool(true));
+        assert_eq!(c.labels["slots"], LabelValue::Int(4));
+        println!("{}", c);
+
+        let json = serde_json::to_value(&c).unwrap();
+        assert_eq!(json["match"], "all");
+        assert_eq!(serde_json::from_value::<Criterion>(json).unwrap(), c);
+
+        // the former format is rejected, and the mode is required
+        assert!(serde_json::from_str::<Criterion>(r#"{"unique_key": "h1"}"#).is_err());
+        assert!(serde_json::from_str::<Criterion>(r#"{"labels": {"gpu": true}}"#).is_err());
+    }
+
+    #[test]
+    fn headers_success() {
+        let c = Criterion::all(labels(&[("env", "dev".into()), ("slots", 4.into())]));
+        let mut headers = FieldTable::new();
+        c.insert_into(&mut headers).unwrap();
+
+        assert_eq!(Criterion::from_headers(&headers), c);
+    }
+
+    #[test]
+    fn routing_success() {
+        let h1 = labels(&[
+            ("unique_key", "h1".into()),
+            ("env", "dev".into()),
+            ("gpu", true.into()),
+        ]);
+        let h2 = labels(&[("unique_key", "h2".into()), ("env", "dev".into())]);
+
+        let cases = [
+            (Criterion::all(labels(&[("env", "dev".into())])), true, true),
+            (
+                Criterion::all(labels(&[("env", "dev".into()), ("gpu", true.into())])),
+                true,
+                false,
+            ),
+            (
+                Criterion::all(labels(&[
+                    ("unique_key", "h1".into()),
+                    ("unique_key2", "h2".into()),
+                ])),
+                false,
+                false,
+            ),
+            (
+                Criterion::any(labels(&[
+                    ("unique_key", "h1".into()),
+                    ("gpu", false.into()),
+                ])),
+                true,
+                false,
+            ),
+            (
+                Criterion::any(labels(&[
+                    ("unique_key", "h3".into()),
+                    ("env", "dev".into()),
+                ])),
+                true,
+                true,
+            ),
+            (
+                Criterion::all(labels(&[("gpu", "true".into())])),
+                false,
+                false,
+            ),
+        ];
+        for (c, to_h1, to_h2) in cases {
+            let mut headers = FieldTable::new();
+            c.insert_into(&mut headers).unwrap();
+
+            assert_eq!(c.matches(&h1), to_h1, "{}", c);
+            assert_eq!(c.matches(&h2), to_h2, "{}", c);
+            assert_eq!(routed(&headers, &h1), to_h1, "{}", c);
+            assert_eq!(routed(&headers, &h2), to_h2, "{}", c);
+        }
+
+        // 3 labels: 3 `any` bindings & 7 `all` bindings
+        assert_eq!(bindings(&h1).unwrap().len(), 10);
+
+        // too many subsets
+        let h9 = (0..9)
+            .map(|i| (format!("k{}", i), LabelValue::Int(i)))
+            .collect();
+        assert!(bindings(&h9).is_err());
+    }
+
+    #[test]
+    fn recipient_serde_success() {
+        let recipients: Vec<Recipient> = serde_json::from_str(
+            r#"["etl.daily.cn", {"match": "any", "labels": {"unique_key": "h1"}}]"#,
+        )
+        .unwrap();
+        assert_eq!(recipients[0], Recipient::from("etl.daily.cn"));
+        assert_eq!(recipients[0].mode(), RoutingMode::Topic);
+        assert_eq!(recipients[1].mode(), RoutingMode::Header);
+        assert_eq!(recipients[1].routing_key(), "");
+
+        let json = serde_json::to_string(&recipients).unwrap();
+        assert_eq!(
+            serde_json::from_str::<Vec<Recipient>>(&json).unwrap(),
+            recipients
+        );
+    }
+
+    #[test]
+    fn stored_recipients_success() {
+        let value = json!([
+            "etl.daily.cn",
+            {"match": "all", "labels": {"gpu": true}},
+            {"unique_key": "h1"}
+        ]);
+        assert!(serde_json::from_value::<Vec<Recipient>>(value.clone()).is_err());
+
+        let recipients = stored_recipients(value).unwrap();
+        assert_eq!(recipients[0], Recipient::from("etl.daily.cn"));
+        assert_eq!(
+            recipients[1],
+            Criterion::all(labels(&[("gpu", true.into())])).into()
+        );
+        assert_eq!(
+            recipients[2],
+            Criterion::any(labels(&[("unique_key", "h1".into())])).into()
+        );
+    }
+}
@@ -0,0 +1,126 @@
+//! file: rpc.rs
+//! author: Jacob Xie
+//! date: 2023/07/12 23:10:52 Wednesday
+//! brief: publish a `Command` and await its `ExecutionResult`
+
+use std::time::Duration;
+
+use pqx::amqprs::channel::Channel;
+use pqx::amqprs::BasicProperties;
+use pqx::error::PqxResult;
+use pqx::mq::{
+    Aggregate, AggregatePolicy, Aggregator, MessageCodec, ReplyMode, RpcClient, RpcFuture,
+    RpcReply, RpcTarget,
+};
+
+use crate::adt::{Command, ExecutionResult};
+use crate::cfg::HeaderQueue;
+use crate::routing::{Criterion, Recipient};
+
+// ================================================================================================
+// CommandRpc
+// ================================================================================================
+
+pub struct CommandRpc<'a> {
+    client: RpcClient<'a>,
+    exchange: String, // the header or topic exchange, by `routing` of `init.yml`
+}
+
+impl<'a> CommandRpc<'a> {
+    pub async fn new(
+        channel: &'a Channel,
+        mode: ReplyMode,
+        exchange: &str,
+    ) -> PqxResult<CommandRpc<'a>> {
+        let client = RpcClient::new(channel, mode).await?;
+
+        Ok(Self {
+            client,
+            exchange: exchange.to_owned(),
+        })
+    }
+
+    // encoding of commands, e.g. MessagePack for compact messages
+    pub fn set_codec(&mut self, codec: MessageCodec) -> &mut Self {
+        self.client.set_codec(codec);
+
+        self
+    }
+
+    // a future for each `mailing_to` recipient, resolving to its `ExecutionResult` or a timeout
+    // error. Since a command is executed after all of its retries, `timeout` should cover them.
+    pub async fn send(
+        &self,
+        cmd: &Command,
+        timeout: Duration,
+    ) -> PqxResult<Vec<(Recipient, RpcFuture<ExecutionResult>)>> {
+        let props_list = Vec::<BasicProperties>::try_from(cmd)?;
+        let targets = cmd
+            .mailing_to
+            .iter()
+            .zip(props_list)
+            .map(|(r, props)| RpcTarget::new(&self.exchange, r.routing_key(), props))
+            .collect();
+        let futs = self.client.request_targets(cmd, targets, timeout).await?;
+
+        Ok(cmd.mailing_to.iter().cloned().zip(futs).collect())
+    }
+
+    // Broadcast-Aggregate: the expected recipient queues are known by routing `mailing_to` against
+    // `header_queues`; an empty `mailing_to` is broadcast to every header queue. Returns when each
+    // expected queue has replied, or `deadline` passes.
+    pub async fn aggregate(
+        &self,
+        cmd: &Command,
+        header_queues: &[HeaderQueue],
+        policy: AggregatePolicy,
+        deadline: Duration,
+    ) -> PqxResult<Aggregate> {
+        let mut targets = vec![];
+        let mut expectations = vec![];
+
+        if cmd.mailing_to.is_empty() {
+            // published to each queue directly via the default exchange
+            for hq in header_queues.iter() {
+                let recipient = Recipient::Criterion(Criterion::all(hq.kv.clone()));
+                targets.push(RpcTarget::new("", &hq.queue, cmd.props(&recipient)?));
+                expectations.push(vec![hq.queue.clone()]);
+            }
+        } else {
+            let props_list = Vec::<BasicProperties>::try_from(cmd)?;
+            for (mt, props) in cmd.mailing_to.iter().zip(props_list) {
+                targets.push(RpcTarget::new(&self.exchange, mt.routing_key(), props));
+                expectations.push(
+                    header_queues
+                        .iter()
+                        .filter(|hq| hq.matches(mt))
+                        .map(|hq| hq.queue.clone())
+                        .collect(),
+                );
+            }
+        }
+
+        // nothing is published if the policy can never be decided
+        policy.check(expectations.iter().map(Vec::len).sum())?;
+
+        let futs = self
+            .client
+            .request_targets::<_, ExecutionResult>(cmd, targets, deadline)
+            .await?;
+        let requests = futs.into_iter().zip(expectations).collect();
+
+        let res = Aggregator::new(policy)
+            .aggregate(requests, |r: &RpcReply| {
+                r.decode::<ExecutionResult>()
+                    .map(|er| er.exit_code == 0)
+                    .unwrap_or(false)
+            })
+            .await;
+
+        Ok(res)
+    }
+
+    pub async fn close(self) -> PqxResult<()> {
+        self.client.close().await
+    }
+}
@@ -0,0 +1,604 @@
+//! file: schedule.rs
+//! author: Jacob Xie
+//! date: 2023/07/26 21:32:47 Wednesday
+//! brief: cron schedules of `Command`, fired by the scheduler
+
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use chrono_tz::Tz;
+use pqx::error::{PqxError, PqxResult};
+use pqx::pqx_util::PqxUtilError;
+use sea_orm::sea_query::OnConflict;
+use sea_orm::*;
+use serde::Deserialize;
+
+use crate::adt::Command;
+use crate::cfg::InitiationsConfig;
+use crate::entities::{message_history, message_result, schedule_firing};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const DEFAULT_TIMEZONE: &str = "UTC";
+const DEFAULT_MISFIRE_GRACE: u64 = 60; // seconds
+const DEFAULT_RUNNING_TIMEOUT: u64 = 3600; // seconds
+const DEFAULT_LOCK_KEY: i64 = 0x7071_785f_7363_6864; // "pqx_schd"
+const MAX_DUE: usize = 1000; // firing times considered at once, the latest ones are kept
+
+// ================================================================================================
+// Config
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Misfire {
+    // drop the firings missed for longer than `misfire_grace`
+    #[default]
+    Skip,
+    // fire the latest `max` missed ones (all if absent), and drop the rest
+    CatchUp {
+        max: Option<usize>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Overlap {
+    // wait until the previous firing is done, i.e. succeeded in every queue or `running_timeout`
+    #[default]
+    Forbid,
+    Allow,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleEntry {
+    pub name: String,             // unique, identifies the firings
+    pub cron: String,             // `sec min hour day-of-month month day-of-week [year]`
+    pub timezone: Option<String>, // IANA name, e.g. `Asia/Shanghai`
+    #[serde(default)]
+    pub misfire: Misfire,
+    #[serde(default)]
+    pub overlap: Overlap,
+    pub running_timeout: Option<u64>, // seconds
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    pub command: Command,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleConfig {
+    pub timezone: Option<String>, // default of the entries, `UTC` if absent
+    pub misfire_grace: Option<u64>, // seconds, a firing later than this is a misfire
+    pub lock_key: Option<i64>,    // advisory lock electing the leader among schedulers
+    pub schedules: Vec<ScheduleEntry>,
+}
+
+impl ScheduleConfig {
+    pub fn misfire_grace(&self) -> Duration {
+        Duration::from_secs(self.misfire_grace.unwrap_or(DEFAULT_MISFIRE_GRACE))
+    }
+
+    pub fn lock_key(&self) -> i64 {
+        self.lock_key.unwrap_or(DEFAULT_LOCK_KEY)
+    }
+
+    // enabled entries, whose cron expressions & time zones are parsed, and recipients checked
+    pub fn compile(&self, init_config: &InitiationsConfig) -> PqxResult<Vec<CronSchedule>> {
+        let mut names = HashSet::new();
+        let mut res = Vec::new();
+        for e in self.schedules.iter() {
+            if !names.insert(e.name.as_str()) {
+                return Err("duplicated schedule name".into());
+            }
+            if !e.enabled {
+                continue;
+            }
+            let mut s = CronSchedule::new(e.clone(), self.timezone.as_deref())?;
+            s.check(init_config)?;
+            res.push(s);
+        }
+
+        Ok(res)
+    }
+}
+
+// ================================================================================================
+// CronSchedule
+// ================================================================================================
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    pub skipped: Vec<DateTime<Local>>, // misfires to drop
+    pub fire: Vec<DateTime<Local>>,    // in order
+}
+
+#[derive(Debug, Clone)]
+pub struct CronSchedule {
+    entry: ScheduleEntry,
+    schedule: cron::Schedule,
+    tz: Tz,
+    expected: usize, // queues the command is routed to
+}
+
+impl CronSchedule {
+    pub fn new(entry: ScheduleEntry, default_timezone: Option<&str>) -> PqxResult<Self> {
+        let schedule = cron::Schedule::from_str(&entry.cron)
+            .map_err(|_| PqxError::from("invalid cron expression"))?;
+        let tz = entry
+            .timezone
+            .as_deref()
+            .or(default_timezone)
+            .unwrap_or(DEFAULT_TIMEZONE)
+            .parse::<Tz>()
+            .map_err(|_| PqxError::from("invalid time zone"))?;
+
+        Ok(Self {
+            entry,
+            schedule,
+            tz,
+            expected: 0,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.entry.name
+    }
+
+    pub fn entry(&self) -> &ScheduleEntry {
+        &self.entry
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.tz
+    }
+
+    pub fn expected(&self) -> usize {
+        self.expected
+    }
+
+    pub fn running_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.entry
+                .running_timeout
+                .unwrap_or(DEFAULT_RUNNING_TIMEOUT),
+        )
+    }
+
+    // same as the publisher, a recipient has to reach some queue
+    pub fn check(&mut self, init_config: &InitiationsConfig) -> PqxResult<()> {
+        let mut expected = 0;
+        for r in self.entry.command.mailing_to() {
+            init_config.check_recipient(r)?;
+            expected += init_config.route(r).len();
+        }
+        self.expected = expected;
+
+        Ok(())
+    }
+
+    // the next `n` firing times after `after`
+    pub fn upcoming(&self, after: DateTime<Local>, n: usize) -> Vec<DateTime<Local>> {
+        self.schedule
+            .after(&after.with_timezone(&self.tz))
+            .take(n)
+            .map(|t| t.with_timezone(&Local))
+            .collect()
+    }
+
+    // firing times in `(after, until]`, at most the latest `MAX_DUE`
+    pub fn due(&self, after: DateTime<Local>, until: DateTime<Local>) -> Vec<DateTime<Local>> {
+        let mut res = VecDeque::new();
+        for t in self.schedule.after(&after.with_timezone(&self.tz)) {
+            let t = t.with_timezone(&Local);
+            if t > until {
+                break;
+            }
+            if res.len() == MAX_DUE {
+                res.pop_front();
+            }
+            res.push_back(t);
+        }
+
+        res.into()
+    }
+
+    // which of the due firing times (ordered) to fire at `now`, by the misfire policy
+    pub fn plan(&self, due: Vec<DateTime<Local>>, now: DateTime<Local>, grace: Duration) -> Plan {
+        let grace = chrono::Duration::from_std(grace).unwrap_or(chrono::Duration::max_value());
+        let (missed, on_time): (Vec<_>, Vec<_>) = due.into_iter().partition(|t| now - *t > grace);
+
+        let (skipped, mut fire) = match self.entry.misfire {
+            Misfire::Skip => (missed, vec![]),
+            Misfire::CatchUp { max } => {
+                let keep = max.unwrap_or(missed.len()).min(missed.len());
+                let mut missed = missed;
+                let fire = missed.split_off(missed.len() - keep);
+                (missed, fire)
+            }
+        };
+        fire.extend(on_time);
+
+        Plan { skipped, fire }
+    }
+
+    // stable for a firing time, so that a command fired twice is deduplicated by subscribers
+    pub fn message_id(&self, scheduled_at: &DateTime<Local>) -> String {
+        format!("schedule/{}/{}", self.entry.name, scheduled_at.timestamp())
+    }
+
+    pub fn command(&self, scheduled_at: &DateTime<Local>) -> Command {
+        let mut cmd = self.entry.command.clone();
+        cmd.message_id = Some(self.message_id(scheduled_at));
+
+        cmd
+    }
+}
+
+// ================================================================================================
+// FiringStatus
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FiringStatus {
+    Firing, // claimed, being published
+    Fired,
+    Failed,
+    SkippedMisfire,
+}
+
+impl fmt::Display for FiringStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FiringStatus::Firing => write!(f, "firing"),
+            FiringStatus::Fired => write!(f, "fired"),
+            FiringStatus::Failed => write!(f, "failed"),
+            FiringStatus::SkippedMisfire => write!(f, "skipped_misfire"),
+        }
+    }
+}
+
+impl FromStr for FiringStatus {
+    type Err = PqxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "firing" => Ok(FiringStatus::Firing),
+            "fired" => Ok(FiringStatus::Fired),
+            "failed" => Ok(FiringStatus::Failed),
+            "skipped_misfire" => Ok(FiringStatus::SkippedMisfire),
+            _ => Err("undefined firing status".into()),
+        }
+    }
+}
+
+// ================================================================================================
+// FiringStore
+//
+// One `schedule_firing` row per firing time of a schedule, the latest one is where the schedule
+// resumes, even after the leader is changed. A firing time is claimed by inserting its row, hence
+// never fired twice.
+// ================================================================================================
+
+#[derive(Clone, Debug)]
+pub struct FiringStore {
+    db: DatabaseConnection,
+    scheduler: String,
+}
+
+impl FiringStore {
+    pub fn new(db: DatabaseConnection, scheduler: impl Into<String>) -> Self {
+        Self {
+            db,
+            scheduler: scheduler.into(),
+        }
+    }
+
+    pub async fn last(&self, schedule: &str) -> PqxResult<Option<schedule_firing::Model>> {
+        let res = schedule_firing::Entity::find()
+            .filter(schedule_firing::Column::Schedule.eq(schedule))
+            .order_by_desc(schedule_firing::Column::ScheduledAt)
+            .one(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res)
+    }
+
+    pub async fn recent(&self, schedule: &str, n: u64) -> PqxResult<Vec<schedule_firing::Model>> {
+        let res = schedule_firing::Entity::find()
+            .filter(schedule_firing::Column::Schedule.eq(schedule))
+            .order_by_desc(schedule_firing::Column::ScheduledAt)
+            .limit(n)
+            .all(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res)
+    }
+
+    // false if the firing time has been claimed
+    pub async fn claim(
+        &self,
+        schedule: &CronSchedule,
+        scheduled_at: DateTime<Local>,
+        status: FiringStatus,
+    ) -> PqxResult<bool> {
+        let message_id = match status {
+            FiringStatus::SkippedMisfire => None,
+            _ => Some(schedule.message_id(&scheduled_at)),
+        };
+        let am = schedule_firing::ActiveModel {
+            schedule: Set(schedule.name().to_owned()),
+            scheduled_at: Set(scheduled_at),
+            status: Set(status.to_string()),
+            message_id: Set(message_id),
+            expected: Set(schedule.expected() as i32),
+            scheduler: Set(self.scheduler.clone()),
+            fired_at: Set(None),
+            error: Set(None),
+        };
+        let on_conflict = OnConflict::columns([
+            schedule_firing::Column::Schedule,
+            schedule_firing::Column::ScheduledAt,
+        ])
+        .do_nothing()
+        .to_owned();
+        let n = schedule_firing::Entity::insert(am)
+            .on_conflict(on_conflict)
+            .exec_without_returning(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(n == 1)
+    }
+
+    pub async fn finish(
+        &self,
+        schedule: &str,
+        scheduled_at: DateTime<Local>,
+        error: Option<String>,
+    ) -> PqxResult<()> {
+        let status = match error {
+            Some(_) => FiringStatus::Failed,
+            None => FiringStatus::Fired,
+        };
+        let am = schedule_firing::ActiveModel {
+            schedule: Unchanged(schedule.to_owned()),
+            scheduled_at: Unchanged(scheduled_at),
+            status: Set(status.to_string()),
+            fired_at: Set(Some(Local::now())),
+            error: Set(error),
+            ..Default::default()
+        };
+        schedule_firing::Entity::update(am)
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+
+    // whether the latest firing of a schedule is still running: not yet succeeded in every
+    // queue it was routed to, within `running_timeout`
+    pub async fn running(&self, schedule: &CronSchedule) -> PqxResult<bool> {
+        let last = match self.last(schedule.name()).await? {
+            Some(l) => l,
+            None => return Ok(false),
+        };
+        let (message_id, fired_at) = match (last.status.parse()?, last.message_id, last.fired_at) {
+            (FiringStatus::Fired, Some(id), Some(at)) => (id, at),
+            _ => return Ok(false),
+        };
+        let timeout = chrono::Duration::from_std(schedule.running_timeout())
+            .unwrap_or(chrono::Duration::max_value());
+        if Local::now() - fired_at > timeout {
+            return Ok(false);
+        }
+
+        let succeeded = message_result::Entity::find()
+            .inner_join(message_history::Entity)
+            .filter(message_history::Column::MessageId.eq(message_id))
+            .filter(message_result::Column::ExitCode.eq(0))
+            .count(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(succeeded < last.expected as u64)
+    }
+}
+
+// ================================================================================================
+// LeaderLock
+//
+// A session level advisory lock, only the scheduler holding it fires. The connection has to be
+// dedicated (a pool of one connection): the lock is gone with its session, and is then taken by
+// whichever scheduler asks first.
+// ================================================================================================
+
+#[derive(Clone, Debug)]
+pub struct LeaderLock {
+    db: DatabaseConnection,
+    key: i64,
+}
+
+impl LeaderLock {
+    pub fn new(db: DatabaseConnection, key: i64) -> Self {
+        Self { db, key }
+    }
+
+    // whether this session holds the lock, acquires it if no one does
+    pub async fn acquire(&self) -> PqxResult<bool> {
+        let sql = r#"
+            SELECT CASE WHEN EXISTS (
+                SELECT 1 FROM pg_locks
+                WHERE locktype = 'advisory' AND granted AND objsubid = 1
+                AND pid = pg_backend_pid()
+                AND ((classid::bigint << 32) | objid::bigint) = $1
+            ) THEN true ELSE pg_try_advisory_lock($1) END AS leader
+        "#;
+        let stmt = Statement::from_sql_and_values(DbBackend::Postgres, sql, [self.key.into()]);
+        let res = self
+            .db
+            .query_one(stmt)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?
+            .ok_or::<PqxError>("query failed".into())?;
+        let leader: bool = res.try_get("", "leader").map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(leader)
+    }
+
+    pub async fn release(&self) -> PqxResult<()> {
+        let stmt = Statement::from_sql_and_values(
+            DbBackend::Postgres,
+            "SELECT pg_advisory_unlock($1)",
+            [self.key.into()],
+        );
+        self.db.execute(stmt).await.map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_schedule {
+    use chrono::TimeZone;
+    use pqx::pqx_util::{get_cur_dir_file, read_yaml};
+
+    use super::*;
+
+    const INIT_CONFIG: &str = "init.template.yml";
+    const SCHEDULE_CONFIG: &str = "schedule.template.yml";
+
+    fn read_configs() -> (InitiationsConfig, ScheduleConfig) {
+        let config_path = get_cur_dir_file(INIT_CONFIG).unwrap();
+        let init_config = read_yaml(config_path.to_string_lossy()).unwrap();
+        let config_path = get_cur_dir_file(SCHEDULE_CONFIG).unwrap();
+        let schedule_config = read_yaml(config_path.to_string_lossy()).unwrap();
+
+        (init_config, schedule_config)
+    }
+
+    fn entry(cron: &str, timezone: Option<&str>, misfire: Misfire) -> ScheduleEntry {
+        ScheduleEntry {
+            name: "test".to_owned(),
+            cron: cron.to_owned(),
+            timezone: timezone.map(str::to_owned),
+            misfire,
+            overlap: Overlap::default(),
+            running_timeout: None,
+            enabled: true,
+            command: Command::new(pqx::ec::CmdArg::ping("localhost")),
+        }
+    }
+
+    #[test]
+    fn compile_success() {
+        let (init_config, schedule_config) = read_configs();
+        let schedules = schedule_config.compile(&init_config).unwrap();
+
+        for s in schedules.iter() {
+            println!("{} {} expected: {}", s.name(), s.timezone(), s.expected());
+            assert!(s.expected() > 0);
+        }
+    }
+
+    #[test]
+    fn compile_fail() {
+        let (init_config, _) = read_configs();
+
+        let config = |cron: &str, timezone: &str| ScheduleConfig {
+            timezone: Some(timezone.to_owned()),
+            misfire_grace: None,
+            lock_key: None,
+            schedules: vec![entry(cron, None, Misfire::Skip)],
+        };
+        assert!(config("0 0 * * * *", "Mars/Olympus")
+            .compile(&init_config)
+            .is_err());
+        assert!(config("every minute", "UTC").compile(&init_config).is_err());
+
+        // `ping` has no recipient
+        let mut c = config("0 0 * * * *", "UTC");
+        assert!(c.compile(&init_config).unwrap()[0].expected() == 0);
+
+        c.schedules.push(c.schedules[0].clone());
+        assert!(c.compile(&init_config).is_err());
+    }
+
+    #[test]
+    fn due_with_timezone_success() {
+        // 09:30 on weekdays in Shanghai, i.e. 01:30 UTC
+        let s = CronSchedule::new(
+            entry("0 30 9 * * Mon-Fri", Some("Asia/Shanghai"), Misfire::Skip),
+            None,
+        )
+        .unwrap();
+
+        // Friday 2023/07/21 00:00 UTC to Tuesday 2023/07/25 00:00 UTC
+        let after = chrono::Utc.with_ymd_and_hms(2023, 7, 21, 0, 0, 0).unwrap();
+        let until = chrono::Utc.with_ymd_and_hms(2023, 7, 25, 0, 0, 0).unwrap();
+        let due = s.due(after.with_timezone(&Local), until.with_timezone(&Local));
+
+        let expected = [(21, 1, 30), (24, 1, 30)]
+            .map(|(d, h, m)| chrono::Utc.with_ymd_and_hms(2023, 7, d, h, m, 0).unwrap());
+        assert_eq!(due, expected.map(|t| t.with_timezone(&Local)));
+
+        // `(after, until]`
+        assert!(s.due(due[0], due[1]) == vec![due[1]]);
+        assert_eq!(s.upcoming(due[0], 1), vec![due[1]]);
+    }
+
+    #[test]
+    fn plan_success() {
+        let grace = Duration::from_secs(120);
+        let now = Local.with_ymd_and_hms(2023, 7, 26, 12, 0, 30).unwrap();
+        // every minute, from 11:56 to 12:00
+        let due = (0..5)
+            .map(|i| now - chrono::Duration::seconds(30 + 60 * (4 - i)))
+            .collect::<Vec<_>>();
+
+        // 11:59 & 12:00 are within the grace
+        let s = CronSchedule::new(entry("0 * * * * *", None, Misfire::Skip), None).unwrap();
+        let plan = s.plan(due.clone(), now, grace);
+        assert_eq!(plan.skipped, due[..3]);
+        assert_eq!(plan.fire, due[3..]);
+
+        let misfire = Misfire::CatchUp { max: Some(1) };
+        let s = CronSchedule::new(entry("0 * * * * *", None, misfire), None).unwrap();
+        let plan = s.plan(due.clone(), now, grace);
+        assert_eq!(plan.skipped, due[..2]);
+        assert_eq!(plan.fire, due[2..]);
+
+        let misfire = Misfire::CatchUp { max: None };
+        let s = CronSchedule::new(entry("0 * * * * *", None, misfire), None).unwrap();
+        let plan = s.plan(due.clone(), now, grace);
+        assert!(plan.skipped.is_empty());
+        assert_eq!(plan.fire, due);
+    }
+
+    #[test]
+    fn message_id_success() {
+        let s = CronSchedule::new(entry("0 0 * * * *", None, Misfire::Skip), None).unwrap();
+        let at = Local.timestamp_opt(1690329600, 0).unwrap();
+
+        assert_eq!(s.message_id(&at), "schedule/test/1690329600");
+        assert_eq!(
+            s.command(&at).message_id(),
+            Some("schedule/test/1690329600")
+        );
+    }
+}
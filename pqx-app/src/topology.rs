@@ -0,0 +1,928 @@
+//! file: topology.rs
+//! author: Jacob Xie
+//! date: 2023/07/18 20:41:07 Tuesday
+//! brief: declarative topology, diffed against the live broker and applied idempotently
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use pqx::amqprs::channel::{
+    Channel, ExchangeBindArguments, ExchangeDeclareArguments, ExchangeDeleteArguments,
+    ExchangeType, ExchangeUnbindArguments, QueueBindArguments, QueueDeclareArguments,
+    QueueDeleteArguments, QueueUnbindArguments,
+};
+use pqx::amqprs::{FieldName, FieldTable, FieldValue};
+use pqx::error::{PqxError, PqxResult};
+use pqx::mq::{
+    MatchType, MqClient, RetryBackend, EXCHANGE_TYPE_DELAYED, X_DEAD_LETTER_EXCHANGE,
+    X_DELAYED_TYPE, X_MATCH, X_MESSAGE_TTL, X_WAIT,
+};
+use pqx::pqx_util::{read_yaml, MqApiClient, MqQuery};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::cfg::{InitiationsConfig, RetryBackendType};
+use crate::routing::{self, RoutingMode};
+
+// ================================================================================================
+// helper
+// ================================================================================================
+
+// arguments of exchanges, queues and bindings, or the definition of a policy
+pub type Arguments = BTreeMap<String, Value>;
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_apply_to() -> String {
+    String::from("all")
+}
+
+// a vhost is percent-encoded as a segment of a path of the management API, e.g. the default "/"
+// as "%2F"
+fn vhost_path(vhost: &str) -> String {
+    let vhost = if vhost.is_empty() { "/" } else { vhost };
+
+    vhost
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                char::from(b).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub fn field_table<'a>(
+    args: impl IntoIterator<Item = (&'a String, &'a Value)>,
+) -> PqxResult<FieldTable> {
+    let mut ft = FieldTable::new();
+    for (k, v) in args {
+        let value = match v {
+            Value::Null => FieldValue::V,
+            Value::Bool(b) => FieldValue::t(*b),
+            Value::Number(n) => match n.as_i64() {
+                Some(i) => FieldValue::l(i),
+                None => FieldValue::d(n.as_f64().unwrap_or_default()),
+            },
+            Value::String(s) => FieldValue::from(s.clone()),
+            Value::Object(o) => FieldValue::F(field_table(o)?),
+            Value::Array(_) => return Err("array argument is not supported".into()),
+        };
+        ft.insert(FieldName::try_from(k.as_str())?, value);
+    }
+
+    Ok(ft)
+}
+
+// a queue declared without `x-queue-type` is listed as "classic" by recent brokers
+fn queue_arguments(args: &Arguments) -> Arguments {
+    let mut args = args.clone();
+    if args.get("x-queue-type") == Some(&json!("classic")) {
+        args.remove("x-queue-type");
+    }
+
+    args
+}
+
+// ================================================================================================
+// Specs
+//
+// Field names follow the management API, so that the live state is deserialized into the same
+// types as the desired one.
+// ================================================================================================
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExchangeSpec {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub exchange_type: String,
+    #[serde(default = "default_true")]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    #[serde(default)]
+    pub internal: bool,
+    #[serde(default)]
+    pub arguments: Arguments,
+}
+
+impl ExchangeSpec {
+    fn drift(&self, live: &ExchangeSpec) -> Vec<&'static str> {
+        let mut res = vec![];
+        if self.exchange_type != live.exchange_type {
+            res.push("type");
+        }
+        if self.durable != live.durable {
+            res.push("durable");
+        }
+        if self.auto_delete != live.auto_delete {
+            res.push("auto_delete");
+        }
+        if self.internal != live.internal {
+            res.push("internal");
+        }
+        if self.arguments != live.arguments {
+            res.push("arguments");
+        }
+
+        res
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueSpec {
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub durable: bool,
+    #[serde(default)]
+    pub auto_delete: bool,
+    #[serde(default)]
+    pub arguments: Arguments,
+}
+
+impl QueueSpec {
+    fn drift(&self, live: &QueueSpec) -> Vec<&'static str> {
+        let mut res = vec![];
+        if self.durable != live.durable {
+            res.push("durable");
+        }
+        if self.auto_delete != live.auto_delete {
+            res.push("auto_delete");
+        }
+        if queue_arguments(&self.arguments) != queue_arguments(&live.arguments) {
+            res.push("arguments");
+        }
+
+        res
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DestinationType {
+    #[default]
+    Queue,
+    Exchange,
+}
+
+impl fmt::Display for DestinationType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestinationType::Queue => write!(f, "queue"),
+            DestinationType::Exchange => write!(f, "exchange"),
+        }
+    }
+}
+
+// a binding has no identity other than all of its fields, hence it is never updated
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BindingSpec {
+    pub source: String,
+    pub destination: String,
+    #[serde(default)]
+    pub destination_type: DestinationType,
+    #[serde(default)]
+    pub routing_key: String,
+    #[serde(default)]
+    pub arguments: Arguments,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PolicySpec {
+    pub name: String,
+    pub pattern: String,
+    #[serde(rename = "apply-to", alias = "apply_to", default = "default_apply_to")]
+    pub apply_to: String,
+    #[serde(default)]
+    pub priority: i64,
+    pub definition: Arguments,
+}
+
+impl PolicySpec {
+    fn drift(&self, live: &PolicySpec) -> Vec<&'static str> {
+        let mut res = vec![];
+        if self.pattern != live.pattern {
+            res.push("pattern");
+        }
+        if self.apply_to != live.apply_to {
+            res.push("apply-to");
+        }
+        if self.priority != live.priority {
+            res.push("priority");
+        }
+        if self.definition != live.definition {
+            res.push("definition");
+        }
+
+        res
+    }
+}
+
+// ================================================================================================
+// Resource & Change
+// ================================================================================================
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Resource {
+    Exchange(ExchangeSpec),
+    Queue(QueueSpec),
+    Binding(BindingSpec),
+    Policy(PolicySpec),
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Exchange(x) => write!(f, "exchange {} ({})", x.name, x.exchange_type),
+            Resource::Queue(q) => write!(f, "queue {}", q.name),
+            Resource::Binding(b) => write!(
+                f,
+                "binding {} -> {} {} (routing_key: {:?}, arguments: {:?})",
+                b.source, b.destination_type, b.destination, b.routing_key, b.arguments
+            ),
+            Resource::Policy(p) => write!(f, "policy {} ({})", p.name, p.pattern),
+        }
+    }
+}
+
+impl Resource {
+    async fn declare(&self, chan: &Channel, api: &MqApiClient, vhost: &str) -> PqxResult<()> {
+        match self {
+            Resource::Exchange(x) => {
+                let mut args = ExchangeDeclareArguments::new(&x.name, &x.exchange_type);
+                args.durable = x.durable;
+                args.auto_delete = x.auto_delete;
+                args.internal = x.internal;
+                args.arguments = field_table(&x.arguments)?;
+                chan.exchange_declare(args).await?;
+            }
+            Resource::Queue(q) => {
+                let mut args = QueueDeclareArguments::new(&q.name);
+                args.durable(q.durable)
+                    .auto_delete(q.auto_delete)
+                    .arguments(field_table(&q.arguments)?);
+                chan.queue_declare(args).await?;
+            }
+            Resource::Binding(b) => match b.destination_type {
+                DestinationType::Queue => {
+                    let mut args =
+                        QueueBindArguments::new(&b.destination, &b.source, &b.routing_key);
+                    args.arguments = field_table(&b.arguments)?;
+                    chan.queue_bind(args).await?;
+                }
+                DestinationType::Exchange => {
+                    let mut args =
+                        ExchangeBindArguments::new(&b.destination, &b.source, &b.routing_key);
+                    args.arguments = field_table(&b.arguments)?;
+                    chan.exchange_bind(args).await?;
+                }
+            },
+            Resource::Policy(p) => {
+                let body = json!({
+                    "pattern": p.pattern,
+                    "apply-to": p.apply_to,
+                    "priority": p.priority,
+                    "definition": p.definition,
+                });
+                api.put_no_content(format!("policies/{}/{}", vhost, p.name), &body)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn delete(&self, chan: &Channel, api: &MqApiClient, vhost: &str) -> PqxResult<()> {
+        match self {
+            Resource::Exchange(x) => {
+                chan.exchange_delete(ExchangeDeleteArguments::new(&x.name))
+                    .await?;
+            }
+            Resource::Queue(q) => {
+                chan.queue_delete(QueueDeleteArguments::new(&q.name))
+                    .await?;
+            }
+            Resource::Binding(b) => match b.destination_type {
+                DestinationType::Queue => {
+                    let mut args =
+                        QueueUnbindArguments::new(&b.destination, &b.source, &b.routing_key);
+                    args.arguments = field_table(&b.arguments)?;
+                    chan.queue_unbind(args).await?;
+                }
+                DestinationType::Exchange => {
+                    let mut args =
+                        ExchangeUnbindArguments::new(&b.destination, &b.source, &b.routing_key);
+                    args.arguments = field_table(&b.arguments)?;
+                    chan.exchange_unbind(args).await?;
+                }
+            },
+            Resource::Policy(p) => {
+                api.delete(format!("policies/{}/{}", vhost, p.name)).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Create,
+    Update,  // only policies, which are overwritten in place
+    Replace, // deleted and declared again, since the broker refuses redeclaring with a difference
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub action: Action,
+    pub resource: Resource,
+    pub drift: Vec<&'static str>, // fields differing from the live state
+}
+
+impl Change {
+    fn new(action: Action, resource: Resource) -> Self {
+        Self {
+            action,
+            resource,
+            drift: vec![],
+        }
+    }
+
+    fn with_drift(action: Action, resource: Resource, drift: Vec<&'static str>) -> Self {
+        Self {
+            action,
+            resource,
+            drift,
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = match self.action {
+            Action::Create => "+",
+            Action::Update => "~",
+            Action::Replace => "-/+",
+            Action::Delete => "-",
+        };
+        write!(f, "{} {}", sign, self.resource)?;
+        if !self.drift.is_empty() {
+            write!(f, ", drifted: {}", self.drift.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// Plan
+// ================================================================================================
+
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub changes: Vec<Change>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn count(&self, action: Action) -> usize {
+        self.changes.iter().filter(|c| c.action == action).count()
+    }
+
+    // replacing an exchange or a queue drops its bindings (and messages of a queue)
+    pub fn replaces(&self) -> bool {
+        self.count(Action::Replace) > 0
+    }
+
+    // changes are executed in order, `allow_replace` has to be set if the plan replaces anything
+    pub async fn apply(
+        &self,
+        client: &MqClient,
+        api: &MqApiClient,
+        allow_replace: bool,
+    ) -> PqxResult<()> {
+        if self.replaces() && !allow_replace {
+            return Err("plan replaces exchanges or queues, which is not allowed".into());
+        }
+
+        let chan = client.channel()?;
+        let vhost = vhost_path(api.vhost());
+        for c in self.changes.iter() {
+            match c.action {
+                Action::Create | Action::Update => c.resource.declare(chan, api, &vhost).await?,
+                Action::Replace => {
+                    c.resource.delete(chan, api, &vhost).await?;
+                    c.resource.declare(chan, api, &vhost).await?;
+                }
+                Action::Delete => c.resource.delete(chan, api, &vhost).await?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes, the broker matches the topology");
+        }
+
+        for c in self.changes.iter() {
+            writeln!(f, "{}", c)?;
+        }
+        write!(
+            f,
+            "{} to create, {} to update, {} to replace, {} to delete",
+            self.count(Action::Create),
+            self.count(Action::Update),
+            self.count(Action::Replace),
+            self.count(Action::Delete),
+        )
+    }
+}
+
+// ================================================================================================
+// LiveTopology
+// ================================================================================================
+
+#[derive(Debug, Clone, Default)]
+pub struct LiveTopology {
+    pub exchanges: Vec<ExchangeSpec>,
+    pub queues: Vec<QueueSpec>,
+    pub bindings: Vec<BindingSpec>,
+    pub policies: Vec<PolicySpec>,
+}
+
+impl LiveTopology {
+    pub async fn fetch(client: &MqApiClient) -> PqxResult<Self> {
+        let query = MqQuery::new(client);
+        let vhost = vhost_path(client.vhost());
+
+        Ok(Self {
+            exchanges: serde_json::from_value(query.exchanges_with_vhost(&vhost).await?)?,
+            queues: serde_json::from_value(query.queues_with_vhost(&vhost).await?)?,
+            bindings: serde_json::from_value(query.bindings_with_vhost(&vhost).await?)?,
+            policies: serde_json::from_value(query.policies_with_vhost(&vhost).await?)?,
+        })
+    }
+
+    fn exchange(&self, name: &str) -> Option<&ExchangeSpec> {
+        self.exchanges.iter().find(|x| x.name == name)
+    }
+
+    fn queue(&self, name: &str) -> Option<&QueueSpec> {
+        self.queues.iter().find(|q| q.name == name)
+    }
+
+    fn policy(&self, name: &str) -> Option<&PolicySpec> {
+        self.policies.iter().find(|p| p.name == name)
+    }
+}
+
+// ================================================================================================
+// Topology
+//
+// Only resources declared in a topology are managed: exchanges and queues absent from it are left
+// untouched, whereas undeclared bindings of managed exchanges and queues are drift, and deleted.
+// ================================================================================================
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Topology {
+    #[serde(default)]
+    pub exchanges: Vec<ExchangeSpec>,
+    #[serde(default)]
+    pub queues: Vec<QueueSpec>,
+    #[serde(default)]
+    pub bindings: Vec<BindingSpec>,
+    #[serde(default)]
+    pub policies: Vec<PolicySpec>,
+}
+
+impl Topology {
+    pub fn from_yaml(path: impl AsRef<str>) -> PqxResult<Self> {
+        Ok(read_yaml(path)?)
+    }
+
+    fn manages(&self, destination_type: DestinationType, name: &str) -> bool {
+        match destination_type {
+            DestinationType::Queue => self.queues.iter().any(|q| q.name == name),
+            DestinationType::Exchange => self.exchanges.iter().any(|x| x.name == name),
+        }
+    }
+
+    // changes turning the live state into this topology
+    pub fn plan(&self, live: &LiveTopology) -> Plan {
+        let mut changes = vec![];
+        // recreated resources lose their bindings
+        let mut replaced = HashSet::new();
+
+        for x in self.exchanges.iter() {
+            let res = Resource::Exchange(x.clone());
+            match live.exchange(&x.name).map(|l| x.drift(l)) {
+                None => changes.push(Change::new(Action::Create, res)),
+                Some(d) if d.is_empty() => {}
+                Some(d) => {
+                    replaced.insert((DestinationType::Exchange, x.name.as_str()));
+                    changes.push(Change::with_drift(Action::Replace, res, d));
+                }
+            }
+        }
+
+        for q in self.queues.iter() {
+            let res = Resource::Queue(q.clone());
+            match live.queue(&q.name).map(|l| q.drift(l)) {
+                None => changes.push(Change::new(Action::Create, res)),
+                Some(d) if d.is_empty() => {}
+                Some(d) => {
+                    replaced.insert((DestinationType::Queue, q.name.as_str()));
+                    changes.push(Change::with_drift(Action::Replace, res, d));
+                }
+            }
+        }
+
+        // bindings of the default exchange are implicit
+        let live_bindings = live
+            .bindings
+            .iter()
+            .filter(|b| {
+                !b.source.is_empty()
+                    && !replaced.contains(&(DestinationType::Exchange, b.source.as_str()))
+                    && !replaced.contains(&(b.destination_type, b.destination.as_str()))
+            })
+            .collect::<Vec<_>>();
+        for b in live_bindings.iter() {
+            let managed = self.manages(DestinationType::Exchange, &b.source)
+                || self.manages(b.destination_type, &b.destination);
+            if managed && !self.bindings.contains(b) {
+                changes.push(Change::new(Action::Delete, Resource::Binding((*b).clone())));
+            }
+        }
+        for b in self.bindings.iter() {
+            if !live_bindings.contains(&b) {
+                changes.push(Change::new(Action::Create, Resource::Binding(b.clone())));
+            }
+        }
+
+        for p in self.policies.iter() {
+            let res = Resource::Policy(p.clone());
+            match live.policy(&p.name).map(|l| p.drift(l)) {
+                None => changes.push(Change::new(Action::Create, res)),
+                Some(d) if d.is_empty() => {}
+                Some(d) => changes.push(Change::with_drift(Action::Update, res, d)),
+            }
+        }
+
+        Plan { changes }
+    }
+
+    // changes removing this topology from the live state
+    pub fn destroy_plan(&self, live: &LiveTopology) -> Plan {
+        let mut changes = vec![];
+
+        for p in self
+            .policies
+            .iter()
+            .filter(|p| live.policy(&p.name).is_some())
+        {
+            changes.push(Change::new(Action::Delete, Resource::Policy(p.clone())));
+        }
+
+        // bindings are dropped along with their exchanges or queues, except the ones between
+        // resources out of this topology
+        for b in self.bindings.iter().filter(|b| live.bindings.contains(b)) {
+            if !self.manages(DestinationType::Exchange, &b.source)
+                && !self.manages(b.destination_type, &b.destination)
+            {
+                changes.push(Change::new(Action::Delete, Resource::Binding(b.clone())));
+            }
+        }
+
+        for q in self.queues.iter().filter(|q| live.queue(&q.name).is_some()) {
+            changes.push(Change::new(Action::Delete, Resource::Queue(q.clone())));
+        }
+
+        for x in self
+            .exchanges
+            .iter()
+            .filter(|x| live.exchange(&x.name).is_some())
+        {
+            changes.push(Change::new(Action::Delete, Resource::Exchange(x.clone())));
+        }
+
+        Plan { changes }
+    }
+}
+
+// the fixed shape set up by `initiator -o init`
+impl TryFrom<&InitiationsConfig> for Topology {
+    type Error = PqxError;
+
+    fn try_from(config: &InitiationsConfig) -> Result<Self, Self::Error> {
+        let mut topology = Topology::default();
+
+        let exchange = |name: &str, exchange_type: &ExchangeType, arguments| ExchangeSpec {
+            name: name.to_owned(),
+            exchange_type: exchange_type.to_string(),
+            durable: true,
+            auto_delete: false,
+            internal: false,
+            arguments,
+        };
+        let binding = |source: &str, destination: &str, arguments| BindingSpec {
+            source: source.to_owned(),
+            destination: destination.to_owned(),
+            destination_type: DestinationType::Queue,
+            routing_key: String::new(),
+            arguments,
+        };
+
+        // header exchange & queues
+        topology.exchanges.push(exchange(
+            &config.header_exchange,
+            &ExchangeType::Headers,
+            Arguments::new(),
+        ));
+        let mut header_bindings = vec![];
+        for hq in config.header_queues.iter() {
+            topology.queues.push(QueueSpec {
+                name: hq.queue.clone(),
+                durable: hq.durable(),
+                auto_delete: false,
+                arguments: hq.arguments(&config.dead_letter_exchange),
+            });
+
+            for arguments in routing::bindings(&hq.kv)? {
+                header_bindings.push((hq.queue.as_str(), arguments));
+            }
+        }
+        for (que, arguments) in header_bindings.iter() {
+            let b = binding(&config.header_exchange, que, arguments.clone());
+            topology.bindings.push(b);
+        }
+
+        // topic exchange, queues are bound by their patterns
+        let topic_bindings = config
+            .header_queues
+            .iter()
+            .flat_map(|hq| hq.patterns.iter().map(|p| (hq.queue.as_str(), p.as_str())))
+            .collect::<Vec<_>>();
+        if let Some(x) = &config.topic_exchange {
+            topology
+                .exchanges
+                .push(exchange(x, &ExchangeType::Topic, Arguments::new()));
+            for (que, pattern) in topic_bindings.iter() {
+                topology.bindings.push(BindingSpec {
+                    routing_key: pattern.to_string(),
+                    ..binding(x, que, Arguments::new())
+                });
+            }
+        }
+
+        // retry backend
+        match config.retry_backend {
+            RetryBackendType::DelayedExchange => {
+                // routes retries the same way as the exchange commands are published to
+                let delayed_type = match config.routing {
+                    RoutingMode::Header => ExchangeType::Headers,
+                    RoutingMode::Topic => ExchangeType::Topic,
+                };
+                let arguments = Arguments::from([(
+                    X_DELAYED_TYPE.to_string(),
+                    json!(delayed_type.to_string()),
+                )]);
+                topology.exchanges.push(exchange(
+                    &config.delayed_exchange,
+                    &EXCHANGE_TYPE_DELAYED,
+                    arguments,
+                ));
+                match config.routing {
+                    RoutingMode::Header => {
+                        for (que, arguments) in header_bindings.into_iter() {
+                            let b = binding(&config.delayed_exchange, que, arguments);
+                            topology.bindings.push(b);
+                        }
+                    }
+                    RoutingMode::Topic => {
+                        for (que, pattern) in topic_bindings.into_iter() {
+                            topology.bindings.push(BindingSpec {
+                                routing_key: pattern.to_owned(),
+                                ..binding(&config.delayed_exchange, que, Arguments::new())
+                            });
+                        }
+                    }
+                }
+            }
+            RetryBackendType::WaitQueues => {
+                if let Some(x) = &config.wait_exchange {
+                    topology
+                        .exchanges
+                        .push(exchange(x, &ExchangeType::Headers, Arguments::new()));
+                    for tier in config.wait_queues.iter() {
+                        let que = RetryBackend::wait_queue_name(x, *tier);
+                        let arguments = Arguments::from([
+                            (
+                                X_DEAD_LETTER_EXCHANGE.to_string(),
+                                json!(config.exchange()?),
+                            ),
+                            (X_MESSAGE_TTL.to_string(), json!(i64::from(*tier) * 1000)),
+                        ]);
+                        topology.queues.push(QueueSpec {
+                            name: que.clone(),
+                            durable: true,
+                            auto_delete: false,
+                            arguments,
+                        });
+                        let arguments = Arguments::from([
+                            (X_MATCH.to_string(), json!(MatchType::AllWithX.to_string())),
+                            (X_WAIT.to_string(), json!(tier)),
+                        ]);
+                        topology.bindings.push(binding(x, &que, arguments));
+                    }
+                }
+            }
+        }
+
+        // control exchange, bound by exclusive queues of subscribers
+        if let Some(x) = &config.control_exchange {
+            topology
+                .exchanges
+                .push(exchange(x, &ExchangeType::Topic, Arguments::new()));
+        }
+
+        // dead letter exchange & queue
+        topology.exchanges.push(exchange(
+            &config.dead_letter_exchange,
+            &ExchangeType::Direct,
+            Arguments::new(),
+        ));
+        let mut arguments = Arguments::new();
+        if let Some(ttl) = config.dead_message_ttl {
+            arguments.insert(X_MESSAGE_TTL.to_string(), json!(ttl));
+        }
+        topology.queues.push(QueueSpec {
+            name: config.dead_letter_queue.clone(),
+            durable: false,
+            auto_delete: false,
+            arguments,
+        });
+        topology.bindings.push(binding(
+            &config.dead_letter_exchange,
+            &config.dead_letter_queue,
+            Arguments::new(),
+        ));
+
+        Ok(topology)
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_topology {
+    use pqx::pqx_util::get_cur_dir_file;
+
+    use super::*;
+
+    const TOPOLOGY: &str = "topology.template.yml";
+    const INIT_CONFIG: &str = "init.template.yml";
+
+    fn topology() -> Topology {
+        let pth = get_cur_dir_file(TOPOLOGY).unwrap();
+        Topology::from_yaml(pth.to_string_lossy()).unwrap()
+    }
+
+    // what the management API lists after applying `topology`
+    fn live_of(topology: &Topology) -> LiveTopology {
+        let mut bindings = topology.bindings.clone();
+        // implicit bindings of the default exchange
+        for q in topology.queues.iter() {
+            bindings.push(BindingSpec {
+                source: String::new(),
+                destination: q.name.clone(),
+                destination_type: DestinationType::Queue,
+                routing_key: q.name.clone(),
+                arguments: Arguments::new(),
+            });
+        }
+
+        LiveTopology {
+            exchanges: topology.exchanges.clone(),
+            queues: topology.queues.clone(),
+            bindings,
+            policies: topology.policies.clone(),
+        }
+    }
+
+    #[test]
+    fn plan_create_success() {
+        let topology = topology();
+        let plan = topology.plan(&LiveTopology::default());
+
+        let expected = topology.exchanges.len()
+            + topology.queues.len()
+            + topology.bindings.len()
+            + topology.policies.len();
+        assert_eq!(plan.count(Action::Create), expected);
+        assert!(!plan.replaces());
+    }
+
+    #[test]
+    fn plan_idempotent_success() {
+        let topology = topology();
+        let plan = topology.plan(&live_of(&topology));
+
+        assert!(plan.is_empty());
+    }
+
+    #[test]
+    fn plan_drift_success() {
+        let topology = topology();
+        let mut live = live_of(&topology);
+        // a queue declared with other arguments, a policy edited by hand, and a stray binding
+        live.queues[0]
+            .arguments
+            .insert("x-max-length".into(), json!(1));
+        live.policies[0].priority += 1;
+        let replaced = &topology.queues[0].name;
+        let mut stray = topology
+            .bindings
+            .iter()
+            .find(|b| &b.destination != replaced)
+            .unwrap()
+            .clone();
+        stray.routing_key = String::from("stray");
+        live.bindings.push(stray.clone());
+        // a classic queue reported with its type
+        live.queues[1]
+            .arguments
+            .insert("x-queue-type".into(), json!("classic"));
+        let plan = topology.plan(&live);
+
+        assert_eq!(plan.count(Action::Replace), 1);
+        assert_eq!(plan.count(Action::Update), 1);
+        let bound = topology
+            .bindings
+            .iter()
+            .filter(|b| &b.destination == replaced)
+            .count();
+        assert_eq!(plan.count(Action::Create), bound);
+        assert!(plan
+            .changes
+            .contains(&Change::new(Action::Delete, Resource::Binding(stray))));
+    }
+
+    #[test]
+    fn destroy_plan_success() {
+        let topology = topology();
+        let plan = topology.destroy_plan(&live_of(&topology));
+
+        assert_eq!(
+            plan.count(Action::Delete),
+            topology.exchanges.len() + topology.queues.len() + topology.policies.len()
+        );
+        assert!(topology.destroy_plan(&LiveTopology::default()).is_empty());
+    }
+
+    #[test]
+    fn vhost_path_success() {
+        assert_eq!(vhost_path(""), "%2F");
+        assert_eq!(vhost_path("/"), "%2F");
+        assert_eq!(vhost_path("dev"), "dev");
+        assert_eq!(vhost_path("/dev/a b"), "%2Fdev%2Fa%20b");
+        assert_eq!(vhost_path("dev%1"), "dev%251");
+    }
+
+    #[test]
+    fn from_init_config_success() {
+        let pth = get_cur_dir_file(INIT_CONFIG).unwrap();
+        let config: InitiationsConfig = read_yaml(pth.to_string_lossy()).unwrap();
+        let topology = Topology::try_from(&config).unwrap();
+
+        // header, topic, delayed & control exchanges, dlx
+        assert_eq!(topology.exchanges.len(), 5);
+        // header queues & dlq
+        assert_eq!(topology.queues.len(), config.header_queues.len() + 1);
+        assert!(topology.plan(&live_of(&topology)).is_empty());
+
+        // dead messages expire by the queue's `x-message-ttl`
+        let dlq = topology
+            .queues
+            .iter()
+            .find(|q| q.name == config.dead_letter_queue)
+            .unwrap();
+        assert_eq!(
+            dlq.arguments.get(X_MESSAGE_TTL.as_ref()),
+            Some(&json!(config.dead_message_ttl.unwrap()))
+        );
+    }
+}
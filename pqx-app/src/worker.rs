@@ -0,0 +1,356 @@
+//! file: worker.rs
+//! author: Jacob Xie
+//! date: 2023/07/29 10:48:12 Saturday
+//! brief: registry of subscribers (workers) with heartbeats, checked against the live consumers
+
+use std::fmt::Display;
+use std::time::Duration;
+
+use chrono::{DateTime, Local};
+use pqx::error::PqxResult;
+use pqx::pqx_util::{hostname, instance_id, PqxUtilError};
+use sea_orm::sea_query::{Expr, OnConflict};
+use sea_orm::*;
+
+use crate::adt::ConsumerInfo;
+use crate::entities::worker;
+use crate::routing::Labels;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+// number of missed heartbeats before a worker is stale, and dead
+const STALE_AFTER: i32 = 3;
+const DEAD_AFTER: i32 = 10;
+
+// ================================================================================================
+// WorkerStatus
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WorkerStatus {
+    Live,
+    Stale, // missed a few heartbeats, e.g. blocked by a command or a slow database
+    Dead,  // stopped, or missed too many heartbeats
+}
+
+impl WorkerStatus {
+    pub fn of(worker: &worker::Model, now: DateTime<Local>) -> Self {
+        if worker.stopped_at.is_some() {
+            return WorkerStatus::Dead;
+        }
+        let interval = i64::from(worker.heartbeat_interval.max(1));
+        let elapsed = (now - worker.heartbeat_at).num_seconds();
+        if elapsed <= interval * i64::from(STALE_AFTER) {
+            WorkerStatus::Live
+        } else if elapsed <= interval * i64::from(DEAD_AFTER) {
+            WorkerStatus::Stale
+        } else {
+            WorkerStatus::Dead
+        }
+    }
+}
+
+impl Display for WorkerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkerStatus::Live => write!(f, "live"),
+            WorkerStatus::Stale => write!(f, "stale"),
+            WorkerStatus::Dead => write!(f, "dead"),
+        }
+    }
+}
+
+// ================================================================================================
+// WorkerInfo
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct WorkerInfo {
+    pub queues: Vec<String>,
+    pub labels: Labels,
+    pub capacity: u16,
+    pub consumer_tags: Vec<String>,
+    pub heartbeat: Duration,
+}
+
+impl WorkerInfo {
+    pub fn new(queue: impl Into<String>, labels: Labels, capacity: u16) -> Self {
+        Self {
+            queues: vec![queue.into()],
+            labels,
+            capacity,
+            consumer_tags: vec![],
+            heartbeat: Duration::from_secs(10),
+        }
+    }
+
+    pub fn add_consumer_tag(&mut self, consumer_tag: impl Into<String>) -> &mut Self {
+        self.consumer_tags.push(consumer_tag.into());
+
+        self
+    }
+
+    pub fn set_heartbeat(&mut self, heartbeat: Duration) -> &mut Self {
+        self.heartbeat = heartbeat;
+
+        self
+    }
+}
+
+// ================================================================================================
+// WorkerRegistry
+//
+// A subscriber registers itself as a worker (`{hostname}/{pid}`) once consuming starts, then
+// heartbeats with the task it is running, and is marked as stopped when shutting down. A worker
+// which stops heartbeating (e.g. killed) turns stale, then dead.
+// ================================================================================================
+
+#[derive(Clone, Debug)]
+pub struct WorkerRegistry {
+    db: DatabaseConnection,
+    worker_id: String,
+}
+
+impl WorkerRegistry {
+    pub fn new(db: DatabaseConnection) -> Self {
+        Self {
+            db,
+            worker_id: instance_id(),
+        }
+    }
+
+    pub fn worker_id(&self) -> &str {
+        &self.worker_id
+    }
+
+    // a worker restarted with the same id is registered again
+    pub async fn register(&self, info: &WorkerInfo) -> PqxResult<()> {
+        let now = Local::now();
+        let interval = i32::try_from(info.heartbeat.as_secs()).unwrap_or(i32::MAX);
+        let am = worker::ActiveModel {
+            worker_id: Set(self.worker_id.clone()),
+            hostname: Set(hostname()),
+            pid: Set(i32::try_from(std::process::id()).unwrap_or(i32::MAX)),
+            queues: Set(serde_json::json!(info.queues)),
+            labels: Set(serde_json::json!(info.labels)),
+            version: Set(env!("CARGO_PKG_VERSION").to_owned()),
+            capacity: Set(i32::from(info.capacity)),
+            consumer_tags: Set(serde_json::json!(info.consumer_tags)),
+            current_task: Set(None),
+            heartbeat_interval: Set(interval),
+            started_at: Set(now),
+            heartbeat_at: Set(now),
+            stopped_at: Set(None),
+        };
+        let on_conflict = OnConflict::column(worker::Column::WorkerId)
+            .update_columns([
+                worker::Column::Hostname,
+                worker::Column::Pid,
+                worker::Column::Queues,
+                worker::Column::Labels,
+                worker::Column::Version,
+                worker::Column::Capacity,
+                worker::Column::ConsumerTags,
+                worker::Column::CurrentTask,
+                worker::Column::HeartbeatInterval,
+                worker::Column::StartedAt,
+                worker::Column::HeartbeatAt,
+                worker::Column::StoppedAt,
+            ])
+            .to_owned();
+        worker::Entity::insert(am)
+            .on_conflict(on_conflict)
+            .exec_without_returning(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+
+    pub async fn heartbeat(&self, current_task: Option<String>) -> PqxResult<()> {
+        worker::Entity::update_many()
+            .col_expr(worker::Column::HeartbeatAt, Expr::value(Local::now()))
+            .col_expr(worker::Column::CurrentTask, Expr::value(current_task))
+            .filter(worker::Column::WorkerId.eq(self.worker_id.as_str()))
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+
+    pub async fn deregister(&self) -> PqxResult<()> {
+        worker::Entity::update_many()
+            .col_expr(worker::Column::StoppedAt, Expr::value(Local::now()))
+            .col_expr(
+                worker::Column::CurrentTask,
+                Expr::value(Option::<String>::None),
+            )
+            .filter(worker::Column::WorkerId.eq(self.worker_id.as_str()))
+            .exec(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(())
+    }
+
+    // every registered worker, ordered by id
+    pub async fn workers(&self) -> PqxResult<Vec<worker::Model>> {
+        let res = worker::Entity::find()
+            .order_by_asc(worker::Column::WorkerId)
+            .all(&self.db)
+            .await
+            .map_err(PqxUtilError::SeaOrm)?;
+
+        Ok(res)
+    }
+}
+
+// ================================================================================================
+// WorkerCheck
+//
+// A worker cross-checked against the consumers listed by the broker, by its consumer tags. A live
+// worker without consumers has lost its channel, whereas a dead one still owning consumers is
+// hanging (messages are prefetched but never acked).
+// ================================================================================================
+
+#[derive(Debug)]
+pub struct WorkerCheck<'a> {
+    pub worker: &'a worker::Model,
+    pub status: WorkerStatus,
+    pub consumers: Vec<&'a ConsumerInfo>,
+}
+
+impl<'a> WorkerCheck<'a> {
+    pub fn consistent(&self) -> bool {
+        match self.status {
+            WorkerStatus::Live => !self.consumers.is_empty(),
+            WorkerStatus::Stale => true,
+            WorkerStatus::Dead => self.consumers.is_empty(),
+        }
+    }
+}
+
+// checks of each worker, and the consumers owned by no worker
+pub fn check_workers<'a>(
+    workers: &'a [worker::Model],
+    consumers: &'a [ConsumerInfo],
+    now: DateTime<Local>,
+) -> (Vec<WorkerCheck<'a>>, Vec<&'a ConsumerInfo>) {
+    let tags = |w: &worker::Model| -> Vec<String> {
+        serde_json::from_value(w.consumer_tags.clone()).unwrap_or_default()
+    };
+
+    let checks = workers
+        .iter()
+        .map(|w| {
+            let tags = tags(w);
+            WorkerCheck {
+                worker: w,
+                status: WorkerStatus::of(w, now),
+                consumers: consumers
+                    .iter()
+                    .filter(|c| tags.contains(&c.consumer_tag))
+                    .collect(),
+            }
+        })
+        .collect::<Vec<_>>();
+    let orphans = consumers
+        .iter()
+        .filter(|c| {
+            !checks.iter().any(|wc| {
+                wc.consumers
+                    .iter()
+                    .any(|o| o.consumer_tag == c.consumer_tag)
+            })
+        })
+        .collect();
+
+    (checks, orphans)
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_worker {
+    use super::*;
+
+    fn worker(id: &str, tags: &[&str], heartbeat_ago: i64, stopped: bool) -> worker::Model {
+        let now = Local::now();
+        worker::Model {
+            worker_id: id.to_owned(),
+            hostname: "dev".to_owned(),
+            pid: 1,
+            queues: serde_json::json!(["h1"]),
+            labels: serde_json::json!({"unique_key": "h1"}),
+            version: "0.1.0".to_owned(),
+            capacity: 1,
+            consumer_tags: serde_json::json!(tags),
+            current_task: None,
+            heartbeat_interval: 10,
+            started_at: now - chrono::Duration::hours(1),
+            heartbeat_at: now - chrono::Duration::seconds(heartbeat_ago),
+            stopped_at: stopped.then_some(now),
+        }
+    }
+
+    fn consumer(tag: &str) -> ConsumerInfo {
+        let value = serde_json::json!({
+            "consumer_tag": tag,
+            "queue": {"name": "h1", "vhost": "dev"},
+            "channel_details": {
+                "connection_name": "172.17.0.1:50000 -> 172.17.0.2:5672",
+                "peer_host": "172.17.0.1"
+            },
+            "prefetch_count": 1,
+            "exclusive": false,
+        });
+
+        ConsumerInfo::try_from(&value).unwrap()
+    }
+
+    #[test]
+    fn worker_status_success() {
+        let now = Local::now();
+
+        assert_eq!(
+            WorkerStatus::of(&worker("w", &[], 5, false), now),
+            WorkerStatus::Live
+        );
+        assert_eq!(
+            WorkerStatus::of(&worker("w", &[], 60, false), now),
+            WorkerStatus::Stale
+        );
+        assert_eq!(
+            WorkerStatus::of(&worker("w", &[], 600, false), now),
+            WorkerStatus::Dead
+        );
+        assert_eq!(
+            WorkerStatus::of(&worker("w", &[], 5, true), now),
+            WorkerStatus::Dead
+        );
+    }
+
+    #[test]
+    fn check_workers_success() {
+        let workers = vec![
+            worker("live", &["ctag-1", "ctag-2"], 5, false),
+            worker("lost", &["ctag-3"], 5, false),
+            worker("hung", &["ctag-4"], 600, false),
+            worker("stopped", &["ctag-5"], 600, true),
+        ];
+        let consumers = ["ctag-1", "ctag-2", "ctag-4", "ctag-6"].map(consumer);
+        assert!(consumers[0].active);
+
+        let (checks, orphans) = check_workers(&workers, &consumers, Local::now());
+        let consistent = checks.iter().map(|c| c.consistent()).collect::<Vec<_>>();
+        assert_eq!(consistent, vec![true, false, false, true]);
+        assert_eq!(checks[0].consumers.len(), 2);
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].consumer_tag, "ctag-6");
+    }
+}
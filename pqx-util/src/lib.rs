@@ -42,12 +42,14 @@ pub mod error;
 pub mod log;
 pub mod misc;
 pub mod mq;
+pub mod trace;
 
 pub use db::*;
 pub use error::*;
 pub use log::*;
 pub use misc::*;
 pub use mq::*;
+pub use trace::*;
 
 // ================================================================================================
 // public macros
@@ -6,7 +6,7 @@
 use tracing::{debug, error, info, instrument, warn, Level};
 use tracing_subscriber::prelude::*;
 
-use crate::{PqxUtilError, PqxUtilResult};
+use crate::{PqxUtilError, PqxUtilResult, SpanExportLayer, TraceExport, TraceGuard};
 
 use super::now;
 
@@ -73,3 +73,76 @@ pub fn logging_init(
 
     Ok(guard)
 }
+
+fn trace_layer(
+    service: &str,
+    export: Option<&TraceExport>,
+) -> PqxUtilResult<(Option<SpanExportLayer>, Option<TraceGuard>)> {
+    match export {
+        Some(e) => {
+            let (l, g) = SpanExportLayer::new(service, e)?;
+            Ok((Some(l), Some(g)))
+        }
+        None => Ok((None, None)),
+    }
+}
+
+// same as `logging_file_init`, and spans of traces are exported as `service` if `export` is set
+pub fn logging_file_init_with_trace(
+    dir: &str,
+    prefix: &str,
+    level: Level,
+    service: &str,
+    export: Option<&TraceExport>,
+) -> PqxUtilResult<(
+    tracing_appender::non_blocking::WorkerGuard,
+    Option<TraceGuard>,
+)> {
+    let file_appender = ::tracing_appender::rolling::daily(dir, prefix);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+    let file_log = tracing_subscriber::fmt::layer()
+        .with_writer(non_blocking.with_max_level(level))
+        .with_ansi(false);
+    let (trace_log, trace_guard) = trace_layer(service, export)?;
+
+    tracing_subscriber::registry()
+        .with(file_log)
+        .with(trace_log)
+        .try_init()
+        .map_err(|_| PqxUtilError::from("tracing_subscriber failed"))?;
+
+    Ok((guard, trace_guard))
+}
+
+// same as `logging_init`, and spans of traces are exported as `service` if `export` is set
+pub fn logging_init_with_trace(
+    dir: &str,
+    prefix: &str,
+    level: Level,
+    service: &str,
+    export: Option<&TraceExport>,
+) -> PqxUtilResult<(
+    tracing_appender::non_blocking::WorkerGuard,
+    Option<TraceGuard>,
+)> {
+    let stdout_log = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stdout.with_max_level(level))
+        .with_ansi(true)
+        .pretty();
+
+    let file_appender = tracing_appender::rolling::daily(dir, prefix);
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+    let file_log = tracing_subscriber::fmt::layer()
+        .with_writer(non_blocking.with_max_level(level))
+        .with_ansi(false);
+    let (trace_log, trace_guard) = trace_layer(service, export)?;
+
+    tracing_subscriber::registry()
+        .with(file_log)
+        .with(stdout_log)
+        .with(trace_log)
+        .try_init()
+        .map_err(|_| PqxUtilError::from("tracing_subscriber failed"))?;
+
+    Ok((guard, trace_guard))
+}
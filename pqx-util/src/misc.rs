@@ -57,5 +57,24 @@ where
 }
 
 pub fn get_cur_dir_file(filename: &str) -> PqxUtilResult<PathBuf> {
-    Ok(join_dir(current_dir()?, filename)?)
+    join_dir(current_dir()?, filename)
+}
+
+// ================================================================================================
+// Host
+// ================================================================================================
+
+// `$HOSTNAME` is not exported to child processes by most shells, hence the kernel's one
+pub fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .map(|h| h.trim().to_owned())
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "unknown".to_owned())
+}
+
+// identifies a running service, e.g. `host-1/4242`
+pub fn instance_id() -> String {
+    format!("{}/{}", hostname(), std::process::id())
 }
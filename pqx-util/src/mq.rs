@@ -172,6 +172,36 @@ impl MqApiClient {
 
         Ok(res)
     }
+
+    // for endpoints replying without a body, e.g. `PUT /api/policies/{vhost}/{name}`
+    pub async fn put_no_content<P: AsRef<str>, T: Serialize>(
+        &self,
+        path: P,
+        req: &T,
+    ) -> PqxUtilResult<()> {
+        let pth = format!("{}/{}", self.url, path.as_ref());
+        let encoded = parse_url(pth)?;
+        self.client
+            .put(encoded)
+            .json(req)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    pub async fn delete<P: AsRef<str>>(&self, path: P) -> PqxUtilResult<()> {
+        let pth = format!("{}/{}", self.url, path.as_ref());
+        let encoded = parse_url(pth)?;
+        self.client
+            .delete(encoded)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
 }
 
 // ================================================================================================
@@ -213,6 +243,7 @@ impl<'a> MqQuery<'a> {
     impl_get_with_vhost!(exchanges, "exchanges");
     impl_get_with_vhost!(queues, "queues");
     impl_get_with_vhost!(bindings, "bindings");
+    impl_get_with_vhost!(consumers, "consumers");
     impl_get_with_vhost!(policies, "policies");
 }
 
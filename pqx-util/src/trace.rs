@@ -0,0 +1,379 @@
+//! file: trace.rs
+//! author: Jacob Xie
+//! date: 2023/07/22 14:08:52 Saturday
+//! brief: export spans of traces as OTLP/JSON, to a file or an OTLP/HTTP collector
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::PqxUtilResult;
+
+// spans are sent in batches, at least once per interval
+const BATCH_SIZE: usize = 512;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// target of the events about exporting, which are never exported themselves
+pub const EXPORT_TARGET: &str = "pqx_util::trace::export";
+
+// ================================================================================================
+// TraceExport
+// ================================================================================================
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TraceExport {
+    // one OTLP/JSON request per line
+    File { path: String },
+    // OTLP/HTTP, e.g. "http://localhost:4318/v1/traces"
+    Otlp { endpoint: String },
+}
+
+enum Sink {
+    File(File),
+    Otlp(reqwest::blocking::Client, String),
+}
+
+impl Sink {
+    fn new(export: &TraceExport) -> PqxUtilResult<Self> {
+        let sink = match export {
+            TraceExport::File { path } => {
+                Sink::File(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            TraceExport::Otlp { endpoint } => {
+                Sink::Otlp(reqwest::blocking::Client::new(), endpoint.clone())
+            }
+        };
+
+        Ok(sink)
+    }
+
+    // errors are logged by `EXPORT_TARGET`, ignored by `SpanExportLayer`
+    fn export(&mut self, body: &Value) {
+        let res = match self {
+            Sink::File(f) => writeln!(f, "{}", body).map_err(|e| e.to_string()),
+            Sink::Otlp(client, endpoint) => client
+                .post(endpoint.as_str())
+                .json(body)
+                .send()
+                .and_then(|r| r.error_for_status())
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+        };
+        if let Err(e) = res {
+            tracing::warn!(target: EXPORT_TARGET, "trace export failed: {}", e);
+        }
+    }
+}
+
+// ================================================================================================
+// SpanRecord
+// ================================================================================================
+
+#[derive(Debug, Clone, Default)]
+struct SpanRecord {
+    name: String,
+    trace_id: String,
+    span_id: String,
+    parent_span_id: String,
+    kind: String,
+    unsampled: bool, // the W3C sampled flag is unset
+    attributes: Vec<(String, String)>,
+    events: Vec<(u128, String)>, // (unix nanos, message)
+    start: u128,
+    end: u128,
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default()
+}
+
+impl Visit for SpanRecord {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "trace_id" => self.trace_id = value.to_owned(),
+            "span_id" => self.span_id = value.to_owned(),
+            "parent_span_id" => self.parent_span_id = value.to_owned(),
+            "otel.kind" => self.kind = value.to_owned(),
+            n => self.attributes.push((n.to_owned(), value.to_owned())),
+        }
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        match field.name() {
+            "sampled" => self.unsampled = !value,
+            n => self.attributes.push((n.to_owned(), value.to_string())),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value))
+    }
+}
+
+// the `message` of an event
+#[derive(Default)]
+struct EventMessage(String);
+
+impl Visit for EventMessage {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
+
+// OTLP/JSON `ExportTraceServiceRequest`
+fn otlp_json(service: &str, spans: &[SpanRecord]) -> Value {
+    let spans = spans
+        .iter()
+        .map(|s| {
+            // SPAN_KIND_INTERNAL/PRODUCER/CONSUMER
+            let kind = match s.kind.as_str() {
+                "producer" => 4,
+                "consumer" => 5,
+                _ => 1,
+            };
+            let attributes = s
+                .attributes
+                .iter()
+                .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+                .collect::<Vec<_>>();
+            let events = s
+                .events
+                .iter()
+                .map(|(t, m)| json!({"timeUnixNano": t.to_string(), "name": m}))
+                .collect::<Vec<_>>();
+
+            json!({
+                "traceId": s.trace_id,
+                "spanId": s.span_id,
+                "parentSpanId": s.parent_span_id,
+                "name": s.name,
+                "kind": kind,
+                "startTimeUnixNano": s.start.to_string(),
+                "endTimeUnixNano": s.end.to_string(),
+                "attributes": attributes,
+                "events": events,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service}}]
+            },
+            "scopeSpans": [{"scope": {"name": "pqx"}, "spans": spans}]
+        }]
+    })
+}
+
+// ================================================================================================
+// SpanExportLayer
+//
+// Exports the spans carrying a `trace_id` field (see `pqx::mq::TraceContext`) unless their
+// `sampled` field is false, with the events logged inside of them or of their child spans (e.g.
+// output lines of a child process). Spans are exported by a background thread when closed, and
+// flushed when the `TraceGuard` drops.
+// ================================================================================================
+
+enum Message {
+    Span(Box<SpanRecord>),
+    Shutdown,
+}
+
+pub struct SpanExportLayer {
+    tx: Sender<Message>,
+}
+
+pub struct TraceGuard {
+    tx: Sender<Message>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for TraceGuard {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Message::Shutdown);
+        if let Some(h) = self.handle.take() {
+            let _ = h.join();
+        }
+    }
+}
+
+impl SpanExportLayer {
+    pub fn new(service: &str, export: &TraceExport) -> PqxUtilResult<(Self, TraceGuard)> {
+        let mut sink = Sink::new(export)?;
+        let service = service.to_owned();
+        let (tx, rx) = channel();
+
+        let handle = std::thread::spawn(move || {
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            let mut flushed_at = Instant::now();
+            loop {
+                let shutdown = match rx.recv_timeout(FLUSH_INTERVAL) {
+                    Ok(Message::Span(s)) => {
+                        batch.push(*s);
+                        false
+                    }
+                    Ok(Message::Shutdown) | Err(RecvTimeoutError::Disconnected) => true,
+                    Err(RecvTimeoutError::Timeout) => false,
+                };
+                let due = batch.len() >= BATCH_SIZE || flushed_at.elapsed() >= FLUSH_INTERVAL;
+                if shutdown || due {
+                    if !batch.is_empty() {
+                        sink.export(&otlp_json(&service, &batch));
+                        batch.clear();
+                    }
+                    flushed_at = Instant::now();
+                }
+                if shutdown {
+                    break;
+                }
+            }
+        });
+
+        let guard = TraceGuard {
+            tx: tx.clone(),
+            handle: Some(handle),
+        };
+
+        Ok((Self { tx }, guard))
+    }
+}
+
+impl<S> Layer<S> for SpanExportLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut record = SpanRecord {
+            name: attrs.metadata().name().to_owned(),
+            start: unix_nanos(),
+            ..Default::default()
+        };
+        attrs.record(&mut record);
+        if record.trace_id.is_empty() || record.unsampled {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(record);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(record) = span.extensions_mut().get_mut::<SpanRecord>() {
+                values.record(record);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if event.metadata().target() == EXPORT_TARGET {
+            return;
+        }
+        let scope = match ctx.event_scope(event) {
+            Some(s) => s,
+            None => return,
+        };
+        // the nearest traced span
+        for span in scope {
+            let mut ext = span.extensions_mut();
+            if let Some(record) = ext.get_mut::<SpanRecord>() {
+                let mut message = EventMessage::default();
+                event.record(&mut message);
+                record.events.push((unix_nanos(), message.0));
+                break;
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let record = ctx
+            .span(&id)
+            .and_then(|s| s.extensions_mut().remove::<SpanRecord>());
+        if let Some(mut record) = record {
+            record.end = unix_nanos();
+            let _ = self.tx.send(Message::Span(Box::new(record)));
+        }
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_trace {
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn export_file_success() {
+        let path = std::env::temp_dir().join("pqx_test_trace.jsonl");
+        let _ = std::fs::remove_file(&path);
+        let export = TraceExport::File {
+            path: path.to_string_lossy().to_string(),
+        };
+
+        let (layer, guard) = SpanExportLayer::new("pqx-test", &export).unwrap();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!(
+                "pqx.delivery",
+                trace_id = "4bf92f3577b34da6a3ce929d0e0e4736",
+                span_id = "00f067aa0ba902b7",
+                parent_span_id = "",
+                "otel.kind" = "consumer",
+                queue = "h1",
+                outcome = tracing::field::Empty,
+            );
+            let _enter = span.enter();
+            // logged inside of an untraced child span
+            tracing::info_span!("logging_info").in_scope(|| tracing::info!("child output"));
+            tracing::warn!(target: EXPORT_TARGET, "not exported");
+            span.record("outcome", "success");
+
+            // not traced
+            tracing::info_span!("other").in_scope(|| {});
+            // not sampled
+            tracing::info_span!(
+                "pqx.publish",
+                trace_id = "4bf92f3577b34da6a3ce929d0e0e4737",
+                span_id = "00f067aa0ba902b8",
+                sampled = false,
+            )
+            .in_scope(|| {});
+        });
+        drop(guard);
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let body: Value = serde_json::from_str(line.trim()).unwrap();
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans.as_array().unwrap().len(), 1);
+        assert_eq!(spans[0]["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(spans[0]["kind"], 5);
+        assert_eq!(spans[0]["events"].as_array().unwrap().len(), 1);
+        assert_eq!(spans[0]["events"][0]["name"], "child output");
+        let attributes = spans[0]["attributes"].as_array().unwrap();
+        assert!(
+            attributes.contains(&json!({"key": "outcome", "value": {"stringValue": "success"}}))
+        );
+    }
+}
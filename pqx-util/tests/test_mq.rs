@@ -178,7 +178,7 @@ async fn auth_success() {
 async fn exchanges_with_vhost_success() {
     let query = MqQuery::new(&CLIENT);
 
-    let res = query.exchanges_with_vhost(&CLIENT.vhost()).await;
+    let res = query.exchanges_with_vhost(CLIENT.vhost()).await;
     assert!(res.is_ok());
     let pretty_json = serde_json::to_string_pretty(&res.unwrap()).unwrap();
     println!("{}", pretty_json);
@@ -188,7 +188,7 @@ async fn exchanges_with_vhost_success() {
 async fn queues_with_vhost_success() {
     let query = MqQuery::new(&CLIENT);
 
-    let res = query.queues_with_vhost(&CLIENT.vhost()).await;
+    let res = query.queues_with_vhost(CLIENT.vhost()).await;
     assert!(res.is_ok());
     let pretty_json = serde_json::to_string_pretty(&res.unwrap()).unwrap();
     println!("{}", pretty_json);
@@ -198,7 +198,7 @@ async fn queues_with_vhost_success() {
 async fn bindings_with_vhost_success() {
     let query = MqQuery::new(&CLIENT);
 
-    let res = query.bindings_with_vhost(&CLIENT.vhost()).await;
+    let res = query.bindings_with_vhost(CLIENT.vhost()).await;
     assert!(res.is_ok());
     let pretty_json = serde_json::to_string_pretty(&res.unwrap()).unwrap();
     println!("{}", pretty_json);
@@ -208,7 +208,7 @@ async fn bindings_with_vhost_success() {
 async fn policies_with_vhost_success() {
     let query = MqQuery::new(&CLIENT);
 
-    let res = query.policies_with_vhost(&CLIENT.vhost()).await;
+    let res = query.policies_with_vhost(CLIENT.vhost()).await;
     assert!(res.is_ok());
     let pretty_json = serde_json::to_string_pretty(&res.unwrap()).unwrap();
     println!("{}", pretty_json);
@@ -56,8 +56,39 @@ impl ChildStdPipe {
 
 // ================================================================================================
 // commands generators
+//
+// On unix, each child leads its own process group, so that it is killed along with its
+// descendants (e.g. the python started by `conda run`), see `kill_process_group`. Elsewhere the
+// process tree of the child is killed by `taskkill`.
 // ================================================================================================
 
+#[cfg(unix)]
+pub fn kill_process_group(pid: u32) -> PqxResult<()> {
+    let pgid = i32::try_from(pid).map_err(|_| PqxError::custom("pid out of range"))?;
+    // SAFETY: `kill` only sends a signal, and touches no memory
+    if unsafe { libc::kill(-pgid, libc::SIGKILL) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    Ok(())
+}
+
+// no process groups, the child is killed along with its tree
+#[cfg(not(unix))]
+pub fn kill_process_group(pid: u32) -> PqxResult<()> {
+    let status = Command::new("taskkill")
+        .args(["/T", "/F", "/PID"])
+        .arg(pid.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err("taskkill failed".into());
+    }
+
+    Ok(())
+}
+
 pub struct CmdChild {
     pub child: Child,
     pub child_stdout: ChildStdout,
@@ -74,12 +105,12 @@ impl CmdChild {
     }
 }
 
-pub fn gen_ping_cmd(addr: &str) -> PqxResult<CmdChild> {
-    let mut child = Command::new("ping")
-        .arg(addr)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
+// pipes stdout & stderr, and (unix) makes the child a process group leader
+fn spawn(command: &mut Command) -> PqxResult<CmdChild> {
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(command, 0);
+    let mut child = command.spawn()?;
 
     let child_stdout = child.stdout.take().unwrap();
     let child_stderr = child.stderr.take().unwrap();
@@ -87,22 +118,16 @@ pub fn gen_ping_cmd(addr: &str) -> PqxResult<CmdChild> {
     Ok(CmdChild::new(child, child_stdout, child_stderr))
 }
 
+pub fn gen_ping_cmd(addr: &str) -> PqxResult<CmdChild> {
+    spawn(Command::new("ping").arg(addr))
+}
+
 pub fn gen_bash_cmd<I, S>(cmd: I) -> PqxResult<CmdChild>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let mut child = Command::new("bash")
-        .arg("-c")
-        .args(cmd)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    let child_stdout = child.stdout.take().unwrap();
-    let child_stderr = child.stderr.take().unwrap();
-
-    Ok(CmdChild::new(child, child_stdout, child_stderr))
+    spawn(Command::new("bash").arg("-c").args(cmd))
 }
 
 pub fn gen_ssh_cmd<I, S>(ip: &str, user: &str, cmd: I) -> PqxResult<CmdChild>
@@ -110,17 +135,11 @@ where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let mut child = Command::new("ssh")
-        .arg(format!("{}@{}", user, ip))
-        .args(cmd)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    let child_stdout = child.stdout.take().unwrap();
-    let child_stderr = child.stderr.take().unwrap();
-
-    Ok(CmdChild::new(child, child_stdout, child_stderr))
+    spawn(
+        Command::new("ssh")
+            .arg(format!("{}@{}", user, ip))
+            .args(cmd),
+    )
 }
 
 pub fn gen_sshpass_cmd<I, S>(ip: &str, user: &str, pass: &str, cmd: I) -> PqxResult<CmdChild>
@@ -128,40 +147,28 @@ where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let mut child = Command::new("sshpass")
-        .arg("-p")
-        .arg(pass)
-        .arg("ssh")
-        .arg(format!("{}@{}", user, ip))
-        .args(cmd)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    let child_stdout = child.stdout.take().unwrap();
-    let child_stderr = child.stderr.take().unwrap();
-
-    Ok(CmdChild::new(child, child_stdout, child_stderr))
+    spawn(
+        Command::new("sshpass")
+            .arg("-p")
+            .arg(pass)
+            .arg("ssh")
+            .arg(format!("{}@{}", user, ip))
+            .args(cmd),
+    )
 }
 
 pub fn gen_conda_python_cmd(env: &str, dir: &str, script: &str) -> PqxResult<CmdChild> {
-    let mut child = Command::new("conda")
-        .current_dir(dir)
-        .arg("run")
-        .arg("-n")
-        .arg(env)
-        .arg("--live-stream")
-        .arg("python")
-        .arg("-u")
-        .arg(script)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    let child_stdout = child.stdout.take().unwrap();
-    let child_stderr = child.stderr.take().unwrap();
-
-    Ok(CmdChild::new(child, child_stdout, child_stderr))
+    spawn(
+        Command::new("conda")
+            .current_dir(dir)
+            .arg("run")
+            .arg("-n")
+            .arg(env)
+            .arg("--live-stream")
+            .arg("python")
+            .arg("-u")
+            .arg(script),
+    )
 }
 
 pub fn gen_docker_exec_cmd<I, S>(container: &str, cmd: I) -> PqxResult<CmdChild>
@@ -169,18 +176,7 @@ where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let mut child = Command::new("docker")
-        .arg("exec")
-        .arg(container)
-        .args(cmd)
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()?;
-
-    let child_stdout = child.stdout.take().unwrap();
-    let child_stderr = child.stderr.take().unwrap();
-
-    Ok(CmdChild::new(child, child_stdout, child_stderr))
+    spawn(Command::new("docker").arg("exec").arg(container).args(cmd))
 }
 
 // ================================================================================================
@@ -4,12 +4,13 @@
 //! brief:
 
 use futures::future::{BoxFuture, Future};
+use std::collections::HashSet;
 use std::process::{ChildStderr, ChildStdout, ExitStatus};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use crate::error::PqxResult;
 
-use super::{send_child_std, ChildStdPipe, CmdArg, CmdChild};
+use super::{kill_process_group, send_child_std, ChildStdPipe, CmdArg, CmdChild};
 
 // ================================================================================================
 // CmdExecutor
@@ -75,6 +76,12 @@ pub struct CmdExecutor {
     stderr_fn: Option<SyncFn>,
 }
 
+impl Default for CmdExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CmdExecutor {
     pub fn new() -> Self {
         Self {
@@ -177,7 +184,7 @@ pub fn gen_async_execution<'a>(
     (so_tx, so_rx)
 }
 
-async fn exec_async_cmd<'a>(
+async fn exec_async_cmd(
     channel_buffer: usize,
     fo: Option<(ChildStdout, Arc<dyn AsyncFn>)>,
     fe: Option<(ChildStderr, Arc<dyn AsyncFn>)>,
@@ -202,10 +209,33 @@ async fn exec_async_cmd<'a>(
     Ok(())
 }
 
+// pids of the running children, shared by the clones of an executor
+#[derive(Clone, Debug, Default)]
+struct RunningChildren(Arc<Mutex<HashSet<u32>>>);
+
+// removes the pid once the child exits, or its execution fails
+struct RunningGuard {
+    running: RunningChildren,
+    pid: u32,
+}
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.running.0.lock().unwrap().remove(&self.pid);
+    }
+}
+
 #[derive(Clone)]
 pub struct CmdAsyncExecutor {
     stdout_fn: Option<Arc<dyn AsyncFn>>,
     stderr_fn: Option<Arc<dyn AsyncFn>>,
+    running: RunningChildren,
+}
+
+impl Default for CmdAsyncExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CmdAsyncExecutor {
@@ -213,6 +243,7 @@ impl CmdAsyncExecutor {
         Self {
             stdout_fn: None,
             stderr_fn: None,
+            running: RunningChildren::default(),
         }
     }
 
@@ -237,11 +268,29 @@ impl CmdAsyncExecutor {
     }
 
     pub async fn exec(&self, channel_buffer: usize, arg: &CmdArg) -> PqxResult<ExitStatus> {
+        self.exec_with(channel_buffer, arg, |_| ()).await
+    }
+
+    // `on_spawn` is called with the pid of the child, and what it returns (e.g. a registration
+    // killing the child, see `kill_process_group`) is kept until the child exits
+    pub async fn exec_with<G>(
+        &self,
+        channel_buffer: usize,
+        arg: &CmdArg,
+        on_spawn: impl FnOnce(u32) -> G,
+    ) -> PqxResult<ExitStatus> {
         let CmdChild {
             mut child,
             child_stdout,
             child_stderr,
         } = arg.gen_cmd()?;
+        let pid = child.id();
+        self.running.0.lock().unwrap().insert(pid);
+        let _running = RunningGuard {
+            running: self.running.clone(),
+            pid,
+        };
+        let _guard = on_spawn(pid);
 
         exec_async_cmd(
             channel_buffer,
@@ -252,6 +301,21 @@ impl CmdAsyncExecutor {
 
         Ok(child.wait()?)
     }
+
+    // number of children being executed, by this executor and its clones
+    pub fn running(&self) -> usize {
+        self.running.0.lock().unwrap().len()
+    }
+
+    // kill the process groups of the running children (e.g. left by a shutdown which failed to
+    // drain them), returns the number of the killed ones
+    pub fn kill_running(&self) -> usize {
+        let pids: Vec<u32> = self.running.0.lock().unwrap().iter().copied().collect();
+
+        pids.into_iter()
+            .filter(|pid| kill_process_group(*pid).is_ok())
+            .count()
+    }
 }
 
 impl std::fmt::Debug for CmdAsyncExecutor {
@@ -259,6 +323,7 @@ impl std::fmt::Debug for CmdAsyncExecutor {
         f.debug_struct("CmdAsyncExecutor")
             .field("stdout_fn", &"Option<Arc<dyn AsyncFn>>")
             .field("stderr_fn", &"Option<Arc<dyn AsyncFn>>")
+            .field("running", &self.running)
             .finish()
     }
 }
@@ -22,6 +22,21 @@ pub enum PqxError {
     #[error(transparent)]
     Serde(serde_json::Error),
 
+    #[error(transparent)]
+    MsgPackEncode(rmp_serde::encode::Error),
+
+    #[error(transparent)]
+    MsgPackDecode(rmp_serde::decode::Error),
+
+    #[error(transparent)]
+    CborEncode(ciborium::ser::Error<std::io::Error>),
+
+    #[error(transparent)]
+    CborDecode(ciborium::de::Error<std::io::Error>),
+
+    #[error(transparent)]
+    FieldTable(crate::mq::FieldTableError),
+
     #[error(transparent)]
     Util(pqx_util::PqxUtilError),
 
@@ -39,6 +54,11 @@ impl_from_error!(std::io::Error, PqxError, StdIO);
 impl_from_error!(std::num::TryFromIntError, PqxError, NumTryFrom);
 impl_from_error!(amqprs::error::Error, PqxError, RbMQ);
 impl_from_error!(serde_json::Error, PqxError, Serde);
+impl_from_error!(rmp_serde::encode::Error, PqxError, MsgPackEncode);
+impl_from_error!(rmp_serde::decode::Error, PqxError, MsgPackDecode);
+impl_from_error!(ciborium::ser::Error<std::io::Error>, PqxError, CborEncode);
+impl_from_error!(ciborium::de::Error<std::io::Error>, PqxError, CborDecode);
+impl_from_error!(crate::mq::FieldTableError, PqxError, FieldTable);
 impl_from_error!(pqx_util::PqxUtilError, PqxError, Util);
 
 impl From<&'static str> for PqxError {
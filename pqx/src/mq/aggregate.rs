@@ -0,0 +1,251 @@
+//! file: aggregate.rs
+//! author: Jacob Xie
+//! date: 2023/07/13 21:02:44 Thursday
+//! brief: Broadcast-Aggregate, gather replies of a request fanned out to multiple queues
+
+use std::str::FromStr;
+
+use futures::future::join_all;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::{RpcFuture, RpcReply};
+use crate::error::{PqxError, PqxResult};
+
+// ================================================================================================
+// AggregatePolicy
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AggregatePolicy {
+    // every expected recipient succeeded
+    #[default]
+    AllSucceeded,
+    // at least one recipient succeeded
+    AnySucceeded,
+    // at least `n` recipients succeeded
+    Quorum {
+        n: usize,
+    },
+}
+
+impl AggregatePolicy {
+    pub fn passed(&self, succeeded: usize, expected: usize) -> bool {
+        match self {
+            AggregatePolicy::AllSucceeded => expected > 0 && succeeded == expected,
+            AggregatePolicy::AnySucceeded => succeeded > 0,
+            AggregatePolicy::Quorum { n } => succeeded >= *n,
+        }
+    }
+
+    // a quorum out of `1..=expected` passes always or never
+    pub fn check(&self, expected: usize) -> PqxResult<()> {
+        match self {
+            AggregatePolicy::Quorum { n } if *n == 0 || *n > expected => {
+                Err("quorum must be between 1 and the number of expected recipients".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+// all | any | quorum:<n>
+impl FromStr for AggregatePolicy {
+    type Err = PqxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("quorum", n)) => {
+                let n = n.parse().map_err(|_| "quorum requires a number")?;
+                Ok(AggregatePolicy::Quorum { n })
+            }
+            None if s == "all" => Ok(AggregatePolicy::AllSucceeded),
+            None if s == "any" => Ok(AggregatePolicy::AnySucceeded),
+            _ => Err("policy: all/any/quorum:<n>".into()),
+        }
+    }
+}
+
+// ================================================================================================
+// RecipientResult
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyStatus {
+    Succeeded,
+    Failed,
+    Missing, // no reply before the deadline
+}
+
+#[derive(Debug, Clone)]
+pub struct RecipientResult {
+    pub correlation_id: String,
+    pub queue: String,
+    pub status: ReplyStatus,
+    pub reply: Option<RpcReply>,
+}
+
+// ================================================================================================
+// Aggregate
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct Aggregate {
+    pub policy: AggregatePolicy,
+    pub recipients: Vec<RecipientResult>,
+    pub unexpected: Vec<RpcReply>, // replied by a queue which is not expected
+    pub complete: bool,            // every expected recipient has replied
+    pub passed: bool,
+}
+
+impl Aggregate {
+    fn count(&self, status: ReplyStatus) -> usize {
+        self.recipients
+            .iter()
+            .filter(|r| r.status == status)
+            .count()
+    }
+
+    pub fn expected(&self) -> usize {
+        self.recipients.len()
+    }
+
+    pub fn succeeded(&self) -> usize {
+        self.count(ReplyStatus::Succeeded)
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(ReplyStatus::Failed)
+    }
+
+    pub fn missing(&self) -> usize {
+        self.count(ReplyStatus::Missing)
+    }
+}
+
+// ================================================================================================
+// Aggregator
+//
+// Each request is paired with the queues it is expected to be routed to (known at publish time).
+// Requests are awaited concurrently, until every expected queue has replied or the timeout of the
+// `RpcFuture` (the deadline) passes.
+// ================================================================================================
+
+pub struct Aggregator {
+    policy: AggregatePolicy,
+}
+
+impl Aggregator {
+    pub fn new(policy: AggregatePolicy) -> Self {
+        Self { policy }
+    }
+
+    pub async fn aggregate<T, F>(
+        &self,
+        requests: Vec<(RpcFuture<T>, Vec<String>)>,
+        is_success: F,
+    ) -> Aggregate
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(&RpcReply) -> bool,
+    {
+        let futs = requests.into_iter().map(|(fut, expected)| async move {
+            let correlation_id = fut.correlation_id().to_owned();
+            let replies = fut.recv_expected(&expected).await;
+            (correlation_id, expected, replies)
+        });
+
+        let mut recipients = vec![];
+        let mut unexpected = vec![];
+        for (correlation_id, expected, mut replies) in join_all(futs).await {
+            for queue in expected.into_iter() {
+                let pos = replies
+                    .iter()
+                    .position(|r| r.replier.is_none() || r.replier.as_ref() == Some(&queue));
+                let (status, reply) = match pos.map(|i| replies.remove(i)) {
+                    Some(r) if is_success(&r) => (ReplyStatus::Succeeded, Some(r)),
+                    Some(r) => (ReplyStatus::Failed, Some(r)),
+                    None => (ReplyStatus::Missing, None),
+                };
+                recipients.push(RecipientResult {
+                    correlation_id: correlation_id.clone(),
+                    queue,
+                    status,
+                    reply,
+                });
+            }
+            unexpected.extend(replies);
+        }
+
+        let mut res = Aggregate {
+            policy: self.policy,
+            recipients,
+            unexpected,
+            complete: false,
+            passed: false,
+        };
+        res.complete = res.missing() == 0;
+        res.passed = self.policy.passed(res.succeeded(), res.expected());
+
+        res
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_aggregate {
+    use super::*;
+
+    #[test]
+    fn policy_success() {
+        let all = AggregatePolicy::AllSucceeded;
+        assert!(all.passed(3, 3));
+        assert!(!all.passed(2, 3));
+        assert!(!all.passed(0, 0));
+
+        let any = AggregatePolicy::AnySucceeded;
+        assert!(any.passed(1, 3));
+        assert!(!any.passed(0, 3));
+
+        let quorum = AggregatePolicy::Quorum { n: 2 };
+        assert!(quorum.passed(2, 3));
+        assert!(!quorum.passed(1, 3));
+
+        assert!(quorum.check(2).is_ok());
+        assert!(quorum.check(1).is_err());
+        assert!(AggregatePolicy::Quorum { n: 0 }.check(3).is_err());
+        assert!(all.check(0).is_ok());
+    }
+
+    #[test]
+    fn policy_parse_success() {
+        assert_eq!(
+            "all".parse::<AggregatePolicy>().unwrap(),
+            AggregatePolicy::AllSucceeded
+        );
+        assert_eq!(
+            "any".parse::<AggregatePolicy>().unwrap(),
+            AggregatePolicy::AnySucceeded
+        );
+        assert_eq!(
+            "quorum:2".parse::<AggregatePolicy>().unwrap(),
+            AggregatePolicy::Quorum { n: 2 }
+        );
+        assert!("quorum:two".parse::<AggregatePolicy>().is_err());
+        assert!("most".parse::<AggregatePolicy>().is_err());
+    }
+
+    #[test]
+    fn policy_serde_success() {
+        let p: AggregatePolicy = serde_json::from_str(r#"{"type": "quorum", "n": 2}"#).unwrap();
+        assert_eq!(p, AggregatePolicy::Quorum { n: 2 });
+
+        let p: AggregatePolicy = serde_json::from_str(r#"{"type": "any_succeeded"}"#).unwrap();
+        assert_eq!(p, AggregatePolicy::AnySucceeded);
+    }
+}
@@ -0,0 +1,368 @@
+//! file: cancel.rs
+//! author: Jacob Xie
+//! date: 2023/07/28 20:36:15 Friday
+//! brief: cancel queued or running tasks by their id (`message_id`)
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use super::{Consumer, ConsumerResult};
+use crate::error::PqxResult;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+// routing key of cancel requests on the control exchange
+pub const CANCEL_ROUTING_KEY: &str = "cancel";
+
+// ================================================================================================
+// CancelRequest
+// ================================================================================================
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CancelRequest {
+    pub task_id: String, // `message_id` of the task
+    pub reason: Option<String>,
+}
+
+impl CancelRequest {
+    pub fn new(task_id: impl Into<String>) -> Self {
+        Self {
+            task_id: task_id.into(),
+            reason: None,
+        }
+    }
+
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+
+        self
+    }
+}
+
+// ================================================================================================
+// Tombstones
+//
+// Ids of cancelled tasks, each of them expires after `ttl`. A delivery whose `message_id` is
+// tombstoned is acked without being consumed, and a running one calls the hooks registered by
+// `on_cancel` (e.g. killing its child process), so that its outcome is taken as cancelled.
+// Shared by all the clones, hence by the consumers of a process.
+// ================================================================================================
+
+type Hook = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+struct TombstonesInner {
+    tick: u64,
+    cancelled: HashMap<String, Instant>,
+    running: HashMap<String, HashMap<u64, Hook>>, // hooks of a task id, by their registration
+}
+
+#[derive(Clone)]
+pub struct Tombstones {
+    ttl: Duration,
+    inner: Arc<Mutex<TombstonesInner>>,
+}
+
+impl Tombstones {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            inner: Arc::new(Mutex::new(TombstonesInner::default())),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().cancelled.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_cancelled(&self, task_id: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .cancelled
+            .get(task_id)
+            .is_some_and(|t| t.elapsed() <= self.ttl)
+    }
+
+    // tombstone a task and call its hooks, returns `false` if it has been tombstoned
+    pub fn cancel(&self, task_id: &str) -> bool {
+        self.cancel_since(task_id, Instant::now())
+    }
+
+    // tombstone a task cancelled `at`, e.g. loaded from persisted cancellations on startup
+    pub fn cancel_since(&self, task_id: &str, at: Instant) -> bool {
+        let hooks = {
+            let mut inner = self.inner.lock().unwrap();
+            let ttl = self.ttl;
+            inner.cancelled.retain(|_, t| t.elapsed() <= ttl);
+            if inner.cancelled.contains_key(task_id) {
+                return false;
+            }
+            inner.cancelled.insert(task_id.to_owned(), at);
+            inner.running.remove(task_id).unwrap_or_default()
+        };
+        // outside of the lock, since a hook might take a while
+        for (_, hook) in hooks {
+            hook();
+        }
+
+        true
+    }
+
+    // `hook` is called once the task is cancelled (at once if it has been), unless the returned
+    // guard is dropped before
+    pub fn on_cancel(&self, task_id: &str, hook: impl FnOnce() + Send + 'static) -> CancelGuard {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let id = inner.tick;
+        let cancelled = inner
+            .cancelled
+            .get(task_id)
+            .is_some_and(|t| t.elapsed() <= self.ttl);
+        if cancelled {
+            drop(inner);
+            hook();
+        } else {
+            inner
+                .running
+                .entry(task_id.to_owned())
+                .or_default()
+                .insert(id, Box::new(hook));
+        }
+
+        CancelGuard {
+            tombstones: self.clone(),
+            task_id: task_id.to_owned(),
+            id,
+        }
+    }
+}
+
+impl Debug for Tombstones {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tombstones")
+            .field("ttl", &self.ttl)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+pub struct CancelGuard {
+    tombstones: Tombstones,
+    task_id: String,
+    id: u64,
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        let mut inner = self.tombstones.inner.lock().unwrap();
+        if let Some(hooks) = inner.running.get_mut(&self.task_id) {
+            hooks.remove(&self.id);
+            if hooks.is_empty() {
+                inner.running.remove(&self.task_id);
+            }
+        }
+    }
+}
+
+// ================================================================================================
+// CancelListener
+//
+// Consumes `CancelRequest` from a queue bound to the control exchange (`CANCEL_ROUTING_KEY`),
+// normally an exclusive one per process, since every process has to receive every request.
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct CancelListener {
+    tombstones: Tombstones,
+}
+
+impl CancelListener {
+    pub fn new(tombstones: Tombstones) -> Self {
+        Self { tombstones }
+    }
+}
+
+#[async_trait]
+impl Consumer<CancelRequest, bool> for CancelListener {
+    async fn consume(&mut self, message: &CancelRequest) -> PqxResult<ConsumerResult<bool>> {
+        let cancelled = self.tombstones.cancel(&message.task_id);
+
+        Ok(ConsumerResult::success(cancelled))
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_cancel {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use amqprs::channel::ExchangeType;
+    use amqprs::BasicProperties;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::mq::{MemoryBroker, Outcome, Publisher, Retry, Subscriber};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct DevMsg {
+        data: String,
+    }
+
+    #[derive(Clone)]
+    struct DevConsumer {
+        tombstones: Tombstones,
+        consumed: Arc<AtomicUsize>,
+        killed: Arc<AtomicUsize>,
+        cancelled: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Consumer<DevMsg, ()> for DevConsumer {
+        async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+            self.consumed.fetch_add(1, Ordering::SeqCst);
+            let killed = self.killed.clone();
+            let _guard = self.tombstones.on_cancel(&message.data, move || {
+                killed.fetch_add(1, Ordering::SeqCst);
+            });
+            // cancelled while running, then fails as a killed process
+            if message.data == "running" {
+                self.tombstones.cancel(&message.data);
+                return Ok(ConsumerResult::retry(None));
+            }
+
+            Ok(ConsumerResult::success(()))
+        }
+
+        // a cancelled task is neither retried nor replied as exhausted
+        fn gen_retry(&self, _message: &DevMsg) -> Option<Retry> {
+            panic!("a cancelled task is retried")
+        }
+
+        fn gen_reply(&self, outcome: Outcome<'_, DevMsg, ()>) -> Option<serde_json::Value> {
+            assert!(!matches!(outcome, Outcome::Exhausted(..)));
+
+            None
+        }
+
+        fn tombstones(&self) -> Option<&Tombstones> {
+            Some(&self.tombstones)
+        }
+
+        async fn cancel_callback(&mut self, _message: &DevMsg, _task_id: &str) -> PqxResult<()> {
+            self.cancelled.fetch_add(1, Ordering::SeqCst);
+
+            Ok(())
+        }
+    }
+
+    fn task_props(task_id: &str) -> BasicProperties {
+        let mut props = BasicProperties::default();
+        props.with_message_id(task_id);
+
+        props
+    }
+
+    #[test]
+    fn tombstones_success() {
+        let tombstones = Tombstones::new(Duration::from_secs(60));
+        let killed = Arc::new(AtomicUsize::new(0));
+
+        // dropped guards are never called
+        let k = killed.clone();
+        drop(tombstones.on_cancel("t1", move || {
+            k.fetch_add(1, Ordering::SeqCst);
+        }));
+        let k = killed.clone();
+        let _guard = tombstones.on_cancel("t2", move || {
+            k.fetch_add(1, Ordering::SeqCst);
+        });
+        assert!(tombstones.cancel("t1"));
+        assert!(tombstones.cancel("t2"));
+        assert!(!tombstones.cancel("t2"));
+        assert_eq!(killed.load(Ordering::SeqCst), 1);
+        assert!(tombstones.is_cancelled("t1"));
+        assert!(!tombstones.is_cancelled("t3"));
+
+        // called at once for a cancelled task
+        let k = killed.clone();
+        let _guard = tombstones.on_cancel("t1", move || {
+            k.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(killed.load(Ordering::SeqCst), 2);
+
+        // expired
+        let tombstones = Tombstones::new(Duration::from_secs(1));
+        let at = Instant::now() - Duration::from_secs(2);
+        assert!(tombstones.cancel_since("t1", at));
+        assert!(!tombstones.is_cancelled("t1"));
+        assert!(tombstones.cancel("t2"));
+        assert_eq!(tombstones.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cancel_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+        broker
+            .declare_exchange("control", &ExchangeType::Topic)
+            .unwrap();
+        broker
+            .declare_and_bind_queue("control", CANCEL_ROUTING_KEY, "ctl")
+            .unwrap();
+        broker.declare_queue("q").unwrap();
+
+        let tombstones = Tombstones::new(Duration::from_secs(60));
+        let mut listener = Subscriber::new(&chan, CancelListener::new(tombstones.clone()));
+        listener.consume("ctl").await.unwrap();
+
+        let consumer = DevConsumer {
+            tombstones: tombstones.clone(),
+            consumed: Arc::new(AtomicUsize::new(0)),
+            killed: Arc::new(AtomicUsize::new(0)),
+            cancelled: Arc::new(AtomicUsize::new(0)),
+        };
+        let mut subscriber = Subscriber::new(&chan, consumer.clone());
+        subscriber.consume("q").await.unwrap();
+
+        // a queued task is cancelled by the control exchange
+        let publisher = Publisher::new(&chan);
+        publisher
+            .publish("control", CANCEL_ROUTING_KEY, CancelRequest::new("queued"))
+            .await
+            .unwrap();
+        assert_eq!(broker.run_until_idle().await.unwrap(), 1);
+        assert!(tombstones.is_cancelled("queued"));
+
+        for id in ["queued", "running", "done"] {
+            let msg = DevMsg {
+                data: id.to_string(),
+            };
+            publisher
+                .publish_with_props("", "q", msg, task_props(id))
+                .await
+                .unwrap();
+        }
+        assert_eq!(broker.run_until_idle().await.unwrap(), 3);
+
+        // the queued one is acked without running, the running one is killed
+        assert_eq!(consumer.consumed.load(Ordering::SeqCst), 2);
+        assert_eq!(consumer.killed.load(Ordering::SeqCst), 1);
+        assert_eq!(consumer.cancelled.load(Ordering::SeqCst), 2);
+        assert_eq!(broker.message_count("q"), 0);
+        assert_eq!(chan.unacked(), 0);
+    }
+}
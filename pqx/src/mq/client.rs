@@ -12,7 +12,10 @@ use amqprs::FieldTable;
 use pqx_util::{read_json, read_yaml};
 use serde::{Deserialize, Serialize};
 
-use super::{get_channel, get_connection, FieldTableBuilder, EXCHANGE_TYPE_DELAYED};
+use super::{
+    get_channel, get_connection, ChannelPool, FieldTableBuilder, QueueType, SharedPublisher,
+    EXCHANGE_TYPE_DELAYED,
+};
 use crate::error::PqxResult;
 
 // ================================================================================================
@@ -59,6 +62,7 @@ pub struct MqClient {
     conn_callback: Option<Arc<dyn ConnectionCallback>>,
     chan_callback: Option<Arc<dyn ChannelCallback>>,
     channel: Option<Channel>,
+    pool: Option<ChannelPool>,
 }
 
 impl MqClient {
@@ -100,6 +104,9 @@ impl MqClient {
     pub async fn disconnect(&mut self) -> PqxResult<()> {
         // ignore error
         let _ = self.close_channel().await;
+        if let Some(p) = self.pool.take() {
+            let _ = p.close().await;
+        }
 
         if let Some(c) = self.connection.take() {
             c.close().await?
@@ -157,6 +164,34 @@ impl MqClient {
         }
     }
 
+    // channels checked out by concurrent publishers, see `SharedPublisher`
+    pub fn open_channel_pool(&mut self, size: usize) -> PqxResult<()> {
+        let conn = get_connection!(self)?;
+
+        self.pool = Some(ChannelPool::new(conn.clone(), size));
+
+        Ok(())
+    }
+
+    pub async fn close_channel_pool(&mut self) -> PqxResult<()> {
+        match self.pool.take() {
+            Some(p) => p.close().await,
+            None => Err("channel pool is empty".into()),
+        }
+    }
+
+    pub fn channel_pool(&self) -> PqxResult<&ChannelPool> {
+        let pool = self.pool.as_ref().ok_or("channel pool is empty")?;
+
+        Ok(pool)
+    }
+
+    pub fn shared_publisher(&self) -> PqxResult<SharedPublisher> {
+        let pool = self.channel_pool()?;
+
+        Ok(SharedPublisher::new(pool.clone()))
+    }
+
     pub async fn declare_exchange(
         &self,
         name: &str,
@@ -256,6 +291,23 @@ impl MqClient {
         Ok(())
     }
 
+    // quorum & stream queues are durable
+    pub async fn declare_queue_with_type(
+        &self,
+        que: &str,
+        queue_type: &QueueType,
+        args: FieldTable,
+    ) -> PqxResult<()> {
+        let mut ft = FieldTableBuilder::from(args);
+        ft.x_queue_type(queue_type);
+        let mut args = QueueDeclareArguments::durable_client_named(que);
+        args.arguments(ft.finish());
+
+        self.declare_queue_by_args(args).await?;
+
+        Ok(())
+    }
+
     pub async fn declare_queue_by_args(&self, args: QueueDeclareArguments) -> PqxResult<()> {
         let chan = get_channel!(self)?;
 
@@ -303,6 +355,20 @@ impl MqClient {
         Ok(())
     }
 
+    // expired messages (after `ttl` milliseconds) are dead-lettered to `dlx`, keeping their
+    // original routing key
+    pub async fn declare_wait_queue(&self, que: &str, dlx: &str, ttl: i64) -> PqxResult<()> {
+        let mut args = QueueDeclareArguments::durable_client_named(que);
+        let mut ft = FieldTableBuilder::new();
+        ft.x_dead_letter_exchange_only(dlx);
+        ft.x_message_ttl(ttl);
+        args.arguments(ft.finish());
+
+        self.declare_queue_by_args(args).await?;
+
+        Ok(())
+    }
+
     pub async fn bind_queue_by_args(&self, args: QueueBindArguments) -> PqxResult<()> {
         let chan = get_channel!(self)?;
 
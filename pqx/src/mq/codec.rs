@@ -0,0 +1,225 @@
+//! file: codec.rs
+//! author: Jacob Xie
+//! date: 2023/07/15 15:32:20 Saturday
+//! brief: message codecs, chosen by `content_type`
+
+use amqprs::BasicProperties;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::decompress_content;
+use crate::error::PqxResult;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+pub const CONTENT_TYPE_MSGPACK: &str = "application/msgpack";
+pub const CONTENT_TYPE_CBOR: &str = "application/cbor";
+
+// ================================================================================================
+// Codec
+// ================================================================================================
+
+pub trait Codec {
+    fn content_type(&self) -> &'static str;
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> PqxResult<Vec<u8>>;
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> PqxResult<T>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn content_type(&self) -> &'static str {
+        CONTENT_TYPE_JSON
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> PqxResult<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> PqxResult<T> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MsgPackCodec;
+
+impl Codec for MsgPackCodec {
+    fn content_type(&self) -> &'static str {
+        CONTENT_TYPE_MSGPACK
+    }
+
+    // structs are encoded as maps (with field names), readable by other languages
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> PqxResult<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> PqxResult<T> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn content_type(&self) -> &'static str {
+        CONTENT_TYPE_CBOR
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> PqxResult<Vec<u8>> {
+        let mut res = vec![];
+        ciborium::ser::into_writer(value, &mut res)?;
+
+        Ok(res)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> PqxResult<T> {
+        Ok(ciborium::de::from_reader(bytes)?)
+    }
+}
+
+// ================================================================================================
+// MessageCodec
+//
+// Publishers set `content_type` by the codec in use, and consumers pick the decoder from the
+// `content_type` of a delivery. A delivery without `content_type` is taken as JSON.
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageCodec {
+    #[default]
+    Json,
+    MsgPack,
+    Cbor,
+}
+
+impl MessageCodec {
+    pub fn from_content_type(content_type: Option<&str>) -> PqxResult<Self> {
+        // ignore parameters, e.g. "application/json; charset=utf-8"
+        let ct = content_type
+            .and_then(|ct| ct.split(';').next())
+            .map(|ct| ct.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        match ct.as_str() {
+            "" | CONTENT_TYPE_JSON => Ok(MessageCodec::Json),
+            CONTENT_TYPE_MSGPACK | "application/x-msgpack" => Ok(MessageCodec::MsgPack),
+            CONTENT_TYPE_CBOR => Ok(MessageCodec::Cbor),
+            _ => Err("unsupported content_type".into()),
+        }
+    }
+}
+
+impl std::str::FromStr for MessageCodec {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(MessageCodec::Json),
+            "msgpack" => Ok(MessageCodec::MsgPack),
+            "cbor" => Ok(MessageCodec::Cbor),
+            _ => Err("codec: json/msgpack/cbor"),
+        }
+    }
+}
+
+impl Codec for MessageCodec {
+    fn content_type(&self) -> &'static str {
+        match self {
+            MessageCodec::Json => JsonCodec.content_type(),
+            MessageCodec::MsgPack => MsgPackCodec.content_type(),
+            MessageCodec::Cbor => CborCodec.content_type(),
+        }
+    }
+
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> PqxResult<Vec<u8>> {
+        match self {
+            MessageCodec::Json => JsonCodec.encode(value),
+            MessageCodec::MsgPack => MsgPackCodec.encode(value),
+            MessageCodec::Cbor => CborCodec.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> PqxResult<T> {
+        match self {
+            MessageCodec::Json => JsonCodec.decode(bytes),
+            MessageCodec::MsgPack => MsgPackCodec.decode(bytes),
+            MessageCodec::Cbor => CborCodec.decode(bytes),
+        }
+    }
+}
+
+// decode a delivery: decompressed by its `content_encoding`, then decoded by its `content_type`
+pub fn decode_content<T: DeserializeOwned>(
+    props: &BasicProperties,
+    content: &[u8],
+) -> PqxResult<T> {
+    let content = decompress_content(props, content)?;
+
+    MessageCodec::from_content_type(props.content_type().map(String::as_str))?.decode(&content)
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_codec {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum DevCmd {
+        Ping { addr: String },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct DevMsg {
+        mailing_to: Vec<HashMap<String, String>>,
+        retry: Option<u8>,
+        cmd: DevCmd,
+    }
+
+    #[test]
+    fn round_trip_success() {
+        let msg = DevMsg {
+            mailing_to: vec![HashMap::from([("unique_key".into(), "h1".into())])],
+            retry: Some(3),
+            cmd: DevCmd::Ping {
+                addr: "localhost".into(),
+            },
+        };
+
+        for codec in [
+            MessageCodec::Json,
+            MessageCodec::MsgPack,
+            MessageCodec::Cbor,
+        ] {
+            let bytes = codec.encode(&msg).unwrap();
+            let ct = MessageCodec::from_content_type(Some(codec.content_type())).unwrap();
+            assert_eq!(ct, codec);
+            let res: DevMsg = ct.decode(&bytes).unwrap();
+            assert_eq!(res, msg);
+        }
+    }
+
+    #[test]
+    fn from_content_type_success() {
+        let c = MessageCodec::from_content_type(None).unwrap();
+        assert_eq!(c, MessageCodec::Json);
+        let c = MessageCodec::from_content_type(Some("application/json; charset=utf-8")).unwrap();
+        assert_eq!(c, MessageCodec::Json);
+        let c = MessageCodec::from_content_type(Some("application/x-msgpack")).unwrap();
+        assert_eq!(c, MessageCodec::MsgPack);
+        assert!(MessageCodec::from_content_type(Some("text/plain")).is_err());
+    }
+}
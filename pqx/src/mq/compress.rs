@@ -0,0 +1,199 @@
+//! file: compress.rs
+//! author: Jacob Xie
+//! date: 2023/07/15 19:40:33 Saturday
+//! brief: payload compression, marked by `content_encoding`
+
+use std::borrow::Cow;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use amqprs::BasicProperties;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+use crate::error::PqxResult;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+pub const CONTENT_ENCODING_GZIP: &str = "gzip";
+pub const CONTENT_ENCODING_ZSTD: &str = "zstd";
+
+// default level of zstd
+const ZSTD_LEVEL: i32 = 3;
+
+// default limit of a decompressed content, against a small message inflating to exhaust memory
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+static MAX_DECOMPRESSED: AtomicUsize = AtomicUsize::new(MAX_DECOMPRESSED_SIZE);
+
+// limit (bytes) of the contents decompressed by this process, see `Compression::decompress`
+pub fn set_max_decompressed_size(max: usize) {
+    MAX_DECOMPRESSED.store(max, Ordering::Relaxed);
+}
+
+pub fn max_decompressed_size() -> usize {
+    MAX_DECOMPRESSED.load(Ordering::Relaxed)
+}
+
+// ================================================================================================
+// Compression
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => CONTENT_ENCODING_GZIP,
+            Compression::Zstd => CONTENT_ENCODING_ZSTD,
+        }
+    }
+
+    // `None` if not compressed
+    pub fn from_content_encoding(content_encoding: Option<&str>) -> PqxResult<Option<Self>> {
+        let ce = content_encoding
+            .map(|ce| ce.trim().to_ascii_lowercase())
+            .unwrap_or_default();
+
+        match ce.as_str() {
+            "" | "identity" => Ok(None),
+            CONTENT_ENCODING_GZIP => Ok(Some(Compression::Gzip)),
+            CONTENT_ENCODING_ZSTD => Ok(Some(Compression::Zstd)),
+            _ => Err("unsupported content_encoding".into()),
+        }
+    }
+
+    pub fn compress(&self, bytes: &[u8]) -> PqxResult<Vec<u8>> {
+        match self {
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                Ok(encoder.finish()?)
+            }
+            Compression::Zstd => Ok(zstd::encode_all(bytes, ZSTD_LEVEL)?),
+        }
+    }
+
+    // fails if the decompressed content exceeds `max_decompressed_size`
+    pub fn decompress(&self, bytes: &[u8]) -> PqxResult<Vec<u8>> {
+        self.decompress_with_limit(bytes, max_decompressed_size())
+    }
+
+    // fails if the decompressed content exceeds `max` bytes, without reading past it
+    pub fn decompress_with_limit(&self, bytes: &[u8], max: usize) -> PqxResult<Vec<u8>> {
+        let limit = u64::try_from(max).unwrap_or(u64::MAX).saturating_add(1);
+        let mut res = Vec::new();
+        match self {
+            Compression::Gzip => GzDecoder::new(bytes).take(limit).read_to_end(&mut res)?,
+            Compression::Zstd => zstd::Decoder::new(bytes)?
+                .take(limit)
+                .read_to_end(&mut res)?,
+        };
+        if res.len() > max {
+            return Err("decompressed content exceeds the max size".into());
+        }
+
+        Ok(res)
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            CONTENT_ENCODING_GZIP => Ok(Compression::Gzip),
+            CONTENT_ENCODING_ZSTD => Ok(Compression::Zstd),
+            _ => Err("compression: gzip/zstd"),
+        }
+    }
+}
+
+// ================================================================================================
+// helpers
+//
+// A compressed message keeps its `content_encoding` all the way (retry, dead letter, replay), and
+// it is only decompressed for decoding.
+// ================================================================================================
+
+// decompress a delivery by its `content_encoding`
+pub fn decompress_content<'a>(
+    props: &BasicProperties,
+    content: &'a [u8],
+) -> PqxResult<Cow<'a, [u8]>> {
+    match Compression::from_content_encoding(props.content_encoding().map(String::as_str))? {
+        Some(c) => Ok(Cow::Owned(c.decompress(content)?)),
+        None => Ok(Cow::Borrowed(content)),
+    }
+}
+
+// compress a (re-encoded) content by the `content_encoding` of `props`
+pub fn compress_content(props: &BasicProperties, content: Vec<u8>) -> PqxResult<Vec<u8>> {
+    match Compression::from_content_encoding(props.content_encoding().map(String::as_str))? {
+        Some(c) => c.compress(&content),
+        None => Ok(content),
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_compress {
+    use super::*;
+
+    #[test]
+    fn round_trip_success() {
+        let raw = "print('hello world')\n".repeat(100).into_bytes();
+
+        for c in [Compression::Gzip, Compression::Zstd] {
+            let compressed = c.compress(&raw).unwrap();
+            assert!(compressed.len() < raw.len());
+
+            let props = BasicProperties::default()
+                .with_content_encoding(c.content_encoding())
+                .finish();
+            let res = decompress_content(&props, &compressed).unwrap();
+            assert_eq!(res.as_ref(), raw.as_slice());
+        }
+    }
+
+    #[test]
+    fn decompress_limit_failure() {
+        let raw = vec![0u8; 1024 * 1024];
+
+        for c in [Compression::Gzip, Compression::Zstd] {
+            let compressed = c.compress(&raw).unwrap();
+
+            let res = c.decompress_with_limit(&compressed, raw.len()).unwrap();
+            assert_eq!(res.len(), raw.len());
+            assert!(c.decompress_with_limit(&compressed, raw.len() - 1).is_err());
+            assert!(c.decompress_with_limit(&compressed, 1024).is_err());
+        }
+    }
+
+    #[test]
+    fn identity_success() {
+        let raw = b"{}".to_vec();
+        let props = BasicProperties::default();
+
+        let res = decompress_content(&props, &raw).unwrap();
+        assert!(matches!(res, Cow::Borrowed(_)));
+        let res = compress_content(&props, raw.clone()).unwrap();
+        assert_eq!(res, raw);
+
+        let props = BasicProperties::default()
+            .with_content_encoding("br")
+            .finish();
+        assert!(decompress_content(&props, &raw).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+//! file: confirm.rs
+//! author: Jacob Xie
+//! date: 2023/07/20 20:52:19 Thursday
+//! brief: publisher confirms, surface messages rejected by the broker
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use amqprs::callbacks::ChannelCallback;
+use amqprs::channel::{Channel, ConfirmSelectArguments};
+use amqprs::{Ack, BasicProperties, Cancel, CloseChannel, Nack, Return};
+use async_trait::async_trait;
+use tokio::sync::oneshot;
+
+use crate::error::PqxResult;
+
+// ================================================================================================
+// PublishConfirm
+//
+// In confirm mode, the broker acks each message published on the channel once it has been routed
+// (and persisted), or nacks it if it cannot be enqueued, e.g. rejected by a queue which is full
+// under `x-overflow: reject-publish`. Delivery tags count from 1 in the order of publishing.
+//
+// An unroutable message published with `mandatory` is returned (`basic.return`) right before
+// being acked. A return carries no delivery tag, hence it is taken for the oldest pending publish
+// not returned yet, which is exact as long as each publish is confirmed before the next one (e.g.
+// `Shovel`).
+//
+// A channel holds a single callback, hence registering confirms replaces any callback registered
+// before.
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Confirmation {
+    Ack,
+    Nack,
+    Returned,
+}
+
+struct Pending {
+    tx: oneshot::Sender<Confirmation>,
+    returned: bool,
+}
+
+#[derive(Default)]
+struct ConfirmInner {
+    last_tag: u64,
+    pending: BTreeMap<u64, Pending>,
+}
+
+#[derive(Clone, Default)]
+pub struct PublishConfirm {
+    inner: Arc<Mutex<ConfirmInner>>,
+    // keeps delivery tags in the same order as publishes
+    publishing: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl PublishConfirm {
+    // put the channel in confirm mode
+    pub async fn select(channel: &Channel) -> PqxResult<Self> {
+        let confirm = Self::default();
+        channel
+            .register_callback(ConfirmCallback(confirm.clone()))
+            .await?;
+        channel
+            .confirm_select(ConfirmSelectArguments::default())
+            .await?;
+
+        Ok(confirm)
+    }
+
+    // number of publishes waiting for their confirms
+    pub fn pending(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    // publish by `f`, and wait until the broker confirms
+    pub async fn publish<F, Fut>(&self, f: F) -> PqxResult<()>
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = PqxResult<()>>,
+    {
+        let rx = {
+            let _guard = self.publishing.lock().await;
+            let (tag, rx) = self.register();
+            if let Err(e) = f().await {
+                self.unregister(tag);
+                return Err(e);
+            }
+            rx
+        };
+
+        match rx.await {
+            Ok(Confirmation::Ack) => Ok(()),
+            Ok(Confirmation::Nack) => Err("message is rejected by the broker (nack)".into()),
+            Ok(Confirmation::Returned) => {
+                Err("message is returned by the broker (unroutable)".into())
+            }
+            Err(_) => Err("channel is closed before the message is confirmed".into()),
+        }
+    }
+
+    fn register(&self) -> (u64, oneshot::Receiver<Confirmation>) {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_tag += 1;
+        let tag = inner.last_tag;
+        inner.pending.insert(
+            tag,
+            Pending {
+                tx,
+                returned: false,
+            },
+        );
+
+        (tag, rx)
+    }
+
+    // the message is not sent, hence its tag is not counted by the broker
+    fn unregister(&self, tag: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.remove(&tag);
+        if inner.last_tag == tag {
+            inner.last_tag -= 1;
+        }
+    }
+
+    pub(crate) fn settle(&self, tag: u64, multiple: bool, ack: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        let tags = if multiple {
+            inner.pending.range(..=tag).map(|(t, _)| *t).collect()
+        } else {
+            vec![tag]
+        };
+        for t in tags {
+            if let Some(p) = inner.pending.remove(&t) {
+                let c = match (ack, p.returned) {
+                    (false, _) => Confirmation::Nack,
+                    (true, true) => Confirmation::Returned,
+                    (true, false) => Confirmation::Ack,
+                };
+                let _ = p.tx.send(c);
+            }
+        }
+    }
+
+    // a `mandatory` message is returned, then acked
+    pub(crate) fn returned(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(p) = inner.pending.values_mut().find(|p| !p.returned) {
+            p.returned = true;
+        }
+    }
+
+    // pending publishes fail with the closed channel
+    fn fail_all(&self) {
+        self.inner.lock().unwrap().pending.clear();
+    }
+}
+
+// ================================================================================================
+// ConfirmCallback
+// ================================================================================================
+
+struct ConfirmCallback(PublishConfirm);
+
+#[async_trait]
+impl ChannelCallback for ConfirmCallback {
+    async fn close(
+        &mut self,
+        _channel: &Channel,
+        _close: CloseChannel,
+    ) -> Result<(), amqprs::error::Error> {
+        self.0.fail_all();
+        Ok(())
+    }
+
+    async fn cancel(
+        &mut self,
+        _channel: &Channel,
+        _cancel: Cancel,
+    ) -> Result<(), amqprs::error::Error> {
+        Ok(())
+    }
+
+    async fn flow(
+        &mut self,
+        _channel: &Channel,
+        active: bool,
+    ) -> Result<bool, amqprs::error::Error> {
+        Ok(active)
+    }
+
+    async fn publish_ack(&mut self, _channel: &Channel, ack: Ack) {
+        self.0.settle(ack.delivery_tag(), ack.mutiple(), true);
+    }
+
+    async fn publish_nack(&mut self, _channel: &Channel, nack: Nack) {
+        self.0.settle(nack.delivery_tag(), nack.multiple(), false);
+    }
+
+    async fn publish_return(
+        &mut self,
+        _channel: &Channel,
+        _ret: Return,
+        _basic_properties: BasicProperties,
+        _content: Vec<u8>,
+    ) {
+        self.0.returned();
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_confirm {
+    use super::*;
+
+    #[tokio::test]
+    async fn settle_success() {
+        let confirm = PublishConfirm::default();
+        let (t1, rx1) = confirm.register();
+        let (t2, rx2) = confirm.register();
+        let (t3, rx3) = confirm.register();
+        assert_eq!((t1, t2, t3), (1, 2, 3));
+
+        // ack 1 & 2 at once, nack 3
+        confirm.settle(2, true, true);
+        confirm.settle(3, false, false);
+        assert_eq!(rx1.await.unwrap(), Confirmation::Ack);
+        assert_eq!(rx2.await.unwrap(), Confirmation::Ack);
+        assert_eq!(rx3.await.unwrap(), Confirmation::Nack);
+        assert_eq!(confirm.pending(), 0);
+
+        // the oldest pending one is returned, then acked with the next
+        let (_, rx4) = confirm.register();
+        let (_, rx5) = confirm.register();
+        confirm.returned();
+        confirm.settle(5, true, true);
+        assert_eq!(rx4.await.unwrap(), Confirmation::Returned);
+        assert_eq!(rx5.await.unwrap(), Confirmation::Ack);
+
+        // an unsent message gives its tag back
+        let (t6, _) = confirm.register();
+        confirm.unregister(t6);
+        let (t7, rx7) = confirm.register();
+        assert_eq!(t7, 6);
+        confirm.fail_all();
+        assert!(rx7.await.is_err());
+    }
+}
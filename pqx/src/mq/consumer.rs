@@ -6,19 +6,24 @@
 use std::fmt::Debug;
 use std::marker::PhantomData;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use amqprs::channel::{BasicAckArguments, BasicNackArguments, Channel};
+use amqprs::channel::{BasicAckArguments, BasicNackArguments, BasicPublishArguments, Channel};
 use amqprs::consumer::AsyncConsumer;
 use amqprs::{BasicProperties, Deliver};
 use async_trait::async_trait;
 use serde::de::DeserializeOwned;
 use serde_json::Value;
 use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 use tokio::time::timeout;
+use tracing::{warn, Instrument, Span};
 
-use super::{FieldTableViewer, Retry};
+use super::{
+    around, decode_content, dedup_key, delivery_span, ChannelOps, Codec, DedupStore, Delivery,
+    DeliveryContext, DeliveryHandler, FieldTableBuilder, Hop, HopOutcome, MessageCodec, Middleware,
+    PqxHeaders, Requeue, Retry, Tombstones, TraceContext,
+};
 use crate::error::{PqxError, PqxResult};
 
 // ================================================================================================
@@ -34,11 +39,11 @@ impl AsyncConsumer for PqxDefaultConsumer {
         &mut self,
         channel: &Channel,
         deliver: Deliver,
-        _basic_properties: BasicProperties,
+        basic_properties: BasicProperties,
         content: Vec<u8>,
     ) {
-        // deserialize from subscriber msg
-        match serde_json::from_slice::<Value>(&content) {
+        // deserialize from subscriber msg, by its `content_encoding` & `content_type`
+        match decode_content::<Value>(&basic_properties, &content) {
             Ok(m) => println!("msg: {:?}", m),
             Err(e) => println!("err: {:?}", e),
         };
@@ -75,6 +80,22 @@ impl<R: Send + Debug> ConsumerResult<R> {
     }
 }
 
+// ================================================================================================
+// Outcome
+//
+// The final outcome of a message, replied to `reply_to` (if requested by the publisher) by the
+// `ConsumerWrapper`. `Failure` is not an outcome, since the message is requeued and reprocessed,
+// unless no requeue is left (`Exhausted`).
+// ================================================================================================
+
+#[derive(Debug)]
+pub enum Outcome<'a, M, R> {
+    Success(&'a M, &'a R),
+    Exhausted(&'a M, Option<&'a R>), // asked for retry (or requeue), but none left
+    Discarded(Option<&'a M>, &'a PqxError),
+    Cancelled(&'a M), // tombstoned before or while being consumed
+}
+
 // ================================================================================================
 // Consumer
 //
@@ -96,10 +117,44 @@ where
     // Err(_) => handle_discard
     async fn consume(&mut self, message: &M) -> PqxResult<ConsumerResult<R>>;
 
-    // [IMPORTANT] no need to override this method unless [`retry`] has been used in code,
+    // [IMPORTANT] override this method if [`retry`] has been used in code. By default there is
+    // no `Retry`, and a message asked for retry is discarded (if DLX is set, then goes to there)
+    #[allow(unused_variables)]
+    fn gen_retry(&self, message: &M) -> Option<Retry> {
+        None
+    }
+
+    // override this method to bound requeues of `Failure`. By default a failed message is
+    // requeued without limit
     #[allow(unused_variables)]
-    fn gen_retry(&self, message: &M) -> Retry {
-        unimplemented!()
+    fn gen_requeue(&self, message: &M) -> Option<Requeue> {
+        None
+    }
+
+    // override this method to answer requests carrying `reply_to` (RPC). By default there is
+    // no reply
+    #[allow(unused_variables)]
+    fn gen_reply(&self, outcome: Outcome<'_, M, R>) -> Option<Value> {
+        None
+    }
+
+    // override this method to deduplicate deliveries by `message_id` (idempotent consumption).
+    // By default every delivery is consumed
+    fn dedup_store(&self) -> Option<Arc<dyn DedupStore>> {
+        None
+    }
+
+    // override this method to consume scheduled messages (`x-run-at`) only when due, re-delaying
+    // the early ones. By default a message is consumed whenever it is delivered
+    fn hop(&self) -> Option<&Hop> {
+        None
+    }
+
+    // override this method to cancel tasks by their `message_id`. A tombstoned delivery is acked
+    // without being consumed, and one tombstoned while being consumed is taken as cancelled
+    // unless it succeeds. By default no task is cancelled
+    fn tombstones(&self) -> Option<&Tombstones> {
+        None
     }
 
     // ================================================================================================
@@ -130,11 +185,21 @@ where
     async fn discard_callback(&mut self, error: PqxError) -> PqxResult<()> {
         Ok(())
     }
+
+    #[allow(unused_variables)]
+    async fn skip_callback(&mut self, message: &M, message_id: &str) -> PqxResult<()> {
+        Ok(())
+    }
+
+    #[allow(unused_variables)]
+    async fn cancel_callback(&mut self, message: &M, task_id: &str) -> PqxResult<()> {
+        Ok(())
+    }
 }
 
 // ================================================================================================
 // ConsumerWrapper<T>
-// A generic type holder for impl AsyncConsumer
+// A generic type holder for impl DeliveryHandler
 //
 // User doesn't need this struct, since it is a holder of user biz logic.
 //
@@ -142,24 +207,35 @@ where
 // Since we can call `consume` multiple times (accepting messages from different queue),
 // and each time's calling is actually cloning a consumer `T`, then multiple senders of a channel
 // is required, which indicates one-fail-all-fail.
+//
+// About `in_flight`:
+// Number of deliveries being handled (from receiving to ack/nack and reply), shared by all the
+// clones, so that a subscriber can wait for them to finish before shutting down.
+//
+// About `middlewares`:
+// Layers around `consume`, see `Middleware`. Shared by all the clones, hence added before
+// consuming starts.
 // ================================================================================================
 
 #[derive(Clone)]
 pub(crate) struct ConsumerWrapper<M, R, C>
 where
-    M: Send + DeserializeOwned,
+    M: Send + Sync + DeserializeOwned + 'static,
     R: Send + Clone + Debug + 'static,
     C: Send + Consumer<M, R>,
 {
     consumer: C,
+    queue: Option<String>, // the queue being consumed, replied as `x-replier`
     consume_signal_sender: Sender<bool>,
     consume_signal_receiver: Arc<Mutex<Receiver<bool>>>,
+    in_flight: Arc<watch::Sender<usize>>,
+    middlewares: Vec<Arc<dyn Middleware<M, R>>>,
     _msg_type: PhantomData<(M, R)>,
 }
 
 impl<M, R, C> ConsumerWrapper<M, R, C>
 where
-    M: Send + DeserializeOwned,
+    M: Send + Sync + DeserializeOwned + 'static,
     R: Send + Clone + Debug + 'static,
     C: Send + Consumer<M, R>,
 {
@@ -168,16 +244,28 @@ where
 
         Self {
             consumer,
+            queue: None,
             consume_signal_sender: tx,
             consume_signal_receiver: Arc::new(Mutex::new(rx)),
+            in_flight: Arc::new(watch::channel(0).0),
+            middlewares: vec![],
             _msg_type: PhantomData,
         }
     }
 
+    // the first layer added is the outermost one
+    pub fn add_middleware(&mut self, middleware: Arc<dyn Middleware<M, R>>) {
+        self.middlewares.push(middleware);
+    }
+
     pub fn consumer(&mut self) -> &mut C {
         &mut self.consumer
     }
 
+    pub fn set_queue(&mut self, queue: &str) {
+        self.queue = Some(queue.to_owned());
+    }
+
     pub async fn signal_consume(&self, signal: bool) {
         let _ = self.consume_signal_sender.send(signal).await;
     }
@@ -186,12 +274,26 @@ where
         self.consume_signal_receiver.clone()
     }
 
+    pub fn in_flight(&self) -> usize {
+        *self.in_flight.borrow()
+    }
+
+    // wait until no delivery is being handled
+    pub async fn wait_idle(&self) {
+        let mut rx = self.in_flight.subscribe();
+        let _ = rx.wait_for(|n| *n == 0).await;
+    }
+
     // ================================================================================================
     // private methods
     // ================================================================================================
 
-    async fn ack<'a>(&'a mut self, channel: &'a Channel, deliver: Deliver) -> PqxResult<()> {
-        let args = BasicAckArguments::new(deliver.delivery_tag(), false);
+    async fn ack<'a>(
+        &'a mut self,
+        channel: &'a dyn ChannelOps,
+        deliver: Delivery,
+    ) -> PqxResult<()> {
+        let args = BasicAckArguments::new(deliver.delivery_tag, false);
         channel.basic_ack(args).await?;
 
         Ok(())
@@ -199,11 +301,11 @@ where
 
     async fn nack<'a>(
         &'a mut self,
-        channel: &'a Channel,
-        deliver: Deliver,
+        channel: &'a dyn ChannelOps,
+        deliver: Delivery,
         requeue: bool,
     ) -> PqxResult<()> {
-        let args = BasicNackArguments::new(deliver.delivery_tag(), false, requeue);
+        let args = BasicNackArguments::new(deliver.delivery_tag, false, requeue);
         channel.basic_nack(args).await?;
 
         Ok(())
@@ -213,88 +315,336 @@ where
 
     async fn handle_success(
         &mut self,
-        channel: &Channel,
-        deliver: Deliver,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
         message: &M,
         result: R,
     ) {
         // if callback failed, signal consume to false
-        if let Err(_) = self.consumer().success_callback(message, result).await {
+        if self
+            .consumer()
+            .success_callback(message, result)
+            .await
+            .is_err()
+        {
             self.signal_consume(false).await;
             return;
         };
-        if let Err(_) = self.ack(channel, deliver).await {
+        if self.ack(channel, deliver).await.is_err() {
             self.signal_consume(false).await;
         };
     }
 
+    // returns `true` if the message has been dead-lettered, since no requeue is left
     async fn handle_requeue(
         &mut self,
-        channel: &Channel,
-        deliver: Deliver,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
+        props: BasicProperties,
+        content: Vec<u8>,
         message: &M,
         result: R,
-    ) {
+    ) -> bool {
+        let reason = format!("{:?}", result);
         // if callback failed, signal consume to false
-        if let Err(_) = self.consumer().requeue_callback(message, result).await {
+        if self
+            .consumer()
+            .requeue_callback(message, result)
+            .await
+            .is_err()
+        {
             self.signal_consume(false).await;
-            return;
+            return false;
         };
-        if let Err(_) = self.nack(channel, deliver, true).await {
-            self.signal_consume(false).await;
+        let queue = self.queue.clone();
+        let res = match self.consumer().gen_requeue(message) {
+            Some(requeue) => {
+                requeue
+                    .requeue(channel, deliver, props, content, queue.as_deref(), &reason)
+                    .await
+            }
+            None => self.nack(channel, deliver, true).await.map(|_| true),
         };
+        match res {
+            Ok(requeued) => !requeued,
+            Err(_) => {
+                self.signal_consume(false).await;
+                false
+            }
+        }
     }
 
     async fn handle_retry(
         &mut self,
-        channel: &Channel,
-        deliver: Deliver,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
         props: BasicProperties,
         content: Vec<u8>,
         message: &M,
         result: Option<R>,
+    ) -> bool {
+        if self
+            .consumer()
+            .retry_callback(message, result)
+            .await
+            .is_err()
+        {
+            self.signal_consume(false).await;
+            return false;
+        }
+        let res = match self.consumer().gen_retry(message) {
+            Some(retry) => {
+                let queue = self.queue.as_deref();
+                retry.retry(channel, deliver, props, content, queue).await
+            }
+            None => self.nack(channel, deliver, false).await.map(|_| false),
+        };
+        match res {
+            Ok(retried) => !retried,
+            Err(_) => {
+                self.signal_consume(false).await;
+                false
+            }
+        }
+    }
+
+    async fn handle_discard(
+        &mut self,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
+        error: PqxError,
     ) {
-        if let Err(_) = self.consumer().retry_callback(message, result).await {
+        // if callback failed, signal consume to false
+        if self.consumer().discard_callback(error).await.is_err() {
             self.signal_consume(false).await;
             return;
-        }
-        let retry = self.consumer().gen_retry(message);
-        if let Err(_) = retry.retry(channel, deliver, props, content).await {
+        };
+        if self.nack(channel, deliver, false).await.is_err() {
             self.signal_consume(false).await;
         };
     }
 
-    async fn handle_discard(&mut self, channel: &Channel, deliver: Deliver, error: PqxError) {
+    async fn handle_cancel(
+        &mut self,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
+        message: &M,
+        task_id: &str,
+    ) {
         // if callback failed, signal consume to false
-        if let Err(_) = self.consumer().discard_callback(error).await {
+        if self
+            .consumer()
+            .cancel_callback(message, task_id)
+            .await
+            .is_err()
+        {
             self.signal_consume(false).await;
             return;
         };
-        if let Err(_) = self.nack(channel, deliver, false).await {
+        if self.ack(channel, deliver).await.is_err() {
             self.signal_consume(false).await;
         };
     }
+
+    // the task id (`message_id`) if it has been cancelled
+    fn cancelled(&self, props: &BasicProperties) -> Option<String> {
+        let task_id = props.message_id()?;
+        let tombstones = self.consumer.tombstones()?;
+
+        tombstones.is_cancelled(task_id).then(|| task_id.clone())
+    }
+
+    fn dedup(&self, props: &BasicProperties) -> Option<(Arc<dyn DedupStore>, String)> {
+        let message_id = props.message_id()?;
+        let store = self.consumer.dedup_store()?;
+
+        Some((store, dedup_key(self.queue.as_deref(), message_id)))
+    }
+
+    // whether the message has been consumed or is being consumed by another consumer, and record
+    // it as skipped if so. Otherwise it is claimed by this consumer
+    async fn check_duplicate(
+        &mut self,
+        props: &BasicProperties,
+        message: &M,
+        redelivered: bool,
+    ) -> PqxResult<bool> {
+        let (store, key) = match self.dedup(props) {
+            Some(d) => d,
+            None => return Ok(false),
+        };
+        if store.claim(&key, redelivered).await? {
+            return Ok(false);
+        }
+        store.skip(&key).await?;
+        let message_id = props.message_id().cloned().unwrap_or_default();
+        self.consumer().skip_callback(message, &message_id).await?;
+
+        Ok(true)
+    }
+
+    // a re-delayed copy reaching another queue than the one it hopped from
+    fn foreign_hop(&self, headers: &PqxHeaders) -> bool {
+        match (&headers.x_hop_queue, &self.queue) {
+            (Some(h), Some(q)) => h != q,
+            _ => false,
+        }
+    }
+
+    // the message is handled anyway, an unmarked claim is taken over on a redelivery
+    async fn mark_consumed(&mut self, props: &BasicProperties) {
+        if let Some((store, key)) = self.dedup(props) {
+            if let Err(e) = store.mark(&key).await {
+                warn!("dedup mark of {} failed: {}", key, e);
+            }
+        }
+    }
+
+    // released before being sent back, so that the next attempt can claim it. Returns `false`
+    // if the store fails, the message should then be redelivered (and taken over) instead
+    async fn release_claim(&mut self, props: &BasicProperties) -> bool {
+        match self.dedup(props) {
+            Some((store, key)) => store.release(&key).await.is_ok(),
+            None => true,
+        }
+    }
+
+    // back to the queue on a transient failure (e.g. a store is unavailable), consuming stops
+    // only if the channel fails
+    async fn requeue(&mut self, channel: &dyn ChannelOps, deliver: Delivery) {
+        Span::current().record("outcome", "requeue");
+        if self.nack(channel, deliver, true).await.is_err() {
+            self.signal_consume(false).await;
+        }
+    }
+
+    // publish the reply to `reply_to` by the default exchange, with the same `correlation_id`, and
+    // encoded as the request (JSON if the request cannot be decoded)
+    async fn reply(
+        &mut self,
+        channel: &dyn ChannelOps,
+        props: &BasicProperties,
+        reply: Option<Value>,
+    ) {
+        let (reply_to, reply) = match (props.reply_to(), reply) {
+            (Some(rt), Some(r)) => (rt, r),
+            _ => return,
+        };
+        let codec = MessageCodec::from_content_type(props.content_type().map(String::as_str))
+            .unwrap_or_default();
+        let content = match codec.encode(&reply) {
+            Ok(c) => c,
+            Err(_) => return,
+        };
+
+        let mut reply_props = BasicProperties::default();
+        reply_props.with_content_type(codec.content_type());
+        if let Some(cid) = props.correlation_id() {
+            reply_props.with_correlation_id(cid);
+        }
+        if let Some(q) = &self.queue {
+            let mut headers = FieldTableBuilder::new();
+            headers.x_replier(q);
+            reply_props.with_headers(headers.finish());
+        }
+        if let Some(ctx) = TraceContext::current() {
+            ctx.inject(&mut reply_props);
+        }
+
+        let args = BasicPublishArguments::new("", reply_to);
+        if channel
+            .basic_publish(reply_props, content, args)
+            .await
+            .is_err()
+        {
+            self.signal_consume(false).await;
+        }
+    }
 }
 
 #[async_trait]
-impl<M, R, C> AsyncConsumer for ConsumerWrapper<M, R, C>
+impl<M, R, C> DeliveryHandler for ConsumerWrapper<M, R, C>
 where
-    M: Send + Sync + DeserializeOwned,
+    M: Send + Sync + DeserializeOwned + 'static,
     R: Send + Sync + Clone + Debug,
     C: Send + Sync + Consumer<M, R>,
 {
-    async fn consume(
+    async fn handle(
         &mut self,
-        channel: &Channel,
-        deliver: Deliver,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
         basic_properties: BasicProperties,
         content: Vec<u8>,
     ) {
-        // deserialize from subscriber msg. simply discard message if cannot be deserialize
-        let msg = match serde_json::from_slice::<M>(&content) {
+        self.in_flight.send_modify(|n| *n += 1);
+        // a span per delivery, as the current trace context while being handled
+        let (ctx, span) = delivery_span(&basic_properties, self.queue.as_deref());
+        let fut = self.handle_delivery(channel, deliver, basic_properties, content);
+        ctx.scope(fut.instrument(span)).await;
+        self.in_flight.send_modify(|n| *n -= 1);
+    }
+}
+
+impl<M, R, C> ConsumerWrapper<M, R, C>
+where
+    M: Send + Sync + DeserializeOwned + 'static,
+    R: Send + Sync + Clone + Debug,
+    C: Send + Sync + Consumer<M, R>,
+{
+    async fn handle_delivery(
+        &mut self,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
+        mut basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let received_at = Instant::now();
+        let headers = PqxHeaders::from_props(&basic_properties).unwrap_or_default();
+
+        // dropped, since the copy of the queue it hopped from is consumed
+        if self.foreign_hop(&headers) {
+            Span::current().record("outcome", "skip");
+            if self.ack(channel, deliver).await.is_err() {
+                self.signal_consume(false).await;
+            }
+            return;
+        }
+
+        // a scheduled message is re-delayed until due, or dropped if cancelled
+        if let (Some(_), Some(hop)) = (headers.x_run_at, self.consumer.hop()) {
+            let queue = self.queue.as_deref();
+            let props = basic_properties.clone();
+            match hop
+                .hop(channel, &deliver, props, content.clone(), queue)
+                .await
+            {
+                Ok(HopOutcome::Due) => {}
+                Ok(HopOutcome::Hopped) => {
+                    Span::current().record("outcome", "hop");
+                    return;
+                }
+                Ok(HopOutcome::Cancelled) => {
+                    Span::current().record("outcome", "cancel");
+                    return;
+                }
+                // e.g. the schedule store is unavailable, hop it again on redelivery
+                Err(_) => {
+                    self.requeue(channel, deliver).await;
+                    return;
+                }
+            }
+        }
+
+        // deserialize from subscriber msg by its `content_encoding` & `content_type`. simply
+        // discard message if cannot be deserialize. `content` is kept as delivered for retry, so
+        // that a retried message stays compressed
+        let msg = match decode_content::<M>(&basic_properties, &content) {
             Ok(m) => m,
             Err(e) => {
-                self.handle_discard(channel, deliver, e.into()).await;
+                Span::current().record("outcome", "discard");
+                let reply = self.consumer().gen_reply(Outcome::Discarded(None, &e));
+                self.handle_discard(channel, deliver, e).await;
+                self.reply(channel, &basic_properties, reply).await;
                 return;
             }
         };
@@ -302,39 +652,148 @@ where
         // handle props
         self.consumer().handle_props(&basic_properties);
 
-        let consume_fut = self.consumer().consume(&msg);
+        // a message which has been consumed is acked without being consumed again
+        let redelivered = deliver.redelivered;
+        match self
+            .check_duplicate(&basic_properties, &msg, redelivered)
+            .await
+        {
+            Ok(false) => {}
+            Ok(true) => {
+                Span::current().record("outcome", "skip");
+                if self.ack(channel, deliver).await.is_err() {
+                    self.signal_consume(false).await;
+                }
+                return;
+            }
+            // e.g. the dedup store is unavailable, checked again on redelivery
+            Err(_) => {
+                self.requeue(channel, deliver).await;
+                return;
+            }
+        }
+
+        // a cancelled task is acked without being consumed
+        if let Some(task_id) = self.cancelled(&basic_properties) {
+            Span::current().record("outcome", "cancel");
+            let reply = self.consumer().gen_reply(Outcome::Cancelled(&msg));
+            self.mark_consumed(&basic_properties).await;
+            self.handle_cancel(channel, deliver, &msg, &task_id).await;
+            self.reply(channel, &basic_properties, reply).await;
+            return;
+        }
 
         // get consume_timeout from headers
-        let opt_dur = basic_properties
-            .headers()
-            .and_then(|ft| FieldTableViewer::from(ft).x_consume_ttl().ok())
-            .and_then(|ct| {
-                if ct > 0 {
-                    Some(Duration::from_millis(ct.try_into().unwrap()))
+        let opt_dur = headers
+            .x_consume_ttl
+            .and_then(|ct| u64::try_from(ct).ok())
+            .filter(|ct| *ct > 0)
+            .map(Duration::from_millis);
+
+        // consume inside of the middleware layers
+        let layers = self.middlewares.clone();
+        let ctx = DeliveryContext {
+            queue: self.queue.as_deref(),
+            properties: &basic_properties,
+            delivery_tag: deliver.delivery_tag,
+            redelivered: deliver.redelivered,
+            received_at,
+        };
+        let consumer = &mut self.consumer;
+        let fut_res = around(&layers, &ctx, &msg, || async {
+            let consume_fut = consumer.consume(&msg);
+
+            // if duration exists, then running `consume_fut` in a timeout environment
+            match opt_dur {
+                // if timeout, then retry
+                Some(dur) => match timeout(dur, consume_fut).await {
+                    Ok(r) => r,
+                    Err(_) => Ok(ConsumerResult::retry(None)),
+                },
+                None => consume_fut.await,
+            }
+        })
+        .await;
+
+        // according to biz logic determine whether responds Ack/Requeue/Discard,
+        // and reply the final outcome if requested
+        let props = basic_properties.clone();
+        let cancelled = match &fut_res {
+            Ok(ConsumerResult::Success(_)) => None,
+            _ => self.cancelled(&props),
+        };
+        let reply = match fut_res {
+            // cancelled while being consumed, e.g. its process is killed
+            _ if cancelled.is_some() => {
+                Span::current().record("outcome", "cancel");
+                let reply = self.consumer().gen_reply(Outcome::Cancelled(&msg));
+                self.mark_consumed(&props).await;
+                let task_id = cancelled.unwrap_or_default();
+                self.handle_cancel(channel, deliver, &msg, &task_id).await;
+                reply
+            }
+            Ok(ConsumerResult::Success(r)) => {
+                Span::current().record("outcome", "success");
+                let reply = self.consumer().gen_reply(Outcome::Success(&msg, &r));
+                self.mark_consumed(&props).await;
+                self.handle_success(channel, deliver, &msg, r).await;
+                reply
+            }
+            Ok(ConsumerResult::Retry(r)) => {
+                Span::current().record("outcome", "retry");
+                // the next attempt is a child of this delivery
+                if let Some(ctx) = TraceContext::current() {
+                    ctx.inject(&mut basic_properties);
+                }
+                let rr = r.clone();
+                if !self.release_claim(&props).await {
+                    self.requeue(channel, deliver).await;
+                    return;
+                }
+                let exhausted = self
+                    .handle_retry(channel, deliver, basic_properties, content, &msg, r)
+                    .await;
+                if exhausted {
+                    self.mark_consumed(&props).await;
+                    self.consumer()
+                        .gen_reply(Outcome::Exhausted(&msg, rr.as_ref()))
                 } else {
                     None
                 }
-            });
-
-        // if duration exists, then running `consume_fut` in a timeout environment
-        let fut_res = match opt_dur {
-            // if timeout, then retry
-            Some(dur) => match timeout(dur, consume_fut).await {
-                Ok(r) => r,
-                Err(_) => Ok(ConsumerResult::retry(None)),
-            },
-            None => consume_fut.await,
-        };
-
-        // according to biz logic determine whether responds Ack/Requeue/Discard
-        match fut_res {
-            Ok(ConsumerResult::Success(r)) => self.handle_success(channel, deliver, &msg, r).await,
-            Ok(ConsumerResult::Retry(r)) => {
-                self.handle_retry(channel, deliver, basic_properties, content, &msg, r)
-                    .await
             }
-            Ok(ConsumerResult::Failure(r)) => self.handle_requeue(channel, deliver, &msg, r).await,
-            Err(e) => self.handle_discard(channel, deliver, e).await,
+            Ok(ConsumerResult::Failure(r)) => {
+                Span::current().record("outcome", "requeue");
+                // the next attempt is a child of this delivery
+                if let Some(ctx) = TraceContext::current() {
+                    ctx.inject(&mut basic_properties);
+                }
+                let rr = r.clone();
+                if !self.release_claim(&props).await {
+                    self.requeue(channel, deliver).await;
+                    return;
+                }
+                let dead = self
+                    .handle_requeue(channel, deliver, basic_properties, content, &msg, r)
+                    .await;
+                if dead {
+                    Span::current().record("outcome", "dead_letter");
+                    self.mark_consumed(&props).await;
+                    self.consumer()
+                        .gen_reply(Outcome::Exhausted(&msg, Some(&rr)))
+                } else {
+                    None
+                }
+            }
+            Err(e) => {
+                Span::current().record("outcome", "discard");
+                let reply = self
+                    .consumer()
+                    .gen_reply(Outcome::Discarded(Some(&msg), &e));
+                self.mark_consumed(&props).await;
+                self.handle_discard(channel, deliver, e).await;
+                reply
+            }
         };
+        self.reply(channel, &props, reply).await;
     }
 }
@@ -0,0 +1,264 @@
+//! file: dedup.rs
+//! author: Jacob Xie
+//! date: 2023/07/14 20:18:52 Friday
+//! brief: idempotent consumption, deduplicate deliveries by `message_id`
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::error::PqxResult;
+
+// ================================================================================================
+// DedupStore
+//
+// A message's key is claimed before it is consumed, so that only one of the consumers sharing the
+// store consumes it. The claim is marked as consumed once its final outcome is known (success,
+// retries exhausted or discarded), and before it is acked; or released if the message is sent back
+// for another attempt (retry or requeue). Hence a redelivery (e.g. the ack is lost by a connection
+// drop) or a duplicate publish is acked without being consumed again, whereas a retried or requeued
+// message is still consumed.
+// ================================================================================================
+
+#[async_trait]
+pub trait DedupStore: Debug + Send + Sync {
+    // claim a message before consuming it, false if it has been consumed or is being consumed (and
+    // not expired). `takeover` claims a message left being consumed, which is redelivered since
+    // its former consumer is gone
+    async fn claim(&self, key: &str, takeover: bool) -> PqxResult<bool>;
+
+    // record a consumed message
+    async fn mark(&self, key: &str) -> PqxResult<()>;
+
+    // release the claim of a message sent back for another attempt, no-op if it is consumed
+    async fn release(&self, key: &str) -> PqxResult<()>;
+
+    // record a duplicate that has been acked without being consumed
+    async fn skip(&self, key: &str) -> PqxResult<()>;
+}
+
+// dedup key of a message consumed from `queue`, since a message fanned out to multiple queues
+// shares the same `message_id`
+pub fn dedup_key(queue: Option<&str>, message_id: &str) -> String {
+    match queue {
+        Some(q) => format!("{}/{}", q, message_id),
+        None => message_id.to_owned(),
+    }
+}
+
+pub fn gen_message_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+// ================================================================================================
+// MemoryDedupStore
+//
+// In-memory LRU of at most `capacity` keys, each of them expires after `ttl`. Only works for the
+// consumers of a single process.
+// ================================================================================================
+
+#[derive(Debug)]
+struct DedupEntry {
+    tick: u64, // the last time of being used, order of `lru`
+    marked_at: Instant,
+    consumed: bool, // false while being claimed
+    skipped: u32,
+}
+
+#[derive(Debug, Default)]
+struct MemoryDedupInner {
+    tick: u64,
+    entries: HashMap<String, DedupEntry>,
+    lru: BTreeMap<u64, String>,
+}
+
+impl MemoryDedupInner {
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        if let Some(e) = self.entries.get_mut(key) {
+            self.lru.remove(&e.tick);
+            e.tick = self.tick;
+            self.lru.insert(self.tick, key.to_owned());
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(e) = self.entries.remove(key) {
+            self.lru.remove(&e.tick);
+        }
+    }
+
+    fn insert(&mut self, key: &str, consumed: bool, capacity: usize) {
+        self.remove(key);
+        self.tick += 1;
+        let tick = self.tick;
+        self.entries.insert(
+            key.to_owned(),
+            DedupEntry {
+                tick,
+                marked_at: Instant::now(),
+                consumed,
+                skipped: 0,
+            },
+        );
+        self.lru.insert(tick, key.to_owned());
+
+        // evict the least recently used
+        while self.entries.len() > capacity {
+            let oldest = match self.lru.iter().next() {
+                Some((_, k)) => k.clone(),
+                None => break,
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MemoryDedupStore {
+    capacity: usize,
+    ttl: Duration,
+    inner: Mutex<MemoryDedupInner>,
+}
+
+impl MemoryDedupStore {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            inner: Mutex::new(MemoryDedupInner::default()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // number of skipped duplicates of a key
+    pub fn skipped(&self, key: &str) -> u32 {
+        let inner = self.inner.lock().unwrap();
+        inner.entries.get(key).map(|e| e.skipped).unwrap_or(0)
+    }
+}
+
+#[async_trait]
+impl DedupStore for MemoryDedupStore {
+    async fn claim(&self, key: &str, takeover: bool) -> PqxResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        let claimed = match inner.entries.get(key) {
+            Some(e) if e.marked_at.elapsed() <= self.ttl => !e.consumed && takeover,
+            _ => true,
+        };
+        if !claimed {
+            inner.touch(key);
+            return Ok(false);
+        }
+        inner.insert(key, false, self.capacity);
+
+        Ok(true)
+    }
+
+    async fn mark(&self, key: &str) -> PqxResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        // keep the duplicates skipped while being consumed
+        match inner.entries.get_mut(key) {
+            Some(e) => {
+                e.marked_at = Instant::now();
+                e.consumed = true;
+                inner.touch(key);
+            }
+            None => inner.insert(key, true, self.capacity),
+        }
+
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> PqxResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.entries.get(key).map(|e| !e.consumed).unwrap_or(false) {
+            inner.remove(key);
+        }
+
+        Ok(())
+    }
+
+    async fn skip(&self, key: &str) -> PqxResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(e) = inner.entries.get_mut(key) {
+            e.skipped += 1;
+        }
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_dedup {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_dedup_success() {
+        let store = MemoryDedupStore::new(2, Duration::from_secs(60));
+
+        assert!(store.claim("a", false).await.unwrap());
+        store.mark("a").await.unwrap();
+        assert!(!store.claim("a", true).await.unwrap());
+        store.skip("a").await.unwrap();
+        assert_eq!(store.skipped("a"), 1);
+
+        // "a" is used recently, "b" is evicted by "c"
+        store.mark("b").await.unwrap();
+        assert!(!store.claim("a", false).await.unwrap());
+        store.mark("c").await.unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(!store.claim("a", false).await.unwrap());
+        assert!(!store.claim("c", false).await.unwrap());
+        assert!(store.claim("b", false).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn memory_dedup_claim_success() {
+        let store = MemoryDedupStore::new(10, Duration::from_secs(60));
+
+        // a message being consumed is claimed once, unless redelivered
+        assert!(store.claim("a", false).await.unwrap());
+        assert!(!store.claim("a", false).await.unwrap());
+        assert!(store.claim("a", true).await.unwrap());
+
+        // released for another attempt
+        store.release("a").await.unwrap();
+        assert!(store.claim("a", false).await.unwrap());
+
+        // a consumed message is not released
+        store.mark("a").await.unwrap();
+        store.release("a").await.unwrap();
+        assert!(!store.claim("a", false).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn memory_dedup_expired_success() {
+        let store = MemoryDedupStore::new(10, Duration::from_millis(10));
+
+        store.mark("a").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(store.claim("a", false).await.unwrap());
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn dedup_key_success() {
+        assert_eq!(dedup_key(Some("h1"), "abc"), "h1/abc");
+        assert_eq!(dedup_key(None, "abc"), "abc");
+    }
+}
@@ -0,0 +1,369 @@
+//! file: delay.rs
+//! author: Jacob Xie
+//! date: 2023/07/27 20:45:12 Thursday
+//! brief: scheduled messages, re-delayed in hops beyond the limit of `x-delay`
+
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use amqprs::channel::{BasicAckArguments, BasicPublishArguments};
+use amqprs::BasicProperties;
+use async_trait::async_trait;
+
+use super::{ChannelOps, Delivery, PqxHeaders};
+use crate::error::PqxResult;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+// milliseconds, `x-delay` of the delayed exchange is a `i32` (about 24.8 days)
+pub const MAX_DELAY: i64 = i32::MAX as i64;
+
+// milliseconds, a message arriving this early is taken as due
+const DUE_TOLERANCE: i64 = 1000;
+
+pub fn epoch_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| i64::try_from(d.as_millis()).unwrap_or(i64::MAX))
+        .unwrap_or(0)
+}
+
+// `x-delay` of the next hop towards `run_at`, `None` if due. Both are in epoch milliseconds
+pub fn next_hop(run_at: i64, now: i64, max_delay: i64) -> Option<i32> {
+    let remaining = run_at.saturating_sub(now);
+    if remaining <= DUE_TOLERANCE {
+        return None;
+    }
+
+    i32::try_from(remaining.min(max_delay).min(MAX_DELAY)).ok()
+}
+
+// ================================================================================================
+// ScheduleStore
+//
+// Pending scheduled messages, recorded by whom publishes them. A cancelled one is dropped when
+// it arrives, no matter whether it is due.
+// ================================================================================================
+
+#[async_trait]
+pub trait ScheduleStore: Debug + Send + Sync {
+    async fn cancelled(&self, message_id: &str) -> PqxResult<bool>;
+}
+
+// ================================================================================================
+// Hop
+//
+// A scheduled message (`x-run-at`) is published to the delayed exchange with `x-delay` of the
+// first hop. Arriving before it is due, it is published to the delayed exchange again with the
+// next `x-delay` and acked. The exchange routes a hop as the original message, hence every copy
+// is pinned (`x-hop-queue`) to the queue it hopped from, and the copies reaching other queues are
+// dropped by their consumers.
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopOutcome {
+    Due,       // to be consumed
+    Hopped,    // re-delayed and acked
+    Cancelled, // acked without being consumed
+}
+
+#[derive(Debug, Clone)]
+pub struct Hop {
+    exchange: String,
+    max_delay: i64, // milliseconds
+    store: Option<Arc<dyn ScheduleStore>>,
+}
+
+impl Hop {
+    pub fn new(exchange: impl Into<String>) -> Self {
+        Self {
+            exchange: exchange.into(),
+            max_delay: MAX_DELAY,
+            store: None,
+        }
+    }
+
+    pub fn exchange(&self) -> &str {
+        &self.exchange
+    }
+
+    // shorter hops, capped by `MAX_DELAY`
+    pub fn set_max_delay(&mut self, max_delay: i64) -> &mut Self {
+        self.max_delay = max_delay.clamp(1, MAX_DELAY);
+
+        self
+    }
+
+    // drop cancelled messages, by their `message_id`
+    pub fn set_store(&mut self, store: Arc<dyn ScheduleStore>) -> &mut Self {
+        self.store = Some(store);
+
+        self
+    }
+
+    async fn cancelled(&self, props: &BasicProperties) -> PqxResult<bool> {
+        match (&self.store, props.message_id()) {
+            (Some(s), Some(id)) => s.cancelled(id).await,
+            _ => Ok(false),
+        }
+    }
+
+    /// A message without `x-run-at` is always due. `queue` is where the message is consumed, and
+    /// `props` and `content` are republished as delivered.
+    pub async fn hop(
+        &self,
+        channel: &dyn ChannelOps,
+        deliver: &Delivery,
+        mut props: BasicProperties,
+        content: Vec<u8>,
+        queue: Option<&str>,
+    ) -> PqxResult<HopOutcome> {
+        let mut headers = PqxHeaders::from_props(&props)?;
+        let run_at = match headers.x_run_at {
+            Some(r) => r,
+            None => return Ok(HopOutcome::Due),
+        };
+
+        let outcome = if self.cancelled(&props).await? {
+            HopOutcome::Cancelled
+        } else {
+            match next_hop(run_at, epoch_millis(), self.max_delay) {
+                Some(delay) => {
+                    headers.x_delay = Some(delay);
+                    headers.x_hop_queue = queue.map(str::to_owned).or(headers.x_hop_queue);
+                    headers.apply(&mut props)?;
+                    let args = BasicPublishArguments::new(&self.exchange, &deliver.routing_key);
+                    channel.basic_publish(props, content, args).await?;
+
+                    HopOutcome::Hopped
+                }
+                None => return Ok(HopOutcome::Due),
+            }
+        };
+
+        channel
+            .basic_ack(BasicAckArguments::new(deliver.delivery_tag, false))
+            .await?;
+
+        Ok(outcome)
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_delay {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use amqprs::channel::ExchangeType;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::mq::{Consumer, ConsumerResult, MemoryBroker, Publisher, Subscriber};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct DevMsg {
+        data: String,
+    }
+
+    #[derive(Debug)]
+    struct CancelledStore(&'static str);
+
+    #[async_trait]
+    impl ScheduleStore for CancelledStore {
+        async fn cancelled(&self, message_id: &str) -> PqxResult<bool> {
+            Ok(message_id == self.0)
+        }
+    }
+
+    // unavailable for the first `n` calls
+    #[derive(Debug)]
+    struct FlakyStore(AtomicUsize);
+
+    #[async_trait]
+    impl ScheduleStore for FlakyStore {
+        async fn cancelled(&self, _message_id: &str) -> PqxResult<bool> {
+            match self
+                .0
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err("store unavailable".into()),
+                Err(_) => Ok(false),
+            }
+        }
+    }
+
+    #[derive(Clone)]
+    struct HopConsumer {
+        count: Arc<AtomicUsize>,
+        hop: Hop,
+    }
+
+    #[async_trait]
+    impl Consumer<DevMsg, ()> for HopConsumer {
+        async fn consume(&mut self, _message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+
+            Ok(ConsumerResult::success(()))
+        }
+
+        fn hop(&self) -> Option<&Hop> {
+            Some(&self.hop)
+        }
+    }
+
+    fn scheduled_props(message_id: &str, run_at: i64, delay: Option<i32>) -> BasicProperties {
+        let headers = PqxHeaders {
+            x_run_at: Some(run_at),
+            x_delay: delay,
+            ..Default::default()
+        };
+        let mut props = BasicProperties::default();
+        props.with_message_id(message_id);
+        headers.apply(&mut props).unwrap();
+
+        props
+    }
+
+    #[test]
+    fn next_hop_success() {
+        let now = 1_690_000_000_000;
+        let day = 86_400_000;
+
+        assert_eq!(next_hop(now - 1, now, MAX_DELAY), None);
+        assert_eq!(next_hop(now + DUE_TOLERANCE, now, MAX_DELAY), None);
+        assert_eq!(next_hop(now + 5_000, now, MAX_DELAY), Some(5_000));
+        // 60 days are 3 hops of (at most) 24.8 days
+        assert_eq!(next_hop(now + 60 * day, now, MAX_DELAY), Some(i32::MAX));
+        let now = now + MAX_DELAY * 2;
+        assert_eq!(
+            next_hop(now + 60 * day - MAX_DELAY * 2, now, MAX_DELAY),
+            Some((60 * day - MAX_DELAY * 2) as i32)
+        );
+        // shorter hops
+        assert_eq!(next_hop(now + 5_000, now, 2_000), Some(2_000));
+    }
+
+    #[tokio::test]
+    async fn hop_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        // two queues sharing the routing key
+        broker
+            .declare_delayed_exchange("delayed", &ExchangeType::Direct)
+            .unwrap();
+        broker.declare_and_bind_queue("delayed", "k", "q1").unwrap();
+        broker.declare_and_bind_queue("delayed", "k", "q2").unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut hop = Hop::new("delayed");
+        hop.set_max_delay(1000)
+            .set_store(Arc::new(CancelledStore("cancelled")));
+        for que in ["q1", "q2"] {
+            let consumer = HopConsumer {
+                count: count.clone(),
+                hop: hop.clone(),
+            };
+            let mut subscriber = Subscriber::new(&chan, consumer);
+            subscriber.consume(que).await.unwrap();
+        }
+
+        let publisher = Publisher::new(&chan);
+        let msg = DevMsg {
+            data: "daily".to_string(),
+        };
+        let now = epoch_millis();
+        for props in [
+            scheduled_props("due", now - 1000, None),
+            scheduled_props("cancelled", now + 3_600_000, None),
+            scheduled_props("pending", now + 3_600_000, Some(1000)),
+        ] {
+            publisher
+                .publish_with_props("delayed", "k", msg.clone(), props)
+                .await
+                .unwrap();
+        }
+
+        // due ones are consumed, cancelled ones are dropped
+        assert_eq!(broker.run_until_idle().await.unwrap(), 4);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // each queue re-delays its own copy
+        broker.advance(Duration::from_secs(1));
+        assert_eq!(broker.run_until_idle().await.unwrap(), 2);
+        assert_eq!(broker.message_count("q1") + broker.message_count("q2"), 0);
+
+        // both hops reach both queues, the copies of the other queue are dropped
+        broker.advance(Duration::from_secs(1));
+        assert_eq!(broker.run_until_idle().await.unwrap(), 4);
+        broker.advance(Duration::from_secs(1));
+        assert_eq!(broker.run_until_idle().await.unwrap(), 4);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(chan.unacked(), 0);
+    }
+
+    #[tokio::test]
+    async fn hop_store_failure() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker
+            .declare_delayed_exchange("delayed", &ExchangeType::Direct)
+            .unwrap();
+        broker.declare_and_bind_queue("delayed", "k", "q1").unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut hop = Hop::new("delayed");
+        hop.set_max_delay(1000)
+            .set_store(Arc::new(FlakyStore(AtomicUsize::new(2))));
+        let consumer = HopConsumer {
+            count: count.clone(),
+            hop,
+        };
+        let mut subscriber = Subscriber::new(&chan, consumer);
+        subscriber.consume("q1").await.unwrap();
+
+        let publisher = Publisher::new(&chan);
+        let msg = DevMsg {
+            data: "daily".to_string(),
+        };
+        let now = epoch_millis();
+        publisher
+            .publish_with_props(
+                "delayed",
+                "k",
+                msg.clone(),
+                scheduled_props("pending", now + 3_600_000, None),
+            )
+            .await
+            .unwrap();
+
+        // requeued while the store fails, then hopped
+        assert_eq!(broker.run_until_idle().await.unwrap(), 3);
+        assert_eq!(broker.message_count("q1"), 0);
+        assert_eq!(chan.unacked(), 0);
+
+        // still consuming
+        publisher
+            .publish_with_props(
+                "delayed",
+                "k",
+                msg,
+                scheduled_props("due", now - 1000, None),
+            )
+            .await
+            .unwrap();
+        assert_eq!(broker.run_until_idle().await.unwrap(), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        broker.advance(Duration::from_secs(1));
+        assert_eq!(broker.run_until_idle().await.unwrap(), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
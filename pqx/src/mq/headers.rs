@@ -0,0 +1,774 @@
+//! file: headers.rs
+//! author: Jacob Xie
+//! date: 2023/07/23 15:32:08 Sunday
+//! brief: serde between Rust types and `FieldTable`, and the typed headers of pqx
+
+use std::fmt::Display;
+
+use amqprs::{BasicProperties, FieldArray, FieldName, FieldTable, FieldValue};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::ser;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use super::{MatchType, TraceContext};
+use crate::error::PqxResult;
+
+// ================================================================================================
+// FieldTableError
+// ================================================================================================
+
+#[derive(Error, Debug)]
+#[error("{0}")]
+pub struct FieldTableError(String);
+
+impl ser::Error for FieldTableError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl de::Error for FieldTableError {
+    fn custom<T: Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, FieldTableError>;
+
+fn err<T>(msg: impl Display) -> Result<T> {
+    Err(FieldTableError(msg.to_string()))
+}
+
+fn field_name(key: String) -> Result<FieldName> {
+    let len = key.len();
+    FieldName::try_from(key).or_else(|_| err(format!("field name too long: {} bytes", len)))
+}
+
+// ================================================================================================
+// functions
+//
+// Value mapping (Rust -> AMQP):
+// bool -> t; i8/u8/i16/u16/i32/u32/i64 -> b/B/s/u/I/i/l; u64 -> l (if fits); f32/f64 -> f/d;
+// char/str -> S; bytes -> x; unit & `None` -> V; sequences & tuples -> A; maps & structs -> F;
+// unit variants -> S, other variants -> F of one entry (externally tagged).
+// `None` fields of a struct are omitted rather than set to V. When deserializing, integers of any
+// width are accepted within range, and the timestamp T is read as `u64`.
+// ================================================================================================
+
+pub fn to_field_value<T: Serialize + ?Sized>(value: &T) -> PqxResult<FieldValue> {
+    Ok(value.serialize(FieldValueSerializer)?)
+}
+
+pub fn to_field_table<T: Serialize + ?Sized>(value: &T) -> PqxResult<FieldTable> {
+    match to_field_value(value)? {
+        FieldValue::F(ft) => Ok(ft),
+        _ => Err(FieldTableError("not a map or a struct".to_owned()).into()),
+    }
+}
+
+pub fn from_field_value<T: DeserializeOwned>(value: &FieldValue) -> PqxResult<T> {
+    Ok(T::deserialize(FieldValueDeserializer(value.clone()))?)
+}
+
+pub fn from_field_table<T: DeserializeOwned>(ft: &FieldTable) -> PqxResult<T> {
+    Ok(T::deserialize(FieldValueDeserializer(FieldValue::F(
+        ft.clone(),
+    )))?)
+}
+
+// ================================================================================================
+// Serializer
+// ================================================================================================
+
+struct FieldValueSerializer;
+
+impl Serializer for FieldValueSerializer {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeVariant<SerializeArray>;
+    type SerializeMap = SerializeTable;
+    type SerializeStruct = SerializeTable;
+    type SerializeStructVariant = SerializeVariant<SerializeTable>;
+
+    fn serialize_bool(self, v: bool) -> Result<FieldValue> {
+        Ok(FieldValue::t(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<FieldValue> {
+        Ok(FieldValue::b(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<FieldValue> {
+        Ok(FieldValue::s(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<FieldValue> {
+        Ok(FieldValue::I(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<FieldValue> {
+        Ok(FieldValue::l(v))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<FieldValue> {
+        Ok(FieldValue::B(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<FieldValue> {
+        Ok(FieldValue::u(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<FieldValue> {
+        Ok(FieldValue::i(v))
+    }
+
+    // RabbitMQ has no unsigned 64-bit integer
+    fn serialize_u64(self, v: u64) -> Result<FieldValue> {
+        match i64::try_from(v) {
+            Ok(v) => Ok(FieldValue::l(v)),
+            Err(_) => err(format!("{} is out of the range of i64", v)),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<FieldValue> {
+        Ok(FieldValue::f(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<FieldValue> {
+        Ok(FieldValue::d(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<FieldValue> {
+        Ok(FieldValue::from(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<FieldValue> {
+        Ok(FieldValue::from(v))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<FieldValue> {
+        match v.to_vec().try_into() {
+            Ok(b) => Ok(FieldValue::x(b)),
+            Err(_) => err("byte array too long"),
+        }
+    }
+
+    fn serialize_none(self) -> Result<FieldValue> {
+        Ok(FieldValue::V)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<FieldValue> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<FieldValue> {
+        Ok(FieldValue::V)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<FieldValue> {
+        Ok(FieldValue::V)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<FieldValue> {
+        Ok(FieldValue::from(variant))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<FieldValue> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<FieldValue> {
+        let mut ft = FieldTable::new();
+        ft.insert(field_name(variant.to_owned())?, value.serialize(self)?);
+
+        Ok(FieldValue::F(ft))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray> {
+        Ok(SerializeArray(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<SerializeArray> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeArray>> {
+        Ok(SerializeVariant(variant, self.serialize_seq(Some(len))?))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeTable> {
+        Ok(SerializeTable(FieldTable::new(), None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeTable> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeTable>> {
+        Ok(SerializeVariant(variant, self.serialize_map(Some(len))?))
+    }
+}
+
+struct SerializeArray(Vec<FieldValue>);
+
+impl SerializeArray {
+    fn finish(self) -> Result<FieldValue> {
+        match FieldArray::try_from(self.0) {
+            Ok(a) => Ok(FieldValue::A(a)),
+            Err(_) => err("field array too long"),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.0.push(value.serialize(FieldValueSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<FieldValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<FieldValue> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<FieldValue> {
+        self.finish()
+    }
+}
+
+// the table, and the key waiting for its value
+struct SerializeTable(FieldTable, Option<FieldName>);
+
+impl ser::SerializeMap for SerializeTable {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        let key = match key.serialize(FieldValueSerializer)? {
+            FieldValue::S(s) => String::from(s),
+            _ => return err("field name is not a string"),
+        };
+        self.1 = Some(field_name(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = match self.1.take() {
+            Some(k) => k,
+            None => return err("value without a field name"),
+        };
+        self.0.insert(key, value.serialize(FieldValueSerializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<FieldValue> {
+        Ok(FieldValue::F(self.0))
+    }
+}
+
+impl ser::SerializeStruct for SerializeTable {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        match value.serialize(FieldValueSerializer)? {
+            FieldValue::V => {}
+            v => {
+                self.0.insert(field_name(key.to_owned())?, v);
+            }
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<FieldValue> {
+        Ok(FieldValue::F(self.0))
+    }
+}
+
+// `{variant: content}`
+struct SerializeVariant<S>(&'static str, S);
+
+impl<S> SerializeVariant<S> {
+    fn finish(variant: &'static str, content: FieldValue) -> Result<FieldValue> {
+        let mut ft = FieldTable::new();
+        ft.insert(field_name(variant.to_owned())?, content);
+
+        Ok(FieldValue::F(ft))
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeArray> {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(&mut self.1, value)
+    }
+
+    fn end(self) -> Result<FieldValue> {
+        Self::finish(self.0, self.1.finish()?)
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeTable> {
+    type Ok = FieldValue;
+    type Error = FieldTableError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeStruct::serialize_field(&mut self.1, key, value)
+    }
+
+    fn end(self) -> Result<FieldValue> {
+        Self::finish(self.0, FieldValue::F(self.1 .0))
+    }
+}
+
+// ================================================================================================
+// Deserializer
+// ================================================================================================
+
+struct FieldValueDeserializer(FieldValue);
+
+fn table_entries(ft: FieldTable) -> Vec<(String, FieldValue)> {
+    ft.as_ref()
+        .iter()
+        .map(|(k, v)| (k.as_ref().clone(), v.clone()))
+        .collect()
+}
+
+impl<'de> Deserializer<'de> for FieldValueDeserializer {
+    type Error = FieldTableError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            FieldValue::t(v) => visitor.visit_bool(v),
+            FieldValue::b(v) => visitor.visit_i8(v),
+            FieldValue::B(v) => visitor.visit_u8(v),
+            FieldValue::s(v) => visitor.visit_i16(v),
+            FieldValue::u(v) => visitor.visit_u16(v),
+            FieldValue::I(v) => visitor.visit_i32(v),
+            FieldValue::i(v) => visitor.visit_u32(v),
+            FieldValue::l(v) => visitor.visit_i64(v),
+            FieldValue::f(v) => visitor.visit_f32(v),
+            FieldValue::d(v) => visitor.visit_f64(v),
+            FieldValue::D(v) => err(format!("{} is not supported", v)),
+            FieldValue::S(v) => visitor.visit_string(String::from(v)),
+            FieldValue::A(v) => visitor.visit_seq(SeqAccessor(Vec::from(v).into_iter())),
+            FieldValue::T(v) => visitor.visit_u64(v),
+            FieldValue::F(v) => visitor.visit_map(MapAccessor {
+                entries: table_entries(v).into_iter(),
+                value: None,
+            }),
+            FieldValue::V => visitor.visit_unit(),
+            FieldValue::x(v) => visitor.visit_byte_buf(Vec::from(v)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            FieldValue::V => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.0 {
+            FieldValue::S(s) => visitor.visit_enum(String::from(s).into_deserializer()),
+            FieldValue::F(ft) => {
+                let mut entries = table_entries(ft);
+                match (entries.pop(), entries.is_empty()) {
+                    (Some((variant, value)), true) => {
+                        visitor.visit_enum(EnumAccessor { variant, value })
+                    }
+                    _ => err("an enum table should have exactly one entry"),
+                }
+            }
+            v => err(format!("{} is not an enum", v)),
+        }
+    }
+
+    // unknown fields (e.g. headers of others) are skipped whatever they are
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_unit()
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier
+    }
+}
+
+struct SeqAccessor(std::vec::IntoIter<FieldValue>);
+
+impl<'de> de::SeqAccess<'de> for SeqAccessor {
+    type Error = FieldTableError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        self.0
+            .next()
+            .map(|v| seed.deserialize(FieldValueDeserializer(v)))
+            .transpose()
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+struct MapAccessor {
+    entries: std::vec::IntoIter<(String, FieldValue)>,
+    value: Option<FieldValue>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccessor {
+    type Error = FieldTableError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        match self.entries.next() {
+            Some((k, v)) => {
+                self.value = Some(v);
+                seed.deserialize(k.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.value.take() {
+            Some(v) => seed.deserialize(FieldValueDeserializer(v)),
+            None => err("value without a field name"),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+struct EnumAccessor {
+    variant: String,
+    value: FieldValue,
+}
+
+impl<'de> de::EnumAccess<'de> for EnumAccessor {
+    type Error = FieldTableError;
+    type Variant = FieldValueDeserializer;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, FieldValueDeserializer)> {
+        let variant = seed.deserialize(self.variant.into_deserializer())?;
+        Ok((variant, FieldValueDeserializer(self.value)))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for FieldValueDeserializer {
+    type Error = FieldTableError;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.0 {
+            FieldValue::V => Ok(()),
+            v => err(format!("{} is not a unit variant", v)),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        self.deserialize_any(visitor)
+    }
+}
+
+// ================================================================================================
+// PqxHeaders
+//
+// The message headers set & read by pqx, as one typed struct. Reading ignores the others (e.g. the
+// recipient kv of a headers exchange), and writing keeps them, replacing only the headers which
+// are set. Equivalent to the per-header methods of `FieldTableBuilder` & `FieldTableViewer`.
+// ================================================================================================
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PqxHeaders {
+    pub x_delay: Option<i32>,    // milliseconds, of the delayed exchange
+    pub x_retries: Option<i16>,  // retries left
+    pub x_attempts: Option<i16>, // retries already performed
+    pub x_requeues: Option<i16>, // requeues already performed
+    pub x_dead_reason: Option<String>,
+    pub x_dead_queue: Option<String>,
+    pub x_message_ttl: Option<i64>, // milliseconds, waiting timeout
+    pub x_consume_ttl: Option<i64>, // milliseconds, consuming timeout
+    pub x_wait: Option<i64>,        // seconds, the delay tier of a wait queue
+    pub x_match: Option<MatchType>,
+    pub x_replier: Option<String>,
+    pub x_run_at: Option<i64>, // epoch milliseconds, when a scheduled message is due
+    pub x_hop_queue: Option<String>,
+    pub traceparent: Option<TraceContext>,
+    // set by quorum queues, never written
+    #[serde(skip_serializing)]
+    pub x_delivery_count: Option<i64>,
+}
+
+impl PqxHeaders {
+    // empty if the message has no headers
+    pub fn from_props(props: &BasicProperties) -> PqxResult<Self> {
+        match props.headers() {
+            Some(h) => Self::try_from(h),
+            None => Ok(Self::default()),
+        }
+    }
+
+    // write into `ft`, replacing the same headers and keeping the others
+    pub fn merge_into(&self, ft: &mut FieldTable) -> PqxResult<()> {
+        for (k, v) in to_field_table(self)?.as_ref() {
+            ft.insert(k.clone(), v.clone());
+        }
+
+        Ok(())
+    }
+
+    // write into the headers of `props`
+    pub fn apply(&self, props: &mut BasicProperties) -> PqxResult<()> {
+        let mut headers = props.headers().cloned().unwrap_or_default();
+        self.merge_into(&mut headers)?;
+        props.with_headers(headers);
+
+        Ok(())
+    }
+}
+
+impl TryFrom<&FieldTable> for PqxHeaders {
+    type Error = crate::error::PqxError;
+
+    fn try_from(ft: &FieldTable) -> std::result::Result<Self, Self::Error> {
+        from_field_table(ft)
+    }
+}
+
+impl TryFrom<&PqxHeaders> for FieldTable {
+    type Error = crate::error::PqxError;
+
+    fn try_from(h: &PqxHeaders) -> std::result::Result<Self, Self::Error> {
+        to_field_table(h)
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_headers {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::mq::{FieldTableBuilder, FieldTableViewer, X_RETRIES};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    #[serde(tag = "type", rename_all = "snake_case")]
+    enum Tagged {
+        Fixed { delay: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum External {
+        Unit,
+        Newtype(String),
+        Tuple(i32, bool),
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Dev {
+        name: String,
+        flag: bool,
+        small: u8,
+        big: u64,
+        ratio: f64,
+        tags: Vec<String>,
+        kv: HashMap<String, i32>,
+        nested: Option<Box<Dev>>,
+        tagged: Tagged,
+        external: Vec<External>,
+    }
+
+    #[test]
+    fn round_trip_success() {
+        let dev = Dev {
+            name: "a".to_string(),
+            flag: true,
+            small: 3,
+            big: 1 << 40,
+            ratio: 0.5,
+            tags: vec!["x".to_string(), "y".to_string()],
+            kv: HashMap::from([("k".to_string(), -1)]),
+            nested: Some(Box::new(Dev {
+                name: "b".to_string(),
+                flag: false,
+                small: 0,
+                big: 0,
+                ratio: 0.0,
+                tags: vec![],
+                kv: HashMap::new(),
+                nested: None,
+                tagged: Tagged::Fixed { delay: 1 },
+                external: vec![],
+            })),
+            tagged: Tagged::Fixed { delay: 5 },
+            external: vec![
+                External::Unit,
+                External::Newtype("n".to_string()),
+                External::Tuple(1, false),
+            ],
+        };
+
+        let ft = to_field_table(&dev).unwrap();
+        assert!(matches!(
+            ft.get(&FieldName::try_from("tags").unwrap()),
+            Some(FieldValue::A(_))
+        ));
+        assert!(matches!(
+            ft.get(&FieldName::try_from("small").unwrap()),
+            Some(FieldValue::B(3))
+        ));
+        let back: Dev = from_field_table(&ft).unwrap();
+        assert_eq!(back, dev);
+
+        // errors rather than panics
+        assert!(to_field_value(&u64::MAX).is_err());
+        assert!(to_field_table(&1).is_err());
+        let long_key = HashMap::from([("k".repeat(256), 1)]);
+        assert!(to_field_table(&long_key).is_err());
+        let v = to_field_value(&300).unwrap();
+        assert!(from_field_value::<u8>(&v).is_err());
+        assert_eq!(from_field_value::<u16>(&v).unwrap(), 300);
+    }
+
+    #[test]
+    fn pqx_headers_success() {
+        // written by the builder, among headers of others
+        let mut ftb = FieldTableBuilder::new();
+        ftb.x_retries(3)
+            .x_consume_ttl(5_000)
+            .x_message_ttl(60_000)
+            .x_common_pair("unique_key", "h1")
+            .unwrap();
+        let mut ft = ftb.finish();
+
+        let mut headers = PqxHeaders::try_from(&ft).unwrap();
+        assert_eq!(headers.x_retries, Some(3));
+        assert_eq!(headers.x_consume_ttl, Some(5_000));
+        assert_eq!(headers.x_message_ttl, Some(60_000));
+        assert_eq!(headers.x_attempts, None);
+
+        // replaces its own headers only
+        headers.x_retries = Some(2);
+        headers.x_attempts = Some(1);
+        headers.traceparent = Some(TraceContext::new_root());
+        headers.merge_into(&mut ft).unwrap();
+        let viewer = FieldTableViewer::new(&ft);
+        assert_eq!(viewer.x_retries().unwrap(), 2);
+        assert_eq!(viewer.x_attempts().unwrap(), 1);
+        assert_eq!(viewer.x_consume_ttl().unwrap(), 5_000);
+        assert_eq!(viewer.x_common_pair("unique_key").unwrap(), "h1");
+        assert_eq!(viewer.traceparent().ok(), headers.traceparent);
+        assert_eq!(PqxHeaders::try_from(&ft).unwrap(), headers);
+
+        // a header of a wrong type is an error
+        ft.insert(X_RETRIES.clone(), FieldValue::from("3"));
+        assert!(PqxHeaders::try_from(&ft).is_err());
+    }
+}
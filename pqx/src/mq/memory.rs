@@ -0,0 +1,1497 @@
+//! file: memory.rs
+//! author: Jacob Xie
+//! date: 2023/07/25 21:17:43 Tuesday
+//! brief: in-memory broker, for unit tests of consumers without RabbitMQ
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use amqprs::channel::{
+    BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicGetArguments,
+    BasicNackArguments, BasicPublishArguments, BasicQosArguments, ExchangeType, QueueBindArguments,
+};
+use amqprs::{BasicProperties, FieldArray, FieldName, FieldTable, FieldValue};
+use async_trait::async_trait;
+use once_cell::sync::Lazy;
+
+use super::{
+    topic_matches, ChannelOps, Delivery, DeliveryHandler, FieldTableBuilder, FieldTableViewer,
+    MatchType, PublishConfirm, EXCHANGE_TYPE_DELAYED, X_DEAD_LETTER_EXCHANGE, X_DEATH,
+    X_DELAYED_TYPE, X_MATCH,
+};
+use crate::error::PqxResult;
+
+static X_DEAD_LETTER_ROUTING_KEY: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-dead-letter-routing-key").unwrap());
+
+// a message requeued forever (e.g. `Failure` without `Requeue`) never lets the broker be idle
+const MAX_DELIVERIES: usize = 100_000;
+
+// ================================================================================================
+// MemoryMessage
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct MemoryMessage {
+    pub exchange: String,
+    pub routing_key: String,
+    pub props: BasicProperties,
+    pub content: Vec<u8>,
+    pub redelivered: bool,
+    ttl: Option<u64>,        // per-message `expiration`, dropped once dead-lettered
+    expires_at: Option<u64>, // milliseconds of the clock
+}
+
+impl MemoryMessage {
+    fn new(props: BasicProperties, content: Vec<u8>, args: &BasicPublishArguments) -> Self {
+        let ttl = props.expiration().and_then(|e| e.parse().ok());
+
+        Self {
+            exchange: args.exchange.clone(),
+            routing_key: args.routing_key.clone(),
+            props,
+            content,
+            redelivered: false,
+            ttl,
+            expires_at: None,
+        }
+    }
+}
+
+// ================================================================================================
+// exchanges & queues
+// ================================================================================================
+
+struct MemoryBinding {
+    queue: String,
+    routing_key: String,
+    arguments: FieldTable,
+}
+
+struct MemoryExchange {
+    exchange_type: ExchangeType, // `x-delayed-type` of a delayed exchange
+    delayed: bool,
+    bindings: Vec<MemoryBinding>,
+}
+
+#[derive(Default)]
+struct MemoryQueue {
+    messages: VecDeque<MemoryMessage>,
+    message_ttl: Option<u64>,
+    dead_letter: Option<(String, Option<String>)>, // (exchange, routing key)
+}
+
+impl MemoryQueue {
+    fn new(args: &FieldTable) -> Self {
+        let get_str = |k: &FieldName| match args.get(k) {
+            Some(FieldValue::S(s)) => Some(s.as_ref().clone()),
+            _ => None,
+        };
+        let message_ttl = FieldTableViewer::new(args)
+            .x_message_ttl()
+            .ok()
+            .and_then(|t| u64::try_from(t).ok());
+        let dead_letter =
+            get_str(&X_DEAD_LETTER_EXCHANGE).map(|x| (x, get_str(&X_DEAD_LETTER_ROUTING_KEY)));
+
+        Self {
+            messages: VecDeque::new(),
+            message_ttl,
+            dead_letter,
+        }
+    }
+}
+
+struct MemoryConsumer {
+    consumer_tag: String,
+    queue: String,
+    channel: u64,
+    no_ack: bool,
+    handler: Arc<tokio::sync::Mutex<Box<dyn DeliveryHandler>>>,
+}
+
+struct Unacked {
+    consumer_tag: String,
+    queue: String,
+    message: MemoryMessage,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    last_delivery_tag: u64,
+    unacked: BTreeMap<u64, Unacked>,
+    prefetch: u16,
+    confirm: Option<(PublishConfirm, u64)>, // the last confirm tag
+}
+
+struct DelayedMessage {
+    at: u64,
+    exchange: String,
+    message: MemoryMessage,
+}
+
+// ================================================================================================
+// routing
+// ================================================================================================
+
+// `x-match` of the binding (`all` by default); keys beginning with "x-" are ignored unless
+// `any-with-x` or `all-with-x`
+fn headers_match(binding: &FieldTable, headers: Option<&FieldTable>) -> bool {
+    let match_type = FieldTableViewer::new(binding)
+        .x_match()
+        .unwrap_or(MatchType::All);
+    let with_x = matches!(match_type, MatchType::AnyWithX | MatchType::AllWithX);
+    let empty = FieldTable::new();
+    let headers = headers.unwrap_or(&empty);
+
+    let mut pairs = binding
+        .as_ref()
+        .iter()
+        .filter(|(k, _)| **k != *X_MATCH)
+        .filter(|(k, _)| with_x || !k.to_string().starts_with("x-"))
+        .map(|(k, v)| headers.get(k) == Some(v));
+
+    match match_type {
+        MatchType::All | MatchType::AllWithX => pairs.all(|m| m),
+        MatchType::Any | MatchType::AnyWithX => pairs.any(|m| m),
+    }
+}
+
+// ================================================================================================
+// MemoryBroker
+//
+// A single-process imitation of RabbitMQ, for testing `Consumer`s fast and deterministically:
+// 1. exchanges: the default one, direct, fanout, topic, headers (by `x-match`) and delayed (by
+//    `x-delay`, routing as its `x-delayed-type`);
+// 2. queues: FIFO, expired by `x-message-ttl` or the message's `expiration`, and dead-lettered to
+//    `x-dead-letter-exchange` (by `x-dead-letter-routing-key` or the original routing key) with an
+//    `x-death` header;
+// 3. deliveries: ack/nack/requeue, prefetch, unacked deliveries are requeued (redelivered) by
+//    `basic_recover` or closing their channel.
+//
+// Nothing runs in the background: time is a virtual clock moved by `advance` (releasing delayed
+// messages and expiring queued ones), and deliveries are dispatched to consumers by
+// `run_until_idle`, one at a time and round-robin over the consumers.
+// ================================================================================================
+
+#[derive(Default)]
+struct BrokerInner {
+    clock: u64, // milliseconds
+    exchanges: HashMap<String, MemoryExchange>,
+    queues: HashMap<String, MemoryQueue>,
+    consumers: Vec<MemoryConsumer>,
+    channels: HashMap<u64, ChannelState>,
+    delayed: Vec<DelayedMessage>,
+    last_channel: u64,
+    last_consumer: u64,
+    cursor: usize, // the next consumer to deliver to
+}
+
+impl BrokerInner {
+    // whether the message reaches a queue, a delayed one is taken as routed
+    fn route(&mut self, exchange: &str, mut message: MemoryMessage) -> PqxResult<bool> {
+        message.redelivered = false;
+        if exchange.is_empty() {
+            if !self.queues.contains_key(&message.routing_key) {
+                return Ok(false);
+            }
+            let que = message.routing_key.clone();
+            self.enqueue(&que, message);
+            return Ok(true);
+        }
+
+        let x = self
+            .exchanges
+            .get(exchange)
+            .ok_or("exchange is not declared in the memory broker")?;
+
+        if x.delayed {
+            let delay = message
+                .props
+                .headers()
+                .and_then(|h| FieldTableViewer::new(h).x_delay().ok())
+                .and_then(|d| u64::try_from(d).ok())
+                .unwrap_or(0);
+            if delay > 0 {
+                let at = self.clock + delay;
+                self.delayed.push(DelayedMessage {
+                    at,
+                    exchange: exchange.to_owned(),
+                    message,
+                });
+                return Ok(true);
+            }
+        }
+
+        Ok(self.route_now(exchange, message))
+    }
+
+    // by the bindings, ignoring `x-delay`
+    fn route_now(&mut self, exchange: &str, message: MemoryMessage) -> bool {
+        let x = match self.exchanges.get(exchange) {
+            Some(x) => x,
+            None => return false,
+        };
+        let mut queues = vec![];
+        for b in x.bindings.iter() {
+            let matched = match x.exchange_type {
+                ExchangeType::Direct => b.routing_key == message.routing_key,
+                ExchangeType::Fanout => true,
+                ExchangeType::Topic => topic_matches(&b.routing_key, &message.routing_key),
+                ExchangeType::Headers => headers_match(&b.arguments, message.props.headers()),
+                _ => false,
+            };
+            if matched && !queues.contains(&b.queue) {
+                queues.push(b.queue.clone());
+            }
+        }
+        let routed = !queues.is_empty();
+        for que in queues {
+            self.enqueue(&que, message.clone());
+        }
+
+        routed
+    }
+
+    fn enqueue(&mut self, que: &str, mut message: MemoryMessage) {
+        let clock = self.clock;
+        if let Some(q) = self.queues.get_mut(que) {
+            let ttl = match (q.message_ttl, message.ttl) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            };
+            message.expires_at = ttl.map(|t| clock + t);
+            q.messages.push_back(message);
+        }
+    }
+
+    // back to the head of its queue
+    fn requeue(&mut self, unacked: Unacked) {
+        if let Some(q) = self.queues.get_mut(&unacked.queue) {
+            let mut message = unacked.message;
+            message.redelivered = true;
+            q.messages.push_front(message);
+        }
+    }
+
+    // reason: rejected/expired
+    fn dead_letter(&mut self, que: &str, reason: &str, mut message: MemoryMessage) {
+        let (exchange, routing_key) = match self.queues.get(que).and_then(|q| q.dead_letter.clone())
+        {
+            Some((x, rk)) => (x, rk.unwrap_or_else(|| message.routing_key.clone())),
+            None => return,
+        };
+
+        let mut headers = message.props.headers().cloned().unwrap_or_default();
+        let mut deaths = match headers.get(&X_DEATH) {
+            Some(FieldValue::A(a)) => Vec::<FieldValue>::from(a.clone()),
+            _ => vec![],
+        };
+        let same = |v: &FieldValue| match v {
+            FieldValue::F(ft) => {
+                ft.get(&FieldName::try_from("queue").unwrap())
+                    == Some(&FieldValue::from(que.to_owned()))
+                    && ft.get(&FieldName::try_from("reason").unwrap())
+                        == Some(&FieldValue::from(reason.to_owned()))
+            }
+            _ => false,
+        };
+        // the same queue & reason counts up, and becomes the most recent one
+        let count = match deaths.iter().position(same) {
+            Some(i) => match deaths.remove(i) {
+                FieldValue::F(ft) => match ft.get(&FieldName::try_from("count").unwrap()) {
+                    Some(FieldValue::l(c)) => c + 1,
+                    _ => 1,
+                },
+                _ => 1,
+            },
+            None => 1,
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let routing_keys = vec![FieldValue::from(message.routing_key.clone())];
+        let mut death = FieldTable::new();
+        for (k, v) in [
+            ("queue", que.to_owned()),
+            ("reason", reason.to_owned()),
+            ("exchange", message.exchange.clone()),
+        ] {
+            death.insert(FieldName::try_from(k).unwrap(), FieldValue::from(v));
+        }
+        death.insert(FieldName::try_from("count").unwrap(), FieldValue::l(count));
+        death.insert(FieldName::try_from("time").unwrap(), FieldValue::T(time));
+        death.insert(
+            FieldName::try_from("routing-keys").unwrap(),
+            FieldValue::A(FieldArray::try_from(routing_keys).unwrap()),
+        );
+        deaths.insert(0, FieldValue::F(death));
+        headers.insert(
+            X_DEATH.clone(),
+            FieldValue::A(FieldArray::try_from(deaths).unwrap()),
+        );
+        message.props.with_headers(headers);
+
+        message.exchange = exchange.clone();
+        message.routing_key = routing_key;
+        message.ttl = None;
+        // dropped silently if the DLX doesn't exist, as RabbitMQ does
+        let _ = self.route(&exchange, message);
+    }
+
+    fn expire(&mut self) {
+        let clock = self.clock;
+        let mut expired = vec![];
+        for (name, q) in self.queues.iter_mut() {
+            let (dead, alive) = q
+                .messages
+                .drain(..)
+                .partition::<VecDeque<_>, _>(|m| m.expires_at.is_some_and(|t| t <= clock));
+            q.messages = alive;
+            expired.extend(dead.into_iter().map(|m| (name.clone(), m)));
+        }
+        expired.sort_by_key(|(_, m)| m.expires_at);
+        for (que, m) in expired {
+            self.dead_letter(&que, "expired", m);
+        }
+    }
+
+    fn release_delayed(&mut self) {
+        let clock = self.clock;
+        let (mut due, pending) = std::mem::take(&mut self.delayed)
+            .into_iter()
+            .partition::<Vec<_>, _>(|d| d.at <= clock);
+        self.delayed = pending;
+        // stable, keeps the order of publishing
+        due.sort_by_key(|d| d.at);
+        for d in due {
+            self.route_now(&d.exchange, d.message);
+        }
+    }
+
+    fn unacked_of(&self, consumer_tag: &str, channel: u64) -> usize {
+        self.channels
+            .get(&channel)
+            .map(|c| {
+                c.unacked
+                    .values()
+                    .filter(|u| u.consumer_tag == consumer_tag)
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    // the next delivery, round-robin over the consumers which have a ready message and a free
+    // prefetch slot
+    #[allow(clippy::type_complexity)]
+    fn next_delivery(
+        &mut self,
+    ) -> Option<(
+        Arc<tokio::sync::Mutex<Box<dyn DeliveryHandler>>>,
+        u64,
+        Delivery,
+        MemoryMessage,
+    )> {
+        let n = self.consumers.len();
+        for i in 0..n {
+            let idx = (self.cursor + i) % n;
+            let c = &self.consumers[idx];
+            let prefetch = self.channels.get(&c.channel).map_or(0, |s| s.prefetch);
+            if prefetch > 0 && self.unacked_of(&c.consumer_tag, c.channel) >= usize::from(prefetch)
+            {
+                continue;
+            }
+            let message = match self
+                .queues
+                .get_mut(&c.queue)
+                .and_then(|q| q.messages.pop_front())
+            {
+                Some(m) => m,
+                None => continue,
+            };
+
+            let c = &self.consumers[idx];
+            let state = self.channels.entry(c.channel).or_default();
+            state.last_delivery_tag += 1;
+            let delivery = Delivery {
+                consumer_tag: c.consumer_tag.clone(),
+                delivery_tag: state.last_delivery_tag,
+                redelivered: message.redelivered,
+                exchange: message.exchange.clone(),
+                routing_key: message.routing_key.clone(),
+            };
+            if !c.no_ack {
+                state.unacked.insert(
+                    delivery.delivery_tag,
+                    Unacked {
+                        consumer_tag: c.consumer_tag.clone(),
+                        queue: c.queue.clone(),
+                        message: message.clone(),
+                    },
+                );
+            }
+            self.cursor = idx + 1;
+
+            return Some((c.handler.clone(), c.channel, delivery, message));
+        }
+
+        None
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct MemoryBroker {
+    inner: Arc<Mutex<BrokerInner>>,
+}
+
+impl MemoryBroker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // each channel has its own delivery tags, prefetch and confirm mode
+    pub fn channel(&self) -> MemoryChannel {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_channel += 1;
+        let id = inner.last_channel;
+        inner.channels.insert(id, ChannelState::default());
+
+        MemoryChannel {
+            broker: self.clone(),
+            id,
+        }
+    }
+
+    pub fn declare_exchange(&self, name: &str, exchange_type: &ExchangeType) -> PqxResult<()> {
+        self.declare_exchange_with_args(name, exchange_type, FieldTable::new())
+    }
+
+    pub fn declare_delayed_exchange(
+        &self,
+        name: &str,
+        exchange_type: &ExchangeType,
+    ) -> PqxResult<()> {
+        let mut args = FieldTableBuilder::new();
+        args.x_delayed_type(exchange_type);
+
+        self.declare_exchange_with_args(name, &EXCHANGE_TYPE_DELAYED, args.finish())
+    }
+
+    // redeclaring keeps the bindings
+    pub fn declare_exchange_with_args(
+        &self,
+        name: &str,
+        exchange_type: &ExchangeType,
+        args: FieldTable,
+    ) -> PqxResult<()> {
+        let delayed = *exchange_type == *EXCHANGE_TYPE_DELAYED;
+        let exchange_type = if delayed {
+            match args.get(&X_DELAYED_TYPE) {
+                Some(FieldValue::S(s)) => ExchangeType::from(s.as_ref().as_str()),
+                _ => return Err("x-delayed-type is required by a delayed exchange".into()),
+            }
+        } else {
+            ExchangeType::from(exchange_type.to_string())
+        };
+        if !matches!(
+            exchange_type,
+            ExchangeType::Direct
+                | ExchangeType::Fanout
+                | ExchangeType::Topic
+                | ExchangeType::Headers
+        ) {
+            return Err("exchange type is not supported by the memory broker".into());
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let bindings = inner
+            .exchanges
+            .remove(name)
+            .map(|x| x.bindings)
+            .unwrap_or_default();
+        inner.exchanges.insert(
+            name.to_owned(),
+            MemoryExchange {
+                exchange_type,
+                delayed,
+                bindings,
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn delete_exchange(&self, exchange: &str) -> PqxResult<()> {
+        self.inner.lock().unwrap().exchanges.remove(exchange);
+
+        Ok(())
+    }
+
+    pub fn declare_queue(&self, que: &str) -> PqxResult<()> {
+        self.declare_queue_with_args(que, FieldTable::new())
+    }
+
+    // `x-message-ttl`, `x-dead-letter-exchange` & `x-dead-letter-routing-key` are taken into
+    // account. Redeclaring keeps the messages
+    pub fn declare_queue_with_args(&self, que: &str, args: FieldTable) -> PqxResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut q = MemoryQueue::new(&args);
+        if let Some(old) = inner.queues.remove(que) {
+            q.messages = old.messages;
+        }
+        inner.queues.insert(que.to_owned(), q);
+
+        Ok(())
+    }
+
+    pub fn declare_and_bind_queue(&self, exchange: &str, rout: &str, que: &str) -> PqxResult<()> {
+        self.declare_queue(que)?;
+        self.bind_queue(exchange, rout, que)
+    }
+
+    // expired messages (after `ttl` milliseconds) are dead-lettered to `dlx`, keeping their
+    // original routing key
+    pub fn declare_wait_queue(&self, que: &str, dlx: &str, ttl: i64) -> PqxResult<()> {
+        let mut ft = FieldTableBuilder::new();
+        ft.x_dead_letter_exchange_only(dlx);
+        ft.x_message_ttl(ttl);
+
+        self.declare_queue_with_args(que, ft.finish())
+    }
+
+    pub fn bind_queue(&self, exchange: &str, rout: &str, que: &str) -> PqxResult<()> {
+        self.bind_queue_by_args(QueueBindArguments::new(que, exchange, rout))
+    }
+
+    pub fn bind_queue_by_args(&self, args: QueueBindArguments) -> PqxResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.queues.contains_key(&args.queue) {
+            return Err("queue is not declared in the memory broker".into());
+        }
+        let x = inner
+            .exchanges
+            .get_mut(&args.exchange)
+            .ok_or("exchange is not declared in the memory broker")?;
+        x.bindings.push(MemoryBinding {
+            queue: args.queue,
+            routing_key: args.routing_key,
+            arguments: args.arguments,
+        });
+
+        Ok(())
+    }
+
+    pub fn delete_queue(&self, que: &str) -> PqxResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.queues.remove(que);
+        for x in inner.exchanges.values_mut() {
+            x.bindings.retain(|b| b.queue != que);
+        }
+        inner.consumers.retain(|c| c.queue != que);
+
+        Ok(())
+    }
+
+    pub fn purge_queue(&self, que: &str) -> PqxResult<()> {
+        if let Some(q) = self.inner.lock().unwrap().queues.get_mut(que) {
+            q.messages.clear();
+        }
+
+        Ok(())
+    }
+
+    // number of ready messages
+    pub fn message_count(&self, que: &str) -> usize {
+        self.inner
+            .lock()
+            .unwrap()
+            .queues
+            .get(que)
+            .map_or(0, |q| q.messages.len())
+    }
+
+    // take the first ready message of the queue, without acknowledgement
+    pub fn get(&self, que: &str) -> Option<MemoryMessage> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.expire();
+        inner.queues.get_mut(que)?.messages.pop_front()
+    }
+
+    // milliseconds elapsed on the clock
+    pub fn now(&self) -> u64 {
+        self.inner.lock().unwrap().clock
+    }
+
+    // move the clock forward: delayed messages are released, and expired messages are
+    // dead-lettered (or dropped)
+    pub fn advance(&self, duration: Duration) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        inner.release_delayed();
+        inner.expire();
+    }
+
+    // dispatch ready messages to consumers until none can be delivered, the handlers are awaited
+    // one by one. Returns the number of deliveries
+    pub async fn run_until_idle(&self) -> PqxResult<usize> {
+        let mut count = 0;
+
+        loop {
+            let next = {
+                let mut inner = self.inner.lock().unwrap();
+                inner.expire();
+                inner.next_delivery()
+            };
+            let (handler, channel, delivery, message) = match next {
+                Some(n) => n,
+                None => break,
+            };
+            let channel = MemoryChannel {
+                broker: self.clone(),
+                id: channel,
+            };
+
+            handler
+                .lock()
+                .await
+                .handle(&channel, delivery, message.props, message.content)
+                .await;
+
+            count += 1;
+            if count >= MAX_DELIVERIES {
+                return Err(
+                    "too many deliveries, check whether a message is requeued forever".into(),
+                );
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+// ================================================================================================
+// MemoryChannel
+// ================================================================================================
+
+#[derive(Clone)]
+pub struct MemoryChannel {
+    broker: MemoryBroker,
+    id: u64,
+}
+
+impl MemoryChannel {
+    pub fn broker(&self) -> &MemoryBroker {
+        &self.broker
+    }
+
+    // number of deliveries waiting for ack/nack
+    pub fn unacked(&self) -> usize {
+        let inner = self.broker.inner.lock().unwrap();
+        inner.channels.get(&self.id).map_or(0, |s| s.unacked.len())
+    }
+
+    // consumers are cancelled, and unacked deliveries are requeued
+    pub fn close(&self) {
+        let mut inner = self.broker.inner.lock().unwrap();
+        inner.consumers.retain(|c| c.channel != self.id);
+        if let Some(state) = inner.channels.remove(&self.id) {
+            for (_, u) in state.unacked.into_iter().rev() {
+                inner.requeue(u);
+            }
+        }
+    }
+
+    fn take_unacked(
+        &self,
+        inner: &mut BrokerInner,
+        delivery_tag: u64,
+        multiple: bool,
+    ) -> PqxResult<Vec<Unacked>> {
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .ok_or("channel is closed")?;
+        let tags = if multiple {
+            state
+                .unacked
+                .range(..=delivery_tag)
+                .map(|(t, _)| *t)
+                .collect()
+        } else if state.unacked.contains_key(&delivery_tag) {
+            vec![delivery_tag]
+        } else {
+            return Err("unknown delivery tag".into());
+        };
+
+        Ok(tags
+            .into_iter()
+            .filter_map(|t| state.unacked.remove(&t))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ChannelOps for MemoryChannel {
+    async fn basic_publish(
+        &self,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+        args: BasicPublishArguments,
+    ) -> PqxResult<()> {
+        let message = MemoryMessage::new(basic_properties, content, &args);
+
+        let (routed, confirm) = {
+            let mut inner = self.broker.inner.lock().unwrap();
+            let routed = inner.route(&args.exchange, message)?;
+            let state = inner
+                .channels
+                .get_mut(&self.id)
+                .ok_or("channel is closed")?;
+            let confirm = state.confirm.as_mut().map(|(c, tag)| {
+                *tag += 1;
+                (c.clone(), *tag)
+            });
+            (routed, confirm)
+        };
+        // an unroutable message is confirmed as well, after being returned if `mandatory`
+        if let Some((c, tag)) = confirm {
+            if args.mandatory && !routed {
+                c.returned();
+            }
+            c.settle(tag, false, true);
+        }
+
+        Ok(())
+    }
+
+    async fn basic_ack(&self, args: BasicAckArguments) -> PqxResult<()> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        self.take_unacked(&mut inner, args.delivery_tag, args.multiple)?;
+
+        Ok(())
+    }
+
+    async fn basic_nack(&self, args: BasicNackArguments) -> PqxResult<()> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        let unacked = self.take_unacked(&mut inner, args.delivery_tag, args.multiple)?;
+        for u in unacked.into_iter().rev() {
+            if args.requeue {
+                inner.requeue(u);
+            } else {
+                inner.dead_letter(&u.queue, "rejected", u.message);
+            }
+        }
+
+        Ok(())
+    }
+
+    // per consumer, `prefetch_size` & `global` are ignored
+    async fn basic_qos(&self, args: BasicQosArguments) -> PqxResult<()> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .ok_or("channel is closed")?;
+        state.prefetch = args.prefetch_count;
+
+        Ok(())
+    }
+
+    // RabbitMQ only supports `requeue = true`, hence unacked deliveries are always requeued
+    async fn basic_recover(&self, _requeue: bool) -> PqxResult<()> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .ok_or("channel is closed")?;
+        let unacked = std::mem::take(&mut state.unacked);
+        for (_, u) in unacked.into_iter().rev() {
+            inner.requeue(u);
+        }
+
+        Ok(())
+    }
+
+    async fn basic_consume(
+        &self,
+        handler: Box<dyn DeliveryHandler>,
+        args: BasicConsumeArguments,
+    ) -> PqxResult<String> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        if !inner.queues.contains_key(&args.queue) {
+            return Err("queue is not declared in the memory broker".into());
+        }
+        let consumer_tag = if args.consumer_tag.is_empty() {
+            inner.last_consumer += 1;
+            format!("memory.ctag-{}", inner.last_consumer)
+        } else {
+            args.consumer_tag
+        };
+        if inner
+            .consumers
+            .iter()
+            .any(|c| c.consumer_tag == consumer_tag)
+        {
+            return Err("consumer tag is in use".into());
+        }
+        inner.consumers.push(MemoryConsumer {
+            consumer_tag: consumer_tag.clone(),
+            queue: args.queue,
+            channel: self.id,
+            no_ack: args.no_ack,
+            handler: Arc::new(tokio::sync::Mutex::new(handler)),
+        });
+
+        Ok(consumer_tag)
+    }
+
+    // unacked deliveries of the consumer stay unacked
+    async fn basic_cancel(&self, args: BasicCancelArguments) -> PqxResult<()> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        inner
+            .consumers
+            .retain(|c| c.consumer_tag != args.consumer_tag);
+
+        Ok(())
+    }
+
+    async fn basic_get(
+        &self,
+        args: BasicGetArguments,
+    ) -> PqxResult<Option<(Delivery, BasicProperties, Vec<u8>)>> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        inner.expire();
+        let message = match inner
+            .queues
+            .get_mut(&args.queue)
+            .ok_or("queue is not declared in the memory broker")?
+            .messages
+            .pop_front()
+        {
+            Some(m) => m,
+            None => return Ok(None),
+        };
+
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .ok_or("channel is closed")?;
+        state.last_delivery_tag += 1;
+        let delivery = Delivery {
+            consumer_tag: String::new(),
+            delivery_tag: state.last_delivery_tag,
+            redelivered: message.redelivered,
+            exchange: message.exchange.clone(),
+            routing_key: message.routing_key.clone(),
+        };
+        if !args.no_ack {
+            state.unacked.insert(
+                delivery.delivery_tag,
+                Unacked {
+                    consumer_tag: String::new(),
+                    queue: args.queue,
+                    message: message.clone(),
+                },
+            );
+        }
+
+        Ok(Some((delivery, message.props, message.content)))
+    }
+
+    async fn confirm_select(&self) -> PqxResult<PublishConfirm> {
+        let mut inner = self.broker.inner.lock().unwrap();
+        let state = inner
+            .channels
+            .get_mut(&self.id)
+            .ok_or("channel is closed")?;
+        let confirm = PublishConfirm::default();
+        state.confirm = Some((confirm.clone(), 0));
+
+        Ok(confirm)
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_memory {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::mq::{
+        Consumer, ConsumerResult, DedupStore, MemoryDedupStore, PqxHeaders, Publisher, Requeue,
+        Retry, RetryBackend, RetryPolicy, Subscriber, X_DELAY,
+    };
+
+    // decisions of each delivery in order: `None` acks, `Some(requeue)` nacks
+    #[derive(Clone, Default)]
+    struct ScriptedHandler {
+        decisions: Arc<Mutex<VecDeque<Option<bool>>>>,
+        deliveries: Arc<Mutex<Vec<Delivery>>>,
+    }
+
+    impl ScriptedHandler {
+        fn new(decisions: impl IntoIterator<Item = Option<bool>>) -> Self {
+            Self {
+                decisions: Arc::new(Mutex::new(decisions.into_iter().collect())),
+                deliveries: Arc::default(),
+            }
+        }
+
+        fn deliveries(&self) -> Vec<Delivery> {
+            self.deliveries.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl DeliveryHandler for ScriptedHandler {
+        async fn handle(
+            &mut self,
+            channel: &dyn ChannelOps,
+            delivery: Delivery,
+            _basic_properties: BasicProperties,
+            _content: Vec<u8>,
+        ) {
+            let tag = delivery.delivery_tag;
+            self.deliveries.lock().unwrap().push(delivery);
+            let decision = self.decisions.lock().unwrap().pop_front().flatten();
+            match decision {
+                None => channel
+                    .basic_ack(BasicAckArguments::new(tag, false))
+                    .await
+                    .unwrap(),
+                Some(requeue) => channel
+                    .basic_nack(BasicNackArguments::new(tag, false, requeue))
+                    .await
+                    .unwrap(),
+            }
+        }
+    }
+
+    // never acks
+    struct NoAckHandler;
+
+    #[async_trait]
+    impl DeliveryHandler for NoAckHandler {
+        async fn handle(
+            &mut self,
+            _: &dyn ChannelOps,
+            _: Delivery,
+            _: BasicProperties,
+            _: Vec<u8>,
+        ) {
+        }
+    }
+
+    async fn publish(chan: &MemoryChannel, exchange: &str, rout: &str, props: BasicProperties) {
+        let args = BasicPublishArguments::new(exchange, rout);
+        chan.basic_publish(props, b"{}".to_vec(), args)
+            .await
+            .unwrap();
+    }
+
+    fn with_headers(pairs: &[(&str, &str)]) -> BasicProperties {
+        let mut ft = FieldTableBuilder::new();
+        for (k, v) in pairs {
+            ft.x_common_pair(*k, *v).unwrap();
+        }
+
+        BasicProperties::default()
+            .with_headers(ft.finish())
+            .finish()
+    }
+
+    #[tokio::test]
+    async fn routing_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker
+            .declare_exchange("direct", &ExchangeType::Direct)
+            .unwrap();
+        broker
+            .declare_exchange("topic", &ExchangeType::Topic)
+            .unwrap();
+        broker
+            .declare_exchange("fanout", &ExchangeType::Fanout)
+            .unwrap();
+        broker
+            .declare_exchange("headers", &ExchangeType::Headers)
+            .unwrap();
+        for q in ["q1", "q2"] {
+            broker.declare_queue(q).unwrap();
+            broker.bind_queue("fanout", "", q).unwrap();
+        }
+        broker.bind_queue("direct", "k1", "q1").unwrap();
+        broker.bind_queue("topic", "etl.*.cn", "q1").unwrap();
+        broker.bind_queue("topic", "etl.#", "q2").unwrap();
+        let mut args = QueueBindArguments::new("q2", "headers", "");
+        let mut ft = FieldTableBuilder::new();
+        ft.x_match(&MatchType::Any);
+        ft.x_common_pair("region", "cn").unwrap();
+        ft.x_common_pair("gpu", "true").unwrap();
+        args.arguments(ft.finish());
+        broker.bind_queue_by_args(args).unwrap();
+
+        // the default exchange routes by queue name
+        publish(&chan, "", "q2", BasicProperties::default()).await;
+        publish(&chan, "direct", "k1", BasicProperties::default()).await;
+        publish(&chan, "direct", "k2", BasicProperties::default()).await;
+        assert_eq!(
+            (broker.message_count("q1"), broker.message_count("q2")),
+            (1, 1)
+        );
+
+        // a queue matched by multiple bindings receives one copy
+        publish(&chan, "topic", "etl.daily.cn", BasicProperties::default()).await;
+        assert_eq!(
+            (broker.message_count("q1"), broker.message_count("q2")),
+            (2, 2)
+        );
+
+        publish(&chan, "fanout", "", BasicProperties::default()).await;
+        assert_eq!(
+            (broker.message_count("q1"), broker.message_count("q2")),
+            (3, 3)
+        );
+
+        publish(&chan, "headers", "", with_headers(&[("region", "us")])).await;
+        publish(&chan, "headers", "", with_headers(&[("region", "cn")])).await;
+        assert_eq!(
+            (broker.message_count("q1"), broker.message_count("q2")),
+            (3, 4)
+        );
+
+        // unknown exchanges fail
+        let args = BasicPublishArguments::new("missing", "");
+        let res = chan
+            .basic_publish(BasicProperties::default(), vec![], args)
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn ttl_dlx_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker
+            .declare_exchange("dlx", &ExchangeType::Direct)
+            .unwrap();
+        broker.declare_and_bind_queue("dlx", "dead", "dlq").unwrap();
+        let mut ft = FieldTableBuilder::new();
+        ft.x_dead_letter_exchange_only("dlx").x_message_ttl(1000);
+        let mut ft = ft.finish();
+        ft.insert(
+            X_DEAD_LETTER_ROUTING_KEY.clone(),
+            FieldValue::from("dead".to_owned()),
+        );
+        broker.declare_queue_with_args("que", ft).unwrap();
+
+        publish(&chan, "", "que", BasicProperties::default()).await;
+        // the per-message expiration is shorter
+        let props = BasicProperties::default().with_expiration("200").finish();
+        publish(&chan, "", "que", props).await;
+
+        broker.advance(Duration::from_millis(200));
+        assert_eq!(broker.message_count("que"), 1);
+        assert_eq!(broker.message_count("dlq"), 1);
+        broker.advance(Duration::from_millis(800));
+        assert_eq!(broker.message_count("que"), 0);
+        assert_eq!(broker.message_count("dlq"), 2);
+
+        let m = broker.get("dlq").unwrap();
+        assert_eq!(m.exchange, "dlx");
+        assert_eq!(m.routing_key, "dead");
+        let deaths = FieldTableViewer::new(m.props.headers().unwrap())
+            .x_death()
+            .unwrap();
+        assert_eq!(deaths.len(), 1);
+        assert_eq!(deaths[0].reason, "expired");
+        assert_eq!(deaths[0].queue, "que");
+        assert_eq!(deaths[0].routing_keys, vec!["que".to_owned()]);
+        assert_eq!(deaths[0].count, 1);
+
+        // no longer expires once dead-lettered
+        broker.advance(Duration::from_secs(60));
+        assert_eq!(broker.message_count("dlq"), 1);
+    }
+
+    #[tokio::test]
+    async fn delayed_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker
+            .declare_delayed_exchange("delayed", &ExchangeType::Direct)
+            .unwrap();
+        broker
+            .declare_and_bind_queue("delayed", "k", "que")
+            .unwrap();
+
+        let mut ft = FieldTable::new();
+        ft.insert(X_DELAY.clone(), FieldValue::I(500));
+        let props = BasicProperties::default().with_headers(ft).finish();
+        publish(&chan, "delayed", "k", props).await;
+        publish(&chan, "delayed", "k", BasicProperties::default()).await;
+        assert_eq!(broker.message_count("que"), 1);
+
+        broker.advance(Duration::from_millis(499));
+        assert_eq!(broker.message_count("que"), 1);
+        broker.advance(Duration::from_millis(1));
+        assert_eq!(broker.message_count("que"), 2);
+        assert_eq!(broker.now(), 500);
+    }
+
+    #[tokio::test]
+    async fn ack_nack_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker
+            .declare_exchange("dlx", &ExchangeType::Fanout)
+            .unwrap();
+        broker.declare_and_bind_queue("dlx", "", "dlq").unwrap();
+        let mut ft = FieldTableBuilder::new();
+        ft.x_dead_letter_exchange_only("dlx");
+        broker.declare_queue_with_args("que", ft.finish()).unwrap();
+
+        // 1st: nack & requeue, 2nd (redelivered): ack, 3rd: nack
+        let handler = ScriptedHandler::new([Some(true), None, Some(false)]);
+        let args = BasicConsumeArguments::new("que", "");
+        let tag = chan
+            .basic_consume(Box::new(handler.clone()), args)
+            .await
+            .unwrap();
+
+        publish(&chan, "", "que", BasicProperties::default()).await;
+        publish(&chan, "", "que", BasicProperties::default()).await;
+        assert_eq!(broker.run_until_idle().await.unwrap(), 3);
+
+        let deliveries = handler.deliveries();
+        assert_eq!(
+            deliveries.iter().map(|d| d.redelivered).collect::<Vec<_>>(),
+            vec![false, true, false]
+        );
+        assert_eq!(
+            deliveries
+                .iter()
+                .map(|d| d.delivery_tag)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(deliveries.iter().all(|d| d.consumer_tag == tag));
+        assert_eq!(chan.unacked(), 0);
+        assert_eq!(broker.message_count("que"), 0);
+
+        let m = broker.get("dlq").unwrap();
+        let deaths = FieldTableViewer::new(m.props.headers().unwrap())
+            .x_death()
+            .unwrap();
+        assert_eq!(deaths[0].reason, "rejected");
+
+        // unacked deliveries (bounded by prefetch) are requeued by closing the channel
+        chan.basic_cancel(BasicCancelArguments::new(&tag))
+            .await
+            .unwrap();
+        let chan2 = broker.channel();
+        chan2
+            .basic_qos(BasicQosArguments::new(0, 1, false))
+            .await
+            .unwrap();
+        chan2
+            .basic_consume(
+                Box::new(NoAckHandler),
+                BasicConsumeArguments::new("que", ""),
+            )
+            .await
+            .unwrap();
+        publish(&chan, "", "que", BasicProperties::default()).await;
+        publish(&chan, "", "que", BasicProperties::default()).await;
+        assert_eq!(broker.run_until_idle().await.unwrap(), 1);
+        assert_eq!((chan2.unacked(), broker.message_count("que")), (1, 1));
+        chan2.close();
+        assert_eq!((chan2.unacked(), broker.message_count("que")), (0, 2));
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct DevMsg {
+        data: String,
+    }
+
+    #[derive(Clone)]
+    struct RetryOnceConsumer(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Consumer<DevMsg, ()> for RetryOnceConsumer {
+        async fn consume(&mut self, _message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+            if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+                Ok(ConsumerResult::retry(None))
+            } else {
+                Ok(ConsumerResult::success(()))
+            }
+        }
+
+        fn gen_retry(&self, _message: &DevMsg) -> Option<Retry> {
+            let mut retry = Retry::new(
+                RetryBackend::wait_queues("wait", [10]),
+                "",
+                RetryPolicy::fixed(1),
+                2,
+            );
+            retry.keep_routing_key();
+
+            Some(retry)
+        }
+    }
+
+    #[tokio::test]
+    async fn subscriber_retry_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker
+            .declare_exchange("work", &ExchangeType::Topic)
+            .unwrap();
+        broker
+            .declare_and_bind_queue("work", "etl.*.cn", "cn")
+            .unwrap();
+        broker
+            .declare_exchange("wait", &ExchangeType::Headers)
+            .unwrap();
+        let que = RetryBackend::wait_queue_name("wait", 10);
+        broker.declare_wait_queue(&que, "work", 10_000).unwrap();
+        let mut args = QueueBindArguments::new(&que, "wait", "");
+        let mut ft = FieldTableBuilder::new();
+        ft.x_match(&MatchType::AllWithX).x_wait(10);
+        args.arguments(ft.finish());
+        broker.bind_queue_by_args(args).unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut subscriber = Subscriber::new(&chan, RetryOnceConsumer(count.clone()));
+        subscriber.consume("cn").await.unwrap();
+
+        let mut publisher = Publisher::new(&chan);
+        publisher.enable_confirm().await.unwrap();
+        let msg = DevMsg {
+            data: "daily".to_string(),
+        };
+        publisher
+            .publish("work", "etl.daily.cn", msg)
+            .await
+            .unwrap();
+
+        // the retry waits for its tier, then comes back by the same routing key
+        assert_eq!(broker.run_until_idle().await.unwrap(), 1);
+        assert_eq!(broker.message_count(&que), 1);
+        broker.advance(Duration::from_secs(10));
+        assert_eq!(broker.run_until_idle().await.unwrap(), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(chan.unacked(), 0);
+
+        // the consumer is gone with its channel
+        chan.close();
+        publish(
+            &broker.channel(),
+            "work",
+            "etl.daily.cn",
+            BasicProperties::default(),
+        )
+        .await;
+        assert_eq!(broker.run_until_idle().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn subscriber_retry_pinned_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        // two queues matching the same routing key
+        broker
+            .declare_exchange("work", &ExchangeType::Topic)
+            .unwrap();
+        for q in ["cn", "cn2"] {
+            broker
+                .declare_and_bind_queue("work", "etl.*.cn", q)
+                .unwrap();
+        }
+        broker
+            .declare_exchange("wait", &ExchangeType::Headers)
+            .unwrap();
+        let que = RetryBackend::wait_queue_name("wait", 10);
+        broker.declare_wait_queue(&que, "work", 10_000).unwrap();
+        let mut args = QueueBindArguments::new(&que, "wait", "");
+        let mut ft = FieldTableBuilder::new();
+        ft.x_match(&MatchType::AllWithX).x_wait(10);
+        args.arguments(ft.finish());
+        broker.bind_queue_by_args(args).unwrap();
+
+        // "cn" retries once, "cn2" succeeds at once
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut subscriber = Subscriber::new(&chan, RetryOnceConsumer(count.clone()));
+        subscriber.consume("cn").await.unwrap();
+        let count2 = Arc::new(AtomicUsize::new(1));
+        let mut subscriber2 = Subscriber::new(&chan, RetryOnceConsumer(count2.clone()));
+        subscriber2.consume("cn2").await.unwrap();
+
+        let publisher = Publisher::new(&chan);
+        let msg = DevMsg {
+            data: "daily".to_string(),
+        };
+        publisher
+            .publish("work", "etl.daily.cn", msg)
+            .await
+            .unwrap();
+        assert_eq!(broker.run_until_idle().await.unwrap(), 2);
+
+        // the retry reaches both queues, and is only consumed again by "cn"
+        broker.advance(Duration::from_secs(10));
+        assert_eq!(broker.run_until_idle().await.unwrap(), 2);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!(count2.load(Ordering::SeqCst), 2);
+        assert_eq!(broker.message_count("cn") + broker.message_count("cn2"), 0);
+        assert_eq!(chan.unacked(), 0);
+    }
+
+    #[derive(Clone)]
+    struct FailConsumer(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl Consumer<DevMsg, String> for FailConsumer {
+        async fn consume(&mut self, _message: &DevMsg) -> PqxResult<ConsumerResult<String>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+
+            Ok(ConsumerResult::failure("always fails".to_string()))
+        }
+
+        fn gen_requeue(&self, _message: &DevMsg) -> Option<Requeue> {
+            Some(Requeue::new(2, "dlx", ""))
+        }
+    }
+
+    #[tokio::test]
+    async fn subscriber_requeue_dead_success() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker.declare_queue("que").unwrap();
+        broker
+            .declare_exchange("dlx", &ExchangeType::Direct)
+            .unwrap();
+        broker.declare_and_bind_queue("dlx", "", "dlq").unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut subscriber = Subscriber::new(&chan, FailConsumer(count.clone()));
+        subscriber.consume("que").await.unwrap();
+
+        let msg = DevMsg {
+            data: "daily".to_string(),
+        };
+        Publisher::new(&chan).publish("", "que", msg).await.unwrap();
+
+        // consumed once, requeued twice, then dead-lettered with its reason & queue
+        broker.run_until_idle().await.unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert_eq!((broker.message_count("que"), chan.unacked()), (0, 0));
+        let dead = broker.get("dlq").unwrap();
+        let headers = PqxHeaders::from_props(&dead.props).unwrap();
+        assert!(headers
+            .x_dead_reason
+            .unwrap()
+            .starts_with("requeued 2 times"));
+        assert_eq!(headers.x_dead_queue.as_deref(), Some("que"));
+    }
+
+    // claims fail for the first `n` calls
+    #[derive(Debug)]
+    struct FlakyDedupStore(MemoryDedupStore, AtomicUsize);
+
+    #[async_trait]
+    impl DedupStore for FlakyDedupStore {
+        async fn claim(&self, key: &str, takeover: bool) -> PqxResult<bool> {
+            match self
+                .1
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            {
+                Ok(_) => Err("store unavailable".into()),
+                Err(_) => self.0.claim(key, takeover).await,
+            }
+        }
+
+        async fn mark(&self, key: &str) -> PqxResult<()> {
+            self.0.mark(key).await
+        }
+
+        async fn release(&self, key: &str) -> PqxResult<()> {
+            self.0.release(key).await
+        }
+
+        async fn skip(&self, key: &str) -> PqxResult<()> {
+            self.0.skip(key).await
+        }
+    }
+
+    #[derive(Clone)]
+    struct DedupConsumer(Arc<AtomicUsize>, Arc<FlakyDedupStore>);
+
+    #[async_trait]
+    impl Consumer<DevMsg, ()> for DedupConsumer {
+        async fn consume(&mut self, _message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+
+            Ok(ConsumerResult::success(()))
+        }
+
+        fn dedup_store(&self) -> Option<Arc<dyn DedupStore>> {
+            Some(self.1.clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn subscriber_dedup_store_failure() {
+        let broker = MemoryBroker::new();
+        let chan = broker.channel();
+
+        broker.declare_queue("que").unwrap();
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let store = FlakyDedupStore(
+            MemoryDedupStore::new(100, Duration::from_secs(60)),
+            AtomicUsize::new(2),
+        );
+        let consumer = DedupConsumer(count.clone(), Arc::new(store));
+        let mut subscriber = Subscriber::new(&chan, consumer);
+        subscriber.consume("que").await.unwrap();
+
+        let publisher = Publisher::new(&chan);
+        let msg = DevMsg {
+            data: "daily".to_string(),
+        };
+        let mut props = BasicProperties::default();
+        props.with_message_id("m1");
+
+        // requeued while the store fails, then consumed
+        publisher
+            .publish_with_props("", "que", msg.clone(), props.clone())
+            .await
+            .unwrap();
+        assert_eq!(broker.run_until_idle().await.unwrap(), 3);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!((broker.message_count("que"), chan.unacked()), (0, 0));
+
+        // still consuming, and deduplicating
+        publisher
+            .publish_with_props("", "que", msg, props)
+            .await
+            .unwrap();
+        assert_eq!(broker.run_until_idle().await.unwrap(), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!((broker.message_count("que"), chan.unacked()), (0, 0));
+    }
+}
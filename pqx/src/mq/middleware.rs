@@ -0,0 +1,345 @@
+//! file: middleware.rs
+//! author: Jacob Xie
+//! date: 2023/07/21 20:14:06 Friday
+//! brief: middleware layers around `Consumer::consume`
+
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use amqprs::BasicProperties;
+use async_trait::async_trait;
+
+use super::ConsumerResult;
+use crate::error::PqxResult;
+
+// ================================================================================================
+// DeliveryContext
+// ================================================================================================
+
+#[derive(Debug)]
+pub struct DeliveryContext<'a> {
+    pub queue: Option<&'a str>,
+    pub properties: &'a BasicProperties,
+    pub delivery_tag: u64,
+    pub redelivered: bool,
+    pub received_at: Instant,
+}
+
+impl<'a> DeliveryContext<'a> {
+    pub fn elapsed(&self) -> Duration {
+        self.received_at.elapsed()
+    }
+}
+
+// ================================================================================================
+// Middleware
+//
+// Layers wrap `Consumer::consume` like an onion: `before` is called from the first layer added
+// to the last one, and `after` the other way round. A layer short-circuits a delivery by
+// returning a result from `before`, then `consume` and the `before` of the inner layers are
+// skipped, whereas the `after` of itself and the outer layers are still called. The final result
+// is handled as if it were returned by `consume`: an `Err` discards the message.
+//
+// Layers only see decoded messages which are not duplicates; undecodable deliveries are
+// discarded before reaching them.
+// ================================================================================================
+
+#[async_trait]
+pub trait Middleware<M, R>: Send + Sync
+where
+    M: Send + Sync + 'static,
+    R: Send + Debug + 'static,
+{
+    // inspect a delivery, and short-circuit it by `Ok(Some(_))` or `Err(_)`
+    #[allow(unused_variables)]
+    async fn before(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        message: &M,
+    ) -> PqxResult<Option<ConsumerResult<R>>> {
+        Ok(None)
+    }
+
+    // observe or modify the result
+    #[allow(unused_variables)]
+    async fn after(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        message: &M,
+        result: PqxResult<ConsumerResult<R>>,
+    ) -> PqxResult<ConsumerResult<R>> {
+        result
+    }
+}
+
+// run `consume` inside of `layers`
+pub(crate) async fn around<M, R, F, Fut>(
+    layers: &[Arc<dyn Middleware<M, R>>],
+    ctx: &DeliveryContext<'_>,
+    message: &M,
+    consume: F,
+) -> PqxResult<ConsumerResult<R>>
+where
+    M: Send + Sync + 'static,
+    R: Send + Debug + 'static,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = PqxResult<ConsumerResult<R>>>,
+{
+    let mut entered = 0;
+    let mut short_circuit = None;
+    for layer in layers.iter() {
+        entered += 1;
+        match layer.before(ctx, message).await {
+            Ok(None) => {}
+            Ok(Some(r)) => {
+                short_circuit = Some(Ok(r));
+                break;
+            }
+            Err(e) => {
+                short_circuit = Some(Err(e));
+                break;
+            }
+        }
+    }
+
+    let mut result = match short_circuit {
+        Some(r) => r,
+        None => consume().await,
+    };
+    for layer in layers[..entered].iter().rev() {
+        result = layer.after(ctx, message, result).await;
+    }
+
+    result
+}
+
+// ================================================================================================
+// Metrics
+//
+// Counts the results of deliveries and the time spent on them, shared by all the clones.
+// ================================================================================================
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    deliveries: AtomicU64,
+    successes: AtomicU64,
+    retries: AtomicU64,
+    failures: AtomicU64,
+    errors: AtomicU64,
+    elapsed_micros: AtomicU64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub deliveries: u64,
+    pub successes: u64,
+    pub retries: u64,
+    pub failures: u64,
+    pub errors: u64,
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<MetricsInner>);
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            deliveries: self.0.deliveries.load(Ordering::Relaxed),
+            successes: self.0.successes.load(Ordering::Relaxed),
+            retries: self.0.retries.load(Ordering::Relaxed),
+            failures: self.0.failures.load(Ordering::Relaxed),
+            errors: self.0.errors.load(Ordering::Relaxed),
+            elapsed: Duration::from_micros(self.0.elapsed_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[async_trait]
+impl<M, R> Middleware<M, R> for Metrics
+where
+    M: Send + Sync + 'static,
+    R: Send + Debug + 'static,
+{
+    async fn after(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        _message: &M,
+        result: PqxResult<ConsumerResult<R>>,
+    ) -> PqxResult<ConsumerResult<R>> {
+        let counter = match &result {
+            Ok(ConsumerResult::Success(_)) => &self.0.successes,
+            Ok(ConsumerResult::Retry(_)) => &self.0.retries,
+            Ok(ConsumerResult::Failure(_)) => &self.0.failures,
+            Err(_) => &self.0.errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.0.deliveries.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(ctx.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.0.elapsed_micros.fetch_add(micros, Ordering::Relaxed);
+
+        result
+    }
+}
+
+// ================================================================================================
+// AppIdFilter
+//
+// Discards deliveries whose `app_id` is missing or not allowed, without consuming them.
+// ================================================================================================
+
+#[derive(Debug, Clone, Default)]
+pub struct AppIdFilter {
+    allowed: HashSet<String>,
+}
+
+impl AppIdFilter {
+    pub fn new<I, S>(allowed: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self {
+            allowed: allowed.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+#[async_trait]
+impl<M, R> Middleware<M, R> for AppIdFilter
+where
+    M: Send + Sync + 'static,
+    R: Send + Debug + 'static,
+{
+    async fn before(
+        &self,
+        ctx: &DeliveryContext<'_>,
+        _message: &M,
+    ) -> PqxResult<Option<ConsumerResult<R>>> {
+        match ctx.properties.app_id() {
+            Some(a) if self.allowed.contains(a) => Ok(None),
+            Some(_) => Err("app_id is not allowed".into()),
+            None => Err("app_id is missing".into()),
+        }
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_middleware {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    // records the order of calls, and short-circuits messages equal to `skip`
+    struct Trace {
+        name: &'static str,
+        skip: Option<u32>,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl Middleware<u32, u32> for Trace {
+        async fn before(
+            &self,
+            _ctx: &DeliveryContext<'_>,
+            message: &u32,
+        ) -> PqxResult<Option<ConsumerResult<u32>>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{}.before", self.name));
+            if self.skip == Some(*message) {
+                return Ok(Some(ConsumerResult::failure(0)));
+            }
+            Ok(None)
+        }
+
+        async fn after(
+            &self,
+            _ctx: &DeliveryContext<'_>,
+            _message: &u32,
+            result: PqxResult<ConsumerResult<u32>>,
+        ) -> PqxResult<ConsumerResult<u32>> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{}.after", self.name));
+            result
+        }
+    }
+
+    #[tokio::test]
+    async fn around_success() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let metrics = Metrics::new();
+        let layers: Vec<Arc<dyn Middleware<u32, u32>>> = vec![
+            Arc::new(metrics.clone()),
+            Arc::new(Trace {
+                name: "outer",
+                skip: None,
+                calls: calls.clone(),
+            }),
+            Arc::new(Trace {
+                name: "inner",
+                skip: Some(2),
+                calls: calls.clone(),
+            }),
+        ];
+        let props = BasicProperties::default();
+        let ctx = DeliveryContext {
+            queue: None,
+            properties: &props,
+            delivery_tag: 1,
+            redelivered: false,
+            received_at: Instant::now(),
+        };
+
+        // 1. consumed inside of all layers
+        let res = around(&layers, &ctx, &1, || async {
+            Ok(ConsumerResult::success(1))
+        })
+        .await;
+        assert!(matches!(res, Ok(ConsumerResult::Success(1))));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["outer.before", "inner.before", "inner.after", "outer.after"]
+        );
+
+        // 2. short-circuited by the inner layer
+        calls.lock().unwrap().clear();
+        let res = around(&layers, &ctx, &2, || async {
+            Ok(ConsumerResult::success(2))
+        })
+        .await;
+        assert!(matches!(res, Ok(ConsumerResult::Failure(0))));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["outer.before", "inner.before", "inner.after", "outer.after"]
+        );
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.deliveries, 2);
+        assert_eq!(snapshot.successes, 1);
+        assert_eq!(snapshot.failures, 1);
+
+        // 3. rejected by `app_id`
+        let filter: Vec<Arc<dyn Middleware<u32, u32>>> = vec![Arc::new(AppIdFilter::new(["pqx"]))];
+        let res = around(&filter, &ctx, &3, || async {
+            Ok(ConsumerResult::success(3))
+        })
+        .await;
+        assert!(res.is_err());
+    }
+}
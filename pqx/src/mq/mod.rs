@@ -3,17 +3,51 @@
 //! date: 2023/05/26 23:52:32 Friday
 //! brief:
 
+pub mod aggregate;
+pub mod cancel;
 pub mod client;
+pub mod codec;
+pub mod compress;
+pub mod confirm;
 pub mod consumer;
+pub mod dedup;
+pub mod delay;
+pub mod headers;
+pub mod memory;
+pub mod middleware;
+pub mod ops;
+pub mod pool;
 pub mod predefined;
 pub mod publish;
+pub mod requeue;
+pub mod retry;
+pub mod rpc;
+pub mod shovel;
 pub mod subscribe;
+pub mod trace;
 
+pub use aggregate::*;
+pub use cancel::*;
 pub use client::*;
+pub use codec::*;
+pub use compress::*;
+pub use confirm::*;
 pub use consumer::*;
+pub use dedup::*;
+pub use delay::*;
+pub use headers::*;
+pub use memory::*;
+pub use middleware::*;
+pub use ops::*;
+pub use pool::*;
 pub use predefined::*;
 pub use publish::*;
+pub use requeue::*;
+pub use retry::*;
+pub use rpc::*;
+pub use shovel::*;
 pub use subscribe::*;
+pub use trace::*;
 
 // ================================================================================================
 // private macros
@@ -102,11 +136,24 @@ macro_rules! impl_set_consumer_no_wait {
     };
 }
 
+// consuming a stream queue requires a prefetch count (`set_prefetch`) and manual acks
+macro_rules! impl_set_stream_offset {
+    () => {
+        pub fn set_stream_offset(&mut self, offset: &crate::mq::StreamOffset) {
+            let arguments = &mut self.consume_args.as_mut().unwrap().arguments;
+
+            arguments.remove(&crate::mq::X_STREAM_OFFSET);
+            arguments.insert(crate::mq::X_STREAM_OFFSET.clone(), offset.field_value());
+        }
+    };
+}
+
 pub(crate) use impl_set_consume_args;
 pub(crate) use impl_set_consumer_exclusive;
 pub(crate) use impl_set_consumer_no_wait;
 pub(crate) use impl_set_consumer_priorities;
 pub(crate) use impl_set_consumer_timeout;
+pub(crate) use impl_set_stream_offset;
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 
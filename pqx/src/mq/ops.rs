@@ -0,0 +1,206 @@
+//! file: ops.rs
+//! author: Jacob Xie
+//! date: 2023/07/25 20:41:16 Tuesday
+//! brief: channel operations behind a trait, implemented by amqprs `Channel` & `MemoryChannel`
+
+use amqprs::channel::{
+    BasicAckArguments, BasicCancelArguments, BasicConsumeArguments, BasicGetArguments,
+    BasicNackArguments, BasicPublishArguments, BasicQosArguments, Channel,
+};
+use amqprs::consumer::AsyncConsumer;
+use amqprs::{BasicProperties, Deliver};
+use async_trait::async_trait;
+
+use super::PublishConfirm;
+use crate::error::PqxResult;
+
+// ================================================================================================
+// Delivery
+//
+// Metadata of a delivered message. amqprs' `Deliver` cannot be built outside of the crate, hence
+// this owned copy is what handlers receive, no matter which channel delivers it.
+// ================================================================================================
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Delivery {
+    pub consumer_tag: String,
+    pub delivery_tag: u64,
+    pub redelivered: bool,
+    pub exchange: String,
+    pub routing_key: String,
+}
+
+impl From<&Deliver> for Delivery {
+    fn from(deliver: &Deliver) -> Self {
+        Self {
+            consumer_tag: deliver.consumer_tag().to_owned(),
+            delivery_tag: deliver.delivery_tag(),
+            redelivered: deliver.redelivered(),
+            exchange: deliver.exchange().to_owned(),
+            routing_key: deliver.routing_key().to_owned(),
+        }
+    }
+}
+
+// ================================================================================================
+// ChannelOps
+//
+// Operations used by `Publisher`, `Subscriber`, `ConsumerWrapper`, `Retry`, `Requeue` and
+// `Shovel`, named after (and taking the same arguments as) the methods of amqprs `Channel`.
+// Implemented by `Channel` for RabbitMQ, and by `MemoryChannel` for unit tests without a broker.
+// ================================================================================================
+
+#[async_trait]
+pub trait ChannelOps: Send + Sync {
+    async fn basic_publish(
+        &self,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+        args: BasicPublishArguments,
+    ) -> PqxResult<()>;
+
+    async fn basic_ack(&self, args: BasicAckArguments) -> PqxResult<()>;
+
+    async fn basic_nack(&self, args: BasicNackArguments) -> PqxResult<()>;
+
+    async fn basic_qos(&self, args: BasicQosArguments) -> PqxResult<()>;
+
+    async fn basic_recover(&self, requeue: bool) -> PqxResult<()>;
+
+    // returns the consumer tag
+    async fn basic_consume(
+        &self,
+        handler: Box<dyn DeliveryHandler>,
+        args: BasicConsumeArguments,
+    ) -> PqxResult<String>;
+
+    async fn basic_cancel(&self, args: BasicCancelArguments) -> PqxResult<()>;
+
+    // fetch a message without consuming, `None` if the queue is empty. The delivery has no
+    // consumer tag
+    async fn basic_get(
+        &self,
+        args: BasicGetArguments,
+    ) -> PqxResult<Option<(Delivery, BasicProperties, Vec<u8>)>>;
+
+    // put the channel in confirm mode
+    async fn confirm_select(&self) -> PqxResult<PublishConfirm>;
+}
+
+// ================================================================================================
+// DeliveryHandler
+//
+// The consumer side of `ChannelOps::basic_consume`, what amqprs `AsyncConsumer` is to `Channel`.
+// ================================================================================================
+
+#[async_trait]
+pub trait DeliveryHandler: Send + Sync {
+    async fn handle(
+        &mut self,
+        channel: &dyn ChannelOps,
+        delivery: Delivery,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    );
+}
+
+// ================================================================================================
+// impl ChannelOps for Channel
+// ================================================================================================
+
+// adapts a `DeliveryHandler` to amqprs
+struct HandlerConsumer(Box<dyn DeliveryHandler>);
+
+#[async_trait]
+impl AsyncConsumer for HandlerConsumer {
+    async fn consume(
+        &mut self,
+        channel: &Channel,
+        deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        let delivery = Delivery::from(&deliver);
+        self.0
+            .handle(channel, delivery, basic_properties, content)
+            .await;
+    }
+}
+
+#[async_trait]
+impl ChannelOps for Channel {
+    async fn basic_publish(
+        &self,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+        args: BasicPublishArguments,
+    ) -> PqxResult<()> {
+        Channel::basic_publish(self, basic_properties, content, args).await?;
+
+        Ok(())
+    }
+
+    async fn basic_ack(&self, args: BasicAckArguments) -> PqxResult<()> {
+        Channel::basic_ack(self, args).await?;
+
+        Ok(())
+    }
+
+    async fn basic_nack(&self, args: BasicNackArguments) -> PqxResult<()> {
+        Channel::basic_nack(self, args).await?;
+
+        Ok(())
+    }
+
+    async fn basic_qos(&self, args: BasicQosArguments) -> PqxResult<()> {
+        Channel::basic_qos(self, args).await?;
+
+        Ok(())
+    }
+
+    async fn basic_recover(&self, requeue: bool) -> PqxResult<()> {
+        Channel::basic_recover(self, requeue).await?;
+
+        Ok(())
+    }
+
+    async fn basic_consume(
+        &self,
+        handler: Box<dyn DeliveryHandler>,
+        args: BasicConsumeArguments,
+    ) -> PqxResult<String> {
+        let consumer_tag = Channel::basic_consume(self, HandlerConsumer(handler), args).await?;
+
+        Ok(consumer_tag)
+    }
+
+    async fn basic_cancel(&self, args: BasicCancelArguments) -> PqxResult<()> {
+        Channel::basic_cancel(self, args).await?;
+
+        Ok(())
+    }
+
+    async fn basic_get(
+        &self,
+        args: BasicGetArguments,
+    ) -> PqxResult<Option<(Delivery, BasicProperties, Vec<u8>)>> {
+        let res = Channel::basic_get(self, args)
+            .await?
+            .map(|(get_ok, props, content)| {
+                let delivery = Delivery {
+                    consumer_tag: String::new(),
+                    delivery_tag: get_ok.delivery_tag(),
+                    redelivered: get_ok.redelivered(),
+                    exchange: get_ok.exchange().to_owned(),
+                    routing_key: get_ok.routing_key().to_owned(),
+                };
+                (delivery, props, content)
+            });
+
+        Ok(res)
+    }
+
+    async fn confirm_select(&self) -> PqxResult<PublishConfirm> {
+        PublishConfirm::select(self).await
+    }
+}
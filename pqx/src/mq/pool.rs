@@ -0,0 +1,169 @@
+//! file: pool.rs
+//! author: Jacob Xie
+//! date: 2023/07/17 21:05:36 Monday
+//! brief: channel pool shared by concurrent publishers
+
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use amqprs::channel::Channel;
+use amqprs::connection::Connection;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use super::PublishConfirm;
+use crate::error::PqxResult;
+
+// ================================================================================================
+// ChannelPool
+//
+// At most `size` channels are checked out at the same time, the others wait for a free one. A
+// channel is returned to the pool when its guard drops, unless it has been closed (e.g. by a
+// channel exception raised by the broker), and a new channel is opened on the next check out.
+//
+// With confirms enabled, every channel checked out is in confirm mode (with its `PublishConfirm`
+// kept along in the pool), and each publish on it has to go through its `PublishConfirm`.
+// ================================================================================================
+
+struct ChannelPoolInner {
+    connection: Connection,
+    size: usize,
+    permits: Arc<Semaphore>,
+    idle: Mutex<Vec<(Channel, Option<PublishConfirm>)>>,
+    confirm: AtomicBool,
+}
+
+#[derive(Clone)]
+pub struct ChannelPool {
+    inner: Arc<ChannelPoolInner>,
+}
+
+impl ChannelPool {
+    pub fn new(connection: Connection, size: usize) -> Self {
+        let size = size.max(1);
+        let inner = ChannelPoolInner {
+            connection,
+            size,
+            permits: Arc::new(Semaphore::new(size)),
+            idle: Mutex::new(Vec::with_capacity(size)),
+            confirm: AtomicBool::new(false),
+        };
+
+        Self {
+            inner: Arc::new(inner),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.size
+    }
+
+    // number of opened channels waiting in the pool
+    pub fn idle(&self) -> usize {
+        self.inner.idle.lock().unwrap().len()
+    }
+
+    // number of channels can be checked out without waiting
+    pub fn available(&self) -> usize {
+        self.inner.permits.available_permits()
+    }
+
+    // channels checked out from now on are in confirm mode, see `PooledChannel::confirm`
+    pub fn enable_confirm(&self) {
+        self.inner.confirm.store(true, Ordering::SeqCst);
+    }
+
+    pub fn confirm_enabled(&self) -> bool {
+        self.inner.confirm.load(Ordering::SeqCst)
+    }
+
+    pub async fn get(&self) -> PqxResult<PooledChannel> {
+        let permit = self
+            .inner
+            .permits
+            .clone()
+            .acquire_owned()
+            .await
+            .map_err(|_| "channel pool is closed")?;
+
+        // drop the channels closed while waiting in the pool
+        let reused = {
+            let mut idle = self.inner.idle.lock().unwrap();
+            loop {
+                match idle.pop() {
+                    Some((c, confirm)) if c.is_open() => break Some((c, confirm)),
+                    Some(_) => continue,
+                    None => break None,
+                }
+            }
+        };
+
+        let (channel, confirm) = match reused {
+            Some(c) => c,
+            None => (self.inner.connection.open_channel(None).await?, None),
+        };
+        // a channel opened before confirms were enabled is put in confirm mode on check out
+        let confirm = match confirm {
+            None if self.confirm_enabled() => Some(PublishConfirm::select(&channel).await?),
+            c => c,
+        };
+
+        Ok(PooledChannel {
+            channel: Some(channel),
+            confirm,
+            pool: self.inner.clone(),
+            _permit: permit,
+        })
+    }
+
+    // close idle channels, and refuse further check outs
+    pub async fn close(&self) -> PqxResult<()> {
+        self.inner.permits.close();
+        let channels = std::mem::take(&mut *self.inner.idle.lock().unwrap());
+        for (c, _) in channels.into_iter().filter(|(c, _)| c.is_open()) {
+            c.close().await?;
+        }
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// PooledChannel
+// ================================================================================================
+
+pub struct PooledChannel {
+    channel: Option<Channel>,
+    confirm: Option<PublishConfirm>,
+    pool: Arc<ChannelPoolInner>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledChannel {
+    // `Some` if the channel is in confirm mode
+    pub fn confirm(&self) -> Option<&PublishConfirm> {
+        self.confirm.as_ref()
+    }
+}
+
+impl Deref for PooledChannel {
+    type Target = Channel;
+
+    fn deref(&self) -> &Self::Target {
+        self.channel.as_ref().unwrap()
+    }
+}
+
+impl Drop for PooledChannel {
+    fn drop(&mut self) {
+        if let Some(c) = self.channel.take() {
+            if c.is_open() && !self.pool.permits.is_closed() {
+                self.pool
+                    .idle
+                    .lock()
+                    .unwrap()
+                    .push((c, self.confirm.take()));
+            }
+        }
+    }
+}
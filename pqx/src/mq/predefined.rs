@@ -5,13 +5,12 @@
 
 use std::str::FromStr;
 
-use amqprs::channel::{
-    BasicAckArguments, BasicNackArguments, BasicPublishArguments, Channel, ExchangeType,
-};
-use amqprs::{BasicProperties, Deliver, FieldName, FieldTable, FieldValue};
+use amqprs::channel::ExchangeType;
+use amqprs::{FieldName, FieldTable, FieldValue};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
+use super::{to_field_value, TraceContext};
 use crate::error::{PqxError, PqxResult};
 
 // ================================================================================================
@@ -31,6 +30,24 @@ pub static X_DELAY: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-delay"
 pub static X_RETRIES: Lazy<FieldName> =
     Lazy::new(|| FieldName::try_from(String::from("x-retries")).unwrap());
 
+pub static X_ATTEMPTS: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from(String::from("x-attempts")).unwrap());
+
+// number of requeues already performed, see `Requeue`
+pub static X_REQUEUES: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-requeues").unwrap());
+
+// why a message is dead-lettered by pqx (rather than by the broker)
+pub static X_DEAD_REASON: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-dead-reason").unwrap());
+
+// the queue a message is dead-lettered from by pqx, which has no `x-death`
+pub static X_DEAD_QUEUE: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-dead-queue").unwrap());
+
+// set by a quorum queue on redeliveries, number of the former failed deliveries
+pub static X_DELIVERY_COUNT: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-delivery-count").unwrap());
+
 pub static X_MATCH: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-match").unwrap());
 
 pub static X_MESSAGE_TTL: Lazy<FieldName> =
@@ -45,22 +62,76 @@ pub static X_DEAD_LETTER_EXCHANGE: Lazy<FieldName> =
 pub static X_DEAD_ROUTING_KEY: Lazy<FieldName> =
     Lazy::new(|| FieldName::try_from("x-dead-routing-key").unwrap());
 
+pub static X_MAX_PRIORITY: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-max-priority").unwrap());
+
+// the queue whose consumer replies an RPC request
+pub static X_REPLIER: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-replier").unwrap());
+
+pub static X_WAIT: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-wait").unwrap());
+
+// epoch milliseconds, when a scheduled message is due
+pub static X_RUN_AT: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-run-at").unwrap());
+
+// the queue a re-delayed (hopped) message belongs to, copies routed to other queues are dropped
+pub static X_HOP_QUEUE: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-hop-queue").unwrap());
+
+pub static X_QUEUE_TYPE: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-queue-type").unwrap());
+
+// quorum queue, dead-letters (or drops) a message after this many failed deliveries
+pub static X_DELIVERY_LIMIT: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-delivery-limit").unwrap());
+
+pub static X_DEAD_LETTER_STRATEGY: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-dead-letter-strategy").unwrap());
+
+// what a full queue does with new messages, see `Overflow`
+pub static X_OVERFLOW: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-overflow").unwrap());
+
+// queue limits, counting ready messages only
+pub static X_MAX_LENGTH: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-max-length").unwrap());
+
+pub static X_MAX_LENGTH_BYTES: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-max-length-bytes").unwrap());
+
+// stream retention, e.g. "7D", "12h"
+pub static X_MAX_AGE: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-max-age").unwrap());
+
+// consume argument of a stream, where to start reading from
+pub static X_STREAM_OFFSET: Lazy<FieldName> =
+    Lazy::new(|| FieldName::try_from("x-stream-offset").unwrap());
+
+// W3C trace context, see `TraceContext`
+pub static TRACEPARENT: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("traceparent").unwrap());
+
+// set by the broker when a message is dead-lettered
+pub static X_DEATH: Lazy<FieldName> = Lazy::new(|| FieldName::try_from("x-death").unwrap());
+
 // ================================================================================================
 // MatchType
 // ================================================================================================
 
-#[derive(Debug, Serialize, Deserialize)]
+// `AnyWithX` & `AllWithX` also take headers beginning with "x-" into account (RabbitMQ 3.10+)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum MatchType {
     Any,
     All,
+    #[serde(rename = "any-with-x")]
+    AnyWithX,
+    #[serde(rename = "all-with-x")]
+    AllWithX,
 }
 
-impl ToString for MatchType {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for MatchType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            MatchType::Any => String::from("any"),
-            MatchType::All => String::from("all"),
+            MatchType::Any => write!(f, "any"),
+            MatchType::All => write!(f, "all"),
+            MatchType::AnyWithX => write!(f, "any-with-x"),
+            MatchType::AllWithX => write!(f, "all-with-x"),
         }
     }
 }
@@ -72,9 +143,215 @@ impl FromStr for MatchType {
         match s {
             "any" => Ok(MatchType::Any),
             "all" => Ok(MatchType::All),
-            _ => Err("match_type: any/all".into()),
+            "any-with-x" => Ok(MatchType::AnyWithX),
+            "all-with-x" => Ok(MatchType::AllWithX),
+            _ => Err("match_type: any/all/any-with-x/all-with-x".into()),
+        }
+    }
+}
+
+// whether a topic `pattern` matches a routing `key`: words are separated by dots, `*` matches
+// exactly one word and `#` matches zero or more words
+pub fn topic_matches(pattern: &str, key: &str) -> bool {
+    fn words_match(pattern: &[&str], key: &[&str]) -> bool {
+        match pattern.split_first() {
+            None => key.is_empty(),
+            Some((&"#", rest)) => (0..=key.len()).any(|i| words_match(rest, &key[i..])),
+            Some((p, rest)) => match key.split_first() {
+                Some((k, key_rest)) => (*p == "*" || p == k) && words_match(rest, key_rest),
+                None => false,
+            },
         }
     }
+
+    let pattern = pattern.split('.').collect::<Vec<_>>();
+    let key = key.split('.').collect::<Vec<_>>();
+
+    words_match(&pattern, &key)
+}
+
+// ================================================================================================
+// QueueType
+// ================================================================================================
+
+// quorum & stream queues are always durable, and support neither priorities nor exclusivity
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueType {
+    #[default]
+    Classic,
+    // replicated, for HA
+    Quorum,
+    // replicated & append-only log, messages stay after being consumed until `x-max-age`
+    Stream,
+}
+
+impl std::fmt::Display for QueueType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueType::Classic => write!(f, "classic"),
+            QueueType::Quorum => write!(f, "quorum"),
+            QueueType::Stream => write!(f, "stream"),
+        }
+    }
+}
+
+impl FromStr for QueueType {
+    type Err = PqxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "classic" => Ok(QueueType::Classic),
+            "quorum" => Ok(QueueType::Quorum),
+            "stream" => Ok(QueueType::Stream),
+            _ => Err("queue_type: classic/quorum/stream".into()),
+        }
+    }
+}
+
+// dead-lettering of quorum queues
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeadLetterStrategy {
+    #[default]
+    AtMostOnce,
+    // requires `x-overflow: reject-publish`
+    AtLeastOnce,
+}
+
+impl std::fmt::Display for DeadLetterStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeadLetterStrategy::AtMostOnce => write!(f, "at-most-once"),
+            DeadLetterStrategy::AtLeastOnce => write!(f, "at-least-once"),
+        }
+    }
+}
+
+// behaviour of a queue reaching `x-max-length` or `x-max-length-bytes`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    // the oldest messages are discarded (or dead-lettered)
+    #[default]
+    DropHead,
+    // new messages are discarded, and nacked to publishers in confirm mode
+    RejectPublish,
+    // same as `RejectPublish`, and the rejected messages are dead-lettered (classic queue only)
+    RejectPublishDlx,
+}
+
+impl std::fmt::Display for Overflow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Overflow::DropHead => write!(f, "drop-head"),
+            Overflow::RejectPublish => write!(f, "reject-publish"),
+            Overflow::RejectPublishDlx => write!(f, "reject-publish-dlx"),
+        }
+    }
+}
+
+impl FromStr for Overflow {
+    type Err = PqxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-head" => Ok(Overflow::DropHead),
+            "reject-publish" => Ok(Overflow::RejectPublish),
+            "reject-publish-dlx" => Ok(Overflow::RejectPublishDlx),
+            _ => Err("overflow: drop-head/reject-publish/reject-publish-dlx".into()),
+        }
+    }
+}
+
+// where a consumer of a stream starts reading from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum StreamOffset {
+    First,
+    Last, // the last chunk of messages
+    #[default]
+    Next, // only messages arriving after subscribing
+    Offset {
+        offset: i64,
+    },
+    Timestamp {
+        timestamp: u64, // seconds since epoch
+    },
+    Interval {
+        interval: String, // e.g. "1h", relative to now
+    },
+}
+
+impl StreamOffset {
+    pub fn field_value(&self) -> FieldValue {
+        match self {
+            StreamOffset::First => FieldValue::from("first"),
+            StreamOffset::Last => FieldValue::from("last"),
+            StreamOffset::Next => FieldValue::from("next"),
+            StreamOffset::Offset { offset } => FieldValue::l(*offset),
+            StreamOffset::Timestamp { timestamp } => FieldValue::T(*timestamp),
+            StreamOffset::Interval { interval } => FieldValue::from(interval.clone()),
+        }
+    }
+}
+
+// ================================================================================================
+// XDeath
+// ================================================================================================
+
+// one entry of the `x-death` header, the most recent dead-lettering comes first
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XDeath {
+    pub reason: String, // rejected/expired/maxlen/delivery_limit
+    pub queue: String,  // the queue where the message was dead-lettered from
+    pub exchange: String,
+    pub routing_keys: Vec<String>,
+    pub count: i64,
+    pub time: Option<u64>, // seconds since epoch
+}
+
+impl TryFrom<&FieldTable> for XDeath {
+    type Error = PqxError;
+
+    fn try_from(ft: &FieldTable) -> Result<Self, Self::Error> {
+        let get_str = |k: &str| match ft.get(&FieldName::try_from(k).unwrap()) {
+            Some(FieldValue::S(s)) => Ok(s.as_ref().clone()),
+            None => Err(PqxError::Custom("x-death entry is missing a field")),
+            _ => Err(PqxError::Custom("x-death entry field is not a string")),
+        };
+
+        let routing_keys = match ft.get(&FieldName::try_from("routing-keys").unwrap()) {
+            Some(FieldValue::A(a)) => Vec::<FieldValue>::from(a.clone())
+                .into_iter()
+                .filter_map(|v| match v {
+                    FieldValue::S(s) => Some(String::from(s)),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        let count = match ft.get(&FieldName::try_from("count").unwrap()) {
+            Some(FieldValue::l(c)) => *c,
+            Some(FieldValue::I(c)) => i64::from(*c),
+            _ => 0,
+        };
+
+        let time = match ft.get(&FieldName::try_from("time").unwrap()) {
+            Some(FieldValue::T(t)) => Some(*t),
+            _ => None,
+        };
+
+        Ok(Self {
+            reason: get_str("reason")?,
+            queue: get_str("queue")?,
+            exchange: get_str("exchange")?,
+            routing_keys,
+            count,
+            time,
+        })
+    }
 }
 
 // ================================================================================================
@@ -83,6 +360,12 @@ impl FromStr for MatchType {
 
 pub struct FieldTableBuilder(FieldTable);
 
+impl Default for FieldTableBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl FieldTableBuilder {
     pub fn new() -> Self {
         Self(FieldTable::new())
@@ -92,13 +375,16 @@ impl FieldTableBuilder {
         self.0
     }
 
-    pub fn x_common_pair(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
-        self.0.insert(
-            FieldName::try_from(key.into()).unwrap(),
-            FieldValue::from(value.into()),
-        );
+    // any serializable value (see `to_field_value`), a key longer than 255 bytes is an error
+    pub fn x_common_pair<V: Serialize>(
+        &mut self,
+        key: impl Into<String>,
+        value: V,
+    ) -> PqxResult<&mut Self> {
+        let key = FieldName::try_from(key.into()).map_err(|_| "key is longer than 255 bytes")?;
+        self.0.insert(key, to_field_value(&value)?);
 
-        self
+        Ok(self)
     }
 
     pub fn x_delayed_type(&mut self, exchange_type: &ExchangeType) -> &mut Self {
@@ -123,6 +409,26 @@ impl FieldTableBuilder {
         self
     }
 
+    // number of retries already performed
+    pub fn x_attempts(&mut self, attempts: i16) -> &mut Self {
+        self.0.insert(X_ATTEMPTS.clone(), FieldValue::s(attempts));
+
+        self
+    }
+
+    pub fn x_requeues(&mut self, requeues: i16) -> &mut Self {
+        self.0.insert(X_REQUEUES.clone(), FieldValue::s(requeues));
+
+        self
+    }
+
+    pub fn x_dead_reason(&mut self, reason: &str) -> &mut Self {
+        self.0
+            .insert(X_DEAD_REASON.clone(), FieldValue::from(reason.to_owned()));
+
+        self
+    }
+
     pub fn x_match(&mut self, t: &MatchType) -> &mut Self {
         self.0
             .insert(X_MATCH.clone(), FieldValue::from(t.to_string()));
@@ -160,6 +466,110 @@ impl FieldTableBuilder {
 
         self
     }
+
+    // dead-lettered messages keep their original routing key
+    pub fn x_dead_letter_exchange_only(&mut self, exchange_name: impl Into<String>) -> &mut Self {
+        self.0.insert(
+            X_DEAD_LETTER_EXCHANGE.clone(),
+            FieldValue::from(exchange_name.into()),
+        );
+
+        self
+    }
+
+    // queue argument, RabbitMQ recommends a value between 1 and 10
+    pub fn x_max_priority(&mut self, max_priority: u8) -> &mut Self {
+        self.0
+            .insert(X_MAX_PRIORITY.clone(), FieldValue::s(max_priority.into()));
+
+        self
+    }
+
+    pub fn x_queue_type(&mut self, queue_type: &QueueType) -> &mut Self {
+        self.0.insert(
+            X_QUEUE_TYPE.clone(),
+            FieldValue::from(queue_type.to_string()),
+        );
+
+        self
+    }
+
+    // quorum queue
+    pub fn x_delivery_limit(&mut self, limit: i64) -> &mut Self {
+        self.0
+            .insert(X_DELIVERY_LIMIT.clone(), FieldValue::l(limit));
+
+        self
+    }
+
+    // quorum queue, "at-least-once" also sets `x-overflow` as required
+    pub fn x_dead_letter_strategy(&mut self, strategy: &DeadLetterStrategy) -> &mut Self {
+        self.0.insert(
+            X_DEAD_LETTER_STRATEGY.clone(),
+            FieldValue::from(strategy.to_string()),
+        );
+        if strategy == &DeadLetterStrategy::AtLeastOnce {
+            self.x_overflow(&Overflow::RejectPublish);
+        }
+
+        self
+    }
+
+    pub fn x_max_length(&mut self, max_length: i64) -> &mut Self {
+        self.0
+            .insert(X_MAX_LENGTH.clone(), FieldValue::l(max_length));
+
+        self
+    }
+
+    pub fn x_max_length_bytes(&mut self, max_length_bytes: i64) -> &mut Self {
+        self.0
+            .insert(X_MAX_LENGTH_BYTES.clone(), FieldValue::l(max_length_bytes));
+
+        self
+    }
+
+    pub fn x_overflow(&mut self, overflow: &Overflow) -> &mut Self {
+        self.0
+            .insert(X_OVERFLOW.clone(), FieldValue::from(overflow.to_string()));
+
+        self
+    }
+
+    // stream queue
+    pub fn x_max_age(&mut self, max_age: impl Into<String>) -> &mut Self {
+        self.0
+            .insert(X_MAX_AGE.clone(), FieldValue::from(max_age.into()));
+
+        self
+    }
+
+    // consume argument of a stream queue
+    pub fn x_stream_offset(&mut self, offset: &StreamOffset) -> &mut Self {
+        self.0.insert(X_STREAM_OFFSET.clone(), offset.field_value());
+
+        self
+    }
+
+    pub fn traceparent(&mut self, ctx: &TraceContext) -> &mut Self {
+        self.0.insert(TRACEPARENT.clone(), FieldValue::from(ctx));
+
+        self
+    }
+
+    pub fn x_replier(&mut self, queue: impl Into<String>) -> &mut Self {
+        self.0
+            .insert(X_REPLIER.clone(), FieldValue::from(queue.into()));
+
+        self
+    }
+
+    // wait: seconds, the delay tier of a wait queue
+    pub fn x_wait(&mut self, wait: i64) -> &mut Self {
+        self.0.insert(X_WAIT.clone(), FieldValue::l(wait));
+
+        self
+    }
 }
 
 impl From<FieldTable> for FieldTableBuilder {
@@ -186,14 +596,15 @@ impl<'a> FieldTableViewer<'a> {
     }
 
     pub fn x_common_pair(&self, key: impl Into<String>) -> PqxResult<String> {
-        match self.0.get(&FieldName::try_from(key.into()).unwrap()) {
+        let key = FieldName::try_from(key.into()).map_err(|_| "key is longer than 255 bytes")?;
+        match self.0.get(&key) {
             Some(FieldValue::S(s)) => Ok(s.as_ref().clone()),
             None => Err("key doesn't exist".into()),
             _ => Err("key is not a string".into()),
         }
     }
 
-    pub fn x_delay(&mut self) -> PqxResult<i32> {
+    pub fn x_delay(&self) -> PqxResult<i32> {
         match self.0.get(&X_DELAY) {
             Some(FieldValue::I(d)) => Ok(*d),
             None => Err("x-delay doesn't exist".into()),
@@ -209,6 +620,40 @@ impl<'a> FieldTableViewer<'a> {
         }
     }
 
+    pub fn x_attempts(&self) -> PqxResult<i16> {
+        match self.0.get(&X_ATTEMPTS) {
+            Some(FieldValue::s(a)) => Ok(*a),
+            None => Err("attempts doesn't exist".into()),
+            _ => Err("attempts is not a `i16`".into()),
+        }
+    }
+
+    pub fn x_requeues(&self) -> PqxResult<i16> {
+        match self.0.get(&X_REQUEUES) {
+            Some(FieldValue::s(r)) => Ok(*r),
+            None => Err("x-requeues doesn't exist".into()),
+            _ => Err("x-requeues is not a `i16`".into()),
+        }
+    }
+
+    pub fn x_dead_reason(&self) -> PqxResult<String> {
+        match self.0.get(&X_DEAD_REASON) {
+            Some(FieldValue::S(s)) => Ok(s.as_ref().clone()),
+            None => Err("x-dead-reason doesn't exist".into()),
+            _ => Err("x-dead-reason is not a string".into()),
+        }
+    }
+
+    pub fn x_delivery_count(&self) -> PqxResult<i64> {
+        match self.0.get(&X_DELIVERY_COUNT) {
+            Some(FieldValue::s(c)) => Ok(i64::from(*c)),
+            Some(FieldValue::I(c)) => Ok(i64::from(*c)),
+            Some(FieldValue::l(c)) => Ok(*c),
+            None => Err("x-delivery-count doesn't exist".into()),
+            _ => Err("x-delivery-count is not an integer".into()),
+        }
+    }
+
     pub fn x_match(&self) -> PqxResult<MatchType> {
         match self.0.get(&X_MATCH) {
             Some(FieldValue::S(s)) => Ok(MatchType::from_str(s.as_ref())?),
@@ -226,13 +671,79 @@ impl<'a> FieldTableViewer<'a> {
     }
 
     pub fn x_consume_ttl(&self) -> PqxResult<i64> {
-        match self.0.get(&X_MESSAGE_TTL) {
+        match self.0.get(&X_CONSUME_TTL) {
             Some(FieldValue::l(t)) => Ok(*t),
             None => Err("x-consume-ttl doesn't exist".into()),
             _ => Err("x-consume-ttl is not a `i64`".into()),
         }
     }
 
+    pub fn x_max_priority(&self) -> PqxResult<u8> {
+        let p = match self.0.get(&X_MAX_PRIORITY) {
+            Some(FieldValue::s(p)) => i64::from(*p),
+            Some(FieldValue::I(p)) => i64::from(*p),
+            Some(FieldValue::l(p)) => *p,
+            None => return Err("x-max-priority doesn't exist".into()),
+            _ => return Err("x-max-priority is not an integer".into()),
+        };
+
+        u8::try_from(p).map_err(|_| "x-max-priority is out of range".into())
+    }
+
+    pub fn x_queue_type(&self) -> PqxResult<QueueType> {
+        match self.0.get(&X_QUEUE_TYPE) {
+            Some(FieldValue::S(s)) => QueueType::from_str(s.as_ref()),
+            // declared without `x-queue-type`
+            None => Ok(QueueType::Classic),
+            _ => Err("x-queue-type is not a string".into()),
+        }
+    }
+
+    pub fn x_delivery_limit(&self) -> PqxResult<i64> {
+        match self.0.get(&X_DELIVERY_LIMIT) {
+            Some(FieldValue::s(l)) => Ok(i64::from(*l)),
+            Some(FieldValue::I(l)) => Ok(i64::from(*l)),
+            Some(FieldValue::l(l)) => Ok(*l),
+            None => Err("x-delivery-limit doesn't exist".into()),
+            _ => Err("x-delivery-limit is not an integer".into()),
+        }
+    }
+
+    pub fn x_max_length(&self) -> PqxResult<i64> {
+        match self.0.get(&X_MAX_LENGTH) {
+            Some(FieldValue::s(l)) => Ok(i64::from(*l)),
+            Some(FieldValue::I(l)) => Ok(i64::from(*l)),
+            Some(FieldValue::l(l)) => Ok(*l),
+            None => Err("x-max-length doesn't exist".into()),
+            _ => Err("x-max-length is not an integer".into()),
+        }
+    }
+
+    pub fn x_overflow(&self) -> PqxResult<Overflow> {
+        match self.0.get(&X_OVERFLOW) {
+            Some(FieldValue::S(s)) => Overflow::from_str(s.as_ref()),
+            // the broker's default
+            None => Ok(Overflow::DropHead),
+            _ => Err("x-overflow is not a string".into()),
+        }
+    }
+
+    pub fn traceparent(&self) -> PqxResult<TraceContext> {
+        match self.0.get(&TRACEPARENT) {
+            Some(FieldValue::S(s)) => TraceContext::from_str(s.as_ref()),
+            None => Err("traceparent doesn't exist".into()),
+            _ => Err("traceparent is not a string".into()),
+        }
+    }
+
+    pub fn x_replier(&self) -> PqxResult<String> {
+        match self.0.get(&X_REPLIER) {
+            Some(FieldValue::S(s)) => Ok(s.as_ref().clone()),
+            None => Err("x-replier doesn't exist".into()),
+            _ => Err("x-replier is not a string".into()),
+        }
+    }
+
     pub fn x_dead_letter_exchange(&self) -> PqxResult<(String, String)> {
         let exchange_name = match self.0.get(&X_DEAD_LETTER_EXCHANGE) {
             Some(FieldValue::S(s)) => Ok(s.as_ref().clone()),
@@ -248,6 +759,20 @@ impl<'a> FieldTableViewer<'a> {
 
         Ok((exchange_name, routing_key))
     }
+
+    pub fn x_death(&self) -> PqxResult<Vec<XDeath>> {
+        match self.0.get(&X_DEATH) {
+            Some(FieldValue::A(a)) => Vec::<FieldValue>::from(a.clone())
+                .iter()
+                .map(|v| match v {
+                    FieldValue::F(ft) => XDeath::try_from(ft),
+                    _ => Err("x-death entry is not a table".into()),
+                })
+                .collect(),
+            None => Err("x-death doesn't exist".into()),
+            _ => Err("x-death is not an array".into()),
+        }
+    }
 }
 
 impl<'a> From<&'a FieldTable> for FieldTableViewer<'a> {
@@ -257,83 +782,46 @@ impl<'a> From<&'a FieldTable> for FieldTableViewer<'a> {
 }
 
 // ================================================================================================
-// Retry
+// Test
 // ================================================================================================
 
-pub struct Retry {
-    exchange: String,
-    routing_key: String,
-    poke: u16,   // secondes
-    retries: u8, // number of retry
-}
-
-impl Retry {
-    pub fn new(exchange: &str, routing_key: &str, poke: u16, retries: u8) -> Self {
-        Self {
-            exchange: exchange.to_owned(),
-            routing_key: routing_key.to_owned(),
-            poke,
-            retries,
-        }
+#[cfg(test)]
+mod test_predefined {
+    use super::*;
+
+    #[test]
+    fn topic_matches_success() {
+        assert!(topic_matches("etl.daily.cn", "etl.daily.cn"));
+        assert!(topic_matches("etl.*.us", "etl.daily.us"));
+        assert!(!topic_matches("etl.*.us", "etl.daily.cn"));
+        assert!(!topic_matches("etl.*.us", "etl.us"));
+        assert!(!topic_matches("etl.*", "etl.daily.us"));
+        assert!(topic_matches("etl.#", "etl"));
+        assert!(topic_matches("etl.#", "etl.daily.us"));
+        assert!(topic_matches("#.us", "etl.daily.us"));
+        assert!(topic_matches("etl.#.us", "etl.us"));
+        assert!(topic_matches("etl.#.us", "etl.a.b.us"));
+        assert!(!topic_matches("etl.#.us", "etl.daily.cn"));
+        assert!(topic_matches("#", ""));
+        assert!(topic_matches("#", "anything.at.all"));
     }
 
-    /// Retry mechanism:
-    /// If retries > 0, then retires -= 1, and publish to delayed-exchange for the next reprocess;
-    /// if retries == 0, then `nack` (if DLX is set, then goes to there).
-    pub async fn retry(
-        &self,
-        channel: &Channel,
-        deliver: Deliver,
-        mut props: BasicProperties,
-        content: Vec<u8>,
-    ) -> PqxResult<()> {
-        // clone or create
-        let mut headers = props.headers().cloned().unwrap_or(FieldTable::new());
-
-        // if x_delay doesn't exist
-        if let None = headers.get(&X_DELAY) {
-            // convert to milliseconds
-            let p = i32::from(self.poke) * 1000;
-            headers.insert(X_DELAY.clone(), FieldValue::I(p));
-        }
-
-        // if x_retries doesn't exist
-        if let None = headers.get(&X_RETRIES) {
-            headers.insert(X_RETRIES.clone(), FieldValue::s(self.retries.into()));
-        }
-
-        // consume 1 retry
-        let retries = FieldTableViewer::new(&headers).x_retries().unwrap() - 1;
-
-        if retries > 0 {
-            // publish to delayed exchange and ack
-            headers.remove(&X_RETRIES);
-            headers.insert(X_RETRIES.clone(), FieldValue::s(retries));
-
-            // publish to delayed-exchange
-            channel
-                .basic_publish(
-                    props.with_headers(headers).finish(),
-                    content,
-                    BasicPublishArguments::new(&self.exchange, &self.routing_key),
-                )
-                .await?;
-
-            // [IMPORTANT] consume message in current queue, otherwise multiple message would be stacked
-            channel
-                .basic_ack(BasicAckArguments::new(deliver.delivery_tag(), false))
-                .await?;
-        } else {
-            // discard message (if DLX is set, then goes to there)
-            channel
-                .basic_nack(BasicNackArguments::new(
-                    deliver.delivery_tag(),
-                    false,
-                    false,
-                ))
-                .await?;
-        }
-
-        Ok(())
+    #[test]
+    fn x_common_pair_success() {
+        let mut ftb = FieldTableBuilder::new();
+        ftb.x_common_pair("unique_key", "h1")
+            .unwrap()
+            .x_common_pair("gpu", true)
+            .unwrap()
+            .x_common_pair("cores", 8)
+            .unwrap();
+        assert!(ftb.x_common_pair("k".repeat(256), "v").is_err());
+        let ft = ftb.finish();
+
+        let viewer = FieldTableViewer::new(&ft);
+        assert_eq!(viewer.x_common_pair("unique_key").unwrap(), "h1");
+        let gpu = ft.get(&FieldName::try_from("gpu").unwrap());
+        assert!(matches!(gpu, Some(FieldValue::t(true))));
+        assert!(ft.get(&FieldName::try_from("cores").unwrap()).is_some());
     }
 }
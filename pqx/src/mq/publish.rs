@@ -3,63 +3,196 @@
 //! date: 2023/05/26 23:54:55 Friday
 //! brief:
 
-use amqprs::channel::{BasicPublishArguments, Channel};
+use amqprs::channel::BasicPublishArguments;
 use amqprs::{BasicProperties, FieldTable};
 use serde::Serialize;
+use tracing::Instrument;
 
+use super::{
+    publish_span, ChannelOps, ChannelPool, Codec, Compression, MessageCodec, PooledChannel,
+    PublishConfirm,
+};
 use crate::error::PqxResult;
 
 // ================================================================================================
-// Publisher
+// MessageState
+//
+// How messages are built, shared by `Publisher` & `SharedPublisher`: the default properties, the
+// codec and the compression.
 // ================================================================================================
 
-#[derive(Clone)]
-pub struct Publisher<'a> {
-    channel: &'a Channel,
-    message_prop: BasicProperties,
+#[derive(Clone, Debug, Default)]
+pub struct MessageState {
+    props: BasicProperties,
+    codec: MessageCodec,
+    compression: Option<(Compression, usize)>,
 }
 
-impl<'a> Publisher<'a> {
-    pub fn new(channel: &'a Channel) -> Self {
-        Self {
-            channel,
-            message_prop: BasicProperties::default(),
-        }
+impl MessageState {
+    // encoding of messages, also set as their `content_type`
+    pub fn set_codec(&mut self, codec: MessageCodec) {
+        self.codec = codec;
+    }
+
+    // compress messages whose encoded size reaches `threshold` bytes, marked by `content_encoding`
+    pub fn set_compression(&mut self, compression: Compression, threshold: usize) {
+        self.compression = Some((compression, threshold));
     }
 
     pub fn set_message_properties(&mut self, message_properties: BasicProperties) {
-        self.message_prop = message_properties;
+        self.props = message_properties;
     }
 
     pub fn set_message_header(&mut self, headers: FieldTable) {
-        self.message_prop.with_headers(headers);
+        self.props.with_headers(headers);
     }
 
     // message ttl
     pub fn set_message_expiration(&mut self, seconds: usize) {
         let ms = (seconds * 1000).to_string();
-        self.message_prop.with_expiration(&ms);
+        self.props.with_expiration(&ms);
+    }
+
+    // only effective if the target queue is declared with `x-max-priority`
+    pub fn set_message_priority(&mut self, priority: u8) {
+        self.props.with_priority(priority);
     }
 
     pub fn set_message_user_id(&mut self, user_id: &str) {
-        self.message_prop.with_user_id(user_id);
+        self.props.with_user_id(user_id);
     }
 
     pub fn set_message_app_id(&mut self, app_id: &str) {
-        self.message_prop.with_app_id(app_id);
+        self.props.with_app_id(app_id);
+    }
+
+    pub fn properties(&self) -> &BasicProperties {
+        &self.props
+    }
+
+    // encode (and compress) a message, and mark its `content_type` (and `content_encoding`)
+    pub fn encode<M: Serialize>(&self, msg: &M, props: &mut BasicProperties) -> PqxResult<Vec<u8>> {
+        let mut content = self.codec.encode(msg)?;
+        props.with_content_type(self.codec.content_type());
+
+        if let Some((c, threshold)) = self.compression {
+            if content.len() >= threshold {
+                content = c.compress(&content)?;
+                props.with_content_encoding(c.content_encoding());
+            }
+        }
+
+        Ok(content)
+    }
+}
+
+// setters of `MessageState`, by the `message` field of a publisher
+macro_rules! impl_set_message {
+    () => {
+        pub fn message_state(&self) -> &MessageState {
+            &self.message
+        }
+
+        pub fn set_codec(&mut self, codec: MessageCodec) {
+            self.message.set_codec(codec);
+        }
+
+        pub fn set_compression(&mut self, compression: Compression, threshold: usize) {
+            self.message.set_compression(compression, threshold);
+        }
+
+        pub fn set_message_properties(&mut self, message_properties: BasicProperties) {
+            self.message.set_message_properties(message_properties);
+        }
+
+        pub fn set_message_header(&mut self, headers: FieldTable) {
+            self.message.set_message_header(headers);
+        }
+
+        pub fn set_message_expiration(&mut self, seconds: usize) {
+            self.message.set_message_expiration(seconds);
+        }
+
+        pub fn set_message_priority(&mut self, priority: u8) {
+            self.message.set_message_priority(priority);
+        }
+
+        pub fn set_message_user_id(&mut self, user_id: &str) {
+            self.message.set_message_user_id(user_id);
+        }
+
+        pub fn set_message_app_id(&mut self, app_id: &str) {
+            self.message.set_message_app_id(app_id);
+        }
+    };
+}
+
+// ================================================================================================
+// Publisher
+// ================================================================================================
+
+#[derive(Clone)]
+pub struct Publisher<'a> {
+    channel: &'a dyn ChannelOps,
+    message: MessageState,
+    confirm: Option<PublishConfirm>,
+}
+
+impl<'a> Publisher<'a> {
+    pub fn new(channel: &'a dyn ChannelOps) -> Self {
+        Self {
+            channel,
+            message: MessageState::default(),
+            confirm: None,
+        }
+    }
+
+    // each publish waits for the broker's confirm, and fails if the message is rejected (e.g. by
+    // a full queue with `x-overflow: reject-publish`). Replaces the callback of the channel
+    pub async fn enable_confirm(&mut self) -> PqxResult<()> {
+        self.confirm = Some(self.channel.confirm_select().await?);
+
+        Ok(())
     }
 
+    // traced by `traceparent`
+    async fn send(
+        &self,
+        mut props: BasicProperties,
+        content: Vec<u8>,
+        args: BasicPublishArguments,
+    ) -> PqxResult<()> {
+        let span = publish_span(&mut props, &args.exchange, &args.routing_key);
+        let fut = async {
+            match &self.confirm {
+                Some(c) => {
+                    c.publish(|| async {
+                        self.channel.basic_publish(props, content, args).await?;
+                        Ok(())
+                    })
+                    .await
+                }
+                None => {
+                    self.channel.basic_publish(props, content, args).await?;
+                    Ok(())
+                }
+            }
+        };
+
+        fut.instrument(span).await
+    }
+
+    impl_set_message!();
+
     pub async fn publish<M>(&self, exchange: &str, rout: &str, msg: M) -> PqxResult<()>
     where
         M: Serialize,
     {
         let args = BasicPublishArguments::new(exchange, rout);
-        let content = serde_json::to_vec(&msg)?;
-        self.channel
-            .basic_publish(self.message_prop.clone(), content, args)
-            .await?;
+        let mut props = self.message.properties().clone();
+        let content = self.message.encode(&msg, &mut props)?;
 
-        Ok(())
+        self.send(props, content, args).await
     }
 
     pub async fn publish_with_props<M>(
@@ -67,16 +200,15 @@ impl<'a> Publisher<'a> {
         exchange: &str,
         rout: &str,
         msg: M,
-        props: BasicProperties,
+        mut props: BasicProperties,
     ) -> PqxResult<()>
     where
         M: Serialize,
     {
         let args = BasicPublishArguments::new(exchange, rout);
-        let content = serde_json::to_vec(&msg)?;
-        self.channel.basic_publish(props, content, args).await?;
+        let content = self.message.encode(&msg, &mut props)?;
 
-        Ok(())
+        self.send(props, content, args).await
     }
 
     pub async fn publish_with_headers<M>(
@@ -90,14 +222,141 @@ impl<'a> Publisher<'a> {
         M: Serialize,
     {
         let args = BasicPublishArguments::new(exchange, rout);
-        let content = serde_json::to_vec(&msg)?;
-        let props = BasicProperties::default().with_headers(headers).finish();
-        self.channel.basic_publish(props, content, args).await?;
+        let mut props = BasicProperties::default().with_headers(headers).finish();
+        let content = self.message.encode(&msg, &mut props)?;
 
-        Ok(())
+        self.send(props, content, args).await
     }
 
     pub async fn block(&self, secs: u64) {
         tokio::time::sleep(tokio::time::Duration::from_secs(secs)).await;
     }
 }
+
+// ================================================================================================
+// SharedPublisher
+//
+// Owned version of `Publisher`, cheap to clone and to move into tokio tasks. Each publish checks
+// out a channel from the pool, hence concurrent publishes never share a channel. A publish failed
+// on a channel closed by the broker is retried once on a new channel. With confirms enabled (on
+// the pool), a publish fails if the message is rejected, or if its channel is closed before it is
+// confirmed (then retried once as well).
+// ================================================================================================
+
+#[derive(Clone)]
+pub struct SharedPublisher {
+    pool: ChannelPool,
+    message: MessageState,
+}
+
+impl SharedPublisher {
+    pub fn new(pool: ChannelPool) -> Self {
+        Self {
+            pool,
+            message: MessageState::default(),
+        }
+    }
+
+    pub fn pool(&self) -> &ChannelPool {
+        &self.pool
+    }
+
+    // each publish waits for the broker's confirm, like `Publisher::enable_confirm`. Enabled on
+    // the pool, hence for every publisher sharing it
+    pub async fn enable_confirm(&mut self) -> PqxResult<()> {
+        self.pool.enable_confirm();
+
+        Ok(())
+    }
+
+    impl_set_message!();
+
+    async fn publish_on(
+        chan: &PooledChannel,
+        props: BasicProperties,
+        content: Vec<u8>,
+        args: BasicPublishArguments,
+    ) -> PqxResult<()> {
+        match chan.confirm() {
+            Some(c) => {
+                c.publish(|| async {
+                    chan.basic_publish(props, content, args).await?;
+                    Ok(())
+                })
+                .await
+            }
+            None => {
+                chan.basic_publish(props, content, args).await?;
+                Ok(())
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        exchange: &str,
+        rout: &str,
+        mut props: BasicProperties,
+        content: Vec<u8>,
+    ) -> PqxResult<()> {
+        let args = BasicPublishArguments::new(exchange, rout);
+        let span = publish_span(&mut props, exchange, rout);
+
+        let fut = async {
+            let chan = self.pool.get().await?;
+            match Self::publish_on(&chan, props.clone(), content.clone(), args.clone()).await {
+                Ok(_) => Ok(()),
+                Err(_) if !chan.is_open() => {
+                    // the closed channel is discarded when returned
+                    drop(chan);
+                    let chan = self.pool.get().await?;
+                    Self::publish_on(&chan, props, content, args).await
+                }
+                Err(e) => Err(e),
+            }
+        };
+
+        fut.instrument(span).await
+    }
+
+    pub async fn publish<M>(&self, exchange: &str, rout: &str, msg: M) -> PqxResult<()>
+    where
+        M: Serialize,
+    {
+        let mut props = self.message.properties().clone();
+        let content = self.message.encode(&msg, &mut props)?;
+
+        self.send(exchange, rout, props, content).await
+    }
+
+    pub async fn publish_with_props<M>(
+        &self,
+        exchange: &str,
+        rout: &str,
+        msg: M,
+        mut props: BasicProperties,
+    ) -> PqxResult<()>
+    where
+        M: Serialize,
+    {
+        let content = self.message.encode(&msg, &mut props)?;
+
+        self.send(exchange, rout, props, content).await
+    }
+
+    pub async fn publish_with_headers<M>(
+        &self,
+        exchange: &str,
+        rout: &str,
+        msg: M,
+        headers: FieldTable,
+    ) -> PqxResult<()>
+    where
+        M: Serialize,
+    {
+        let mut props = BasicProperties::default().with_headers(headers).finish();
+        let content = self.message.encode(&msg, &mut props)?;
+
+        self.send(exchange, rout, props, content).await
+    }
+}
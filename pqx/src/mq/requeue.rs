@@ -0,0 +1,212 @@
+//! file: requeue.rs
+//! author: Jacob Xie
+//! date: 2023/07/23 10:12:36 Sunday
+//! brief: bounded requeue of failed messages
+
+use std::time::Duration;
+
+use amqprs::channel::{BasicAckArguments, BasicNackArguments, BasicPublishArguments};
+use amqprs::BasicProperties;
+
+use super::{ChannelOps, Delivery, PqxHeaders};
+use crate::error::PqxResult;
+
+// ================================================================================================
+// Requeue
+//
+// A `ConsumerResult::Failure` puts the message back to its queue, which spins forever if the
+// failure is deterministic. `Requeue` bounds it: the number of requeues already performed is the
+// larger of the `x-requeues` header (set by pqx) and the `x-delivery-count` header (set by quorum
+// queues); once it reaches `max_requeues`, the message is published to the dead letter exchange
+// (usually the DLX of its queue) with an `x-dead-reason` header instead. The `redelivered` flag is
+// not counted, since the broker also sets it on redeliveries which are not requeues (e.g. a closed
+// channel). Since the broker adds no `x-death` to it, the `x-dead-queue` header tells where it
+// comes from.
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct Requeue {
+    max_requeues: u8,
+    delay: Option<Duration>,
+    dead_letter: (String, String), // (exchange, routing key)
+}
+
+impl Requeue {
+    pub fn new(max_requeues: u8, dead_letter_exchange: &str, routing_key: &str) -> Self {
+        Self {
+            max_requeues,
+            delay: None,
+            dead_letter: (dead_letter_exchange.to_owned(), routing_key.to_owned()),
+        }
+    }
+
+    // wait before each requeue, the delivery stays unacked meanwhile (taking a prefetch slot)
+    pub fn set_delay(&mut self, delay: Duration) -> &mut Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn max_requeues(&self) -> u8 {
+        self.max_requeues
+    }
+
+    // number of requeues already performed
+    pub fn requeues(props: &BasicProperties) -> u32 {
+        let headers = PqxHeaders::from_props(props).unwrap_or_default();
+        let by_header = u32::try_from(headers.x_requeues.unwrap_or(0)).unwrap_or(0);
+        let by_count = u32::try_from(headers.x_delivery_count.unwrap_or(0)).unwrap_or(u32::MAX);
+
+        by_header.max(by_count)
+    }
+
+    /// Requeue mechanism:
+    /// If requeues < max_requeues, then wait for the delay (if any) and put the message back to
+    /// `queue` with `x-requeues` += 1 (published by the default exchange, then acked), or `nack`
+    /// with requeue if the queue is unknown (only bounded by `x-delivery-count` of quorum queues);
+    /// otherwise dead-letter it with `x-dead-reason`.
+    /// Returns `true` if the message has been requeued.
+    pub async fn requeue(
+        &self,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
+        mut props: BasicProperties,
+        content: Vec<u8>,
+        queue: Option<&str>,
+        reason: &str,
+    ) -> PqxResult<bool> {
+        let requeues = Self::requeues(&props);
+        let tag = deliver.delivery_tag;
+
+        if requeues >= u32::from(self.max_requeues) {
+            let reason = format!("requeued {} times, last failure: {}", requeues, reason);
+            self.dead(channel, tag, props, content, queue, &reason)
+                .await?;
+            return Ok(false);
+        }
+
+        if let Some(d) = self.delay {
+            tokio::time::sleep(d).await;
+        }
+
+        match queue {
+            Some(q) => {
+                let headers = PqxHeaders {
+                    x_requeues: Some(i16::try_from(requeues + 1).unwrap_or(i16::MAX)),
+                    ..Default::default()
+                };
+                headers.apply(&mut props)?;
+
+                channel
+                    .basic_publish(props, content, BasicPublishArguments::new("", q))
+                    .await?;
+                channel
+                    .basic_ack(BasicAckArguments::new(tag, false))
+                    .await?;
+            }
+            None => {
+                channel
+                    .basic_nack(BasicNackArguments::new(tag, false, true))
+                    .await?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn dead(
+        &self,
+        channel: &dyn ChannelOps,
+        tag: u64,
+        mut props: BasicProperties,
+        content: Vec<u8>,
+        queue: Option<&str>,
+        reason: &str,
+    ) -> PqxResult<()> {
+        let headers = PqxHeaders {
+            x_dead_reason: Some(reason.to_owned()),
+            x_dead_queue: queue.map(str::to_owned),
+            ..Default::default()
+        };
+        headers.apply(&mut props)?;
+
+        let (exchange, rout) = &self.dead_letter;
+        channel
+            .basic_publish(props, content, BasicPublishArguments::new(exchange, rout))
+            .await?;
+        channel
+            .basic_ack(BasicAckArguments::new(tag, false))
+            .await?;
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_requeue {
+    use amqprs::channel::BasicGetArguments;
+    use amqprs::FieldValue;
+
+    use super::*;
+    use crate::mq::{FieldTableBuilder, MemoryBroker, X_DELIVERY_COUNT};
+
+    #[test]
+    fn requeues_success() {
+        let mut props = BasicProperties::default();
+        assert_eq!(Requeue::requeues(&props), 0);
+
+        // republished by pqx
+        let mut headers = FieldTableBuilder::new();
+        headers.x_requeues(3);
+        props.with_headers(headers.finish());
+        assert_eq!(Requeue::requeues(&props), 3);
+
+        // redelivered by a quorum queue
+        let mut headers = props.headers().cloned().unwrap();
+        headers.insert(X_DELIVERY_COUNT.clone(), FieldValue::l(5));
+        props.with_headers(headers);
+        assert_eq!(Requeue::requeues(&props), 5);
+    }
+
+    #[tokio::test]
+    async fn requeue_redelivered_success() {
+        let broker = MemoryBroker::new();
+        broker.declare_queue("que").unwrap();
+        broker
+            .channel()
+            .basic_publish(
+                BasicProperties::default(),
+                b"msg".to_vec(),
+                BasicPublishArguments::new("", "que"),
+            )
+            .await
+            .unwrap();
+
+        // redelivered after its channel is closed, which is not a requeue
+        let chan = broker.channel();
+        chan.basic_get(BasicGetArguments::new("que"))
+            .await
+            .unwrap()
+            .unwrap();
+        chan.close();
+        let chan = broker.channel();
+        let (deliver, props, content) = chan
+            .basic_get(BasicGetArguments::new("que"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(deliver.redelivered);
+
+        let requeue = Requeue::new(1, "dlx", "");
+        let requeued = requeue
+            .requeue(&chan, deliver, props, content, Some("que"), "failed")
+            .await
+            .unwrap();
+        assert!(requeued);
+        let msg = broker.get("que").unwrap();
+        assert_eq!(Requeue::requeues(&msg.props), 1);
+    }
+}
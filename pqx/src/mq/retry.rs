@@ -0,0 +1,425 @@
+//! file: retry.rs
+//! author: Jacob Xie
+//! date: 2023/07/08 10:21:37 Saturday
+//! brief:
+
+use std::time::Duration;
+
+use amqprs::channel::{BasicAckArguments, BasicNackArguments, BasicPublishArguments};
+use amqprs::BasicProperties;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{ChannelOps, Delivery, PqxHeaders};
+use crate::error::PqxResult;
+
+// ================================================================================================
+// Jitter
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Jitter {
+    #[default]
+    None,
+    // random between 0 and the computed delay
+    Full,
+    // random between `initial` and 3 times the previous delay, capped by `max`
+    Decorrelated,
+}
+
+// ================================================================================================
+// RetryPolicy
+//
+// All durations are in seconds, and `attempt` starts from 1 (the first retry).
+// ================================================================================================
+
+fn default_factor() -> u32 {
+    2
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RetryPolicy {
+    // delay
+    Fixed {
+        delay: u32,
+    },
+    // initial + step * (attempt - 1), capped by `max`
+    Linear {
+        initial: u32,
+        step: u32,
+        max: Option<u32>,
+    },
+    // initial * factor ^ (attempt - 1), capped by `max`
+    Exponential {
+        initial: u32,
+        #[serde(default = "default_factor")]
+        factor: u32,
+        max: u32,
+        #[serde(default)]
+        jitter: Jitter,
+    },
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::Fixed { delay: 10 }
+    }
+}
+
+impl RetryPolicy {
+    pub fn fixed(delay: u32) -> Self {
+        Self::Fixed { delay }
+    }
+
+    pub fn linear(initial: u32, step: u32, max: Option<u32>) -> Self {
+        Self::Linear { initial, step, max }
+    }
+
+    pub fn exponential(initial: u32, factor: u32, max: u32, jitter: Jitter) -> Self {
+        Self::Exponential {
+            initial,
+            factor,
+            max,
+            jitter,
+        }
+    }
+
+    /// Delay before the `attempt`-th retry. `prev` is the delay used by the former attempt,
+    /// which is only required by [`Jitter::Decorrelated`].
+    pub fn next_delay(&self, attempt: u32, prev: Option<Duration>) -> Duration {
+        let attempt = attempt.max(1);
+
+        let ms = match *self {
+            RetryPolicy::Fixed { delay } => secs_to_ms(delay),
+            RetryPolicy::Linear { initial, step, max } => {
+                let d = secs_to_ms(initial)
+                    .saturating_add(secs_to_ms(step).saturating_mul(u64::from(attempt - 1)));
+                match max {
+                    Some(m) => d.min(secs_to_ms(m)),
+                    None => d,
+                }
+            }
+            RetryPolicy::Exponential {
+                initial,
+                factor,
+                max,
+                jitter,
+            } => {
+                let initial = secs_to_ms(initial);
+                let max = secs_to_ms(max);
+                let d = u64::from(factor)
+                    .checked_pow(attempt - 1)
+                    .and_then(|f| f.checked_mul(initial))
+                    .unwrap_or(u64::MAX)
+                    .min(max);
+
+                match jitter {
+                    Jitter::None => d,
+                    Jitter::Full => rand::thread_rng().gen_range(0..=d),
+                    Jitter::Decorrelated => {
+                        let prev = prev
+                            .map(|p| u64::try_from(p.as_millis()).unwrap_or(u64::MAX))
+                            .unwrap_or(initial)
+                            .max(initial);
+                        let upper = prev.saturating_mul(3).min(max).max(initial);
+                        rand::thread_rng().gen_range(initial..=upper)
+                    }
+                }
+            }
+        };
+
+        Duration::from_millis(ms)
+    }
+}
+
+fn secs_to_ms(secs: u32) -> u64 {
+    u64::from(secs) * 1000
+}
+
+// ================================================================================================
+// RetryBackend
+//
+// 1. `DelayedExchange`: based on plugin `rabbitmq_delayed_message_exchange`, the message is held
+//    by the exchange for `x-delay` milliseconds;
+// 2. `WaitQueues`: no plugin required. A headers exchange routes the message (by `x-wait`) to a
+//    wait queue, whose `x-message-ttl` is the delay tier. Expired messages are dead-lettered back
+//    to the work exchange with their original routing key and headers. Since a wait queue has a
+//    fixed TTL, the delay computed by the policy is rounded up to the nearest tier (or the largest
+//    one).
+// ================================================================================================
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RetryBackend {
+    DelayedExchange {
+        exchange: String,
+    },
+    WaitQueues {
+        exchange: String,
+        tiers: Vec<u32>, // seconds
+    },
+}
+
+impl RetryBackend {
+    pub fn delayed_exchange(exchange: impl Into<String>) -> Self {
+        Self::DelayedExchange {
+            exchange: exchange.into(),
+        }
+    }
+
+    pub fn wait_queues(exchange: impl Into<String>, tiers: impl IntoIterator<Item = u32>) -> Self {
+        let mut tiers = tiers.into_iter().collect::<Vec<_>>();
+        tiers.sort_unstable();
+        tiers.dedup();
+
+        Self::WaitQueues {
+            exchange: exchange.into(),
+            tiers,
+        }
+    }
+
+    pub fn exchange(&self) -> &str {
+        match self {
+            RetryBackend::DelayedExchange { exchange } => exchange,
+            RetryBackend::WaitQueues { exchange, .. } => exchange,
+        }
+    }
+
+    // the smallest tier which is not less than `delay`, otherwise the largest tier
+    pub fn wait_tier(tiers: &[u32], delay: Duration) -> Option<u32> {
+        tiers
+            .iter()
+            .find(|t| secs_to_ms(**t) >= u64::try_from(delay.as_millis()).unwrap_or(u64::MAX))
+            .or(tiers.last())
+            .copied()
+    }
+
+    pub fn wait_queue_name(exchange: &str, tier: u32) -> String {
+        format!("{}.{}s", exchange, tier)
+    }
+}
+
+// ================================================================================================
+// Retry
+// ================================================================================================
+
+pub struct Retry {
+    backend: RetryBackend,
+    routing_key: String,
+    keep_routing_key: bool,
+    policy: RetryPolicy,
+    retries: u8, // number of retry
+}
+
+impl Retry {
+    pub fn new(backend: RetryBackend, routing_key: &str, policy: RetryPolicy, retries: u8) -> Self {
+        Self {
+            backend,
+            routing_key: routing_key.to_owned(),
+            keep_routing_key: false,
+            policy,
+            retries,
+        }
+    }
+
+    // republish with the routing key of the delivery instead of `routing_key`, e.g. a message
+    // published to a topic exchange is retried by the same key
+    pub fn keep_routing_key(&mut self) -> &mut Self {
+        self.keep_routing_key = true;
+        self
+    }
+
+    pub fn backend(&self) -> &RetryBackend {
+        &self.backend
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Retry mechanism:
+    /// If retries > 0, then retires -= 1, attempts += 1, and publish to the retry backend with a
+    /// delay computed by the policy for the next reprocess;
+    /// if retries == 0, then `nack` (if DLX is set, then goes to there).
+    /// Returns `true` if the message has been republished for another attempt.
+    /// `props` and `content` are republished as delivered, a compressed message keeps its
+    /// `content_encoding`. The retry is pinned to `queue` (`x-hop-queue`), so that the copies
+    /// routed to the other matching queues are dropped.
+    pub async fn retry(
+        &self,
+        channel: &dyn ChannelOps,
+        deliver: Delivery,
+        mut props: BasicProperties,
+        content: Vec<u8>,
+        queue: Option<&str>,
+    ) -> PqxResult<bool> {
+        let mut headers = PqxHeaders::from_props(&props)?;
+
+        // consume 1 retry, `retries` if x-retries doesn't exist
+        let retries = headers.x_retries.unwrap_or(self.retries.into()) - 1;
+
+        if retries > 0 {
+            // publish to delayed exchange and ack
+            self.prepare_headers(&mut headers, retries, queue)?;
+            headers.apply(&mut props)?;
+
+            // publish to delayed-exchange or wait-exchange
+            let routing_key = if self.keep_routing_key {
+                &deliver.routing_key
+            } else {
+                &self.routing_key
+            };
+            channel
+                .basic_publish(
+                    props,
+                    content,
+                    BasicPublishArguments::new(self.backend.exchange(), routing_key),
+                )
+                .await?;
+
+            // [IMPORTANT] consume message in current queue, otherwise multiple message would be stacked
+            channel
+                .basic_ack(BasicAckArguments::new(deliver.delivery_tag, false))
+                .await?;
+        } else {
+            // discard message (if DLX is set, then goes to there)
+            channel
+                .basic_nack(BasicNackArguments::new(deliver.delivery_tag, false, false))
+                .await?;
+        }
+
+        Ok(retries > 0)
+    }
+
+    // update `x-retries`, `x-attempts`, `x-delay` (and `x-wait`) for the next reprocess, pinned
+    // to the consuming queue by `x-hop-queue`
+    fn prepare_headers(
+        &self,
+        headers: &mut PqxHeaders,
+        retries: i16,
+        queue: Option<&str>,
+    ) -> PqxResult<()> {
+        let attempt = headers.x_attempts.unwrap_or(0).saturating_add(1);
+        let prev = headers
+            .x_delay
+            .and_then(|d| u64::try_from(d).ok())
+            .map(Duration::from_millis);
+
+        let mut delay = self
+            .policy
+            .next_delay(u32::try_from(attempt).unwrap_or(1), prev);
+
+        // route to the wait queue of the chosen tier
+        if let RetryBackend::WaitQueues { tiers, .. } = &self.backend {
+            let tier = RetryBackend::wait_tier(tiers, delay).ok_or("wait queue tiers are empty")?;
+            headers.x_wait = Some(tier.into());
+            delay = Duration::from_secs(tier.into());
+        }
+
+        // `x-delay` is a `i32` in milliseconds
+        let delay = i32::try_from(delay.as_millis()).unwrap_or(i32::MAX);
+
+        headers.x_retries = Some(retries);
+        headers.x_attempts = Some(attempt);
+        headers.x_delay = Some(delay);
+        if let Some(q) = queue {
+            headers.x_hop_queue = Some(q.to_owned());
+        }
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_retry {
+    use super::*;
+
+    fn ms(d: Duration) -> u128 {
+        d.as_millis()
+    }
+
+    #[test]
+    fn fixed_success() {
+        let p = RetryPolicy::fixed(5);
+
+        assert_eq!(ms(p.next_delay(1, None)), 5_000);
+        assert_eq!(ms(p.next_delay(7, None)), 5_000);
+    }
+
+    #[test]
+    fn linear_success() {
+        let p = RetryPolicy::linear(5, 10, Some(30));
+
+        assert_eq!(ms(p.next_delay(1, None)), 5_000);
+        assert_eq!(ms(p.next_delay(2, None)), 15_000);
+        assert_eq!(ms(p.next_delay(3, None)), 25_000);
+        assert_eq!(ms(p.next_delay(4, None)), 30_000);
+    }
+
+    #[test]
+    fn exponential_success() {
+        let p = RetryPolicy::exponential(1, 2, 60, Jitter::None);
+
+        assert_eq!(ms(p.next_delay(1, None)), 1_000);
+        assert_eq!(ms(p.next_delay(2, None)), 2_000);
+        assert_eq!(ms(p.next_delay(5, None)), 16_000);
+        assert_eq!(ms(p.next_delay(7, None)), 60_000);
+        // no overflow
+        assert_eq!(ms(p.next_delay(200, None)), 60_000);
+    }
+
+    #[test]
+    fn jitter_success() {
+        let full = RetryPolicy::exponential(1, 2, 60, Jitter::Full);
+        let decorrelated = RetryPolicy::exponential(1, 2, 60, Jitter::Decorrelated);
+
+        for attempt in 1..20 {
+            let d = ms(full.next_delay(attempt, None));
+            assert!(d <= 60_000);
+
+            let prev = Duration::from_secs(4);
+            let d = ms(decorrelated.next_delay(attempt, Some(prev)));
+            assert!((1_000..=12_000).contains(&d));
+        }
+    }
+
+    #[test]
+    fn wait_tier_success() {
+        let tiers = [10, 60, 300];
+
+        assert_eq!(
+            RetryBackend::wait_tier(&tiers, Duration::from_secs(1)),
+            Some(10)
+        );
+        assert_eq!(
+            RetryBackend::wait_tier(&tiers, Duration::from_secs(10)),
+            Some(10)
+        );
+        assert_eq!(
+            RetryBackend::wait_tier(&tiers, Duration::from_millis(10_001)),
+            Some(60)
+        );
+        assert_eq!(
+            RetryBackend::wait_tier(&tiers, Duration::from_secs(3600)),
+            Some(300)
+        );
+        assert_eq!(RetryBackend::wait_tier(&[], Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn serde_success() {
+        let p: RetryPolicy = serde_json::from_str(
+            r#"{"type": "exponential", "initial": 2, "max": 120, "jitter": "full"}"#,
+        )
+        .unwrap();
+        assert_eq!(p, RetryPolicy::exponential(2, 2, 120, Jitter::Full));
+
+        let p: RetryPolicy = serde_json::from_str(r#"{"type": "fixed", "delay": 3}"#).unwrap();
+        assert_eq!(p, RetryPolicy::fixed(3));
+    }
+}
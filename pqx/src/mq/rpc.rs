@@ -0,0 +1,436 @@
+//! file: rpc.rs
+//! author: Jacob Xie
+//! date: 2023/07/12 21:36:18 Wednesday
+//! brief: request/reply on top of publishing and consuming
+
+use std::collections::HashMap;
+use std::future::IntoFuture;
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use amqprs::channel::*;
+use amqprs::consumer::AsyncConsumer;
+use amqprs::{BasicProperties, Deliver};
+use async_trait::async_trait;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::{decode_content, Codec, MessageCodec, PqxHeaders};
+use crate::error::PqxResult;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+// pseudo-queue of RabbitMQ's direct reply-to
+pub const DIRECT_REPLY_TO: &str = "amq.rabbitmq.reply-to";
+
+// ================================================================================================
+// ReplyMode
+// ================================================================================================
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ReplyMode {
+    // no queue declared, replies are pushed to the requesting channel directly
+    #[default]
+    DirectReplyTo,
+    // a server-named exclusive queue, deleted once the connection closes
+    ExclusiveQueue,
+}
+
+// ================================================================================================
+// RpcReply
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct RpcReply {
+    pub correlation_id: String,
+    pub replier: Option<String>, // the queue whose consumer replied
+    pub props: BasicProperties,
+    pub content: Vec<u8>,
+}
+
+impl RpcReply {
+    pub fn json<T: DeserializeOwned>(&self) -> PqxResult<T> {
+        Ok(serde_json::from_slice(&self.content)?)
+    }
+
+    // decoded by its `content_encoding` & `content_type`
+    pub fn decode<T: DeserializeOwned>(&self) -> PqxResult<T> {
+        decode_content(&self.props, &self.content)
+    }
+}
+
+// ================================================================================================
+// Pending
+//
+// correlation_id -> sender, a request can be replied more than once (routed to multiple queues)
+// ================================================================================================
+
+type Pending = Arc<Mutex<HashMap<String, UnboundedSender<RpcReply>>>>;
+
+#[derive(Clone)]
+struct ReplyConsumer {
+    pending: Pending,
+}
+
+#[async_trait]
+impl AsyncConsumer for ReplyConsumer {
+    async fn consume(
+        &mut self,
+        _channel: &Channel,
+        _deliver: Deliver,
+        basic_properties: BasicProperties,
+        content: Vec<u8>,
+    ) {
+        // replies without a known `correlation_id` are dropped
+        let correlation_id = match basic_properties.correlation_id() {
+            Some(c) => c.clone(),
+            None => return,
+        };
+        let replier = PqxHeaders::from_props(&basic_properties)
+            .ok()
+            .and_then(|h| h.x_replier);
+
+        let pending = self.pending.lock().unwrap();
+        if let Some(tx) = pending.get(&correlation_id) {
+            let _ = tx.send(RpcReply {
+                correlation_id,
+                replier,
+                props: basic_properties,
+                content,
+            });
+        }
+    }
+}
+
+// ================================================================================================
+// RpcFuture
+//
+// Resolves to the first reply of a request, or an error if timeout. Await it directly for the
+// deserialized reply. The request is forgotten once the future is dropped, received or not.
+// ================================================================================================
+
+pub struct RpcFuture<T> {
+    correlation_id: String,
+    receiver: UnboundedReceiver<RpcReply>,
+    pending: Pending,
+    timeout: Duration,
+    _reply_type: PhantomData<T>,
+}
+
+impl<T> RpcFuture<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    pub fn correlation_id(&self) -> &str {
+        &self.correlation_id
+    }
+
+    // the raw reply
+    pub async fn recv(mut self) -> PqxResult<RpcReply> {
+        let res = tokio::time::timeout(self.timeout, self.receiver.recv()).await;
+
+        match res {
+            Ok(Some(r)) => Ok(r),
+            Ok(None) => Err("rpc reply channel closed".into()),
+            Err(_) => Err("rpc reply timeout".into()),
+        }
+    }
+
+    // every reply until timeout, for requests routed to multiple queues
+    pub async fn recv_all(mut self) -> Vec<RpcReply> {
+        let mut res = vec![];
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        while let Ok(Some(r)) = tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+            res.push(r);
+        }
+
+        res
+    }
+
+    // replies until each of the `expected` queues has replied (a reply without `x-replier` counts
+    // for any of them), or timeout
+    pub async fn recv_expected(mut self, expected: &[String]) -> Vec<RpcReply> {
+        let mut res = vec![];
+        let mut waiting = expected.to_vec();
+        let deadline = tokio::time::Instant::now() + self.timeout;
+        while !waiting.is_empty() {
+            let r = match tokio::time::timeout_at(deadline, self.receiver.recv()).await {
+                Ok(Some(r)) => r,
+                _ => break,
+            };
+            let pos = match &r.replier {
+                Some(q) => waiting.iter().position(|w| w == q),
+                None => Some(0),
+            };
+            if let Some(i) = pos {
+                waiting.remove(i);
+            }
+            res.push(r);
+        }
+
+        res
+    }
+}
+
+// replies arriving later are dropped, since the sender is removed
+impl<T> Drop for RpcFuture<T> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.correlation_id);
+    }
+}
+
+// `.await` resolves to the deserialized reply
+impl<T> IntoFuture for RpcFuture<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    type Output = PqxResult<T>;
+    type IntoFuture = BoxFuture<'static, PqxResult<T>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        async move { self.recv().await?.decode::<T>() }.boxed()
+    }
+}
+
+// ================================================================================================
+// RpcTarget
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct RpcTarget {
+    pub exchange: String,
+    pub routing_key: String,
+    pub props: BasicProperties,
+}
+
+impl RpcTarget {
+    pub fn new(exchange: &str, routing_key: &str, props: BasicProperties) -> Self {
+        Self {
+            exchange: exchange.to_owned(),
+            routing_key: routing_key.to_owned(),
+            props,
+        }
+    }
+}
+
+// ================================================================================================
+// RpcClient
+//
+// Replies are consumed by the same channel as the one publishing requests, which is required by
+// direct reply-to.
+// ================================================================================================
+
+pub struct RpcClient<'a> {
+    channel: &'a Channel,
+    reply_to: String,
+    consumer_tag: String,
+    pending: Pending,
+    codec: MessageCodec,
+}
+
+impl<'a> RpcClient<'a> {
+    pub async fn new(channel: &'a Channel, mode: ReplyMode) -> PqxResult<RpcClient<'a>> {
+        let reply_to = match mode {
+            ReplyMode::DirectReplyTo => DIRECT_REPLY_TO.to_string(),
+            ReplyMode::ExclusiveQueue => {
+                let args = QueueDeclareArguments::exclusive_server_named();
+                let (name, _, _) = channel
+                    .queue_declare(args)
+                    .await?
+                    .ok_or("exclusive queue declaration returns nothing")?;
+                name
+            }
+        };
+
+        let pending = Pending::default();
+        let consumer = ReplyConsumer {
+            pending: pending.clone(),
+        };
+        // direct reply-to requires `no_ack`
+        let args = BasicConsumeArguments::new(&reply_to, "")
+            .manual_ack(false)
+            .finish();
+        let consumer_tag = channel.basic_consume(consumer, args).await?;
+
+        Ok(Self {
+            channel,
+            reply_to,
+            consumer_tag,
+            pending,
+            codec: MessageCodec::default(),
+        })
+    }
+
+    // encoding of requests, replies are encoded the same way
+    pub fn set_codec(&mut self, codec: MessageCodec) {
+        self.codec = codec;
+    }
+
+    pub fn reply_to(&self) -> &str {
+        &self.reply_to
+    }
+
+    // publish one request for each of `props_list` (e.g. for each `mailing_to` recipient), and
+    // return a future for each of them, in the same order
+    pub async fn request<M, T>(
+        &self,
+        exchange: &str,
+        rout: &str,
+        msg: &M,
+        props_list: Vec<BasicProperties>,
+        timeout: Duration,
+    ) -> PqxResult<Vec<RpcFuture<T>>>
+    where
+        M: Serialize,
+        T: DeserializeOwned + Send + 'static,
+    {
+        let targets = props_list
+            .into_iter()
+            .map(|p| RpcTarget::new(exchange, rout, p))
+            .collect();
+
+        self.request_targets(msg, targets, timeout).await
+    }
+
+    // same as `request`, but each request has its own exchange and routing key
+    pub async fn request_targets<M, T>(
+        &self,
+        msg: &M,
+        targets: Vec<RpcTarget>,
+        timeout: Duration,
+    ) -> PqxResult<Vec<RpcFuture<T>>>
+    where
+        M: Serialize,
+        T: DeserializeOwned + Send + 'static,
+    {
+        let content = self.codec.encode(msg)?;
+        let mut res = vec![];
+
+        for t in targets.into_iter() {
+            let RpcTarget {
+                exchange,
+                routing_key,
+                mut props,
+            } = t;
+            let correlation_id = gen_correlation_id();
+            props
+                .with_reply_to(&self.reply_to)
+                .with_correlation_id(&correlation_id)
+                .with_content_type(self.codec.content_type());
+
+            // register before publishing, in case of an immediate reply
+            let (tx, rx) = unbounded_channel();
+            self.pending
+                .lock()
+                .unwrap()
+                .insert(correlation_id.clone(), tx);
+
+            let args = BasicPublishArguments::new(&exchange, &routing_key);
+            if let Err(e) = self
+                .channel
+                .basic_publish(props, content.clone(), args)
+                .await
+            {
+                self.pending.lock().unwrap().remove(&correlation_id);
+                return Err(e.into());
+            }
+
+            res.push(RpcFuture {
+                correlation_id,
+                receiver: rx,
+                pending: self.pending.clone(),
+                timeout,
+                _reply_type: PhantomData,
+            });
+        }
+
+        Ok(res)
+    }
+
+    // a single request
+    pub async fn call<M, T>(
+        &self,
+        exchange: &str,
+        rout: &str,
+        msg: &M,
+        props: BasicProperties,
+        timeout: Duration,
+    ) -> PqxResult<T>
+    where
+        M: Serialize,
+        T: DeserializeOwned + Send + 'static,
+    {
+        let fut = self
+            .request::<M, T>(exchange, rout, msg, vec![props], timeout)
+            .await?
+            .pop()
+            .ok_or("rpc request publishes nothing")?;
+
+        fut.await
+    }
+
+    pub async fn close(self) -> PqxResult<()> {
+        let args = BasicCancelArguments::new(&self.consumer_tag);
+        self.channel.basic_cancel(args).await?;
+
+        Ok(())
+    }
+}
+
+fn gen_correlation_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_rpc {
+    use super::*;
+
+    fn future(pending: &Pending, correlation_id: &str) -> RpcFuture<String> {
+        let (tx, rx) = unbounded_channel();
+        pending
+            .lock()
+            .unwrap()
+            .insert(correlation_id.to_owned(), tx);
+
+        RpcFuture {
+            correlation_id: correlation_id.to_owned(),
+            receiver: rx,
+            pending: pending.clone(),
+            timeout: Duration::from_millis(10),
+            _reply_type: PhantomData,
+        }
+    }
+
+    #[tokio::test]
+    async fn pending_removed_success() {
+        let pending = Pending::default();
+
+        // received (timeout), or dropped without being polled
+        let f1 = future(&pending, "c1");
+        let f2 = future(&pending, "c2");
+        assert_eq!(pending.lock().unwrap().len(), 2);
+        assert!(f1.recv().await.is_err());
+        drop(f2);
+        assert!(pending.lock().unwrap().is_empty());
+
+        // a cancelled `join_all`
+        let futures = vec![future(&pending, "c3"), future(&pending, "c4")];
+        let res = tokio::time::timeout(
+            Duration::from_millis(1),
+            futures::future::join_all(futures.into_iter().map(|f| f.recv())),
+        )
+        .await;
+        assert!(res.is_err());
+        assert!(pending.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,485 @@
+//! file: shovel.rs
+//! author: Jacob Xie
+//! date: 2023/07/10 21:17:34 Monday
+//! brief: browse messages of a queue, and republish (move) them to another queue or exchange
+
+use amqprs::channel::BasicPublishArguments;
+use amqprs::channel::{BasicAckArguments, BasicGetArguments, BasicNackArguments};
+use amqprs::{BasicProperties, FieldName, FieldTable};
+use tokio::sync::OnceCell;
+
+use super::{
+    ChannelOps, FieldTableViewer, PqxHeaders, PublishConfirm, XDeath, X_ATTEMPTS, X_DEAD_QUEUE,
+    X_DEAD_REASON, X_DEATH, X_DELAY, X_REQUEUES, X_RETRIES, X_WAIT,
+};
+use crate::error::PqxResult;
+
+// ================================================================================================
+// ShovelMessage
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct ShovelMessage {
+    pub index: usize, // position in the queue at the time of fetching
+    pub delivery_tag: u64,
+    pub redelivered: bool,
+    pub exchange: String,
+    pub routing_key: String,
+    pub props: BasicProperties,
+    pub content: Vec<u8>,
+}
+
+impl ShovelMessage {
+    pub fn headers(&self) -> Option<&FieldTable> {
+        self.props.headers()
+    }
+
+    // empty if the message has never been dead-lettered
+    pub fn x_death(&self) -> Vec<XDeath> {
+        self.headers()
+            .and_then(|ft| FieldTableViewer::new(ft).x_death().ok())
+            .unwrap_or_default()
+    }
+
+    // the latest dead-lettering, which tells where the message comes from
+    pub fn last_death(&self) -> Option<XDeath> {
+        self.x_death().into_iter().next()
+    }
+
+    // by `x-death`, or `x-dead-queue` if dead-lettered by pqx (see `Requeue`)
+    pub fn origin_queue(&self) -> Option<String> {
+        self.last_death().map(|d| d.queue).or_else(|| {
+            PqxHeaders::from_props(&self.props)
+                .ok()
+                .and_then(|h| h.x_dead_queue)
+        })
+    }
+}
+
+// ================================================================================================
+// Republish
+// ================================================================================================
+
+#[derive(Debug, Clone)]
+pub struct Republish {
+    exchange: String,
+    routing_key: String,
+    props: BasicProperties,
+    content: Vec<u8>,
+}
+
+impl Republish {
+    pub fn new(exchange: &str, routing_key: &str, message: &ShovelMessage) -> Self {
+        Self {
+            exchange: exchange.to_owned(),
+            routing_key: routing_key.to_owned(),
+            props: message.props.clone(),
+            content: message.content.clone(),
+        }
+    }
+
+    // publish to a queue directly, by the default exchange
+    pub fn to_queue(que: &str, message: &ShovelMessage) -> Self {
+        Self::new("", que, message)
+    }
+
+    // back to the queue where the message was dead-lettered from
+    pub fn to_origin_queue(message: &ShovelMessage) -> PqxResult<Self> {
+        let que = message
+            .origin_queue()
+            .ok_or("neither x-death nor x-dead-queue exists")?;
+
+        Ok(Self::to_queue(&que, message))
+    }
+
+    // back to the exchange (and routing key) the message was originally published to
+    pub fn to_origin_exchange(message: &ShovelMessage) -> PqxResult<Self> {
+        let death = message.last_death().ok_or("x-death doesn't exist")?;
+        let rout = death.routing_keys.first().cloned().unwrap_or_default();
+
+        Ok(Self::new(&death.exchange, &rout, message))
+    }
+
+    pub fn exchange(&self) -> &str {
+        &self.exchange
+    }
+
+    pub fn routing_key(&self) -> &str {
+        &self.routing_key
+    }
+
+    pub fn set_props(&mut self, props: BasicProperties) -> &mut Self {
+        self.props = props;
+        self
+    }
+
+    pub fn set_headers(&mut self, headers: FieldTable) -> &mut Self {
+        self.props.with_headers(headers);
+        self
+    }
+
+    pub fn set_priority(&mut self, priority: u8) -> &mut Self {
+        self.props.with_priority(priority);
+        self
+    }
+
+    pub fn set_message_id(&mut self, message_id: &str) -> &mut Self {
+        self.props.with_message_id(message_id);
+        self
+    }
+
+    pub fn set_content(&mut self, content: Vec<u8>) -> &mut Self {
+        self.content = content;
+        self
+    }
+
+    // remove retry & dead-lettering traces, so that the message starts over
+    pub fn reset_retries(&mut self) -> &mut Self {
+        let mut headers = self.props.headers().cloned().unwrap_or_default();
+        let traces = [
+            &X_RETRIES,
+            &X_ATTEMPTS,
+            &X_REQUEUES,
+            &X_DEAD_REASON,
+            &X_DEAD_QUEUE,
+            &X_DELAY,
+            &X_WAIT,
+            &X_DEATH,
+        ];
+        for k in traces {
+            headers.remove(k);
+        }
+        for k in [
+            "x-first-death-reason",
+            "x-first-death-queue",
+            "x-first-death-exchange",
+            "x-last-death-reason",
+            "x-last-death-queue",
+            "x-last-death-exchange",
+        ] {
+            headers.remove(&FieldName::try_from(k).unwrap());
+        }
+        self.props.with_headers(headers);
+        self
+    }
+}
+
+// ================================================================================================
+// Shovel
+//
+// Messages are fetched by `basic.get` without auto-ack, and stay unacked until the end of an
+// operation. Hence a message is never fetched twice, and unselected messages are requeued (to
+// their original position) once the operation finishes.
+//
+// Republishing puts the channel in confirm mode, and publishes with `mandatory`: a message is
+// acked (removed) only once every copy has been confirmed and none has been returned as
+// unroutable (e.g. a missing queue, or an exchange without a matching binding), otherwise it is
+// requeued.
+// ================================================================================================
+
+pub struct Shovel<'a> {
+    channel: &'a dyn ChannelOps,
+    confirm: OnceCell<PublishConfirm>,
+}
+
+impl<'a> Shovel<'a> {
+    pub fn new(channel: &'a dyn ChannelOps) -> Self {
+        Self {
+            channel,
+            confirm: OnceCell::new(),
+        }
+    }
+
+    async fn fetch(&self, que: &str, limit: usize) -> PqxResult<Vec<ShovelMessage>> {
+        let mut res = vec![];
+
+        while res.len() < limit {
+            let msg = match self.channel.basic_get(BasicGetArguments::new(que)).await? {
+                Some(m) => m,
+                None => break,
+            };
+            let (delivery, props, content) = msg;
+
+            res.push(ShovelMessage {
+                index: res.len(),
+                delivery_tag: delivery.delivery_tag,
+                redelivered: delivery.redelivered,
+                exchange: delivery.exchange,
+                routing_key: delivery.routing_key,
+                props,
+                content,
+            });
+        }
+
+        Ok(res)
+    }
+
+    async fn requeue(&self, messages: &[ShovelMessage]) -> PqxResult<()> {
+        for m in messages.iter() {
+            self.channel
+                .basic_nack(BasicNackArguments::new(m.delivery_tag, false, true))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    // list at most `limit` messages, the queue remains unchanged
+    pub async fn browse(&self, que: &str, limit: usize) -> PqxResult<Vec<ShovelMessage>> {
+        let messages = self.fetch(que, limit).await?;
+        self.requeue(&messages).await?;
+
+        Ok(messages)
+    }
+
+    // for each of at most `limit` messages, `f` decides where to republish it (a message can be
+    // republished to multiple destinations). A republished message is acked (removed from `que`),
+    // and those with no destination are requeued. On the first failed republish, the message and
+    // the remaining ones are requeued, while the copies confirmed before the failure stay.
+    // Returns the number of republished messages.
+    pub async fn transfer<F>(&self, que: &str, limit: usize, mut f: F) -> PqxResult<usize>
+    where
+        F: FnMut(&ShovelMessage) -> PqxResult<Vec<Republish>>,
+    {
+        let messages = self.fetch(que, limit).await?;
+        let mut kept = vec![];
+        let mut count = 0;
+
+        for (i, m) in messages.iter().enumerate() {
+            let res = match f(m) {
+                Ok(r) if r.is_empty() => {
+                    kept.push(m.clone());
+                    continue;
+                }
+                Ok(r) => self.republish(m, r).await,
+                Err(e) => Err(e),
+            };
+
+            // on error, give back the current and the remaining messages. If the channel has been
+            // closed by the broker, they are requeued by the broker, hence the error of
+            // republishing is the one reported
+            if let Err(e) = res {
+                kept.extend_from_slice(&messages[i..]);
+                let _ = self.requeue(&kept).await;
+                return Err(e);
+            }
+            count += 1;
+        }
+
+        self.requeue(&kept).await?;
+
+        Ok(count)
+    }
+
+    // move at most `limit` messages from one queue to another, as they are
+    pub async fn move_messages(&self, from: &str, to: &str, limit: usize) -> PqxResult<usize> {
+        self.transfer(from, limit, |m| Ok(vec![Republish::to_queue(to, m)]))
+            .await
+    }
+
+    async fn republish(&self, message: &ShovelMessage, list: Vec<Republish>) -> PqxResult<()> {
+        let confirm = self
+            .confirm
+            .get_or_try_init(|| self.channel.confirm_select())
+            .await?;
+
+        for r in list.into_iter() {
+            let Republish {
+                exchange,
+                routing_key,
+                props,
+                content,
+            } = r;
+            let args = BasicPublishArguments::new(&exchange, &routing_key)
+                .mandatory(true)
+                .finish();
+
+            confirm
+                .publish(|| self.channel.basic_publish(props, content, args))
+                .await?;
+        }
+
+        // [IMPORTANT] remove from the current queue only after being republished & confirmed
+        self.channel
+            .basic_ack(BasicAckArguments::new(message.delivery_tag, false))
+            .await?;
+
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_shovel {
+    use amqprs::channel::ExchangeType;
+    use amqprs::{FieldArray, FieldValue};
+
+    use super::*;
+    use crate::mq::{FieldTableBuilder, MemoryBroker};
+
+    fn death(reason: &str, queue: &str) -> FieldValue {
+        let mut ft = FieldTable::new();
+        for (k, v) in [
+            ("reason", reason),
+            ("queue", queue),
+            ("exchange", "pqx.dev.header"),
+        ] {
+            ft.insert(FieldName::try_from(k).unwrap(), FieldValue::from(v));
+        }
+        ft.insert(FieldName::try_from("count").unwrap(), FieldValue::l(2));
+        ft.insert(
+            FieldName::try_from("routing-keys").unwrap(),
+            FieldValue::A(FieldArray::try_from(vec![FieldValue::from("")]).unwrap()),
+        );
+
+        FieldValue::F(ft)
+    }
+
+    fn message() -> ShovelMessage {
+        let mut headers = FieldTableBuilder::new();
+        headers
+            .x_retries(0)
+            .x_attempts(5)
+            .x_common_pair("unique_key", "h1")
+            .unwrap();
+        let mut headers = headers.finish();
+        let deaths = vec![death("rejected", "pqx.dev.que.h1"), death("expired", "x")];
+        headers.insert(
+            X_DEATH.clone(),
+            FieldValue::A(FieldArray::try_from(deaths).unwrap()),
+        );
+
+        ShovelMessage {
+            index: 0,
+            delivery_tag: 1,
+            redelivered: false,
+            exchange: "pqx.dev.dlx".to_string(),
+            routing_key: "".to_string(),
+            props: BasicProperties::default().with_headers(headers).finish(),
+            content: vec![],
+        }
+    }
+
+    #[test]
+    fn x_death_success() {
+        let m = message();
+
+        let deaths = m.x_death();
+        assert_eq!(deaths.len(), 2);
+        assert_eq!(deaths[0].reason, "rejected");
+        assert_eq!(deaths[0].count, 2);
+        assert_eq!(deaths[0].routing_keys, vec![String::new()]);
+        assert_eq!(m.origin_queue().as_deref(), Some("pqx.dev.que.h1"));
+
+        // dead-lettered by `Requeue`, without `x-death`
+        let headers = PqxHeaders {
+            x_dead_reason: Some("requeued 3 times".to_owned()),
+            x_dead_queue: Some("pqx.dev.que.h2".to_owned()),
+            ..Default::default()
+        };
+        let mut m = m;
+        m.props.with_headers(FieldTable::new());
+        headers.apply(&mut m.props).unwrap();
+        assert!(m.x_death().is_empty());
+        assert_eq!(m.origin_queue().as_deref(), Some("pqx.dev.que.h2"));
+    }
+
+    #[test]
+    fn reset_retries_success() {
+        let m = message();
+
+        let mut r = Republish::to_origin_queue(&m).unwrap();
+        r.reset_retries();
+        assert_eq!(r.exchange(), "");
+        assert_eq!(r.routing_key(), "pqx.dev.que.h1");
+
+        let headers = r.props.headers().unwrap();
+        let viewer = FieldTableViewer::new(headers);
+        assert!(viewer.x_retries().is_err());
+        assert!(viewer.x_attempts().is_err());
+        assert!(viewer.x_death().is_err());
+        assert_eq!(viewer.x_common_pair("unique_key").unwrap(), "h1");
+    }
+
+    async fn memory_dlq(broker: &MemoryBroker, n: usize) {
+        broker.declare_queue("dlq").unwrap();
+        let chan = broker.channel();
+        for i in 0..n {
+            chan.basic_publish(
+                BasicProperties::default(),
+                format!("m{i}").into_bytes(),
+                BasicPublishArguments::new("", "dlq"),
+            )
+            .await
+            .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_success() {
+        let broker = MemoryBroker::new();
+        memory_dlq(&broker, 3).await;
+        broker.declare_queue("q1").unwrap();
+        let chan = broker.channel();
+        let shovel = Shovel::new(&chan);
+
+        let listed = shovel.browse("dlq", 10).await.unwrap();
+        assert_eq!(listed.len(), 3);
+        assert_eq!(broker.message_count("dlq"), 3);
+
+        // the first one stays
+        let res = shovel
+            .transfer("dlq", 10, |m| match m.index {
+                0 => Ok(vec![]),
+                _ => Ok(vec![Republish::to_queue("q1", m)]),
+            })
+            .await;
+        assert_eq!(res.unwrap(), 2);
+        assert_eq!(broker.message_count("dlq"), 1);
+        assert_eq!(broker.message_count("q1"), 2);
+        assert_eq!(chan.unacked(), 0);
+    }
+
+    #[tokio::test]
+    async fn transfer_unroutable_failure() {
+        let broker = MemoryBroker::new();
+        memory_dlq(&broker, 3).await;
+        broker.declare_queue("q1").unwrap();
+        broker
+            .declare_exchange("unbound", &ExchangeType::Headers)
+            .unwrap();
+        let chan = broker.channel();
+        let shovel = Shovel::new(&chan);
+
+        // a missing queue, the messages are kept
+        let res = shovel.move_messages("dlq", "missing", 10).await;
+        assert!(res.is_err());
+        assert_eq!(broker.message_count("dlq"), 3);
+        assert_eq!(chan.unacked(), 0);
+
+        // an exchange routing to no queue
+        let res = shovel
+            .transfer("dlq", 10, |m| Ok(vec![Republish::new("unbound", "", m)]))
+            .await;
+        assert!(res.is_err());
+        assert_eq!(broker.message_count("dlq"), 3);
+
+        // stops at the first failure, a message with an unroutable copy is kept
+        let res = shovel
+            .transfer("dlq", 10, |m| match m.index {
+                0 => Ok(vec![Republish::to_queue("q1", m)]),
+                _ => Ok(vec![
+                    Republish::to_queue("q1", m),
+                    Republish::to_queue("missing", m),
+                ]),
+            })
+            .await;
+        assert!(res.is_err());
+        assert_eq!(broker.message_count("dlq"), 2);
+        assert_eq!(broker.message_count("q1"), 2);
+        assert_eq!(chan.unacked(), 0);
+    }
+}
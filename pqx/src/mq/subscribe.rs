@@ -4,14 +4,44 @@
 //!
 
 use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
 
 use amqprs::channel::*;
 use amqprs::consumer::AsyncConsumer;
 use serde::de::DeserializeOwned;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 
 use super::*;
 use crate::error::PqxResult;
 
+// ================================================================================================
+// shutdown signal
+// ================================================================================================
+
+// resolves on SIGTERM (e.g. `kill`, `docker stop`) or SIGINT (ctrl-c)
+#[cfg(unix)]
+pub async fn shutdown_signal() -> PqxResult<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    }
+
+    Ok(())
+}
+
+// resolves on ctrl-c, the only one available beyond unix
+#[cfg(not(unix))]
+pub async fn shutdown_signal() -> PqxResult<()> {
+    tokio::signal::ctrl_c().await?;
+
+    Ok(())
+}
+
 // ================================================================================================
 // BasicSubscriber
 // ================================================================================================
@@ -44,6 +74,7 @@ where
     impl_set_consumer_no_wait!();
     impl_set_consumer_priorities!();
     impl_set_consumer_timeout!();
+    impl_set_stream_offset!();
 
     impl_set_prefetch!();
     impl_recover!();
@@ -94,7 +125,7 @@ where
     R: Send + Sync + Clone + Debug + 'static,
     C: Send + Sync + Consumer<M, R> + 'static,
 {
-    channel: &'a Channel,
+    channel: &'a dyn ChannelOps,
     consume_args: Option<BasicConsumeArguments>,
     consumer: ConsumerWrapper<M, R, C>,
     consumer_tag: Option<String>,
@@ -107,7 +138,7 @@ where
     R: Send + Sync + Clone + Debug + 'static,
     C: Send + Sync + Consumer<M, R> + 'static,
 {
-    pub fn new(channel: &'a Channel, consumer: C) -> Self {
+    pub fn new(channel: &'a dyn ChannelOps, consumer: C) -> Self {
         Self {
             channel,
             consume_args: Some(BasicConsumeArguments::default()),
@@ -117,18 +148,31 @@ where
         }
     }
 
+    // layers around `Consumer::consume`, the first added is the outermost one. Add them before
+    // consuming starts
+    pub fn add_middleware<L>(&mut self, middleware: L) -> &mut Self
+    where
+        L: Middleware<M, R> + 'static,
+    {
+        self.consumer.add_middleware(Arc::new(middleware));
+
+        self
+    }
+
     impl_set_consume_args!();
     impl_set_consumer_priorities!();
     impl_set_consumer_timeout!();
     impl_set_consumer_exclusive!();
     impl_set_consumer_no_wait!();
+    impl_set_stream_offset!();
 
     impl_set_prefetch!();
     impl_recover!();
     impl_block!();
 
     pub async fn consume(&mut self, que: &str) -> PqxResult<()> {
-        let consumer = self.consumer.clone();
+        let mut consumer = self.consumer.clone();
+        consumer.set_queue(que);
 
         let args = self
             .consume_args
@@ -139,7 +183,7 @@ where
 
         // start to consume
         self.consumer.signal_consume(true).await;
-        let consumer_tag = self.channel.basic_consume(consumer, args).await?;
+        let consumer_tag = self.channel.basic_consume(Box::new(consumer), args).await?;
         // save consumer tag
         self.consumer_tag = Some(consumer_tag);
         self.queue = Some(que.to_owned());
@@ -187,4 +231,41 @@ where
             }
         }
     }
+
+    // server generated, listed by `MqQuery::consumers`
+    pub fn consumer_tag(&self) -> Option<&str> {
+        self.consumer_tag.as_deref()
+    }
+
+    // number of deliveries being handled
+    pub fn in_flight(&self) -> usize {
+        self.consumer.in_flight()
+    }
+
+    // wait for in-flight deliveries to finish (acked or nacked), returns `false` if timeout
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, self.consumer.wait_idle())
+            .await
+            .is_ok()
+    }
+
+    // stop taking new deliveries and drain the in-flight ones. Returns `false` if timeout, and the
+    // unacked deliveries are requeued by the broker once the channel is closed.
+    pub async fn shutdown(&mut self, drain_timeout: Duration) -> PqxResult<bool> {
+        if self.consumer_tag.is_some() {
+            self.cancel_consume(false).await?;
+        }
+
+        Ok(self.drain(drain_timeout).await)
+    }
+
+    // block until consuming fails or a shutdown signal (SIGTERM/SIGINT) arrives, then shutdown
+    pub async fn graceful_block(&mut self, drain_timeout: Duration) -> PqxResult<bool> {
+        tokio::select! {
+            _ = self.soft_fail_block() => {},
+            res = shutdown_signal() => res?,
+        }
+
+        self.shutdown(drain_timeout).await
+    }
 }
@@ -0,0 +1,264 @@
+//! file: trace.rs
+//! author: Jacob Xie
+//! date: 2023/07/22 10:41:27 Saturday
+//! brief: W3C trace context, propagated through the `traceparent` header
+
+use std::fmt::Display;
+use std::future::Future;
+use std::str::FromStr;
+
+use amqprs::{BasicProperties, FieldValue};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tracing::{info_span, Span};
+
+use super::{FieldTableBuilder, PqxHeaders};
+use crate::error::PqxError;
+
+// ================================================================================================
+// TraceContext
+//
+// `traceparent`: "{version}-{trace_id}-{parent_id}-{flags}", e.g.
+// "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01". A publish and each delivery of a
+// message are spans of the same trace: the publisher injects the context of its span into the
+// headers, and a consumer opens a child span of it, which is carried forward by retries and
+// replies.
+// ================================================================================================
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+    sampled: bool,
+}
+
+tokio::task_local! {
+    // the span of the delivery being consumed
+    static CURRENT: TraceContext;
+}
+
+impl TraceContext {
+    // a new trace
+    pub fn new_root() -> Self {
+        Self {
+            trace_id: non_zero(rand::random),
+            span_id: non_zero(rand::random),
+            sampled: true,
+        }
+    }
+
+    // a new span of the same trace
+    pub fn child(&self) -> Self {
+        Self {
+            trace_id: self.trace_id,
+            span_id: non_zero(rand::random),
+            sampled: self.sampled,
+        }
+    }
+
+    // the context of the delivery being consumed, if any
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|c| *c).ok()
+    }
+
+    // run `f` as the current context
+    pub async fn scope<F: Future>(self, f: F) -> F::Output {
+        CURRENT.scope(self, f).await
+    }
+
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    pub fn span_id(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.sampled
+    }
+
+    // extract from the `traceparent` header
+    pub fn extract(props: &BasicProperties) -> Option<Self> {
+        PqxHeaders::from_props(props).ok()?.traceparent
+    }
+
+    // set as the `traceparent` header, replacing the former one
+    pub fn inject(&self, props: &mut BasicProperties) {
+        let mut headers = FieldTableBuilder::from(props.headers());
+        headers.traceparent(self);
+        props.with_headers(headers.finish());
+    }
+}
+
+fn non_zero<T: PartialEq + Default>(gen: impl Fn() -> T) -> T {
+    loop {
+        let v = gen();
+        if v != T::default() {
+            break v;
+        }
+    }
+}
+
+impl Display for TraceContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            self.trace_id(),
+            self.span_id(),
+            u8::from(self.sampled)
+        )
+    }
+}
+
+impl FromStr for TraceContext {
+    type Err = PqxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || PqxError::Custom("traceparent: 00-{32 hex}-{16 hex}-{2 hex}");
+        let parts = s.trim().split('-').collect::<Vec<_>>();
+        let (trace_id, span_id, flags) = match parts.as_slice() {
+            [v, t, p, f] if v.len() == 2 && *v != "ff" => (t, p, f),
+            _ => return Err(err()),
+        };
+        if trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+            return Err(err());
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).map_err(|_| err())?;
+        let span_id = u64::from_str_radix(span_id, 16).map_err(|_| err())?;
+        let flags = u8::from_str_radix(flags, 16).map_err(|_| err())?;
+        if trace_id == 0 || span_id == 0 {
+            return Err(err());
+        }
+
+        Ok(Self {
+            trace_id,
+            span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+// as a `traceparent` string
+impl Serialize for TraceContext {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TraceContext {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        TraceContext::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<&TraceContext> for FieldValue {
+    fn from(c: &TraceContext) -> Self {
+        FieldValue::from(c.to_string())
+    }
+}
+
+// ================================================================================================
+// spans
+//
+// Fields `trace_id`, `span_id` & `parent_span_id` mark the spans to export, and `sampled` drops
+// the unsampled ones, see `pqx_util::SpanExportLayer`.
+// ================================================================================================
+
+// inject a child of the former `traceparent` (or the current context, or a new trace) into `props`,
+// and open the span of the publish
+pub(crate) fn publish_span(props: &mut BasicProperties, exchange: &str, rout: &str) -> Span {
+    let parent = TraceContext::extract(props).or_else(TraceContext::current);
+    let ctx = parent
+        .map(|p| p.child())
+        .unwrap_or_else(TraceContext::new_root);
+    ctx.inject(props);
+
+    info_span!(
+        "pqx.publish",
+        trace_id = %ctx.trace_id(),
+        span_id = %ctx.span_id(),
+        parent_span_id = %parent.map(|p| p.span_id()).unwrap_or_default(),
+        sampled = ctx.sampled(),
+        "otel.kind" = "producer",
+        exchange,
+        routing_key = rout,
+    )
+}
+
+// open the span of a delivery, as a child of the publish (or a new trace)
+pub(crate) fn delivery_span(props: &BasicProperties, queue: Option<&str>) -> (TraceContext, Span) {
+    let parent = TraceContext::extract(props);
+    let ctx = parent
+        .map(|p| p.child())
+        .unwrap_or_else(TraceContext::new_root);
+    // 1 for the first delivery, increased by each retry
+    let attempt = PqxHeaders::from_props(props)
+        .ok()
+        .and_then(|h| h.x_attempts)
+        .unwrap_or(0)
+        .saturating_add(1);
+
+    let span = info_span!(
+        "pqx.delivery",
+        trace_id = %ctx.trace_id(),
+        span_id = %ctx.span_id(),
+        parent_span_id = %parent.map(|p| p.span_id()).unwrap_or_default(),
+        sampled = ctx.sampled(),
+        "otel.kind" = "consumer",
+        queue = queue.unwrap_or_default(),
+        task_id = props.message_id().map(String::as_str).unwrap_or_default(),
+        attempt,
+        outcome = tracing::field::Empty,
+    );
+
+    (ctx, span)
+}
+
+// ================================================================================================
+// Test
+// ================================================================================================
+
+#[cfg(test)]
+mod test_trace {
+    use super::*;
+
+    #[test]
+    fn traceparent_success() {
+        let s = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let ctx = TraceContext::from_str(s).unwrap();
+        assert_eq!(ctx.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(ctx.span_id(), "00f067aa0ba902b7");
+        assert!(ctx.sampled());
+        assert_eq!(ctx.to_string(), s);
+
+        let child = ctx.child();
+        assert_eq!(child.trace_id(), ctx.trace_id());
+        assert_ne!(child.span_id(), ctx.span_id());
+
+        // all zero ids & unknown versions are invalid
+        assert!(
+            TraceContext::from_str("00-00000000000000000000000000000000-00f067aa0ba902b7-01")
+                .is_err()
+        );
+        assert!(
+            TraceContext::from_str("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                .is_err()
+        );
+        assert!(TraceContext::from_str("00-4bf92f35-00f067aa0ba902b7-01").is_err());
+
+        // injected & extracted by headers
+        let mut props = BasicProperties::default();
+        ctx.inject(&mut props);
+        assert_eq!(TraceContext::extract(&props), Some(ctx));
+    }
+
+    #[tokio::test]
+    async fn scope_success() {
+        assert!(TraceContext::current().is_none());
+        let ctx = TraceContext::new_root();
+        let cur = ctx.scope(async { TraceContext::current() }).await;
+        assert_eq!(cur, Some(ctx));
+    }
+}
@@ -0,0 +1,134 @@
+//! file: test_aggregate.rs
+//! author: Jacob Xie
+//! date: 2023/07/13 22:40:16 Thursday
+//! brief: test broadcast-aggregate
+//! process:
+//! 1. three queues bound to a fanout exchange, only two of them have a replying consumer
+//! 2. an rpc client broadcasts a request, and aggregates the replies of all three queues
+//! 3. the silent queue is missing after the deadline, which fails `all_succeeded`
+
+use std::time::Duration;
+
+use amqprs::channel::*;
+use amqprs::BasicProperties;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.fanout.agg";
+const QUE1: &str = "pqx.test.que.agg1";
+const QUE2: &str = "pqx.test.que.agg2";
+const QUE3: &str = "pqx.test.que.agg3"; // no consumer
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AddMsg {
+    a: i64,
+    b: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct AddReply {
+    sum: i64,
+}
+
+#[derive(Clone)]
+struct AddConsumer;
+
+#[async_trait]
+impl Consumer<AddMsg, i64> for AddConsumer {
+    async fn consume(&mut self, message: &AddMsg) -> PqxResult<ConsumerResult<i64>> {
+        Ok(ConsumerResult::success(message.a + message.b))
+    }
+
+    fn gen_reply(&self, outcome: Outcome<'_, AddMsg, i64>) -> Option<Value> {
+        match outcome {
+            Outcome::Success(_, sum) => serde_json::to_value(AddReply { sum: *sum }).ok(),
+            _ => None,
+        }
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+async fn connect() -> MqClient {
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    client
+}
+
+#[tokio::test]
+async fn aggregate_success() {
+    /*
+    cargo test --package pqx --test test_aggregate -- aggregate_success --exact --nocapture
+     */
+
+    // 0. declare exchange & queues (start from empty ones)
+    let server = connect().await;
+    let res = server.declare_exchange(EXCHG, &ExchangeType::Fanout).await;
+    assert!(res.is_ok());
+    for que in [QUE1, QUE2, QUE3] {
+        let _ = server.delete_queue(que).await;
+        let res = server.declare_and_bind_queue(EXCHG, "", que).await;
+        assert!(res.is_ok());
+    }
+
+    // 1. server side, consumers of QUE1 & QUE2
+    let mut subscriber1 = Subscriber::new(server.channel().unwrap(), AddConsumer);
+    let res = subscriber1.consume(QUE1).await;
+    assert!(res.is_ok());
+    let mut subscriber2 = Subscriber::new(server.channel().unwrap(), AddConsumer);
+    let res = subscriber2.consume(QUE2).await;
+    assert!(res.is_ok());
+
+    // 2. client side, one request expected to be replied by all three queues
+    let client = connect().await;
+    let rpc = RpcClient::new(client.channel().unwrap(), ReplyMode::DirectReplyTo)
+        .await
+        .unwrap();
+    let targets = vec![RpcTarget::new(EXCHG, "", BasicProperties::default())];
+    let futs = rpc
+        .request_targets::<_, AddReply>(&AddMsg { a: 1, b: 2 }, targets, Duration::from_secs(3))
+        .await
+        .unwrap();
+    let expected = vec![QUE1.to_string(), QUE2.to_string(), QUE3.to_string()];
+    let requests = futs.into_iter().map(|f| (f, expected.clone())).collect();
+
+    let is_success = |r: &RpcReply| r.json::<AddReply>().map(|r| r.sum == 3).unwrap_or(false);
+    let agg = Aggregator::new(AggregatePolicy::AllSucceeded)
+        .aggregate(requests, is_success)
+        .await;
+    println!("{:?}", agg);
+
+    // 3. QUE3 is missing
+    assert_eq!(agg.expected(), 3);
+    assert_eq!(agg.succeeded(), 2);
+    assert_eq!(agg.missing(), 1);
+    assert!(!agg.complete);
+    assert!(!agg.passed);
+    assert!(AggregatePolicy::Quorum { n: 2 }.passed(agg.succeeded(), agg.expected()));
+
+    let res = rpc.close().await;
+    assert!(res.is_ok());
+    let res = subscriber1.cancel_consume(false).await;
+    assert!(res.is_ok());
+    let res = subscriber2.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
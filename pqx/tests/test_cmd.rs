@@ -95,8 +95,8 @@ async fn cmd_compose_and_exec_success2() {
 #[tokio::test]
 async fn cmd_executor_success2() {
     let mut executor = CmdExecutor::new();
-    executor.register_stdout_fn(&print_stdout);
-    executor.register_stderr_fn(&print_stderr);
+    executor.register_stdout_fn(print_stdout);
+    executor.register_stderr_fn(print_stderr);
 
     let py = cmd_which("python3").unwrap();
     let py = py.strip_suffix("\n").unwrap();
@@ -177,3 +177,24 @@ async fn cmd_executor_success4() {
     assert!(res.is_ok());
     println!("{:?}", res.unwrap());
 }
+
+// the child is waited by blocking a worker
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn cmd_executor_kill_running_success() {
+    let executor = CmdAsyncExecutor::new();
+
+    let exec = executor.clone();
+    let task = tokio::spawn(async move {
+        let arg = CmdArg::bash(["sleep 30 & wait"]);
+        exec.exec(1, &arg).await
+    });
+    while executor.running() == 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+
+    // the process group is killed, along with the `sleep` started by bash
+    assert_eq!(executor.kill_running(), 1);
+    let es = task.await.unwrap().unwrap();
+    assert!(!es.success());
+    assert_eq!(executor.running(), 0);
+}
@@ -0,0 +1,105 @@
+//! file: test_codec.rs
+//! author: Jacob Xie
+//! date: 2023/07/15 16:48:51 Saturday
+//! brief: test message codecs
+//! process:
+//! 1. a subscriber whose consumer collects messages
+//! 2. publish the same message by JSON, MessagePack and CBOR
+//! 3. each of them is decoded by its `content_type`
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use amqprs::channel::*;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.codec";
+const QUE: &str = "pqx.test.que.codec";
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct DevMsg {
+    name: String,
+    values: Vec<f64>,
+}
+
+#[derive(Clone)]
+struct CollectConsumer {
+    received: Arc<Mutex<Vec<DevMsg>>>,
+}
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for CollectConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        println!("consume: {:?}", message);
+        self.received.lock().unwrap().push(message.clone());
+        Ok(ConsumerResult::success(()))
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn codec_success() {
+    /*
+    cargo test --package pqx --test test_codec -- codec_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 1. subscriber
+    let consumer = CollectConsumer {
+        received: Arc::new(Mutex::new(vec![])),
+    };
+    let mut subscriber = Subscriber::new(client.channel().unwrap(), consumer.clone());
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    // 2. publish by each codec
+    let msg = DevMsg {
+        name: "dev".to_string(),
+        values: vec![1.0, 2.5],
+    };
+    let mut publisher = Publisher::new(client.channel().unwrap());
+    for codec in [
+        MessageCodec::Json,
+        MessageCodec::MsgPack,
+        MessageCodec::Cbor,
+    ] {
+        publisher.set_codec(codec);
+        let res = publisher.publish(EXCHG, ROUT, msg.clone()).await;
+        assert!(res.is_ok());
+    }
+
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // 3. all decoded
+    let received = consumer.received.lock().unwrap().clone();
+    assert_eq!(received, vec![msg.clone(), msg.clone(), msg]);
+
+    let res = subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
@@ -0,0 +1,112 @@
+//! file: test_compress.rs
+//! author: Jacob Xie
+//! date: 2023/07/15 21:17:42 Saturday
+//! brief: test payload compression
+//! process:
+//! 1. a publisher compresses messages above a threshold
+//! 2. fetch the raw deliveries: only the large one is compressed and marked by `content_encoding`
+//! 3. both are decoded transparently
+
+use amqprs::channel::*;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.compress";
+const QUE: &str = "pqx.test.que.compress";
+
+const THRESHOLD: usize = 256;
+
+// ================================================================================================
+// msg
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct DevMsg {
+    script: String,
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+async fn compress_success(compression: Compression) {
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(QUE).await;
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 1. a small message and a large one
+    let chan = client.channel().unwrap();
+    let mut publisher = Publisher::new(chan);
+    publisher.set_compression(compression, THRESHOLD);
+    let small = DevMsg {
+        script: "print('hello')".to_string(),
+    };
+    let large = DevMsg {
+        script: "print('hello')\n".repeat(100),
+    };
+    for msg in [&small, &large] {
+        let res = publisher.publish(EXCHG, ROUT, msg.clone()).await;
+        assert!(res.is_ok());
+    }
+    publisher.block(1).await;
+
+    // 2. raw deliveries
+    let mut received = vec![];
+    while let Some((_, props, content)) = chan
+        .basic_get(BasicGetArguments::new(QUE).no_ack(true).finish())
+        .await
+        .unwrap()
+    {
+        println!(
+            "content_encoding: {:?}, size: {}",
+            props.content_encoding(),
+            content.len()
+        );
+        received.push((props, content));
+    }
+    assert_eq!(received.len(), 2);
+    assert_eq!(received[0].0.content_encoding(), None);
+    assert_eq!(
+        received[1].0.content_encoding().map(String::as_str),
+        Some(compression.content_encoding())
+    );
+    assert!(received[1].1.len() < THRESHOLD);
+
+    // 3. decoded transparently
+    let res: DevMsg = decode_content(&received[0].0, &received[0].1).unwrap();
+    assert_eq!(res, small);
+    let res: DevMsg = decode_content(&received[1].0, &received[1].1).unwrap();
+    assert_eq!(res, large);
+}
+
+#[tokio::test]
+async fn gzip_success() {
+    /*
+    cargo test --package pqx --test test_compress -- gzip_success --exact --nocapture
+     */
+
+    compress_success(Compression::Gzip).await;
+}
+
+#[tokio::test]
+async fn zstd_success() {
+    /*
+    cargo test --package pqx --test test_compress -- zstd_success --exact --nocapture
+     */
+
+    compress_success(Compression::Zstd).await;
+}
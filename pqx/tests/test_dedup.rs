@@ -0,0 +1,128 @@
+//! file: test_dedup.rs
+//! author: Jacob Xie
+//! date: 2023/07/14 22:01:35 Friday
+//! brief: test idempotent consumption
+//! process:
+//! 1. a subscriber whose consumer counts consumed messages, with an in-memory dedup store
+//! 2. publish the same message (same `message_id`) several times, and another one
+//! 3. each `message_id` is consumed once, and the duplicates are skipped
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::channel::*;
+use amqprs::BasicProperties;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.dedup";
+const QUE: &str = "pqx.test.que.dedup";
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    seq: usize,
+}
+
+#[derive(Clone)]
+struct CountConsumer {
+    consumed: Arc<AtomicUsize>,
+    skipped: Arc<AtomicUsize>,
+    store: Arc<MemoryDedupStore>,
+}
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for CountConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        println!("consume: {:?}", message);
+        self.consumed.fetch_add(1, Ordering::SeqCst);
+        Ok(ConsumerResult::success(()))
+    }
+
+    fn dedup_store(&self) -> Option<Arc<dyn DedupStore>> {
+        Some(self.store.clone())
+    }
+
+    async fn skip_callback(&mut self, message: &DevMsg, message_id: &str) -> PqxResult<()> {
+        println!("skip: {:?}, message_id: {}", message, message_id);
+        self.skipped.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn dedup_success() {
+    /*
+    cargo test --package pqx --test test_dedup -- dedup_success --exact --nocapture
+     */
+
+    // 0. client connection and open channel, declare exchange & queue
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(QUE).await;
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 1. subscriber
+    let store = Arc::new(MemoryDedupStore::new(100, Duration::from_secs(60)));
+    let consumer = CountConsumer {
+        consumed: Arc::new(AtomicUsize::new(0)),
+        skipped: Arc::new(AtomicUsize::new(0)),
+        store: store.clone(),
+    };
+    let mut subscriber = Subscriber::new(client.channel().unwrap(), consumer.clone());
+    let res = subscriber.set_prefetch(0, 1, false).await;
+    assert!(res.is_ok());
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    // 2. the same message published 3 times, and another message
+    let publisher = Publisher::new(client.channel().unwrap());
+    let id = gen_message_id();
+    for _ in 0..3 {
+        let mut props = BasicProperties::default();
+        props.with_message_id(&id);
+        let res = publisher
+            .publish_with_props(EXCHG, ROUT, DevMsg { seq: 0 }, props)
+            .await;
+        assert!(res.is_ok());
+    }
+    let mut props = BasicProperties::default();
+    props.with_message_id(&gen_message_id());
+    let res = publisher
+        .publish_with_props(EXCHG, ROUT, DevMsg { seq: 1 }, props)
+        .await;
+    assert!(res.is_ok());
+
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // 3. consumed once for each `message_id`
+    assert_eq!(consumer.consumed.load(Ordering::SeqCst), 2);
+    assert_eq!(consumer.skipped.load(Ordering::SeqCst), 2);
+    assert_eq!(store.skipped(&dedup_key(Some(QUE), &id)), 2);
+
+    let res = subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
@@ -9,11 +9,11 @@
 //!
 //! 1. mailman consumer receives a message from RabbitMQ and fails processing;
 //! 2. It then ACK's the original message and publishes it to the delayed exchange with an
-//! incremented `x-retires` header, a calculated `x-delay` header to have the message delayed
-//! before it is being forwarded on and a routing key matching the name of the queue the
-//! message originated from `mailman.users.created`;
+//!    incremented `x-retires` header, a calculated `x-delay` header to have the message delayed
+//!    before it is being forwarded on and a routing key matching the name of the queue the
+//!    message originated from `mailman.users.created`;
 //! 3. When the TTL expires the delayed exchange forwards the message back to the queue
-//! `mailman.users.created` which is attached to it via a routing key of its name;
+//!    `mailman.users.created` which is attached to it via a routing key of its name;
 //! 4. mailman consumes the message again.
 //!
 //! Other references:
@@ -163,7 +163,7 @@ async fn mq_subscribe_success() {
 
     let res = subscriber.consume(QUE).await;
     assert!(res.is_ok());
-    println!("Start listening on {}:{} ...", "HOST", "PORT");
+    println!("Start listening on HOST:PORT ...");
     subscriber.block().await;
 }
 
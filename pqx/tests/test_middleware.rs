@@ -0,0 +1,106 @@
+//! file: test_middleware.rs
+//! author: Jacob Xie
+//! date: 2023/07/21 22:25:13 Friday
+//! brief: test middleware layers around a consumer
+//! process:
+//! 1. a subscriber with `Metrics` (outer) and `AppIdFilter` (inner) layers
+//! 2. messages from an allowed app are consumed, the others are discarded without being consumed
+//! 3. the metrics count both
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::channel::*;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.middleware";
+const QUE: &str = "pqx.test.que.middleware";
+
+const APP_ID: &str = "pqx";
+const OTHER_APP_ID: &str = "other";
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    seq: usize,
+}
+
+#[derive(Clone)]
+struct CountConsumer(Arc<AtomicUsize>);
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for CountConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        println!("consume: {:?}", message);
+        self.0.fetch_add(1, Ordering::SeqCst);
+
+        Ok(ConsumerResult::success(()))
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn middleware_success() {
+    /*
+    cargo test --package pqx --test test_middleware -- middleware_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(QUE).await;
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 1. subscribe with middleware layers
+    let chan = client.channel().unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+    let metrics = Metrics::new();
+    let mut subscriber = Subscriber::new(chan, CountConsumer(count.clone()));
+    subscriber
+        .add_middleware(metrics.clone())
+        .add_middleware(AppIdFilter::new([APP_ID]));
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    // 2. publish from two apps
+    let mut publisher = Publisher::new(chan);
+    for (seq, app_id) in [APP_ID, OTHER_APP_ID, APP_ID].into_iter().enumerate() {
+        publisher.set_message_app_id(app_id);
+        let res = publisher.publish(EXCHG, ROUT, DevMsg { seq }).await;
+        assert!(res.is_ok());
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // 3. the message of the other app is discarded
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+    let snapshot = metrics.snapshot();
+    println!("{:?}", snapshot);
+    assert_eq!(snapshot.deliveries, 3);
+    assert_eq!(snapshot.successes, 2);
+    assert_eq!(snapshot.errors, 1);
+
+    let res = subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
@@ -0,0 +1,115 @@
+//! file: test_priority.rs
+//! author: Jacob Xie
+//! date: 2023/07/11 20:41:07 Tuesday
+//! brief: test message priorities
+//! process:
+//! 1. declare a queue with `x-max-priority`
+//! 2. publish a backlog of low-priority messages, then a few high-priority ones
+//! 3. fetch messages one by one, high-priority messages come first
+
+use amqprs::channel::*;
+use chrono::{DateTime, Local};
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.priority";
+const QUE: &str = "pqx.test.que.priority";
+
+const MAX_PRIORITY: u8 = 10;
+const LOW: u8 = 1;
+const HIGH: u8 = 9;
+
+// ================================================================================================
+// msg
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    seq: usize,
+    priority: u8,
+    time: DateTime<Local>,
+}
+
+impl DevMsg {
+    fn new(seq: usize, priority: u8) -> Self {
+        Self {
+            seq,
+            priority,
+            time: Local::now(),
+        }
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn high_priority_overtakes_success() {
+    /*
+    cargo test --package pqx --test test_priority -- high_priority_overtakes_success --exact --nocapture
+     */
+
+    // 0. client connection and open channel
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    // 1. declare a priority queue (start from an empty one)
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(QUE).await;
+    let mut args = QueueDeclareArguments::durable_client_named(QUE);
+    let mut headers = FieldTableBuilder::new();
+    headers.x_max_priority(MAX_PRIORITY);
+    args.arguments(headers.finish());
+    let res = client.declare_queue_by_args(args).await;
+    assert!(res.is_ok());
+    let res = client.bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 2. a backlog of low-priority messages, followed by high-priority ones
+    let chan = client.channel().unwrap();
+    let mut publisher = Publisher::new(chan);
+    publisher.set_message_priority(LOW);
+    for seq in 0..10 {
+        let res = publisher.publish(EXCHG, ROUT, DevMsg::new(seq, LOW)).await;
+        assert!(res.is_ok());
+    }
+    publisher.set_message_priority(HIGH);
+    for seq in 10..13 {
+        let res = publisher.publish(EXCHG, ROUT, DevMsg::new(seq, HIGH)).await;
+        assert!(res.is_ok());
+    }
+    publisher.block(1).await;
+
+    // 3. fetch all: the high-priority messages overtake the backlog
+    let mut received = vec![];
+    while let Some((_, props, content)) = chan
+        .basic_get(BasicGetArguments::new(QUE).no_ack(true).finish())
+        .await
+        .unwrap()
+    {
+        let msg: DevMsg = serde_json::from_slice(&content).unwrap();
+        println!("{:?} priority: {:?}", msg, props.priority());
+        received.push(msg);
+    }
+
+    assert_eq!(received.len(), 13);
+    assert!(received[..3].iter().all(|m| m.priority == HIGH));
+    assert!(received[3..].iter().all(|m| m.priority == LOW));
+    // FIFO within the same priority
+    assert_eq!(
+        received[3..].iter().map(|m| m.seq).collect::<Vec<_>>(),
+        (0..10).collect::<Vec<_>>()
+    );
+}
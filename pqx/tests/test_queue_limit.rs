@@ -0,0 +1,135 @@
+//! file: test_queue_limit.rs
+//! author: Jacob Xie
+//! date: 2023/07/20 22:08:41 Thursday
+//! brief: test queue limits & overflow behaviours
+//! process:
+//! 1. a queue of `x-max-length` 2 with `x-overflow: reject-publish`: the third publish is nacked,
+//!    and fails through publisher confirms
+//! 2. a queue of `x-max-length` 2 with the default `drop-head`: the oldest messages are dropped,
+//!    and the latest ones stay
+
+use amqprs::channel::*;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const REJECT_ROUT: &str = "pqx.test.reject";
+const REJECT_QUE: &str = "pqx.test.que.reject";
+const DROP_ROUT: &str = "pqx.test.drop";
+const DROP_QUE: &str = "pqx.test.que.drop";
+
+const MAX_LENGTH: i64 = 2;
+
+// ================================================================================================
+// msg
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    seq: i64,
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+async fn connect() -> MqClient {
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+
+    client
+}
+
+async fn declare_limited_queue(client: &MqClient, rout: &str, que: &str, overflow: &Overflow) {
+    let _ = client.delete_queue(que).await;
+    let mut args = FieldTableBuilder::new();
+    args.x_max_length(MAX_LENGTH).x_overflow(overflow);
+    let res = client
+        .declare_queue_with_type(que, &QueueType::Classic, args.finish())
+        .await;
+    assert!(res.is_ok());
+    let res = client.bind_queue(EXCHG, rout, que).await;
+    assert!(res.is_ok());
+}
+
+#[tokio::test]
+async fn reject_publish_success() {
+    /*
+    cargo test --package pqx --test test_queue_limit -- reject_publish_success --exact --nocapture
+     */
+
+    let client = connect().await;
+    declare_limited_queue(&client, REJECT_ROUT, REJECT_QUE, &Overflow::RejectPublish).await;
+
+    let chan = client.channel().unwrap();
+    let mut publisher = Publisher::new(chan);
+    let res = publisher.enable_confirm().await;
+    assert!(res.is_ok());
+
+    // 1. the queue is full after `MAX_LENGTH` messages
+    for seq in 0..MAX_LENGTH {
+        let res = publisher.publish(EXCHG, REJECT_ROUT, DevMsg { seq }).await;
+        assert!(res.is_ok());
+    }
+    let res = publisher
+        .publish(EXCHG, REJECT_ROUT, DevMsg { seq: MAX_LENGTH })
+        .await;
+    println!("{:?}", res);
+    assert!(res.is_err());
+
+    // the rejected message is not enqueued
+    let mut count = 0;
+    while chan
+        .basic_get(BasicGetArguments::new(REJECT_QUE).no_ack(true).finish())
+        .await
+        .unwrap()
+        .is_some()
+    {
+        count += 1;
+    }
+    assert_eq!(count, MAX_LENGTH);
+}
+
+#[tokio::test]
+async fn drop_head_success() {
+    /*
+    cargo test --package pqx --test test_queue_limit -- drop_head_success --exact --nocapture
+     */
+
+    let client = connect().await;
+    declare_limited_queue(&client, DROP_ROUT, DROP_QUE, &Overflow::DropHead).await;
+
+    let chan = client.channel().unwrap();
+    let mut publisher = Publisher::new(chan);
+    let res = publisher.enable_confirm().await;
+    assert!(res.is_ok());
+
+    // 2. every publish is confirmed, the oldest messages are dropped
+    let total = MAX_LENGTH * 2;
+    for seq in 0..total {
+        let res = publisher.publish(EXCHG, DROP_ROUT, DevMsg { seq }).await;
+        assert!(res.is_ok());
+    }
+
+    let mut seqs = vec![];
+    while let Some((_, _, content)) = chan
+        .basic_get(BasicGetArguments::new(DROP_QUE).no_ack(true).finish())
+        .await
+        .unwrap()
+    {
+        let msg: DevMsg = serde_json::from_slice(&content).unwrap();
+        seqs.push(msg.seq);
+    }
+    assert_eq!(seqs, (total - MAX_LENGTH..total).collect::<Vec<_>>());
+}
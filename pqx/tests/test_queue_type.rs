@@ -0,0 +1,178 @@
+//! file: test_queue_type.rs
+//! author: Jacob Xie
+//! date: 2023/07/19 21:26:35 Wednesday
+//! brief: test quorum & stream queues
+//! process:
+//! 1. a quorum queue with `x-delivery-limit`: a message requeued too many times is dead-lettered
+//! 2. a stream queue: messages stay after being consumed, and a subscriber reads them again from
+//!    the first offset
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::channel::*;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const QUORUM_ROUT: &str = "pqx.test.quorum";
+const QUORUM_QUE: &str = "pqx.test.que.quorum";
+const STREAM_ROUT: &str = "pqx.test.stream";
+const STREAM_QUE: &str = "pqx.test.que.stream";
+const DLX: &str = "pqx.test.dlx.quorum";
+const DLQ: &str = "pqx.test.dlq.quorum";
+
+const DELIVERY_LIMIT: i64 = 1;
+const STREAM_MSGS: usize = 3;
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    seq: usize,
+}
+
+#[derive(Clone)]
+struct CountConsumer(Arc<AtomicUsize>);
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for CountConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        println!("consume: {:?}", message);
+        self.0.fetch_add(1, Ordering::SeqCst);
+
+        Ok(ConsumerResult::success(()))
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+async fn connect() -> MqClient {
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+
+    client
+}
+
+#[tokio::test]
+async fn quorum_delivery_limit_success() {
+    /*
+    cargo test --package pqx --test test_queue_type -- quorum_delivery_limit_success --exact --nocapture
+     */
+
+    // 0. a quorum queue dead-lettering to DLQ
+    let client = connect().await;
+    let res = client.declare_exchange(DLX, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(DLQ).await;
+    let res = client.declare_and_bind_queue(DLX, QUORUM_ROUT, DLQ).await;
+    assert!(res.is_ok());
+
+    let _ = client.delete_queue(QUORUM_QUE).await;
+    let mut args = FieldTableBuilder::new();
+    args.x_dead_letter_exchange_only(DLX)
+        .x_delivery_limit(DELIVERY_LIMIT)
+        .x_dead_letter_strategy(&DeadLetterStrategy::AtMostOnce);
+    let res = client
+        .declare_queue_with_type(QUORUM_QUE, &QueueType::Quorum, args.finish())
+        .await;
+    assert!(res.is_ok());
+    let res = client.bind_queue(EXCHG, QUORUM_ROUT, QUORUM_QUE).await;
+    assert!(res.is_ok());
+
+    // 1. requeue the message until the delivery limit is exceeded
+    let chan = client.channel().unwrap();
+    let publisher = Publisher::new(chan);
+    let res = publisher
+        .publish(EXCHG, QUORUM_ROUT, DevMsg { seq: 0 })
+        .await;
+    assert!(res.is_ok());
+    publisher.block(1).await;
+
+    let mut deliveries = 0;
+    while let Some((get_ok, _, _)) = chan
+        .basic_get(BasicGetArguments::new(QUORUM_QUE))
+        .await
+        .unwrap()
+    {
+        deliveries += 1;
+        let args = BasicNackArguments::new(get_ok.delivery_tag(), false, true);
+        chan.basic_nack(args).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    assert_eq!(deliveries, DELIVERY_LIMIT + 1);
+
+    // 2. dead-lettered
+    let (_, props, _) = chan
+        .basic_get(BasicGetArguments::new(DLQ).no_ack(true).finish())
+        .await
+        .unwrap()
+        .unwrap();
+    let x_death = FieldTableViewer::from(props.headers().unwrap())
+        .x_death()
+        .unwrap();
+    println!("{:?}", x_death);
+    assert_eq!(x_death[0].reason, "delivery_limit");
+}
+
+#[tokio::test]
+async fn stream_offset_success() {
+    /*
+    cargo test --package pqx --test test_queue_type -- stream_offset_success --exact --nocapture
+     */
+
+    // 0. a stream queue
+    let client = connect().await;
+    let _ = client.delete_queue(STREAM_QUE).await;
+    let mut args = FieldTableBuilder::new();
+    args.x_max_age("1h");
+    let res = client
+        .declare_queue_with_type(STREAM_QUE, &QueueType::Stream, args.finish())
+        .await;
+    assert!(res.is_ok());
+    let res = client.bind_queue(EXCHG, STREAM_ROUT, STREAM_QUE).await;
+    assert!(res.is_ok());
+
+    let chan = client.channel().unwrap();
+    let publisher = Publisher::new(chan);
+    for seq in 0..STREAM_MSGS {
+        let res = publisher.publish(EXCHG, STREAM_ROUT, DevMsg { seq }).await;
+        assert!(res.is_ok());
+    }
+    publisher.block(1).await;
+
+    // 1. read twice from the first offset, messages stay in the stream
+    for _ in 0..2 {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut subscriber = Subscriber::new(chan, CountConsumer(count.clone()));
+        let res = subscriber.set_prefetch(0, 10, false).await;
+        assert!(res.is_ok());
+        subscriber.set_stream_offset(&StreamOffset::First);
+        let res = subscriber.consume(STREAM_QUE).await;
+        assert!(res.is_ok());
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(count.load(Ordering::SeqCst), STREAM_MSGS);
+
+        let res = subscriber.cancel_consume(false).await;
+        assert!(res.is_ok());
+    }
+}
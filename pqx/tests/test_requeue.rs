@@ -0,0 +1,144 @@
+//! file: test_requeue.rs
+//! author: Jacob Xie
+//! date: 2023/07/23 11:05:48 Sunday
+//! brief: test bounded requeue of failed messages
+//! process:
+//! 1. declare a work queue and a dead queue
+//! 2. subscribe the work queue by a consumer that always fails, requeued at most twice with a delay
+//! 3. subscribe the dead queue, recording `x-dead-reason`
+//! 4. publish a message: consumed 3 times, then dead-lettered with a reason
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use amqprs::channel::*;
+use amqprs::BasicProperties;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.requeue";
+const QUE: &str = "pqx.test.que.requeue";
+const DEAD_ROUT: &str = "pqx.test.requeue.dead";
+const DEAD_QUE: &str = "pqx.test.que.requeue.dead";
+
+const MAX_REQUEUES: u8 = 2;
+const REQUEUE_DELAY: u64 = 200; // milliseconds
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    data: String,
+}
+
+#[derive(Clone)]
+struct FailConsumer(Arc<AtomicUsize>);
+
+#[async_trait]
+impl Consumer<DevMsg, String> for FailConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<String>> {
+        println!("consume: {:?}", message);
+        self.0.fetch_add(1, Ordering::SeqCst);
+
+        Ok(ConsumerResult::failure("always fails".to_string()))
+    }
+
+    fn gen_requeue(&self, _message: &DevMsg) -> Option<Requeue> {
+        let mut requeue = Requeue::new(MAX_REQUEUES, EXCHG, DEAD_ROUT);
+        requeue.set_delay(Duration::from_millis(REQUEUE_DELAY));
+
+        Some(requeue)
+    }
+}
+
+#[derive(Clone)]
+struct DeadConsumer(Arc<Mutex<Vec<String>>>);
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for DeadConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        println!("dead: {:?}", message);
+
+        Ok(ConsumerResult::success(()))
+    }
+
+    fn handle_props(&self, props: &BasicProperties) {
+        if let Some(reason) = props
+            .headers()
+            .and_then(|h| FieldTableViewer::new(h).x_dead_reason().ok())
+        {
+            self.0.lock().unwrap().push(reason);
+        }
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn requeue_success() {
+    /*
+    cargo test --package pqx --test test_requeue -- requeue_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    // 1. work & dead queues
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    for (rout, que) in [(ROUT, QUE), (DEAD_ROUT, DEAD_QUE)] {
+        let _ = client.delete_queue(que).await;
+        let res = client.declare_and_bind_queue(EXCHG, rout, que).await;
+        assert!(res.is_ok());
+    }
+
+    // 2. & 3. subscribe
+    let chan = client.channel().unwrap();
+    let count = Arc::new(AtomicUsize::new(0));
+    let mut subscriber = Subscriber::new(chan, FailConsumer(count.clone()));
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    let reasons = Arc::new(Mutex::new(Vec::new()));
+    let mut dead_subscriber = Subscriber::new(chan, DeadConsumer(reasons.clone()));
+    let res = dead_subscriber.consume(DEAD_QUE).await;
+    assert!(res.is_ok());
+
+    // 4. publish
+    let publisher = Publisher::new(chan);
+    let msg = DevMsg {
+        data: "fail".to_string(),
+    };
+    let res = publisher.publish(EXCHG, ROUT, msg).await;
+    assert!(res.is_ok());
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // the first delivery and two requeues
+    assert_eq!(count.load(Ordering::SeqCst), usize::from(MAX_REQUEUES) + 1);
+    let reasons = reasons.lock().unwrap().clone();
+    println!("{:?}", reasons);
+    assert_eq!(reasons.len(), 1);
+    assert!(reasons[0].contains("always fails"));
+
+    let res = subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+    let res = dead_subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
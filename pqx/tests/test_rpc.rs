@@ -0,0 +1,168 @@
+//! file: test_rpc.rs
+//! author: Jacob Xie
+//! date: 2023/07/12 22:48:30 Wednesday
+//! brief: test request/reply
+//! process:
+//! 1. a subscriber whose consumer replies the result of each request
+//! 2. an rpc client publishes requests (direct reply-to or exclusive queue), and awaits replies
+
+use std::time::Duration;
+
+use amqprs::channel::*;
+use amqprs::BasicProperties;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.rpc";
+const QUE: &str = "pqx.test.que.rpc";
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct AddMsg {
+    a: i64,
+    b: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct AddReply {
+    sum: i64,
+}
+
+#[derive(Clone)]
+struct AddConsumer;
+
+#[async_trait]
+impl Consumer<AddMsg, i64> for AddConsumer {
+    async fn consume(&mut self, message: &AddMsg) -> PqxResult<ConsumerResult<i64>> {
+        Ok(ConsumerResult::success(message.a + message.b))
+    }
+
+    fn gen_reply(&self, outcome: Outcome<'_, AddMsg, i64>) -> Option<Value> {
+        match outcome {
+            Outcome::Success(_, sum) => serde_json::to_value(AddReply { sum: *sum }).ok(),
+            _ => None,
+        }
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+async fn connect() -> MqClient {
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    client
+}
+
+async fn rpc_success(mode: ReplyMode) {
+    // 0. declare exchange & queue
+    let server = connect().await;
+    let res = server.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let res = server.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 1. server side
+    let mut subscriber = Subscriber::new(server.channel().unwrap(), AddConsumer);
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    // 2. client side
+    let client = connect().await;
+    let rpc = RpcClient::new(client.channel().unwrap(), mode)
+        .await
+        .unwrap();
+    println!("reply_to: {}", rpc.reply_to());
+
+    let timeout = Duration::from_secs(5);
+    let props_list = vec![BasicProperties::default(); 3];
+    let futs = rpc
+        .request::<_, AddReply>(EXCHG, ROUT, &AddMsg { a: 1, b: 2 }, props_list, timeout)
+        .await
+        .unwrap();
+    assert_eq!(futs.len(), 3);
+    for fut in futs {
+        let res = fut.await;
+        println!("{:?}", res);
+        assert_eq!(res.unwrap(), AddReply { sum: 3 });
+    }
+
+    let res: AddReply = rpc
+        .call(
+            EXCHG,
+            ROUT,
+            &AddMsg { a: 2, b: 3 },
+            BasicProperties::default(),
+            timeout,
+        )
+        .await
+        .unwrap();
+    assert_eq!(res, AddReply { sum: 5 });
+
+    let res = rpc.close().await;
+    assert!(res.is_ok());
+    let res = subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
+
+#[tokio::test]
+async fn rpc_direct_reply_to_success() {
+    /*
+    cargo test --package pqx --test test_rpc -- rpc_direct_reply_to_success --exact --nocapture
+     */
+
+    rpc_success(ReplyMode::DirectReplyTo).await;
+}
+
+#[tokio::test]
+async fn rpc_exclusive_queue_success() {
+    /*
+    cargo test --package pqx --test test_rpc -- rpc_exclusive_queue_success --exact --nocapture
+     */
+
+    rpc_success(ReplyMode::ExclusiveQueue).await;
+}
+
+#[tokio::test]
+async fn rpc_timeout_success() {
+    /*
+    cargo test --package pqx --test test_rpc -- rpc_timeout_success --exact --nocapture
+     */
+
+    // no subscriber on this routing key
+    let client = connect().await;
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let rpc = RpcClient::new(client.channel().unwrap(), ReplyMode::DirectReplyTo)
+        .await
+        .unwrap();
+
+    let res = rpc
+        .call::<_, AddReply>(
+            EXCHG,
+            "pqx.test.nobody",
+            &AddMsg { a: 1, b: 1 },
+            BasicProperties::default(),
+            Duration::from_millis(500),
+        )
+        .await;
+    assert!(res.is_err());
+}
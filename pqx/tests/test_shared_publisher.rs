@@ -0,0 +1,155 @@
+//! file: test_shared_publisher.rs
+//! author: Jacob Xie
+//! date: 2023/07/17 22:12:08 Monday
+//! brief: test concurrent publishing by a pooled publisher
+//! process:
+//! 1. a channel pool of 4 channels, shared by 32 tokio tasks publishing concurrently
+//! 2. a publish to a missing exchange makes the broker close its channel
+//! 3. the closed channel is replaced, and all messages arrive
+//! 4. with confirms, a publish closing its channel fails instead of being lost silently
+
+use std::time::Duration;
+
+use amqprs::channel::*;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.shared";
+const QUE: &str = "pqx.test.que.shared";
+const MISSING_EXCHG: &str = "pqx.test.missing";
+
+const POOL_SIZE: usize = 4;
+const TASKS: usize = 32;
+const MSGS_PER_TASK: usize = 10;
+
+// ================================================================================================
+// msg
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    task: usize,
+    seq: usize,
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn shared_publisher_success() {
+    /*
+    cargo test --package pqx --test test_shared_publisher -- shared_publisher_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(QUE).await;
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 1. concurrent publishing
+    let res = client.open_channel_pool(POOL_SIZE);
+    assert!(res.is_ok());
+    let publisher = client.shared_publisher().unwrap();
+
+    let handles = (0..TASKS).map(|task| {
+        let publisher = publisher.clone();
+        tokio::spawn(async move {
+            for seq in 0..MSGS_PER_TASK {
+                publisher
+                    .publish(EXCHG, ROUT, DevMsg { task, seq })
+                    .await
+                    .unwrap();
+            }
+        })
+    });
+    for h in futures::future::join_all(handles).await {
+        assert!(h.is_ok());
+    }
+    assert!(publisher.pool().idle() <= POOL_SIZE);
+
+    // 2. the broker closes the channel publishing to a missing exchange
+    let res = publisher
+        .publish(MISSING_EXCHG, ROUT, DevMsg { task: 0, seq: 0 })
+        .await;
+    assert!(res.is_ok());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // 3. the closed channel is replaced
+    let res = publisher
+        .publish(
+            EXCHG,
+            ROUT,
+            DevMsg {
+                task: TASKS,
+                seq: 0,
+            },
+        )
+        .await;
+    assert!(res.is_ok());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let info = client
+        .declare_and_bind_queue(EXCHG, ROUT, QUE)
+        .await
+        .unwrap();
+    println!("{:?}", info);
+    assert_eq!(info.message_count as usize, TASKS * MSGS_PER_TASK + 1);
+
+    let res = client.disconnect().await;
+    assert!(res.is_ok());
+}
+
+#[tokio::test]
+async fn shared_publisher_confirm_success() {
+    /*
+    cargo test --package pqx --test test_shared_publisher -- shared_publisher_confirm_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    let res = client.open_channel_pool(POOL_SIZE);
+    assert!(res.is_ok());
+    let mut publisher = client.shared_publisher().unwrap();
+    let res = publisher.enable_confirm().await;
+    assert!(res.is_ok());
+
+    // 4. confirmed, then a channel closed by the broker fails the publish (retried once)
+    let res = publisher
+        .publish(EXCHG, ROUT, DevMsg { task: 0, seq: 0 })
+        .await;
+    assert!(res.is_ok());
+    let res = publisher
+        .publish(MISSING_EXCHG, ROUT, DevMsg { task: 0, seq: 1 })
+        .await;
+    assert!(res.is_err());
+    let res = publisher
+        .publish(EXCHG, ROUT, DevMsg { task: 0, seq: 2 })
+        .await;
+    assert!(res.is_ok());
+
+    let res = client.disconnect().await;
+    assert!(res.is_ok());
+}
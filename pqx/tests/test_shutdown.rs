@@ -0,0 +1,140 @@
+//! file: test_shutdown.rs
+//! author: Jacob Xie
+//! date: 2023/07/15 10:26:09 Saturday
+//! brief: test graceful shutdown
+//! process:
+//! 1. a subscriber whose consumer takes a while for each message
+//! 2. shutdown while a message is in flight
+//! 3. the in-flight message is finished and acked, the rest stays in the queue
+
+use std::time::Duration;
+
+use amqprs::channel::*;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.shutdown";
+const QUE: &str = "pqx.test.que.shutdown";
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    seq: usize,
+    secs: u64,
+}
+
+#[derive(Clone)]
+struct SleepConsumer;
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for SleepConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        println!("start: {:?}", message);
+        tokio::time::sleep(Duration::from_secs(message.secs)).await;
+        println!("end: {:?}", message);
+        Ok(ConsumerResult::success(()))
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+async fn setup(client: &mut MqClient) {
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(QUE).await;
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+}
+
+async fn publish(client: &MqClient, secs: u64, n: usize) {
+    let publisher = Publisher::new(client.channel().unwrap());
+    for seq in 0..n {
+        let res = publisher.publish(EXCHG, ROUT, DevMsg { seq, secs }).await;
+        assert!(res.is_ok());
+    }
+}
+
+#[tokio::test]
+async fn shutdown_drain_success() {
+    /*
+    cargo test --package pqx --test test_shutdown -- shutdown_drain_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    setup(&mut client).await;
+
+    let mut subscriber = Subscriber::new(client.channel().unwrap(), SleepConsumer);
+    let res = subscriber.set_prefetch(0, 1, false).await;
+    assert!(res.is_ok());
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    // 3 messages, 2 seconds each
+    publish(&client, 2, 3).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(subscriber.in_flight(), 1);
+
+    // the first one is finished, the other two are left
+    let res = subscriber.shutdown(Duration::from_secs(5)).await;
+    assert!(matches!(res, Ok(true)));
+    assert_eq!(subscriber.in_flight(), 0);
+
+    drop(subscriber);
+    let res = client.disconnect().await;
+    assert!(res.is_ok());
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    client.connect_by_yaml(pth.to_str().unwrap()).await.unwrap();
+    client.open_channel(None).await.unwrap();
+    let (_, message_count, _) = client
+        .channel()
+        .unwrap()
+        .queue_declare(QueueDeclareArguments::new(QUE).passive(true).finish())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(message_count, 2);
+}
+
+#[tokio::test]
+async fn shutdown_timeout_success() {
+    /*
+    cargo test --package pqx --test test_shutdown -- shutdown_timeout_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    setup(&mut client).await;
+
+    let mut subscriber = Subscriber::new(client.channel().unwrap(), SleepConsumer);
+    let res = subscriber.set_prefetch(0, 1, false).await;
+    assert!(res.is_ok());
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    publish(&client, 10, 1).await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // not drained, the message is requeued once the channel is closed
+    let res = subscriber.shutdown(Duration::from_secs(1)).await;
+    assert!(matches!(res, Ok(false)));
+    assert_eq!(subscriber.in_flight(), 1);
+}
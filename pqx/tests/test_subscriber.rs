@@ -122,7 +122,7 @@ async fn mq_subscribe_success() {
     let res = subscriber.consume(QUE).await;
     assert!(res.is_ok());
 
-    println!("Start listening on {}:{} ...", "HOST", "PORT");
+    println!("Start listening on HOST:PORT ...");
 
     // 7. block
     subscriber.block().await;
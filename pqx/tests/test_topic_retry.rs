@@ -0,0 +1,147 @@
+//! file: test_topic_retry.rs
+//! author: Jacob Xie
+//! date: 2023/07/24 15:32:09 Monday
+//! brief: test retries of messages routed by a topic exchange
+//! process:
+//! 1. declare a topic exchange with two queues (`*.cn` & `*.us`), and a wait queue (1s) which
+//!    dead-letters back to the topic exchange
+//! 2. subscribe both queues by a consumer that asks for retry once, keeping the routing key
+//! 3. publish a message with a `cn` routing key
+//! 4. the `cn` queue receives it twice, the `us` queue never
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use amqprs::channel::*;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.topic.retry";
+const ROUT: &str = "pqx.test.daily.cn";
+
+const CN_PATTERN: &str = "pqx.test.*.cn";
+const CN_QUE: &str = "pqx.test.que.topic.cn";
+const US_PATTERN: &str = "pqx.test.*.us";
+const US_QUE: &str = "pqx.test.que.topic.us";
+
+const WAIT_EXCHG: &str = "pqx.test.topic.retry.wait";
+const WAIT_TIERS: [u32; 1] = [1];
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    data: String,
+}
+
+#[derive(Clone)]
+struct RetryOnceConsumer(Arc<AtomicUsize>);
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for RetryOnceConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        println!("consume: {:?}", message);
+
+        if self.0.fetch_add(1, Ordering::SeqCst) == 0 {
+            Ok(ConsumerResult::retry(None))
+        } else {
+            Ok(ConsumerResult::success(()))
+        }
+    }
+
+    fn gen_retry(&self, _message: &DevMsg) -> Option<Retry> {
+        let mut retry = Retry::new(
+            RetryBackend::wait_queues(WAIT_EXCHG, WAIT_TIERS),
+            "",
+            RetryPolicy::fixed(1),
+            2,
+        );
+        retry.keep_routing_key();
+
+        Some(retry)
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn topic_retry_success() {
+    /*
+    cargo test --package pqx --test test_topic_retry -- topic_retry_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    // 1. topic exchange, work & wait queues
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Topic).await;
+    assert!(res.is_ok());
+    for (pattern, que) in [(CN_PATTERN, CN_QUE), (US_PATTERN, US_QUE)] {
+        let _ = client.delete_queue(que).await;
+        let res = client.declare_and_bind_queue(EXCHG, pattern, que).await;
+        assert!(res.is_ok());
+    }
+    let res = client
+        .declare_exchange(WAIT_EXCHG, &ExchangeType::Headers)
+        .await;
+    assert!(res.is_ok());
+    for tier in WAIT_TIERS {
+        let que = RetryBackend::wait_queue_name(WAIT_EXCHG, tier);
+        let res = client
+            .declare_wait_queue(&que, EXCHG, i64::from(tier) * 1000)
+            .await;
+        assert!(res.is_ok());
+
+        let mut args = QueueBindArguments::new(&que, WAIT_EXCHG, "");
+        let mut headers = FieldTableBuilder::new();
+        headers.x_match(&MatchType::AllWithX).x_wait(tier.into());
+        args.arguments(headers.finish());
+        let res = client.bind_queue_by_args(args).await;
+        assert!(res.is_ok());
+    }
+
+    // 2. subscribe
+    let chan = client.channel().unwrap();
+    let cn_count = Arc::new(AtomicUsize::new(0));
+    let mut cn_subscriber = Subscriber::new(chan, RetryOnceConsumer(cn_count.clone()));
+    let res = cn_subscriber.consume(CN_QUE).await;
+    assert!(res.is_ok());
+    let us_count = Arc::new(AtomicUsize::new(0));
+    let mut us_subscriber = Subscriber::new(chan, RetryOnceConsumer(us_count.clone()));
+    let res = us_subscriber.consume(US_QUE).await;
+    assert!(res.is_ok());
+
+    // 3. publish
+    let publisher = Publisher::new(chan);
+    let msg = DevMsg {
+        data: "daily".to_string(),
+    };
+    let res = publisher.publish(EXCHG, ROUT, msg).await;
+    assert!(res.is_ok());
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    // 4. the retry is routed by the same key
+    assert_eq!(cn_count.load(Ordering::SeqCst), 2);
+    assert_eq!(us_count.load(Ordering::SeqCst), 0);
+
+    let res = cn_subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+    let res = us_subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
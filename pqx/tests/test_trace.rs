@@ -0,0 +1,142 @@
+//! file: test_trace.rs
+//! author: Jacob Xie
+//! date: 2023/07/22 16:20:47 Saturday
+//! brief: test trace context propagated through AMQP headers
+//! process:
+//! 1. declare a work queue, and a wait queue (1s) which dead-letters back to it
+//! 2. a subscriber whose consumer asks for retry once, recording the context of each delivery
+//! 3. publish a message under a root context
+//! 4. both deliveries are spans of the root's trace, each with its own span id
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use amqprs::channel::*;
+use amqprs::BasicProperties;
+use async_trait::async_trait;
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.trace";
+const QUE: &str = "pqx.test.que.trace";
+
+const WAIT_EXCHG: &str = "pqx.test.trace.wait";
+const WAIT_TIERS: [u32; 1] = [1];
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    data: String,
+}
+
+#[derive(Clone)]
+struct TraceConsumer(Arc<Mutex<Vec<TraceContext>>>);
+
+#[async_trait]
+impl Consumer<DevMsg, ()> for TraceConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<()>> {
+        let ctx = TraceContext::current().unwrap();
+        println!("consume: {:?}, traceparent: {}", message, ctx);
+
+        let mut contexts = self.0.lock().unwrap();
+        contexts.push(ctx);
+        if contexts.len() == 1 {
+            Ok(ConsumerResult::retry(None))
+        } else {
+            Ok(ConsumerResult::success(()))
+        }
+    }
+
+    fn gen_retry(&self, _message: &DevMsg) -> Option<Retry> {
+        Some(Retry::new(
+            RetryBackend::wait_queues(WAIT_EXCHG, WAIT_TIERS),
+            ROUT,
+            RetryPolicy::fixed(1),
+            1,
+        ))
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn trace_success() {
+    /*
+    cargo test --package pqx --test test_trace -- trace_success --exact --nocapture
+     */
+
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    // 1. work & wait queues
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let _ = client.delete_queue(QUE).await;
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+    let res = client
+        .declare_exchange(WAIT_EXCHG, &ExchangeType::Headers)
+        .await;
+    assert!(res.is_ok());
+    for tier in WAIT_TIERS {
+        let que = RetryBackend::wait_queue_name(WAIT_EXCHG, tier);
+        let res = client
+            .declare_wait_queue(&que, EXCHG, i64::from(tier) * 1000)
+            .await;
+        assert!(res.is_ok());
+
+        let mut args = QueueBindArguments::new(&que, WAIT_EXCHG, "");
+        let mut headers = FieldTableBuilder::new();
+        headers.x_match(&MatchType::AllWithX).x_wait(tier.into());
+        args.arguments(headers.finish());
+        let res = client.bind_queue_by_args(args).await;
+        assert!(res.is_ok());
+    }
+
+    // 2. subscribe
+    let chan = client.channel().unwrap();
+    let contexts = Arc::new(Mutex::new(Vec::new()));
+    let mut subscriber = Subscriber::new(chan, TraceConsumer(contexts.clone()));
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+
+    // 3. publish under a root context
+    let root = TraceContext::new_root();
+    let mut props = BasicProperties::default();
+    root.inject(&mut props);
+    let publisher = Publisher::new(chan);
+    let msg = DevMsg {
+        data: "traced".to_string(),
+    };
+    let res = publisher.publish_with_props(EXCHG, ROUT, msg, props).await;
+    assert!(res.is_ok());
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    // 4. the delivery and its retry are in the same trace
+    let contexts = contexts.lock().unwrap().clone();
+    assert_eq!(contexts.len(), 2);
+    for ctx in contexts.iter() {
+        assert_eq!(ctx.trace_id(), root.trace_id());
+        assert_ne!(ctx.span_id(), root.span_id());
+    }
+    assert_ne!(contexts[0].span_id(), contexts[1].span_id());
+
+    let res = subscriber.cancel_consume(false).await;
+    assert!(res.is_ok());
+}
@@ -0,0 +1,168 @@
+//! file: test_wait_retry.rs
+//! author: Jacob Xie
+//! date: 2023/07/09 16:02:11 Sunday
+//! brief: test retry without the `delayed_message_exchange` plugin
+//! process:
+//! 1. declare a direct exchange and a work queue
+//! 2. declare a headers wait exchange, and wait queues (with `x-message-ttl`) which dead-letter
+//!    back to the direct exchange
+//! 3. subscribe the work queue by a consumer that always asks for retry
+//! 4. publish message, and watch it coming back after each delay tier
+
+use amqprs::channel::*;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use pqx::error::PqxResult;
+use pqx::mq::*;
+use pqx_util::get_cur_dir_file;
+use serde::{Deserialize, Serialize};
+
+// ================================================================================================
+// const
+// ================================================================================================
+
+const EXCHG: &str = "pqx.test.direct";
+const ROUT: &str = "pqx.test.rout";
+const QUE: &str = "pqx.test.que";
+
+const WAIT_EXCHG: &str = "pqx.test.wait";
+const WAIT_TIERS: [u32; 2] = [2, 5];
+
+// ================================================================================================
+// msg & consumer
+// ================================================================================================
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DevMsg {
+    data: String,
+    time: DateTime<Local>,
+}
+
+impl DevMsg {
+    fn new(data: &str) -> Self {
+        Self {
+            data: data.to_string(),
+            time: Local::now(),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct RetryConsumer;
+
+#[async_trait]
+impl Consumer<DevMsg, String> for RetryConsumer {
+    async fn consume(&mut self, message: &DevMsg) -> PqxResult<ConsumerResult<String>> {
+        println!("{} consume: {:?}", Local::now(), message);
+
+        Ok(ConsumerResult::retry(Some("again".to_string())))
+    }
+
+    fn gen_retry(&self, _message: &DevMsg) -> Option<Retry> {
+        Some(Retry::new(
+            RetryBackend::wait_queues(WAIT_EXCHG, WAIT_TIERS),
+            ROUT, // direct exchange, the original routing key is kept by dead-lettering
+            RetryPolicy::linear(2, 3, None),
+            4,
+        ))
+    }
+}
+
+// ================================================================================================
+// test
+// ================================================================================================
+
+#[tokio::test]
+async fn declare_wait_queues_success() {
+    /*
+    cargo test --package pqx --test test_wait_retry -- declare_wait_queues_success --exact --nocapture
+     */
+
+    // 0. client connection and open channel
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    // 1. work exchange & queue
+    let res = client.declare_exchange(EXCHG, &ExchangeType::Direct).await;
+    assert!(res.is_ok());
+    let res = client.declare_and_bind_queue(EXCHG, ROUT, QUE).await;
+    assert!(res.is_ok());
+
+    // 2. wait exchange & queues
+    let res = client
+        .declare_exchange(WAIT_EXCHG, &ExchangeType::Headers)
+        .await;
+    assert!(res.is_ok());
+    for tier in WAIT_TIERS {
+        let que = RetryBackend::wait_queue_name(WAIT_EXCHG, tier);
+        let res = client
+            .declare_wait_queue(&que, EXCHG, i64::from(tier) * 1000)
+            .await;
+        assert!(res.is_ok());
+
+        let mut args = QueueBindArguments::new(&que, WAIT_EXCHG, "");
+        let mut headers = FieldTableBuilder::new();
+        headers.x_match(&MatchType::AllWithX).x_wait(tier.into());
+        args.arguments(headers.finish());
+        let res = client.bind_queue_by_args(args).await;
+        assert!(res.is_ok());
+    }
+}
+
+#[tokio::test]
+async fn mq_subscribe_success() {
+    /*
+    cargo test --package pqx --test test_wait_retry -- mq_subscribe_success --exact --nocapture
+     */
+
+    // 0. client connection and open channel
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    // 1. subscriber
+    let mut subscriber = Subscriber::new(client.channel().unwrap(), RetryConsumer);
+    let res = subscriber.set_prefetch(0, 1, false).await;
+    assert!(res.is_ok());
+
+    // 2. consume: expect redelivery after 2s, 5s, 5s, then dropped (retries: 4)
+    let res = subscriber.consume(QUE).await;
+    assert!(res.is_ok());
+    println!("Start listening...");
+
+    subscriber.soft_fail_block().await;
+}
+
+#[tokio::test]
+async fn mq_publish_success() {
+    /*
+    cargo test --package pqx --test test_wait_retry -- mq_publish_success --exact --nocapture
+     */
+
+    // 0. client connection and open channel
+    let mut client = MqClient::new();
+    let pth = get_cur_dir_file("conn.yml").unwrap();
+    let res = client.connect_by_yaml(pth.to_str().unwrap()).await;
+    assert!(res.is_ok());
+    let res = client.open_channel(None).await;
+    assert!(res.is_ok());
+
+    // 1. publisher
+    let publisher = Publisher::new(client.channel().unwrap());
+
+    // 2. send msg
+    let res = publisher
+        .publish(EXCHG, ROUT, DevMsg::new("retry me"))
+        .await;
+    assert!(res.is_ok());
+
+    // 3. block until msg has been sent
+    publisher.block(1).await;
+}